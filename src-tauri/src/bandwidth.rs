@@ -0,0 +1,143 @@
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use directories::ProjectDirs;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // Bytes counted since the last flush. Updated synchronously from the progress
+    // reader/writer, so this is a std mutex held only for a couple of additions.
+    static ref PENDING: std::sync::Mutex<BandwidthStats> = std::sync::Mutex::new(BandwidthStats::default());
+    static ref FLUSH_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyUsage {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+// Cumulative traffic, persisted as bandwidth.json next to config.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BandwidthStats {
+    pub total_uploaded: u64,
+    pub total_downloaded: u64,
+    pub daily: BTreeMap<String, DailyUsage>,  // Keyed by local date, YYYY-MM-DD
+}
+
+impl BandwidthStats {
+    fn add(&mut self, day: &str, direction: Direction, bytes: u64) {
+        let usage = self.daily.entry(day.to_string()).or_default();
+        match direction {
+            Direction::Upload => {
+                self.total_uploaded += bytes;
+                usage.uploaded += bytes;
+            }
+            Direction::Download => {
+                self.total_downloaded += bytes;
+                usage.downloaded += bytes;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &BandwidthStats) {
+        self.total_uploaded += other.total_uploaded;
+        self.total_downloaded += other.total_downloaded;
+        for (day, usage) in &other.daily {
+            let entry = self.daily.entry(day.clone()).or_default();
+            entry.uploaded += usage.uploaded;
+            entry.downloaded += usage.downloaded;
+        }
+    }
+}
+
+fn get_stats_path() -> Result<PathBuf> {
+    let data_dir = ProjectDirs::from("com", "tvault", "t-vault")
+        .ok_or_else(|| anyhow::anyhow!("Failed to get data directory"))?
+        .data_dir()
+        .to_path_buf();
+
+    Ok(data_dir.join("bandwidth.json"))
+}
+
+async fn read_from_disk() -> Result<BandwidthStats> {
+    let stats_path = get_stats_path()?;
+
+    if !stats_path.exists() {
+        return Ok(BandwidthStats::default());
+    }
+
+    let content = tokio::fs::read_to_string(&stats_path).await
+        .context("Failed to read bandwidth stats")?;
+
+    serde_json::from_str(&content).context("Failed to parse bandwidth stats")
+}
+
+async fn write_to_disk(stats: &BandwidthStats) -> Result<()> {
+    let stats_path = get_stats_path()?;
+
+    if let Some(parent) = stats_path.parent() {
+        tokio::fs::create_dir_all(parent).await
+            .context("Failed to create data directory")?;
+    }
+
+    let content = serde_json::to_string_pretty(stats)
+        .context("Failed to serialize bandwidth stats")?;
+
+    tokio::fs::write(&stats_path, content).await
+        .context("Failed to write bandwidth stats")
+}
+
+// Count bytes that went over the wire. Cheap enough to call from poll_read/poll_write.
+pub fn record(direction: Direction, bytes: u64) {
+    if bytes == 0 {
+        return;
+    }
+    let day = chrono::Local::now().format("%Y-%m-%d").to_string();
+    if let Ok(mut pending) = PENDING.lock() {
+        pending.add(&day, direction, bytes);
+    }
+}
+
+fn take_pending() -> BandwidthStats {
+    PENDING.lock()
+        .map(|mut pending| std::mem::take(&mut *pending))
+        .unwrap_or_default()
+}
+
+// Persist everything recorded since the last flush
+pub async fn flush() -> Result<()> {
+    let _guard = FLUSH_LOCK.lock().await;
+
+    let pending = take_pending();
+    if pending.total_uploaded == 0 && pending.total_downloaded == 0 {
+        return Ok(());
+    }
+
+    let mut stats = read_from_disk().await.unwrap_or_else(|e| {
+        eprintln!("Warning: Failed to load bandwidth stats, starting over: {}", e);
+        BandwidthStats::default()
+    });
+    stats.merge(&pending);
+    write_to_disk(&stats).await
+}
+
+pub async fn get_stats() -> Result<BandwidthStats> {
+    flush().await?;
+    read_from_disk().await
+}
+
+pub async fn reset() -> Result<()> {
+    let _guard = FLUSH_LOCK.lock().await;
+    take_pending();
+    write_to_disk(&BandwidthStats::default()).await
+}
@@ -0,0 +1,389 @@
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use directories::ProjectDirs;
+use lazy_static::lazy_static;
+use tokio::sync::RwLock;
+
+lazy_static! {
+    static ref CONFIG_CACHE: RwLock<Option<AppConfig>> = RwLock::new(None);
+    // Serializes read-modify-write updates so two settings changes can't drop each other
+    static ref CONFIG_UPDATE: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+const PHONE_HASH_PREFIX: &str = "sha256:";
+
+fn hash_phone(phone: &str) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{}{:x}", PHONE_HASH_PREFIX, Sha256::digest(phone.trim().as_bytes()))
+}
+
+// 0 leaves the cap to available memory; anything else has to fit at least one part
+fn validate_download_buffer(bytes: u64) -> Result<()> {
+    let min = crate::storage::MAX_TRANSFER_CHUNK_SIZE;
+    if bytes != 0 && bytes < min {
+        return Err(anyhow::anyhow!("must be 0 (automatic) or at least {} bytes", min));
+    }
+    Ok(())
+}
+
+// User-tunable settings, persisted as config.json next to the metadata.
+// Missing fields fall back to their defaults so older config files keep loading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub max_dialogs_to_search: usize,     // First pass when resolving a chat by id
+    pub max_dialogs_second_pass: usize,   // Extended pass before giving up
+    pub caption_prefix: String,           // Shown before the file name in upload captions
+    pub transfer_chunk_size: u64,         // Download part size in bytes; see storage::validate_chunk_size
+    pub last_phone: Option<String>,       // Last login phone, plain or "sha256:<hex>" when hashed
+    pub hash_last_phone: bool,            // Privacy: keep only a hash of the phone (no pre-fill)
+    pub encryption_stream_threshold: u64, // Encrypted uploads this size and up are streamed
+    pub session_path: Option<String>,     // Alternate .session file inside the data dir (None = default)
+    pub max_concurrent_downloads: usize,  // Transfers running at once across all commands
+    pub max_concurrent_uploads: usize,
+    pub batch_max_retries: u32,           // Retries one batch may spend before it pauses
+    pub batch_max_flood_wait_secs: u64,   // Flood-wait seconds one batch may spend before it pauses
+    pub generate_thumbnails: bool,        // Attach a locally made preview to plain image/video uploads
+    pub stale_connection_secs: u64,       // Idle time after which the connection is checked before use
+    pub upload_delay_enabled: bool,       // Pause between uploads; see transfers::next_upload_delay
+    pub upload_delay_min_ms: u64,
+    pub upload_delay_max_ms: u64,
+    pub follow_symlinks: bool,            // Upload what a symlink points to (false = reject links)
+    pub root_chat_id: Option<i64>,        // Channel for root uploads (None = Saved Messages)
+    pub maintenance_interval_mins: u64,   // Cache and temp file cleanup in the background (0 = off)
+    pub thumbnail_cache_max_bytes: u64,   // Generated previews beyond this are pruned, oldest first
+    pub max_vault_bytes: u64,             // Uploads that would grow the vault past this are refused (0 = no limit)
+    pub max_download_buffer_bytes: u64,   // Parts held in memory across parallel downloads (0 = share of free memory)
+}
+
+// The config as the app actually uses it, plus where it keeps its data
+#[derive(Debug, Clone, Serialize)]
+pub struct EffectiveConfig {
+    #[serde(flatten)]
+    pub settings: AppConfig,
+    pub data_dir: String,
+}
+
+// A partial settings change: only the fields present are applied.
+// The login phone and the root channel aren't here; they have their own commands because
+// changing them has side effects.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ConfigUpdate {
+    pub max_dialogs_to_search: Option<usize>,
+    pub max_dialogs_second_pass: Option<usize>,
+    pub caption_prefix: Option<String>,
+    pub transfer_chunk_size: Option<u64>,
+    pub encryption_stream_threshold: Option<u64>,
+    pub session_path: Option<String>,  // "" resets to the default session file
+    pub max_concurrent_downloads: Option<usize>,
+    pub max_concurrent_uploads: Option<usize>,
+    pub batch_max_retries: Option<u32>,
+    pub batch_max_flood_wait_secs: Option<u64>,
+    pub generate_thumbnails: Option<bool>,
+    pub stale_connection_secs: Option<u64>,
+    pub upload_delay_enabled: Option<bool>,
+    pub upload_delay_min_ms: Option<u64>,
+    pub upload_delay_max_ms: Option<u64>,
+    pub follow_symlinks: Option<bool>,
+    pub maintenance_interval_mins: Option<u64>,
+    pub thumbnail_cache_max_bytes: Option<u64>,
+    pub max_vault_bytes: Option<u64>,
+    pub max_download_buffer_bytes: Option<u64>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            max_dialogs_to_search: 50,
+            max_dialogs_second_pass: 500,
+            caption_prefix: crate::storage::DEFAULT_CAPTION_PREFIX.to_string(),
+            transfer_chunk_size: crate::storage::MAX_TRANSFER_CHUNK_SIZE,
+            last_phone: None,
+            hash_last_phone: false,
+            encryption_stream_threshold: crate::encryption::DEFAULT_STREAM_THRESHOLD,
+            session_path: None,
+            max_concurrent_downloads: 3,
+            max_concurrent_uploads: 2,
+            batch_max_retries: 20,
+            batch_max_flood_wait_secs: 15 * 60,
+            generate_thumbnails: true,
+            stale_connection_secs: 60,
+            upload_delay_enabled: true,
+            upload_delay_min_ms: 0,
+            upload_delay_max_ms: 30_000,
+            follow_symlinks: true,
+            root_chat_id: None,
+            maintenance_interval_mins: 60,
+            thumbnail_cache_max_bytes: 200 * 1024 * 1024,
+            max_vault_bytes: 0,
+            max_download_buffer_bytes: 0,
+        }
+    }
+}
+
+impl AppConfig {
+    // The configured chunk size, or the default if a hand-edited config holds an invalid one
+    pub fn transfer_chunk_size(&self) -> u64 {
+        if crate::storage::validate_chunk_size(self.transfer_chunk_size).is_ok() {
+            self.transfer_chunk_size
+        } else {
+            crate::storage::MAX_TRANSFER_CHUNK_SIZE
+        }
+    }
+
+    // The download buffer cap in bytes, worked out from available memory when unset.
+    // Never below one part, so a download can always make progress.
+    pub fn max_download_buffer_bytes(&self) -> u64 {
+        match self.max_download_buffer_bytes {
+            0 => crate::transfers::auto_download_buffer_bytes(),
+            bytes => bytes.max(crate::storage::MAX_TRANSFER_CHUNK_SIZE),
+        }
+    }
+
+    // (min, max) pause between uploads; a hand-edited min above the max gives way to the max
+    pub fn upload_delay_bounds(&self) -> (u64, u64) {
+        (self.upload_delay_min_ms.min(self.upload_delay_max_ms), self.upload_delay_max_ms)
+    }
+
+    // The last login phone, if it was stored in plain form
+    pub fn last_phone(&self) -> Option<String> {
+        self.last_phone.clone().filter(|p| !p.starts_with(PHONE_HASH_PREFIX))
+    }
+
+    // Remember the phone a code was sent to, honouring the privacy flag
+    pub async fn remember_phone(phone: &str) -> Result<()> {
+        Self::modify(|config| {
+            config.last_phone = Some(if config.hash_last_phone {
+                hash_phone(phone)
+            } else {
+                phone.to_string()
+            });
+        }).await.map(|_| ())
+    }
+
+    // Point root uploads at a channel, or back at Saved Messages with None
+    pub async fn set_root_chat_id(chat_id: Option<i64>) -> Result<()> {
+        Self::modify(|config| config.root_chat_id = chat_id).await.map(|_| ())
+    }
+
+    // Change some settings and save them, without racing any other change
+    pub async fn modify(change: impl FnOnce(&mut Self)) -> Result<Self> {
+        let _update = CONFIG_UPDATE.lock().await;
+        let mut config = Self::load().await;
+        change(&mut config);
+        config.write().await?;
+        Ok(config)
+    }
+
+    // Apply `update` (all fields or none) and save. Every invalid field is reported,
+    // one "field: problem" per line.
+    pub async fn update(update: ConfigUpdate) -> Result<Self> {
+        let _update = CONFIG_UPDATE.lock().await;
+        let mut config = Self::load().await;
+        let mut errors = Vec::new();
+
+        if let Some(limit) = update.max_dialogs_to_search {
+            config.max_dialogs_to_search = limit;
+        }
+        if let Some(limit) = update.max_dialogs_second_pass {
+            config.max_dialogs_second_pass = limit;
+        }
+        if config.max_dialogs_to_search == 0 {
+            errors.push("max_dialogs_to_search: must be at least 1".to_string());
+        } else if config.max_dialogs_second_pass < config.max_dialogs_to_search {
+            errors.push("max_dialogs_second_pass: must not be lower than max_dialogs_to_search".to_string());
+        }
+
+        if let Some(prefix) = update.caption_prefix {
+            if prefix.contains('\n') {
+                errors.push("caption_prefix: must be a single line".to_string());
+            }
+            config.caption_prefix = prefix;
+        }
+
+        if let Some(chunk_size) = update.transfer_chunk_size {
+            if let Err(e) = crate::storage::validate_chunk_size(chunk_size) {
+                errors.push(format!("transfer_chunk_size: {}", e));
+            }
+            config.transfer_chunk_size = chunk_size;
+        }
+
+        if let Some(threshold) = update.encryption_stream_threshold {
+            config.encryption_stream_threshold = threshold;
+        }
+
+        if let Some(path) = update.session_path {
+            let path = Some(path.trim().to_string()).filter(|p| !p.is_empty());
+            if let Some(ref requested) = path {
+                if let Err(e) = crate::telegram::validate_session_path(requested).await {
+                    errors.push(format!("session_path: {}", e));
+                }
+            }
+            config.session_path = path;
+        }
+
+        let max = crate::transfers::MAX_CONCURRENT_TRANSFERS;
+        for (field, value, limit) in [
+            ("max_concurrent_downloads", update.max_concurrent_downloads, &mut config.max_concurrent_downloads),
+            ("max_concurrent_uploads", update.max_concurrent_uploads, &mut config.max_concurrent_uploads),
+        ] {
+            if let Some(value) = value {
+                if value == 0 || value > max {
+                    errors.push(format!("{}: must be between 1 and {}", field, max));
+                }
+                *limit = value;
+            }
+        }
+
+        if let Some(retries) = update.batch_max_retries {
+            if retries == 0 {
+                errors.push("batch_max_retries: must be at least 1".to_string());
+            }
+            config.batch_max_retries = retries;
+        }
+        if let Some(secs) = update.batch_max_flood_wait_secs {
+            if secs == 0 {
+                errors.push("batch_max_flood_wait_secs: must be at least 1".to_string());
+            }
+            config.batch_max_flood_wait_secs = secs;
+        }
+
+        if let Some(enabled) = update.generate_thumbnails {
+            config.generate_thumbnails = enabled;
+        }
+        if let Some(secs) = update.stale_connection_secs {
+            config.stale_connection_secs = secs;
+        }
+
+        if let Some(enabled) = update.upload_delay_enabled {
+            config.upload_delay_enabled = enabled;
+        }
+        if let Some(ms) = update.upload_delay_min_ms {
+            config.upload_delay_min_ms = ms;
+        }
+        if let Some(ms) = update.upload_delay_max_ms {
+            config.upload_delay_max_ms = ms;
+        }
+        if config.upload_delay_min_ms > config.upload_delay_max_ms {
+            errors.push("upload_delay_max_ms: must not be lower than upload_delay_min_ms".to_string());
+        }
+
+        if let Some(follow) = update.follow_symlinks {
+            config.follow_symlinks = follow;
+        }
+
+        if let Some(minutes) = update.maintenance_interval_mins {
+            config.maintenance_interval_mins = minutes;
+        }
+        if let Some(bytes) = update.thumbnail_cache_max_bytes {
+            config.thumbnail_cache_max_bytes = bytes;
+        }
+        if let Some(bytes) = update.max_vault_bytes {
+            config.max_vault_bytes = bytes;
+        }
+        if let Some(bytes) = update.max_download_buffer_bytes {
+            if let Err(e) = validate_download_buffer(bytes) {
+                errors.push(format!("max_download_buffer_bytes: {}", e));
+            }
+            config.max_download_buffer_bytes = bytes;
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow::anyhow!(errors.join("\n")));
+        }
+
+        config.write().await?;
+        Ok(config)
+    }
+
+    // The settings as they take effect, e.g. an invalid hand-edited chunk size shows as the default
+    pub async fn effective() -> Result<EffectiveConfig> {
+        let mut settings = Self::load().await;
+        settings.transfer_chunk_size = settings.transfer_chunk_size();
+        settings.last_phone = settings.last_phone();
+        for limit in [&mut settings.max_concurrent_downloads, &mut settings.max_concurrent_uploads] {
+            *limit = (*limit).clamp(1, crate::transfers::MAX_CONCURRENT_TRANSFERS);
+        }
+        (settings.upload_delay_min_ms, settings.upload_delay_max_ms) = settings.upload_delay_bounds();
+        settings.max_download_buffer_bytes = settings.max_download_buffer_bytes();
+
+        Ok(EffectiveConfig {
+            settings,
+            data_dir: Self::data_dir()?.to_string_lossy().to_string(),
+        })
+    }
+
+    fn data_dir() -> Result<PathBuf> {
+        Ok(ProjectDirs::from("com", "tvault", "t-vault")
+            .ok_or_else(|| anyhow::anyhow!("Failed to get data directory"))?
+            .data_dir()
+            .to_path_buf())
+    }
+
+    fn get_config_path() -> Result<PathBuf> {
+        Ok(Self::data_dir()?.join("config.json"))
+    }
+
+    // Load the config (cached after the first read). Falls back to defaults if missing or unreadable.
+    pub async fn load() -> Self {
+        if let Some(config) = CONFIG_CACHE.read().await.as_ref() {
+            return config.clone();
+        }
+
+        let config = match Self::read_from_disk().await {
+            Ok(Some(config)) => config,
+            Ok(None) => Self::default(),
+            Err(e) => {
+                eprintln!("Warning: Failed to load config, using defaults: {}", e);
+                Self::default()
+            }
+        };
+
+        *CONFIG_CACHE.write().await = Some(config.clone());
+        config
+    }
+
+    async fn read_from_disk() -> Result<Option<Self>> {
+        let config_path = Self::get_config_path()?;
+
+        if !config_path.exists() {
+            return Ok(None);
+        }
+
+        let content = tokio::fs::read_to_string(&config_path).await
+            .context("Failed to read config file")?;
+
+        let config: AppConfig = serde_json::from_str(&content)
+            .context("Failed to parse config file")?;
+
+        Ok(Some(config))
+    }
+
+    // Callers hold CONFIG_UPDATE, so nothing saved in between gets overwritten
+    async fn write(&self) -> Result<()> {
+        let config_path = Self::get_config_path()?;
+
+        // Ensure directory exists
+        if let Some(parent) = config_path.parent() {
+            tokio::fs::create_dir_all(parent).await
+                .context("Failed to create config directory")?;
+        }
+
+        let content = serde_json::to_string_pretty(self)
+            .context("Failed to serialize config")?;
+
+        // Write to a temporary file first so a crash can't leave a half-written config
+        let temp_path = config_path.with_extension(format!("json.{:016x}.tmp", rand::random::<u64>()));
+        tokio::fs::write(&temp_path, content).await
+            .context("Failed to write config file")?;
+        if let Err(e) = tokio::fs::rename(&temp_path, &config_path).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e).context("Failed to replace config file");
+        }
+
+        *CONFIG_CACHE.write().await = Some(self.clone());
+        Ok(())
+    }
+}
@@ -1,6 +1,17 @@
-// Encryption module - Ready for future encryption feature implementation
-// Currently unused but kept for when encryption support is added
-#![allow(dead_code)]
+// Client-side encryption of file contents.
+//
+// Every encrypted blob starts with a one-byte format marker:
+//
+//   0x01 FORMAT_ONESHOT  marker | nonce (12) | AES-256-GCM ciphertext + tag (16)
+//                        Written by `Encryptor::encrypt`. The whole file is held in memory,
+//                        so it is only used below the streaming threshold.
+//   0x02 FORMAT_STREAM   marker | nonce prefix (7) | frames
+//                        Written by `EncryptingReader`. The plaintext is cut into
+//                        STREAM_CHUNK_SIZE chunks, each sealed on its own with the nonce
+//                        prefix | big-endian u32 frame counter | last-frame flag.
+//                        Every frame but the last holds a full chunk, so the last one
+//                        (possibly empty) is recognised by its size and a truncated
+//                        file fails to decrypt instead of coming back short.
 
 use aes_gcm::{
     aead::{Aead, KeyInit},
@@ -9,48 +20,78 @@ use aes_gcm::{
 use rand::Rng;
 use sha2::{Sha256, Digest};
 use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use lazy_static::lazy_static;
 
+pub const FORMAT_ONESHOT: u8 = 0x01;
+pub const FORMAT_STREAM: u8 = 0x02;
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+pub const DEFAULT_STREAM_THRESHOLD: u64 = 8 * 1024 * 1024; // Files this size and up are streamed
+const NONCE_LEN: usize = 12;
+const STREAM_PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+const KDF_ROUNDS: u32 = 600_000;  // PBKDF2-HMAC-SHA256 iterations for new vaults
+const SALT_LEN: usize = 16;
+const CANARY: &[u8] = b"T-Vault key check";
+
+lazy_static! {
+    // Key of the unlocked vault. Only ever kept in memory.
+    static ref SESSION_KEY: std::sync::RwLock<Option<Encryptor>> = std::sync::RwLock::new(None);
+}
+
+#[derive(Clone)]
 pub struct Encryptor {
     cipher: Aes256Gcm,
 }
 
 impl Encryptor {
+    // Key as vaults from before KeyCheck derived it: unsalted SHA-256 of the password
     pub fn new(password: &str) -> Self {
         // Derive key from password
         let mut hasher = Sha256::new();
         hasher.update(password.as_bytes());
         let key = hasher.finalize();
-        
+
         let cipher = Aes256Gcm::new(&key);
-        
+
         Self { cipher }
     }
 
+    fn from_key(key: &[u8; 32]) -> Self {
+        Self { cipher: Aes256Gcm::new(key.into()) }
+    }
+
     pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
         // Generate random nonce
         let mut rng = rand::thread_rng();
-        let nonce_bytes: [u8; 12] = rng.gen();
+        let nonce_bytes: [u8; NONCE_LEN] = rng.gen();
         let nonce = Nonce::from_slice(&nonce_bytes);
 
         // Encrypt
         let ciphertext = self.cipher.encrypt(nonce, data)
             .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
 
-        // Prepend nonce to ciphertext
-        let mut result = nonce_bytes.to_vec();
+        // Prepend format marker and nonce to ciphertext
+        let mut result = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        result.push(FORMAT_ONESHOT);
+        result.extend_from_slice(&nonce_bytes);
         result.extend_from_slice(&ciphertext);
 
         Ok(result)
     }
 
     pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        if data.len() < 12 {
+        if data.len() < 1 + NONCE_LEN + TAG_LEN || data[0] != FORMAT_ONESHOT {
             return Err(anyhow::anyhow!("Invalid encrypted data"));
         }
 
         // Extract nonce and ciphertext
-        let nonce = Nonce::from_slice(&data[..12]);
-        let ciphertext = &data[12..];
+        let nonce = Nonce::from_slice(&data[1..1 + NONCE_LEN]);
+        let ciphertext = &data[1 + NONCE_LEN..];
 
         // Decrypt
         let plaintext = self.cipher.decrypt(nonce, ciphertext)
@@ -60,6 +101,321 @@ impl Encryptor {
     }
 }
 
+// How a vault's key comes from its password
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Kdf {
+    Sha256,        // Encryptor::new, for vaults that had encrypted files before the key check
+    Pbkdf2Sha256,
+}
+
+// Kept with the vault so a password is checked before anything is sealed with it: the
+// canary only decrypts under the right key. Neither the salt nor the canary is secret.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyCheck {
+    pub kdf: Kdf,
+    #[serde(default)]
+    pub salt: String,   // Base64, empty for Kdf::Sha256
+    #[serde(default)]
+    pub rounds: u32,
+    pub canary: String, // Base64 of CANARY sealed with the key
+}
+
+fn pbkdf2_key(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+impl KeyCheck {
+    // A fresh salted key for a vault without encrypted files yet
+    pub fn create(password: &str) -> Result<(Self, Encryptor)> {
+        Self::create_with_rounds(password, KDF_ROUNDS)
+    }
+
+    fn create_with_rounds(password: &str, rounds: u32) -> Result<(Self, Encryptor)> {
+        let salt: [u8; SALT_LEN] = rand::thread_rng().gen();
+        let encryptor = Encryptor::from_key(&pbkdf2_key(password, &salt, rounds));
+        let check = Self { kdf: Kdf::Pbkdf2Sha256, salt: STANDARD.encode(salt), rounds, canary: String::new() };
+        Ok((check.sealed_with(&encryptor)?, encryptor))
+    }
+
+    // A check for a key already in use with the unsalted derivation
+    pub fn legacy(encryptor: &Encryptor) -> Result<Self> {
+        Self { kdf: Kdf::Sha256, salt: String::new(), rounds: 0, canary: String::new() }.sealed_with(encryptor)
+    }
+
+    fn sealed_with(mut self, encryptor: &Encryptor) -> Result<Self> {
+        self.canary = STANDARD.encode(encryptor.encrypt(CANARY)?);
+        Ok(self)
+    }
+
+    // The key `password` gives, or an error if it isn't this vault's password
+    pub fn open(&self, password: &str) -> Result<Encryptor> {
+        let encryptor = match self.kdf {
+            Kdf::Sha256 => Encryptor::new(password),
+            Kdf::Pbkdf2Sha256 => {
+                let salt = STANDARD.decode(&self.salt)
+                    .map_err(|e| anyhow::anyhow!("Invalid key check salt: {}", e))?;
+                Encryptor::from_key(&pbkdf2_key(password, &salt, self.rounds))
+            }
+        };
+        if self.accepts(&encryptor) {
+            Ok(encryptor)
+        } else {
+            Err(anyhow::anyhow!("Wrong vault password"))
+        }
+    }
+
+    pub fn accepts(&self, encryptor: &Encryptor) -> bool {
+        STANDARD.decode(&self.canary).ok()
+            .and_then(|canary| encryptor.decrypt(&canary).ok())
+            .map_or(false, |plain| plain == CANARY)
+    }
+}
+
+// Use `encryptor` (from KeyCheck::open) for encrypted uploads and downloads until `lock` is called
+pub fn unlock(encryptor: Encryptor) {
+    if let Ok(mut key) = SESSION_KEY.write() {
+        *key = Some(encryptor);
+    }
+}
+
+pub fn lock() {
+    if let Ok(mut key) = SESSION_KEY.write() {
+        *key = None;
+    }
+}
+
+pub fn session_encryptor() -> Option<Encryptor> {
+    SESSION_KEY.read().ok().and_then(|key| key.clone())
+}
+
+// Whether `encryptor` holds the key unlocked right now (false while locked)
+pub fn is_session_key(encryptor: &Encryptor) -> bool {
+    let Some(current) = session_encryptor() else { return false };
+    current.encrypt(CANARY)
+        .map_or(false, |probe| encryptor.decrypt(&probe).is_ok())
+}
+
+// Files below the threshold are sealed in one piece, larger ones are streamed
+pub fn uses_streaming(plain_size: u64, threshold: u64) -> bool {
+    plain_size >= threshold
+}
+
+// Exact size of the encrypted blob, needed up front by the upload
+pub fn encrypted_size(plain_size: u64, threshold: u64) -> u64 {
+    if uses_streaming(plain_size, threshold) {
+        let frames = plain_size / STREAM_CHUNK_SIZE as u64 + 1;
+        1 + STREAM_PREFIX_LEN as u64 + plain_size + frames * TAG_LEN as u64
+    } else {
+        1 + NONCE_LEN as u64 + plain_size + TAG_LEN as u64
+    }
+}
+
+// Reader over the encrypted form of `reader`, in whichever format its size calls for.
+// Returns the reader and the exact number of bytes it will produce.
+pub async fn encrypted_reader<'a, R>(
+    encryptor: &Encryptor,
+    mut reader: R,
+    plain_size: u64,
+    threshold: u64,
+) -> Result<(Box<dyn AsyncRead + Unpin + Send + 'a>, u64)>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    if uses_streaming(plain_size, threshold) {
+        let size = encrypted_size(plain_size, threshold);
+        Ok((Box::new(EncryptingReader::new(reader, encryptor)), size))
+    } else {
+        let mut data = Vec::with_capacity(plain_size as usize);
+        reader.read_to_end(&mut data).await
+            .map_err(|e| anyhow::anyhow!("Failed to read file for encryption: {}", e))?;
+        let sealed = encryptor.encrypt(&data)?;
+        let size = sealed.len() as u64;
+        Ok((Box::new(std::io::Cursor::new(sealed)), size))
+    }
+}
+
+fn stream_nonce(prefix: &[u8; STREAM_PREFIX_LEN], counter: u32, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..STREAM_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[STREAM_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+// Encrypts an AsyncRead on the fly in the FORMAT_STREAM layout, one chunk in memory at a time
+pub struct EncryptingReader<R> {
+    inner: R,
+    cipher: Aes256Gcm,
+    prefix: [u8; STREAM_PREFIX_LEN],
+    counter: u32,
+    plain: Vec<u8>,   // Chunk being filled from `inner`
+    out: Vec<u8>,     // Sealed bytes not yet handed to the caller
+    out_pos: usize,
+    finished: bool,   // Last frame sealed
+}
+
+impl<R: AsyncRead + Unpin> EncryptingReader<R> {
+    pub fn new(inner: R, encryptor: &Encryptor) -> Self {
+        let prefix: [u8; STREAM_PREFIX_LEN] = rand::thread_rng().gen();
+
+        // The header goes out before the first frame
+        let mut out = Vec::with_capacity(1 + STREAM_PREFIX_LEN);
+        out.push(FORMAT_STREAM);
+        out.extend_from_slice(&prefix);
+
+        Self {
+            inner,
+            cipher: encryptor.cipher.clone(),
+            prefix,
+            counter: 0,
+            plain: Vec::with_capacity(STREAM_CHUNK_SIZE),
+            out,
+            out_pos: 0,
+            finished: false,
+        }
+    }
+
+    fn seal(&mut self, last: bool) -> std::io::Result<()> {
+        let nonce = stream_nonce(&self.prefix, self.counter, last);
+        self.out = self.cipher.encrypt(Nonce::from_slice(&nonce), self.plain.as_slice())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Encryption failed: {}", e)))?;
+        self.out_pos = 0;
+        self.plain.clear();
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "File too large to encrypt"))?;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for EncryptingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.out_pos < this.out.len() {
+                let n = (this.out.len() - this.out_pos).min(buf.remaining());
+                buf.put_slice(&this.out[this.out_pos..this.out_pos + n]);
+                this.out_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            // Fill the next chunk; the input ending before it is full makes it the last frame
+            let start = this.plain.len();
+            this.plain.resize(STREAM_CHUNK_SIZE, 0);
+            let mut chunk = ReadBuf::new(&mut this.plain[start..]);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(())) => {
+                    let filled = chunk.filled().len();
+                    this.plain.truncate(start + filled);
+                    if filled == 0 {
+                        this.seal(true)?;
+                    } else if this.plain.len() == STREAM_CHUNK_SIZE {
+                        this.seal(false)?;
+                    }
+                }
+                Poll::Ready(Err(e)) => {
+                    this.plain.truncate(start);
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => {
+                    this.plain.truncate(start);
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+// Read until `buf` is full or the input ends; returns the number of bytes read
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+// Decrypt either format from `reader` into `writer`, telling them apart by the marker.
+// Returns the number of plaintext bytes written.
+pub async fn decrypt_stream<R, W>(encryptor: &Encryptor, mut reader: R, mut writer: W) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let marker = reader.read_u8().await
+        .map_err(|_| anyhow::anyhow!("Encrypted data is empty"))?;
+    let mut written: u64 = 0;
+
+    match marker {
+        FORMAT_ONESHOT => {
+            let mut data = vec![FORMAT_ONESHOT];
+            reader.read_to_end(&mut data).await?;
+            let plaintext = encryptor.decrypt(&data)?;
+            writer.write_all(&plaintext).await?;
+            written = plaintext.len() as u64;
+        }
+        FORMAT_STREAM => {
+            let mut prefix = [0u8; STREAM_PREFIX_LEN];
+            reader.read_exact(&mut prefix).await
+                .map_err(|_| anyhow::anyhow!("Encrypted data is truncated"))?;
+
+            let mut frame = vec![0u8; STREAM_CHUNK_SIZE + TAG_LEN];
+            let mut counter: u32 = 0;
+            loop {
+                let len = read_full(&mut reader, &mut frame).await?;
+                let last = len < frame.len();
+                let nonce = stream_nonce(&prefix, counter, last);
+                let plaintext = encryptor.cipher.decrypt(Nonce::from_slice(&nonce), &frame[..len])
+                    .map_err(|_| anyhow::anyhow!("Decryption failed: wrong password or corrupted data"))?;
+                writer.write_all(&plaintext).await?;
+                written += plaintext.len() as u64;
+
+                if last {
+                    break;
+                }
+                counter = counter.checked_add(1)
+                    .ok_or_else(|| anyhow::anyhow!("Encrypted data is corrupted"))?;
+            }
+        }
+        other => {
+            return Err(anyhow::anyhow!("Unknown encryption format marker: {:#04x}", other));
+        }
+    }
+
+    writer.flush().await?;
+    Ok(written)
+}
+
+// Decrypt the file at `source` into `destination`
+pub async fn decrypt_file(encryptor: &Encryptor, source: &std::path::Path, destination: &std::path::Path) -> Result<u64> {
+    let input = tokio::fs::File::open(source).await
+        .map_err(|e| anyhow::anyhow!("Failed to open encrypted file: {}", e))?;
+    let output = tokio::fs::File::create(destination).await
+        .map_err(|e| anyhow::anyhow!("Failed to create decrypted file: {}", e))?;
+
+    decrypt_stream(
+        encryptor,
+        tokio::io::BufReader::new(input),
+        tokio::io::BufWriter::new(output),
+    ).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -68,10 +424,74 @@ mod tests {
     fn test_encryption_decryption() {
         let encryptor = Encryptor::new("test_password");
         let data = b"Hello, World!";
-        
+
         let encrypted = encryptor.encrypt(data).unwrap();
         let decrypted = encryptor.decrypt(&encrypted).unwrap();
-        
+
         assert_eq!(data.to_vec(), decrypted);
     }
+
+    async fn round_trip(data: &[u8], threshold: u64) -> Vec<u8> {
+        let encryptor = Encryptor::new("test_password");
+        let (mut reader, size) = encrypted_reader(&encryptor, data, data.len() as u64, threshold).await.unwrap();
+        let mut sealed = Vec::new();
+        reader.read_to_end(&mut sealed).await.unwrap();
+        assert_eq!(sealed.len() as u64, size);
+        assert_eq!(size, encrypted_size(data.len() as u64, threshold));
+
+        let mut plaintext = Vec::new();
+        decrypt_stream(&encryptor, sealed.as_slice(), &mut plaintext).await.unwrap();
+        assert_eq!(plaintext, data);
+        sealed
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_at_threshold() {
+        let below = vec![7u8; DEFAULT_STREAM_THRESHOLD as usize - 1];
+        assert_eq!(round_trip(&below, DEFAULT_STREAM_THRESHOLD).await[0], FORMAT_ONESHOT);
+
+        let at = vec![7u8; DEFAULT_STREAM_THRESHOLD as usize];
+        assert_eq!(round_trip(&at, DEFAULT_STREAM_THRESHOLD).await[0], FORMAT_STREAM);
+    }
+
+    #[tokio::test]
+    async fn test_stream_round_trip_at_chunk_boundaries() {
+        for size in [0, 1, STREAM_CHUNK_SIZE - 1, STREAM_CHUNK_SIZE, STREAM_CHUNK_SIZE + 1, 3 * STREAM_CHUNK_SIZE] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            round_trip(&data, 0).await;
+        }
+    }
+
+    #[test]
+    fn test_key_check_refuses_wrong_password() {
+        let (check, encryptor) = KeyCheck::create_with_rounds("right horse", 1000).unwrap();
+        let sealed = encryptor.encrypt(b"data").unwrap();
+        assert_eq!(check.open("right horse").unwrap().decrypt(&sealed).unwrap(), b"data");
+        assert!(check.open("right hose").is_err());
+
+        // Same password, new vault: another salt gives another key
+        let (other, _) = KeyCheck::create_with_rounds("right horse", 1000).unwrap();
+        assert_ne!(check.salt, other.salt);
+        assert!(other.open("right horse").unwrap().decrypt(&sealed).is_err());
+
+        // Vaults from before the check keep their unsalted key
+        let legacy = KeyCheck::legacy(&Encryptor::new("old")).unwrap();
+        let sealed = Encryptor::new("old").encrypt(b"data").unwrap();
+        assert_eq!(legacy.open("old").unwrap().decrypt(&sealed).unwrap(), b"data");
+        assert!(legacy.open("olds").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_rejects_truncation_and_wrong_password() {
+        let data = vec![1u8; 2 * STREAM_CHUNK_SIZE];
+        let sealed = round_trip(&data, 0).await;
+        let encryptor = Encryptor::new("test_password");
+
+        // Dropping the empty final frame leaves only full frames
+        let truncated = &sealed[..sealed.len() - TAG_LEN];
+        assert!(decrypt_stream(&encryptor, truncated, Vec::new()).await.is_err());
+
+        let other = Encryptor::new("other_password");
+        assert!(decrypt_stream(&other, sealed.as_slice(), Vec::new()).await.is_err());
+    }
 }
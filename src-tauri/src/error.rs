@@ -0,0 +1,94 @@
+use std::fmt;
+
+// Typed errors for failures the UI needs to tell apart.
+// They flow through anyhow like any other error and reach the frontend via `to_string()`.
+#[derive(Debug, Clone)]
+pub enum TvaultError {
+    UnsupportedMedia(String),
+    ReadOnly,
+    VaultLocked,
+    PasswordRequired { hint: Option<String> },  // Hint as set by the user on Telegram, if any
+    NotAFile { path: String, kind: String },     // Upload source is a directory, socket, device...
+    SymlinkRejected(String),                     // Upload source is a symlink and following them is off
+    NotReadable(String),                         // Upload source exists but can't be opened for reading
+    QuotaExceeded { size: u64, used: u64, reserved: u64, limit: u64 },  // reserved = uploads already underway
+    UploadFailed { kind: UploadFailureKind, attempts: u32, detail: String },  // An upload gave up for good
+}
+
+// Why an upload finally gave up, so the UI can suggest what to do about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum UploadFailureKind {
+    NetworkUnstable,
+    FloodLimited,
+    FileTooLarge,
+    AuthExpired,
+    PeerGone,   // The folder's channel was deleted or we lost access to it
+    Unknown,
+}
+
+impl UploadFailureKind {
+    pub fn remedy(&self) -> &'static str {
+        match self {
+            UploadFailureKind::NetworkUnstable => "Your connection to Telegram kept dropping. Check your network and try again.",
+            UploadFailureKind::FloodLimited => "Telegram is rate limiting uploads. Wait a while before trying again.",
+            UploadFailureKind::FileTooLarge => "The file is over Telegram's size limit. Split it into smaller parts.",
+            UploadFailureKind::AuthExpired => "Your session has expired. Please log in again.",
+            UploadFailureKind::PeerGone => "The folder's channel is gone or no longer writable. Repair the folder or upload elsewhere.",
+            UploadFailureKind::Unknown => "Try again; if it keeps failing, check the logs.",
+        }
+    }
+}
+
+impl fmt::Display for TvaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TvaultError::UnsupportedMedia(kind) => {
+                write!(f, "Unsupported media type for download: {}", kind)
+            }
+            TvaultError::ReadOnly => {
+                write!(f, "T-Vault is in read-only mode. Turn it off to make changes.")
+            }
+            TvaultError::VaultLocked => {
+                write!(f, "The vault is locked. Unlock it to work with encrypted files.")
+            }
+            TvaultError::PasswordRequired { hint: Some(hint) } => {
+                write!(f, "2FA password required (hint: {}) - please disable 2FA temporarily", hint)
+            }
+            TvaultError::PasswordRequired { hint: None } => {
+                write!(f, "2FA password required - please disable 2FA temporarily")
+            }
+            TvaultError::NotAFile { path, kind } => {
+                write!(f, "Cannot upload {}: it is a {}, not a regular file", path, kind)
+            }
+            TvaultError::SymlinkRejected(path) => {
+                write!(f, "Cannot upload {}: it is a symbolic link and following links is turned off", path)
+            }
+            TvaultError::NotReadable(path) => {
+                write!(f, "Cannot upload {}: permission denied", path)
+            }
+            TvaultError::QuotaExceeded { size, used, reserved, limit } => {
+                write!(
+                    f,
+                    "Vault quota exceeded: {} bytes used{} of {} allowed, no room for another {} bytes",
+                    used,
+                    if *reserved > 0 { format!(" (plus {} in uploads underway)", reserved) } else { String::new() },
+                    limit,
+                    size
+                )
+            }
+            TvaultError::UploadFailed { kind, attempts: 0, detail } => {
+                write!(f, "{} {}", detail, kind.remedy())
+            }
+            TvaultError::UploadFailed { kind, attempts, detail } => {
+                write!(
+                    f,
+                    "Upload failed after {} attempt{}: {} {}",
+                    attempts, if *attempts == 1 { "" } else { "s" }, detail, kind.remedy()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TvaultError {}
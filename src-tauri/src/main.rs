@@ -5,9 +5,15 @@ mod telegram;
 mod storage;
 mod encryption;
 mod api_keys;
+mod error;
+mod config;
+mod bandwidth;
+mod operations;
+mod transfers;
 
 use tokio::sync::Mutex;
 use tauri::Manager;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 // Load environment variables from .env file
 fn init_env() {
@@ -18,6 +24,49 @@ fn init_env() {
 
 struct AppState {
     telegram_client: Mutex<Option<telegram::TelegramClient>>,
+    readonly: AtomicBool,  // Guest mode: mutating commands are refused
+}
+
+impl AppState {
+    // Checked first thing in every command that changes the vault
+    fn ensure_writable(&self) -> Result<(), String> {
+        if self.readonly.load(Ordering::SeqCst) {
+            return Err(error::TvaultError::ReadOnly.to_string());
+        }
+        Ok(())
+    }
+
+    // Called before user-initiated transfers so a connection that died while the machine
+    // slept is replaced up front instead of timing out inside the operation
+    async fn refresh_stale_client(&self) {
+        let stale_after = config::AppConfig::load().await.stale_connection_secs;
+        let client_ref = match *self.telegram_client.lock().await {
+            Some(ref client) => client.get_client_ref(),
+            None => return,
+        }; // Lock released here, before the round trip
+        let Some(client) = client_ref.lock().await.clone() else {
+            return;
+        };
+        let seen_alive = telegram::TelegramClient::last_alive();
+        if !telegram::TelegramClient::connection_is_dead(&client, stale_after).await {
+            return;
+        }
+
+        // Only swap if nobody logged out or replaced the client meanwhile, and no concurrent
+        // refresh already rebuilt it (that would quit the pool it just started)
+        let client_guard = self.telegram_client.lock().await;
+        if telegram::TelegramClient::last_alive() != seen_alive {
+            return;
+        }
+        if let Some(ref current) = *client_guard {
+            if std::sync::Arc::ptr_eq(&current.get_client_ref(), &client_ref) {
+                match current.rebuild_connection().await {
+                    Ok(()) => println!("Reconnected to Telegram"),
+                    Err(e) => eprintln!("Warning: Failed to rebuild stale client: {}", e),
+                }
+            }
+        }
+    }
 }
 
 #[tauri::command]
@@ -36,9 +85,10 @@ async fn telegram_login(
     
     // Create new client if needed
     if client_guard.is_none() {
-        let client = telegram::TelegramClient::new()
+        let mut client = telegram::TelegramClient::new()
             .await
             .map_err(|e| e.to_string())?;
+        client.mark_created_for_login();
         *client_guard = Some(client);
     }
     
@@ -49,6 +99,10 @@ async fn telegram_login(
             .await
             .map_err(|e| e.to_string())?;
     }
+
+    if let Err(e) = config::AppConfig::remember_phone(&phone).await {
+        eprintln!("Warning: Failed to remember login phone: {}", e);
+    }
     
     Ok("Verification code sent! Check your Telegram app for the code.".to_string())
 }
@@ -83,6 +137,63 @@ async fn telegram_verify_code(
     }
 }
 
+// Abandon a login started with telegram_login so the next attempt starts fresh
+#[tauri::command]
+async fn cancel_login(state: tauri::State<'_, AppState>) -> Result<(), String> {
+    let mut client_guard = state.telegram_client.lock().await;
+
+    if let Some(client) = client_guard.as_mut() {
+        if client.is_authenticated().await.unwrap_or(false) {
+            return Err("Already authenticated - nothing to cancel".to_string());
+        }
+        if client.cancel_login().await {
+            // The client was only created for this attempt; drop it
+            *client_guard = None;
+        }
+    }
+
+    Ok(())
+}
+
+// New device: get a tg://login link to show as a QR code. Poll it until `authorized`.
+#[tauri::command]
+async fn export_login_token(state: tauri::State<'_, AppState>) -> Result<telegram::QrLogin, String> {
+    let mut client_guard = state.telegram_client.lock().await;
+
+    if client_guard.is_none() {
+        let mut client = telegram::TelegramClient::new()
+            .await
+            .map_err(|e| e.to_string())?;
+        client.mark_created_for_login();
+        *client_guard = Some(client);
+    }
+
+    match client_guard.as_mut() {
+        Some(client) => client.export_login_token().await.map_err(|e| e.to_string()),
+        None => Err("Client not available".to_string()),
+    }
+}
+
+// Old device: approve the link shown by the new install (accepts the tg:// link or the bare token)
+#[tauri::command]
+async fn import_login_token(token: String, state: tauri::State<'_, AppState>) -> Result<(), String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let client = client_ref.lock().await.as_ref().cloned()
+        .ok_or_else(|| "Client not initialized".to_string())?;
+
+    telegram::accept_login_token(&client, &token)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn telegram_check_auth(state: tauri::State<'_, AppState>) -> Result<bool, String> {
     let client_guard = state.telegram_client.lock().await;
@@ -94,6 +205,41 @@ async fn telegram_check_auth(state: tauri::State<'_, AppState>) -> Result<bool,
     }
 }
 
+// Phone to pre-fill on the login screen. None if never stored or only kept as a hash.
+#[tauri::command]
+async fn get_last_phone() -> Result<Option<String>, String> {
+    Ok(config::AppConfig::load().await.last_phone())
+}
+
+#[tauri::command]
+async fn clear_last_phone() -> Result<(), String> {
+    config::AppConfig::modify(|config| config.last_phone = None)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Switching to hashed storage replaces a stored plain phone with its hash;
+// switching back clears it since the hash can't be reversed
+#[tauri::command]
+async fn set_hash_last_phone(enabled: bool) -> Result<(), String> {
+    let mut plain = None;
+    config::AppConfig::modify(|config| {
+        plain = config.last_phone();
+        config.hash_last_phone = enabled;
+        if enabled || plain.is_none() {
+            config.last_phone = None;
+        }
+    }).await.map_err(|e| e.to_string())?;
+
+    if enabled {
+        if let Some(phone) = plain {
+            config::AppConfig::remember_phone(&phone).await.map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
 #[tauri::command]
 async fn check_api_keys_configured() -> Result<bool, String> {
     Ok(api_keys::ApiKeys::exists().await)
@@ -103,9 +249,16 @@ async fn check_api_keys_configured() -> Result<bool, String> {
 async fn upload_file(
     file_path: String,
     folder: String,
+    description: Option<String>,
+    supersedes: Option<String>,
+    encrypt: Option<bool>,
+    created_at_override: Option<i64>,
+    as_photo: Option<bool>,
     state: tauri::State<'_, AppState>,
     app_handle: tauri::AppHandle,
 ) -> Result<String, String> {
+    state.ensure_writable()?;
+
     // Validate inputs
     if file_path.trim().is_empty() {
         return Err("Invalid file path".to_string());
@@ -125,6 +278,8 @@ async fn upload_file(
         "progress": 0
     })).ok();
     
+    state.refresh_stale_client().await;
+
     // Get client reference (clone Arc to avoid holding lock)
     let client_ref = {
         let client_guard = state.telegram_client.lock().await;
@@ -156,7 +311,15 @@ async fn upload_file(
     let file_name_clone = file_name.to_string();
     
     let file_path_clone = file_path.clone();
-    let result = storage::upload_file(client_ref, &file_path, &folder, move |progress, current, total| {
+    let options = storage::UploadOptions {
+        description,
+        supersedes,
+        encrypt: encrypt.unwrap_or(false),
+        created_at: created_at_override,
+        as_photo: as_photo.unwrap_or(false),
+        ..Default::default()
+    };
+    let result = storage::upload_file(client_ref, &file_path, &folder, options, move |progress, current, total| {
         app_handle_clone.emit_all("upload-progress", serde_json::json!({
             "filePath": file_path_clone,
             "file": file_name_clone,
@@ -181,13 +344,22 @@ async fn upload_file(
             })).ok();
         }
         Err(e) => {
-            // Emit error
+            // Emit error, with its category when the upload gave up after trying
+            let failure = match e.downcast_ref::<error::TvaultError>() {
+                Some(error::TvaultError::UploadFailed { kind, attempts, .. }) => Some(serde_json::json!({
+                    "kind": kind,
+                    "attempts": attempts,
+                    "remedy": kind.remedy()
+                })),
+                _ => None,
+            };
             app_handle.emit_all("upload-progress", serde_json::json!({
                 "filePath": file_path,
                 "file": file_name,
                 "folder": folder,
                 "status": "error",
                 "error": e.to_string(),
+                "failure": failure,
                 "progress": 0
             })).ok();
         }
@@ -196,10 +368,74 @@ async fn upload_file(
     result.map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+async fn recent_upload_failures(
+    _state: tauri::State<'_, AppState>,
+) -> Result<Vec<storage::UploadFailure>, String> {
+    Ok(storage::recent_upload_failures())
+}
+
+#[tauri::command]
+async fn upload_from_url(
+    url: String,
+    folder: String,
+    file_name: Option<String>,
+    encrypt: Option<bool>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::FileMetadata, String> {
+    state.ensure_writable()?;
+    state.refresh_stale_client().await;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let progress_handle = app_handle.clone();
+    let progress_url = url.clone();
+    let progress_folder = folder.clone();
+    let result = storage::upload_from_url(client_ref, &url, &folder, file_name.as_deref(), encrypt.unwrap_or(false), move |progress, current, total| {
+        progress_handle.emit_all("upload-progress", serde_json::json!({
+            "filePath": progress_url,
+            "folder": progress_folder,
+            "status": "uploading",
+            "progress": progress,
+            "current": current,
+            "total": total
+        })).ok();
+    }).await;
+
+    match &result {
+        Ok(file) => app_handle.emit_all("upload-progress", serde_json::json!({
+            "filePath": url,
+            "file": file.name,
+            "folder": folder,
+            "status": "completed",
+            "progress": 100
+        })).ok(),
+        Err(e) => app_handle.emit_all("upload-progress", serde_json::json!({
+            "filePath": url,
+            "folder": folder,
+            "status": "error",
+            "error": e.to_string(),
+            "progress": 0
+        })).ok(),
+    };
+
+    result.map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn download_file(
     file_id: String,
     destination: String,
+    verify_type: Option<bool>,
+    fix_extension: Option<bool>,
     state: tauri::State<'_, AppState>,
     app_handle: tauri::AppHandle,
 ) -> Result<String, String> {
@@ -218,6 +454,8 @@ async fn download_file(
         .unwrap_or("file")
         .to_string();
 
+    state.refresh_stale_client().await;
+
     let client_ref = {
         let client_guard = state.telegram_client.lock().await;
         if let Some(ref client) = *client_guard {
@@ -238,7 +476,7 @@ async fn download_file(
     let file_id_clone = file_id.clone();
     let file_name_clone = file_name.clone();
 
-    let result = storage::download_file(client_ref, &file_id, &destination, move |progress, current, total| {
+    let result = storage::download_file(client_ref, &file_id, &destination, storage::ProgressConfig::default(), move |progress, current, total| {
         app_handle_clone.emit_all("download-progress", serde_json::json!({
             "fileId": file_id_clone,
             "file": file_name_clone,
@@ -249,14 +487,39 @@ async fn download_file(
         })).ok();
     }).await;
 
+    // Opt-in: check the content against the stored name/type (files synced from captions can lose their extension)
+    let result = match result {
+        Ok(path) if verify_type.unwrap_or(false) => {
+            match storage::verify_download_type(&file_id, &path, fix_extension.unwrap_or(false)).await {
+                Ok(Some(mismatch)) => {
+                    app_handle.emit_all("download-type-mismatch", serde_json::json!({
+                        "fileId": file_id,
+                        "file": file_name,
+                        "mismatch": mismatch
+                    })).ok();
+                    Ok(mismatch.renamed_to.unwrap_or(path))
+                }
+                Ok(None) => Ok(path),
+                Err(e) => {
+                    eprintln!("Warning: Failed to verify downloaded file type: {}", e);
+                    Ok(path)
+                }
+            }
+        }
+        other => other,
+    };
+
     match &result {
-        Ok(_) => {
+        Ok(path) => {
             tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            // The name on disk can differ from the requested one (e.g. characters Windows rejects)
+            let saved_as = std::path::Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(&file_name);
             app_handle.emit_all("download-progress", serde_json::json!({
                 "fileId": file_id,
                 "file": file_name,
                 "status": "completed",
-                "progress": 100
+                "progress": 100,
+                "savedAs": saved_as
             })).ok();
         }
         Err(e) => {
@@ -274,11 +537,25 @@ async fn download_file(
 }
 
 #[tauri::command]
-async fn download_thumbnail(
-    file_id: String,
-    destination: String,
+async fn resume_downloads() -> Result<Vec<storage::PendingDownload>, String> {
+    storage::resume_downloads()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn upload_files(
+    file_paths: Vec<String>,
+    folder: String,
+    aggregate_only: bool,
+    operation_id: Option<String>,
     state: tauri::State<'_, AppState>,
-) -> Result<Option<String>, String> {
+    app_handle: tauri::AppHandle,
+) -> Result<storage::BatchReport, String> {
+    state.ensure_writable()?;
+
+    state.refresh_stale_client().await;
+
     let client_ref = {
         let client_guard = state.telegram_client.lock().await;
         if let Some(ref client) = *client_guard {
@@ -288,45 +565,24 @@ async fn download_thumbnail(
         }
     }; // Lock released here
 
-    storage::download_thumbnail(client_ref, &file_id, &destination)
-        .await
-        .map_err(|e| e.to_string())
-}
-
-#[tauri::command]
-async fn list_files(
-    folder: String,
-    _state: tauri::State<'_, AppState>,
-) -> Result<Vec<storage::FileMetadata>, String> {
-    storage::list_files(&folder)
-        .await
-        .map_err(|e| e.to_string())
+    let operation_id = operation_id.unwrap_or_else(|| operations::unique_id("upload"));
+    upload_batch(client_ref, &file_paths, &folder, aggregate_only, false, &operation_id, &app_handle).await
 }
 
 #[tauri::command]
-async fn get_folder_stats(
-    folder_path: String,
-) -> Result<storage::FolderStats, String> {
-    storage::get_folder_stats(&folder_path)
-        .await
-        .map_err(|e| e.to_string())
-}
+async fn upload_folder(
+    local_dir: String,
+    target_folder: String,
+    aggregate_only: bool,
+    preserve_timestamps: Option<bool>,
+    operation_id: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::BatchReport, String> {
+    state.ensure_writable()?;
 
-#[tauri::command]
-async fn list_files_recursive(
-    folder_path: String,
-) -> Result<Vec<storage::FileMetadata>, String> {
-    storage::list_files_recursive(&folder_path)
-        .await
-        .map_err(|e| e.to_string())
-}
+    state.refresh_stale_client().await;
 
-#[tauri::command]
-async fn create_folder(
-    folder_name: String,
-    parent_folder: String,
-    state: tauri::State<'_, AppState>,
-) -> Result<String, String> {
     let client_ref = {
         let client_guard = state.telegram_client.lock().await;
         if let Some(ref client) = *client_guard {
@@ -334,21 +590,145 @@ async fn create_folder(
         } else {
             return Err("Not authenticated".to_string());
         }
-    }; // Lock released
-    
-    let result = storage::create_folder(client_ref, &folder_name, &parent_folder).await;
-    
-    match &result {
-        Ok(path) => Ok(path.clone()),
-        Err(e) => Err(e.to_string()),
+    }; // Lock released here
+
+    let file_paths = storage::list_local_files(&local_dir)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let operation_id = operation_id.unwrap_or_else(|| operations::unique_id("upload"));
+    // Imported files keep their modification time so they sort by their real age
+    let preserve_timestamps = preserve_timestamps.unwrap_or(true);
+    upload_batch(client_ref, &file_paths, &target_folder, aggregate_only, preserve_timestamps, &operation_id, &app_handle).await
+}
+
+// Upload several files into one folder. The folder's chat is resolved (or created) once
+// up front so the files don't each rescan dialogs or race to create the channel.
+// All files share one retry budget; when it runs out the batch pauses (see resume_batch).
+async fn upload_batch(
+    client_ref: std::sync::Arc<Mutex<Option<grammers_client::Client>>>,
+    file_paths: &[String],
+    folder: &str,
+    aggregate_only: bool,
+    preserve_timestamps: bool,
+    operation_id: &str,
+    app_handle: &tauri::AppHandle,
+) -> Result<storage::BatchReport, String> {
+    let target = storage::prepare_upload_target(client_ref.clone(), folder)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let config = config::AppConfig::load().await;
+    let budget = std::sync::Arc::new(operations::RetryBudget::new(
+        operation_id,
+        config.batch_max_retries,
+        config.batch_max_flood_wait_secs,
+    ));
+
+    let total = file_paths.len();
+    let mut report = storage::BatchReport {
+        total,
+        ..Default::default()
+    };
+    let cancel = operations::register(operation_id);
+
+    for (index, file_path) in file_paths.iter().enumerate() {
+        let file_name = std::path::Path::new(file_path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("file")
+            .to_string();
+        let source_meta = tokio::fs::metadata(file_path).await.ok();
+        let file_size = source_meta.as_ref().map(|m| m.len()).unwrap_or(0);
+
+        if cancel.is_cancelled() {
+            report.failed.push(storage::BatchFailure {
+                item: file_path.clone(),
+                error: "Cancelled".to_string(),
+            });
+            continue;
+        }
+
+        // Don't start the next file while the batch waits for the user, nor after they stopped it
+        if !storage::retry_budget_checkpoint(&budget, app_handle).await {
+            report.failed.push(storage::BatchFailure {
+                item: file_path.clone(),
+                error: "Batch stopped after repeated retries".to_string(),
+            });
+            continue;
+        }
+
+        app_handle.emit_all("upload-progress", serde_json::json!({
+            "filePath": file_path,
+            "file": file_name,
+            "folder": folder,
+            "status": "uploading",
+            "progress": 0
+        })).ok();
+
+        let options = storage::UploadOptions {
+            progress: storage::batch_progress_config(file_size, aggregate_only),
+            target: Some(target.clone()),
+            retry_budget: Some(budget.clone()),
+            created_at: source_meta.as_ref()
+                .filter(|_| preserve_timestamps)
+                .and_then(storage::modified_timestamp),
+            ..Default::default()
+        };
+        let result = storage::upload_file(client_ref.clone(), file_path, folder, options, |_, _, _| {}, app_handle.clone()).await;
+
+        match result {
+            Ok(_) => {
+                report.succeeded += 1;
+                app_handle.emit_all("upload-progress", serde_json::json!({
+                    "filePath": file_path,
+                    "file": file_name,
+                    "folder": folder,
+                    "status": "completed",
+                    "progress": 100
+                })).ok();
+            }
+            Err(e) => {
+                app_handle.emit_all("upload-progress", serde_json::json!({
+                    "filePath": file_path,
+                    "file": file_name,
+                    "folder": folder,
+                    "status": "error",
+                    "error": e.to_string(),
+                    "progress": 0
+                })).ok();
+                report.failed.push(storage::BatchFailure {
+                    item: file_path.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+
+        app_handle.emit_all("batch-progress", serde_json::json!({
+            "operation": "upload",
+            "current": index + 1,
+            "total": total,
+            "succeeded": report.succeeded,
+            "failed": report.failed.len(),
+            "progress": ((index + 1) as f64 / total as f64 * 100.0) as u32,
+        })).ok();
     }
+    operations::finish(operation_id);
+
+    Ok(report)
 }
 
+// Send a file's content to the UI as base64 `stream-chunk` events for in-app playback.
+// The UI acknowledges each chunk with ack_stream_chunk; `stream-end` follows the last one.
 #[tauri::command]
-async fn delete_file(
+async fn stream_file(
     file_id: String,
+    operation_id: Option<String>,
     state: tauri::State<'_, AppState>,
-) -> Result<bool, String> {
+    app_handle: tauri::AppHandle,
+) -> Result<u64, String> {
+    state.refresh_stale_client().await;
+
     let client_ref = {
         let client_guard = state.telegram_client.lock().await;
         if let Some(ref client) = *client_guard {
@@ -358,22 +738,56 @@ async fn delete_file(
         }
     }; // Lock released here
 
-    storage::delete_file(client_ref, &file_id)
-        .await
-        .map_err(|e| e.to_string())
+    let operation_id = operation_id.unwrap_or_else(|| format!("stream-{}", file_id));
+    let cancel = operations::register(&operation_id);
+
+    let chunk_handle = app_handle.clone();
+    let chunk_id = operation_id.clone();
+    let result = storage::stream_file(client_ref, &file_id, &operation_id, &cancel, move |offset, chunk| {
+        use base64::Engine as _;
+        chunk_handle.emit_all("stream-chunk", serde_json::json!({
+            "operationId": chunk_id,
+            "offset": offset,
+            "data": base64::engine::general_purpose::STANDARD.encode(chunk)
+        })).ok();
+    }).await;
+
+    operations::finish(&operation_id);
+
+    app_handle.emit_all("stream-end", serde_json::json!({
+        "operationId": operation_id,
+        "status": match &result {
+            Ok(_) => "completed",
+            Err(_) if cancel.is_cancelled() => "cancelled",
+            Err(_) => "failed",
+        },
+        "bytes": result.as_ref().ok(),
+        "error": result.as_ref().err().map(|e| e.to_string())
+    })).ok();
+
+    result.map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-async fn get_storage_stats(
-    _state: tauri::State<'_, AppState>,
-) -> Result<storage::StorageStats, String> {
-    storage::get_storage_stats()
-        .await
-        .map_err(|e| e.to_string())
+async fn ack_stream_chunk(operation_id: String) -> Result<bool, String> {
+    Ok(storage::ack_stream_chunk(&operation_id))
 }
 
 #[tauri::command]
-async fn sync_metadata(state: tauri::State<'_, AppState>) -> Result<usize, String> {
+async fn cancel_stream(operation_id: String) -> Result<bool, String> {
+    Ok(storage::cancel_stream(&operation_id))
+}
+
+#[tauri::command]
+async fn download_files(
+    items: Vec<storage::BatchDownloadItem>,
+    aggregate_only: bool,
+    operation_id: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::BatchReport, String> {
+    state.refresh_stale_client().await;
+
     let client_ref = {
         let client_guard = state.telegram_client.lock().await;
         if let Some(ref client) = *client_guard {
@@ -381,18 +795,100 @@ async fn sync_metadata(state: tauri::State<'_, AppState>) -> Result<usize, Strin
         } else {
             return Err("Not authenticated".to_string());
         }
+    }; // Lock released here
+
+    let total = items.len();
+    let mut report = storage::BatchReport {
+        total,
+        ..Default::default()
     };
-    
-    storage::sync_from_telegram(client_ref)
-        .await
-        .map_err(|e| e.to_string())
+    let operation_id = operation_id.unwrap_or_else(|| operations::unique_id("download"));
+    let cancel = operations::register(&operation_id);
+
+    for (index, item) in items.iter().enumerate() {
+        if cancel.is_cancelled() {
+            report.failed.push(storage::BatchFailure {
+                item: item.file_id.clone(),
+                error: "Cancelled".to_string(),
+            });
+            continue;
+        }
+
+        let file_name = std::path::Path::new(&item.destination)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("file")
+            .to_string();
+        let file_size = storage::get_file_size(&item.file_id).await.unwrap_or(0);
+
+        app_handle.emit_all("download-progress", serde_json::json!({
+            "fileId": item.file_id,
+            "file": file_name,
+            "status": "downloading",
+            "progress": 0
+        })).ok();
+
+        let app_handle_clone = app_handle.clone();
+        let file_id_clone = item.file_id.clone();
+        let file_name_clone = file_name.clone();
+        let progress_config = storage::batch_progress_config(file_size, aggregate_only);
+
+        let result = storage::download_file(client_ref.clone(), &item.file_id, &item.destination, progress_config, move |progress, current, total| {
+            app_handle_clone.emit_all("download-progress", serde_json::json!({
+                "fileId": file_id_clone,
+                "file": file_name_clone,
+                "status": "downloading",
+                "progress": progress,
+                "current": current,
+                "total": total
+            })).ok();
+        }).await;
+
+        match result {
+            Ok(_) => {
+                report.succeeded += 1;
+                app_handle.emit_all("download-progress", serde_json::json!({
+                    "fileId": item.file_id,
+                    "file": file_name,
+                    "status": "completed",
+                    "progress": 100
+                })).ok();
+            }
+            Err(e) => {
+                app_handle.emit_all("download-progress", serde_json::json!({
+                    "fileId": item.file_id,
+                    "file": file_name,
+                    "status": "error",
+                    "error": e.to_string(),
+                    "progress": 0
+                })).ok();
+                report.failed.push(storage::BatchFailure {
+                    item: item.file_id.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+
+        app_handle.emit_all("batch-progress", serde_json::json!({
+            "operation": "download",
+            "current": index + 1,
+            "total": total,
+            "succeeded": report.succeeded,
+            "failed": report.failed.len(),
+            "progress": ((index + 1) as f64 / total as f64 * 100.0) as u32,
+        })).ok();
+    }
+    operations::finish(&operation_id);
+
+    Ok(report)
 }
 
 #[tauri::command]
-async fn delete_folder(
-    folder_path: String,
+async fn download_thumbnail(
+    file_id: String,
+    destination: String,
     state: tauri::State<'_, AppState>,
-) -> Result<bool, String> {
+) -> Result<Option<String>, String> {
     let client_ref = {
         let client_guard = state.telegram_client.lock().await;
         if let Some(ref client) = *client_guard {
@@ -400,9 +896,1487 @@ async fn delete_folder(
         } else {
             return Err("Not authenticated".to_string());
         }
-    };
-    
-    storage::delete_folder(client_ref, &folder_path)
+    }; // Lock released here
+
+    storage::download_thumbnail(client_ref, &file_id, &destination)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_folder_avatar(
+    folder_path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::get_folder_avatar(client_ref, &folder_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_folder_avatar(
+    folder_path: String,
+    image_path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::set_folder_avatar(client_ref, &folder_path, &image_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn can_download(
+    file_id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::MediaSupport, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::can_download(client_ref, &file_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn preflight_download(
+    file_id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::DownloadPreflight, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::preflight_download(client_ref, &file_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn preview_text(
+    file_id: String,
+    max_bytes: usize,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::preview_text(client_ref, &file_id, max_bytes)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_files(
+    folder: String,
+    _state: tauri::State<'_, AppState>,
+) -> Result<Vec<storage::FileMetadata>, String> {
+    storage::list_files(&folder)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_recent(limit: usize) -> Result<Vec<storage::FileMetadata>, String> {
+    storage::list_recent(limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn mime_type_breakdown(top_level: Option<bool>) -> Result<Vec<storage::MimeTypeUsage>, String> {
+    storage::mime_type_breakdown(top_level.unwrap_or(false))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn find_file_by_message(chat_id: Option<i64>, message_id: i32) -> Result<Option<storage::FileMetadata>, String> {
+    storage::find_file_by_message(chat_id, message_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_files_by_size(
+    min_bytes: u64,
+    max_bytes: Option<u64>,
+    limit: usize,
+) -> Result<Vec<storage::FileMetadata>, String> {
+    storage::list_files_by_size(min_bytes, max_bytes, limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_largest_files(limit: usize) -> Result<Vec<storage::FileMetadata>, String> {
+    storage::list_files_by_size(0, None, limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Drag-to-reorder: store the folder's manual order (local only)
+#[tauri::command]
+async fn set_file_order(
+    folder: String,
+    ordered_ids: Vec<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<usize, String> {
+    state.ensure_writable()?;
+
+    storage::set_file_order(&folder, &ordered_ids)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_files_without_checksum() -> Result<Vec<storage::FileMetadata>, String> {
+    storage::list_files_without_checksum()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn backfill_checksums(
+    operation_id: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::BatchReport, String> {
+    state.ensure_writable()?;
+    state.refresh_stale_client().await;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let operation_id = operation_id.unwrap_or_else(|| "backfill-checksums".to_string());
+    let cancel = operations::register(&operation_id);
+
+    let progress_handle = app_handle.clone();
+    let progress_id = operation_id.clone();
+    let result = storage::backfill_checksums(client_ref, &cancel, move |file, done, total| {
+        progress_handle.emit_all("checksum-progress", serde_json::json!({
+            "operationId": progress_id,
+            "file": file,
+            "done": done,
+            "total": total,
+            "progress": if total > 0 { (done as f64 / total as f64 * 100.0) as u32 } else { 100 }
+        })).ok();
+    }).await;
+
+    operations::finish(&operation_id);
+    result.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn repair_vault(
+    operation_id: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::VaultRepairReport, String> {
+    state.ensure_writable()?;
+    state.refresh_stale_client().await;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let operation_id = operation_id.unwrap_or_else(|| "repair-vault".to_string());
+    let cancel = operations::register(&operation_id);
+
+    let progress_handle = app_handle.clone();
+    let progress_id = operation_id.clone();
+    let result = storage::repair_vault(client_ref, &cancel, move |step, done, total| {
+        progress_handle.emit_all("repair-progress", serde_json::json!({
+            "operationId": progress_id,
+            "step": step,
+            "done": done,
+            "total": total,
+            "progress": if total > 0 { (done as f64 / total as f64 * 100.0) as u32 } else { 100 }
+        })).ok();
+    }).await;
+
+    operations::finish(&operation_id);
+    result.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_folder_dedup_stats(
+    folder_path: String,
+) -> Result<storage::FolderDedupStats, String> {
+    storage::get_folder_dedup_stats(&folder_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_folder_stats(
+    folder_path: String,
+) -> Result<storage::FolderStats, String> {
+    storage::get_folder_stats(&folder_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_files_recursive(
+    folder_path: String,
+) -> Result<Vec<storage::FileMetadata>, String> {
+    storage::list_files_recursive(&folder_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn create_folder(
+    folder_name: String,
+    parent_folder: String,
+    megagroup: Option<bool>,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released
+
+    let kind = if megagroup.unwrap_or(false) {
+        storage::ChannelKind::Megagroup
+    } else {
+        storage::ChannelKind::Broadcast
+    };
+    let result = storage::create_folder(client_ref, &folder_name, &parent_folder, kind).await;
+
+    match &result {
+        Ok(path) => Ok(path.clone()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+async fn ensure_folder(
+    folder_name: String,
+    parent_folder: String,
+    megagroup: Option<bool>,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::EnsuredFolder, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released
+
+    let kind = if megagroup.unwrap_or(false) {
+        storage::ChannelKind::Megagroup
+    } else {
+        storage::ChannelKind::Broadcast
+    };
+
+    storage::ensure_folder(client_ref, &folder_name, &parent_folder, kind)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn precreate_folders(
+    paths: Vec<String>,
+    megagroup: Option<bool>,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::PrecreateReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released
+
+    let kind = if megagroup.unwrap_or(false) {
+        storage::ChannelKind::Megagroup
+    } else {
+        storage::ChannelKind::Broadcast
+    };
+
+    storage::precreate_folders(client_ref, &paths, kind)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn audit_folder_titles(
+    repair: bool,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FolderTitleReport, String> {
+    if repair {
+        state.ensure_writable()?;
+    }
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::audit_folder_titles(client_ref, repair)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn audit_channels(state: tauri::State<'_, AppState>) -> Result<Vec<storage::ChannelAudit>, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::audit_channels(client_ref)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Latency to the current Telegram DC over a few lightweight calls (default 5, at most 20)
+#[tauri::command]
+async fn ping_telegram(
+    state: tauri::State<'_, AppState>,
+    samples: Option<u32>,
+) -> Result<telegram::LatencyReport, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let client = client_ref.lock().await.as_ref().cloned()
+        .ok_or_else(|| "Client not initialized".to_string())?;
+
+    Ok(telegram::ping_telegram(&client, samples.unwrap_or(5).clamp(1, 20)).await)
+}
+
+#[tauri::command]
+async fn find_duplicate_folders() -> Result<Vec<storage::DuplicateFolder>, String> {
+    storage::find_duplicate_folders()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn merge_folders(
+    folder_path: String,
+    keep_chat_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<usize, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::merge_folders(client_ref, &folder_path, keep_chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn convert_folder_channel(
+    folder_path: String,
+    to_megagroup: bool,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FolderMetadata, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::convert_folder_channel(client_ref, &folder_path, to_megagroup)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_file(
+    file_id: String,
+    purge_versions: Option<bool>,
+    state: tauri::State<'_, AppState>,
+) -> Result<bool, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::delete_file(client_ref, &file_id, purge_versions.unwrap_or(false))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_file_versions(file_id: String) -> Result<Vec<storage::FileVersion>, String> {
+    storage::list_file_versions(&file_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn download_file_version(
+    file_id: String,
+    version_index: usize,
+    destination: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let file_id_clone = file_id.clone();
+    storage::download_file_version(client_ref, &file_id, version_index, &destination, storage::ProgressConfig::default(), move |progress, current, total| {
+        app_handle.emit_all("download-progress", serde_json::json!({
+            "fileId": file_id_clone,
+            "version": version_index,
+            "status": "downloading",
+            "progress": progress,
+            "current": current,
+            "total": total
+        })).ok();
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_file_description(
+    file_id: String,
+    description: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FileMetadata, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::set_file_description(client_ref, &file_id, description)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_file_custom(
+    file_id: String,
+    custom: std::collections::BTreeMap<String, String>,
+    embed: Option<bool>,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FileMetadata, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::set_file_custom(client_ref, &file_id, custom, embed.unwrap_or(false))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_file_custom(
+    file_id: String,
+    _state: tauri::State<'_, AppState>,
+) -> Result<std::collections::BTreeMap<String, String>, String> {
+    storage::get_file_custom(&file_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn clean_file_names(state: tauri::State<'_, AppState>) -> Result<Vec<storage::NameFix>, String> {
+    state.ensure_writable()?;
+
+    storage::clean_file_names()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn refresh_file_name(
+    file_id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FileMetadata, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::refresh_file_name(client_ref, &file_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn relink_file(
+    file_id: String,
+    new_message_id: i32,
+    chat_id: Option<i64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FileMetadata, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::relink_file(client_ref, &file_id, new_message_id, chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn rebuild_metadata_from_telegram(
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::RebuildReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::rebuild_metadata_from_telegram(client_ref)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn repair_folder_metadata(
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FolderRepairReport, String> {
+    state.ensure_writable()?;
+
+    storage::repair_folder_metadata()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn compact_metadata(state: tauri::State<'_, AppState>) -> Result<storage::CompactReport, String> {
+    state.ensure_writable()?;
+
+    storage::compact_metadata()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn take_metadata_recovery() -> Result<Option<storage::MetadataRecovery>, String> {
+    Ok(storage::take_metadata_recovery().await)
+}
+
+#[tauri::command]
+async fn merge_metadata(
+    other_metadata_json: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::MetadataMergeReport, String> {
+    state.ensure_writable()?;
+
+    storage::merge_metadata(&other_metadata_json)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn schema_status() -> Result<storage::SchemaStatus, String> {
+    storage::schema_status()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn normalize_ids(state: tauri::State<'_, AppState>) -> Result<Vec<storage::IdChange>, String> {
+    state.ensure_writable()?;
+
+    storage::normalize_ids()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn export_metadata(format: String, destination: String) -> Result<usize, String> {
+    storage::export_metadata(&format, &destination)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn export_folder_manifest(folder_path: String, destination: String) -> Result<usize, String> {
+    storage::export_folder_manifest(&folder_path, &destination)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn import_folder_manifest(
+    path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::ManifestImportReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::import_folder_manifest(client_ref, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn export_folder(
+    folder_path: String,
+    destination: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::FolderExportReport, String> {
+    state.refresh_stale_client().await;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::export_folder(client_ref, &folder_path, &destination, |done, total, file| {
+        app_handle.emit_all("export-progress", serde_json::json!({
+            "folder": folder_path,
+            "file": file,
+            "done": done,
+            "total": total
+        })).ok();
+    })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn download_folder(
+    folder_path: String,
+    dest_dir: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::FolderDownloadReport, String> {
+    state.refresh_stale_client().await;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let progress_handle = app_handle.clone();
+    let progress_folder = folder_path.clone();
+    storage::download_folder(client_ref, &folder_path, &dest_dir, move |done, total, bytes_done, bytes_total| {
+        progress_handle.emit_all("folder-download-progress", serde_json::json!({
+            "folder": progress_folder,
+            "done": done,
+            "total": total,
+            "bytesDone": bytes_done,
+            "bytesTotal": bytes_total,
+            "progress": if bytes_total > 0 { (bytes_done as f64 / bytes_total as f64 * 100.0) as u32 } else { 100 }
+        })).ok();
+    })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn download_changed_since(
+    since: i64,
+    dest_dir: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::ChangedDownloadReport, String> {
+    state.refresh_stale_client().await;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let progress_handle = app_handle.clone();
+    storage::download_changed_since(client_ref, since, &dest_dir, move |done, total, bytes_done, bytes_total| {
+        progress_handle.emit_all("folder-download-progress", serde_json::json!({
+            "folder": "/",
+            "since": since,
+            "done": done,
+            "total": total,
+            "bytesDone": bytes_done,
+            "bytesTotal": bytes_total,
+            "progress": if bytes_total > 0 { (bytes_done as f64 / bytes_total as f64 * 100.0) as u32 } else { 100 }
+        })).ok();
+    })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_storage_stats(
+    _state: tauri::State<'_, AppState>,
+) -> Result<storage::StorageStats, String> {
+    storage::get_storage_stats()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn sync_metadata(
+    operation_id: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<usize, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    let operation_id = operation_id.unwrap_or_else(|| "sync".to_string());
+    let cancel = operations::register(&operation_id);
+
+    let progress_handle = app_handle.clone();
+    let progress_id = operation_id.clone();
+    let result = storage::sync_from_telegram(client_ref, &cancel, move |scanned, found| {
+        progress_handle.emit_all("sync-progress", serde_json::json!({
+            "operationId": progress_id,
+            "status": "scanning",
+            "scanned": scanned,
+            "found": found
+        })).ok();
+    }).await;
+
+    operations::finish(&operation_id);
+
+    if let Ok(found) = &result {
+        app_handle.emit_all("sync-progress", serde_json::json!({
+            "operationId": operation_id,
+            "status": if cancel.is_cancelled() { "cancelled" } else { "completed" },
+            "found": found
+        })).ok();
+    }
+
+    result.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn cancel_operation(operation_id: String) -> Result<bool, String> {
+    Ok(operations::cancel(&operation_id))
+}
+
+// Stop button for everything at once, e.g. before logging out or quitting. Entries are
+// only written after a file is fully sent, so aborted uploads leave no metadata behind.
+#[tauri::command]
+async fn cancel_all() -> Result<usize, String> {
+    Ok(operations::cancel_all())
+}
+
+// Let transfers start again after cancel_all, or hold new ones in the queue
+#[tauri::command]
+async fn set_queue_paused(paused: bool) -> Result<(), String> {
+    transfers::set_queue_paused(paused);
+    Ok(())
+}
+
+#[tauri::command]
+async fn is_queue_paused() -> Result<bool, String> {
+    Ok(transfers::queue_paused())
+}
+
+// Transfers waiting for a concurrency slot, with the ids cancel_queued takes
+#[tauri::command]
+async fn list_queued_operations() -> Result<Vec<transfers::QueuedTransfer>, String> {
+    Ok(transfers::queued())
+}
+
+// Drop a transfer from the queue before it starts; one already running keeps going
+// (cancel_operation / cancel_all stop those)
+#[tauri::command]
+async fn cancel_queued(operation_id: String) -> Result<bool, String> {
+    Ok(transfers::cancel_queued(&operation_id))
+}
+
+// Move a queued transfer to the front of its queue; a running one is left as it is
+#[tauri::command]
+async fn prioritize_operation(operation_id: String) -> Result<bool, String> {
+    Ok(transfers::prioritize(&operation_id))
+}
+
+// Answer a batch-paused event: continue with a fresh retry budget, or stop the batch
+#[tauri::command]
+async fn resume_batch(operation_id: String, proceed: bool) -> Result<bool, String> {
+    Ok(operations::resume(&operation_id, proceed))
+}
+
+#[tauri::command]
+async fn delete_folder(
+    folder_path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<bool, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+    
+    storage::delete_folder(client_ref, &folder_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn check_folder_channel(
+    folder_path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<telegram::ChannelStatus, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::check_folder_channel(client_ref, &folder_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn folder_permissions(
+    folder_path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FolderPermissions, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::folder_permissions(client_ref, &folder_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn share_file_to_chat(
+    file_id: String,
+    target_chat_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::SharedFile, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::share_file_to_chat(client_ref, &file_id, target_chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn verify_vault(state: tauri::State<'_, AppState>) -> Result<storage::VerifyReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::verify_vault(client_ref)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Check every file in the background; a cancelled scan continues with `resume`
+#[tauri::command]
+async fn scan_vault_health(
+    operation_id: Option<String>,
+    resume: Option<bool>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::HealthScanReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let operation_id = operation_id.unwrap_or_else(|| "health-scan".to_string());
+    let cancel = operations::register(&operation_id);
+
+    let progress_handle = app_handle.clone();
+    let progress_id = operation_id.clone();
+    let result = storage::scan_vault_health(client_ref, resume.unwrap_or(false), &cancel, move |checked, total| {
+        progress_handle.emit_all("health-progress", serde_json::json!({
+            "operationId": progress_id,
+            "status": "scanning",
+            "checked": checked,
+            "total": total
+        })).ok();
+    }).await;
+
+    operations::finish(&operation_id);
+
+    if let Ok(report) = &result {
+        app_handle.emit_all("health-progress", serde_json::json!({
+            "operationId": operation_id,
+            "status": if cancel.is_cancelled() { "cancelled" } else { "completed" },
+            "checked": report.checked,
+            "remaining": report.remaining
+        })).ok();
+    }
+
+    result.map_err(|e| e.to_string())
+}
+
+// Compare recorded sizes with Telegram's documents (all files when `file_ids` is empty)
+#[tauri::command]
+async fn audit_file_sizes(
+    file_ids: Option<Vec<String>>,
+    repair: bool,
+    operation_id: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::SizeAuditReport, String> {
+    if repair {
+        state.ensure_writable()?;
+    }
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let operation_id = operation_id.unwrap_or_else(|| "size-audit".to_string());
+    let cancel = operations::register(&operation_id);
+    let result = storage::audit_file_sizes(client_ref, &file_ids.unwrap_or_default(), repair, &cancel).await;
+    operations::finish(&operation_id);
+
+    result.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn vault_health_report() -> Result<storage::VaultHealthReport, String> {
+    storage::vault_health_report()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn run_maintenance(app_handle: tauri::AppHandle) -> Result<storage::MaintenanceReport, String> {
+    let report = storage::run_maintenance()
+        .await
+        .map_err(|e| e.to_string())?;
+    app_handle.emit_all("maintenance-complete", &report).ok();
+    Ok(report)
+}
+
+// Runs cache cleanup on the configured interval. The setting is re-read every round,
+// so turning it off (0) or changing it applies without a restart.
+async fn maintenance_loop(app_handle: tauri::AppHandle) {
+    loop {
+        let minutes = config::AppConfig::load().await.maintenance_interval_mins;
+        if minutes == 0 {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            continue;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(minutes * 60)).await;
+        if config::AppConfig::load().await.maintenance_interval_mins == 0 {
+            continue;
+        }
+
+        match storage::run_maintenance().await {
+            Ok(report) => {
+                app_handle.emit_all("maintenance-complete", &report).ok();
+            }
+            Err(e) => eprintln!("Maintenance failed: {}", e),
+        }
+    }
+}
+
+#[tauri::command]
+async fn audit_file_locations(
+    state: tauri::State<'_, AppState>,
+    sample: Option<usize>,
+    repair: bool,
+) -> Result<storage::LocationAudit, String> {
+    if repair {
+        state.ensure_writable()?;
+    }
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::audit_file_locations(client_ref, sample, repair)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn find_duplicate_messages() -> Result<Vec<storage::DuplicateMessageGroup>, String> {
+    storage::find_duplicate_messages()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn repair_duplicate_messages(state: tauri::State<'_, AppState>) -> Result<storage::DuplicateRepairReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::repair_duplicate_messages(client_ref)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn find_unreferenced_uploads(
+    state: tauri::State<'_, AppState>,
+    depth: Option<usize>,
+) -> Result<storage::UnreferencedScan, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::find_unreferenced_uploads(client_ref, depth.unwrap_or(storage::UNREFERENCED_SCAN_DEPTH))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn import_unreferenced_uploads(
+    state: tauri::State<'_, AppState>,
+    uploads: Vec<storage::FileMetadata>,
+) -> Result<Vec<storage::FileMetadata>, String> {
+    state.ensure_writable()?;
+
+    storage::import_unreferenced_uploads(uploads)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_unreferenced_uploads(
+    state: tauri::State<'_, AppState>,
+    uploads: Vec<storage::FileMetadata>,
+) -> Result<storage::BatchReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::delete_unreferenced_uploads(client_ref, uploads)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_tombstones() -> Result<Vec<storage::Tombstone>, String> {
+    storage::list_tombstones()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn clear_tombstones(state: tauri::State<'_, AppState>) -> Result<usize, String> {
+    state.ensure_writable()?;
+
+    storage::clear_tombstones()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_orphaned_channels(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<storage::OrphanedChannel>, String> {
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::list_orphaned_channels(client_ref)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_orphaned_channels(
+    chat_ids: Vec<i64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::BatchReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::delete_orphaned_channels(client_ref, &chat_ids)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_folder_preserving(
+    folder_path: String,
+    move_to: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::PreservingDeleteReport, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::delete_folder_preserving(client_ref, &folder_path, &move_to, app_handle)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn move_file(
+    file_id: String,
+    target_folder: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::FileMetadata, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::move_file(client_ref, &file_id, &target_folder, app_handle)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn copy_file(
+    file_id: String,
+    target_folder: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::FileMetadata, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    };
+
+    storage::copy_file(client_ref, &file_id, &target_folder, app_handle)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Duplicate a folder subtree under `target_parent`; running it again finishes an interrupted copy
+#[tauri::command]
+async fn copy_folder(
+    folder_path: String,
+    target_parent: String,
+    operation_id: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::FolderCopyReport, String> {
+    state.ensure_writable()?;
+
+    state.refresh_stale_client().await;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let operation_id = operation_id.unwrap_or_else(|| "copy-folder".to_string());
+    let cancel = operations::register(&operation_id);
+
+    let progress_handle = app_handle.clone();
+    let progress_id = operation_id.clone();
+    let result = storage::copy_folder(client_ref, &folder_path, &target_parent, &cancel, move |done, total| {
+        progress_handle.emit_all("copy-progress", serde_json::json!({
+            "operationId": progress_id,
+            "status": "copying",
+            "current": done,
+            "total": total,
+            "progress": if total > 0 { (done as f64 / total as f64 * 100.0) as u32 } else { 100 }
+        })).ok();
+    }, app_handle.clone()).await;
+
+    operations::finish(&operation_id);
+
+    if let Ok(report) = &result {
+        app_handle.emit_all("copy-progress", serde_json::json!({
+            "operationId": operation_id,
+            "status": if cancel.is_cancelled() { "cancelled" } else { "completed" },
+            "copied": report.copied,
+            "skipped": report.skipped,
+            "failed": report.failed.len()
+        })).ok();
+    }
+
+    result.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn move_folder(
+    folder_path: String,
+    new_parent: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, String> {
+    state.ensure_writable()?;
+
+    storage::move_folder(&folder_path, &new_parent)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_folders() -> Result<Vec<storage::FolderMetadata>, String> {
+    storage::list_folders()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_folder_appearance(
+    folder_path: String,
+    color: Option<String>,
+    icon: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<storage::FolderMetadata, String> {
+    state.ensure_writable()?;
+
+    storage::set_folder_appearance(&folder_path, color, icon)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn migration_status() -> Result<storage::MigrationStatus, String> {
+    storage::migration_status()
         .await
         .map_err(|e| e.to_string())
 }
@@ -411,7 +2385,10 @@ async fn delete_folder(
 async fn migrate_files_to_folders(
     state: tauri::State<'_, AppState>,
     app_handle: tauri::AppHandle,
+    save_every: Option<usize>,
 ) -> Result<storage::MigrationReport, String> {
+    state.ensure_writable()?;
+
     let client_ref = {
         let client_guard = state.telegram_client.lock().await;
         if let Some(ref client) = *client_guard {
@@ -429,29 +2406,360 @@ async fn migrate_files_to_folders(
             "total": total,
             "progress": (current as f64 / total as f64 * 100.0) as u32,
         })).ok();
-    }, app_handle.clone()).await
+    }, app_handle.clone(), save_every.unwrap_or(storage::MIGRATION_SAVE_EVERY)).await
     .map_err(|e| e.to_string())
 }
 
+// Encrypt every file that isn't yet, with `password` as the vault key (it stays unlocked).
+// The password has to pass the vault's key check, like unlock_vault.
+#[tauri::command]
+async fn encrypt_existing_vault(
+    password: String,
+    operation_id: Option<String>,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<storage::BatchReport, String> {
+    state.ensure_writable()?;
+
+    if password.is_empty() {
+        return Err("Password cannot be empty".to_string());
+    }
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    let key = storage::vault_key(Some(client_ref.clone()), &password)
+        .await
+        .map_err(|e| e.to_string())?;
+    // Never swap out a key that's already unlocked: files sealed with it would stop decrypting
+    if encryption::session_encryptor().is_none() {
+        encryption::unlock(key);
+    } else if !encryption::is_session_key(&key) {
+        return Err("The vault is unlocked with a different password; lock it first".to_string());
+    }
+
+    let operation_id = operation_id.unwrap_or_else(|| "encrypt-vault".to_string());
+    let cancel = operations::register(&operation_id);
+    let config = config::AppConfig::load().await;
+    let budget = std::sync::Arc::new(operations::RetryBudget::new(
+        &operation_id,
+        config.batch_max_retries,
+        config.batch_max_flood_wait_secs,
+    ));
+
+    let progress_handle = app_handle.clone();
+    let progress_id = operation_id.clone();
+    let result = storage::encrypt_existing_vault(client_ref, &cancel, budget, move |file, done, total| {
+        progress_handle.emit_all("encrypt-progress", serde_json::json!({
+            "operationId": progress_id,
+            "file": file,
+            "done": done,
+            "total": total,
+            "progress": if total > 0 { (done as f64 / total as f64 * 100.0) as u32 } else { 100 }
+        })).ok();
+    }, app_handle.clone()).await;
+
+    operations::finish(&operation_id);
+    result.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_dialog_search_limits(first_pass: usize, second_pass: usize) -> Result<(), String> {
+    if first_pass == 0 {
+        return Err("Dialog search limit must be at least 1".to_string());
+    }
+
+    config::AppConfig::modify(|config| {
+        config.max_dialogs_to_search = first_pass;
+        config.max_dialogs_second_pass = second_pass.max(first_pass);
+    }).await.map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_caption_prefix(prefix: String) -> Result<(), String> {
+    if prefix.contains('\n') {
+        return Err("Caption prefix must be a single line".to_string());
+    }
+
+    config::AppConfig::modify(|config| config.caption_prefix = prefix)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Invalid sizes are rejected rather than rounded, so the UI can show why
+#[tauri::command]
+async fn set_transfer_chunk_size(chunk_size: u64) -> Result<(), String> {
+    storage::validate_chunk_size(chunk_size).map_err(|e| e.to_string())?;
+
+    config::AppConfig::modify(|config| config.transfer_chunk_size = chunk_size)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_transfer_chunk_size() -> Result<u64, String> {
+    Ok(config::AppConfig::load().await.transfer_chunk_size())
+}
+
+fn validate_concurrency(limit: usize) -> Result<(), String> {
+    if limit == 0 || limit > transfers::MAX_CONCURRENT_TRANSFERS {
+        return Err(format!("Concurrency must be between 1 and {}", transfers::MAX_CONCURRENT_TRANSFERS));
+    }
+    Ok(())
+}
+
+// Shared by every download command, batch or single; takes effect for the next transfer
+#[tauri::command]
+async fn set_max_concurrent_downloads(limit: usize) -> Result<(), String> {
+    validate_concurrency(limit)?;
+
+    config::AppConfig::modify(|config| config.max_concurrent_downloads = limit)
+        .await
+        .map_err(|e| e.to_string())?;
+    transfers::limits_changed();
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_max_concurrent_downloads() -> Result<usize, String> {
+    Ok(config::AppConfig::load().await.max_concurrent_downloads)
+}
+
+#[tauri::command]
+async fn set_max_concurrent_uploads(limit: usize) -> Result<(), String> {
+    validate_concurrency(limit)?;
+
+    config::AppConfig::modify(|config| config.max_concurrent_uploads = limit)
+        .await
+        .map_err(|e| e.to_string())?;
+    transfers::limits_changed();
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_max_concurrent_uploads() -> Result<usize, String> {
+    Ok(config::AppConfig::load().await.max_concurrent_uploads)
+}
+
+// Bytes parallel downloads may hold in memory at once (0 = a share of available memory)
+#[tauri::command]
+async fn set_max_download_buffer_bytes(max_bytes: u64) -> Result<(), String> {
+    let update = config::ConfigUpdate { max_download_buffer_bytes: Some(max_bytes), ..Default::default() };
+    config::AppConfig::update(update).await.map_err(|e| e.to_string())?;
+    transfers::limits_changed();
+    Ok(())
+}
+
+// The cap in effect, worked out from available memory when none is set
+#[tauri::command]
+async fn get_max_download_buffer_bytes() -> Result<u64, String> {
+    Ok(config::AppConfig::load().await.max_download_buffer_bytes())
+}
+
+// Cap the vault's total size (0 = no limit); uploads past it fail with a quota error
+#[tauri::command]
+async fn set_vault_quota(max_bytes: u64) -> Result<(), String> {
+    let update = config::ConfigUpdate { max_vault_bytes: Some(max_bytes), ..Default::default() };
+    config::AppConfig::update(update).await.map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_vault_quota() -> Result<storage::VaultQuota, String> {
+    storage::vault_quota()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Hold the key for encrypted uploads and downloads in memory until lock_vault.
+// A password that fails the vault's key check is refused.
+#[tauri::command]
+async fn unlock_vault(password: String, state: tauri::State<'_, AppState>) -> Result<(), String> {
+    if password.is_empty() {
+        return Err("Password cannot be empty".to_string());
+    }
+    let client_ref = state.telegram_client.lock().await.as_ref().map(|client| client.get_client_ref());
+    let key = storage::vault_key(client_ref, &password)
+        .await
+        .map_err(|e| e.to_string())?;
+    encryption::unlock(key);
+    Ok(())
+}
+
+#[tauri::command]
+async fn lock_vault() -> Result<(), String> {
+    encryption::lock();
+    Ok(())
+}
+
+#[tauri::command]
+async fn is_vault_unlocked() -> Result<bool, String> {
+    Ok(encryption::session_encryptor().is_some())
+}
+
+// Encrypted files below this size are sealed in memory, larger ones are streamed
+#[tauri::command]
+async fn set_encryption_stream_threshold(threshold: u64) -> Result<(), String> {
+    config::AppConfig::modify(|config| config.encryption_stream_threshold = threshold)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_readonly(enabled: bool, state: tauri::State<'_, AppState>) -> Result<(), String> {
+    state.readonly.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+#[tauri::command]
+async fn is_readonly(state: tauri::State<'_, AppState>) -> Result<bool, String> {
+    Ok(state.readonly.load(Ordering::SeqCst))
+}
+
+#[tauri::command]
+async fn get_bandwidth_stats() -> Result<bandwidth::BandwidthStats, String> {
+    bandwidth::get_stats().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn reset_bandwidth_stats() -> Result<(), String> {
+    bandwidth::reset().await.map_err(|e| e.to_string())
+}
+
+// Check a candidate api_id/api_hash against Telegram without touching the saved keys
+#[tauri::command]
+async fn validate_api_keys(api_id: i32, api_hash: String) -> Result<(), String> {
+    telegram::TelegramClient::validate_credentials(api_id, &api_hash).await
+        .map_err(|e| format!("Invalid API credentials: {}. Please check your API ID and API Hash from https://my.telegram.org/apps", e))
+}
+
 #[tauri::command]
 async fn save_api_keys(api_id: i32, api_hash: String) -> Result<(), String> {
     // Validate the API keys by attempting to use them
     // This ensures the keys are correct before saving
-    match telegram::TelegramClient::validate_credentials(api_id, &api_hash).await {
-        Ok(_) => {
-            // Keys are valid, save them
-            let keys = api_keys::ApiKeys {
-                api_id,
-                api_hash,
-            };
-            keys.save().await.map_err(|e| e.to_string())?;
-            Ok(())
-        }
-        Err(e) => {
-            // Validation failed - keys are invalid
-            Err(format!("Invalid API credentials: {}. Please check your API ID and API Hash from https://my.telegram.org/apps", e))
+    validate_api_keys(api_id, api_hash.clone()).await?;
+
+    let keys = api_keys::ApiKeys {
+        api_id,
+        api_hash,
+    };
+    keys.save().await.map_err(|e| e.to_string())
+}
+
+// Point the app at another .session file inside the data directory (None = default).
+// Takes effect the next time the client is created, i.e. after a restart.
+#[tauri::command]
+async fn set_session_path(path: Option<String>) -> Result<(), String> {
+    let path = path.map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+
+    if let Some(ref requested) = path {
+        telegram::validate_session_path(requested).await.map_err(|e| e.to_string())?;
+    }
+
+    config::AppConfig::modify(|config| config.session_path = path)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_session_path() -> Result<Option<String>, String> {
+    Ok(config::AppConfig::load().await.session_path)
+}
+
+// Back up the session file; with `encrypt` it is sealed with the unlocked vault key
+#[tauri::command]
+async fn export_session(destination: String, encrypt: Option<bool>) -> Result<u64, String> {
+    let encryptor = if encrypt.unwrap_or(false) {
+        Some(encryption::session_encryptor().ok_or_else(|| error::TvaultError::VaultLocked.to_string())?)
+    } else {
+        None
+    };
+
+    telegram::export_session(std::path::Path::new(&destination), encryptor.as_ref())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Restore a session backup. It is only installed if Telegram still accepts it;
+// the client is then recreated on it. Returns whether the new client is logged in.
+#[tauri::command]
+async fn import_session(path: String, state: tauri::State<'_, AppState>) -> Result<bool, String> {
+    state.ensure_writable()?;
+
+    let encryptor = encryption::session_encryptor();
+    let staged = telegram::stage_session_import(std::path::Path::new(&path), encryptor.as_ref())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut client_guard = state.telegram_client.lock().await;
+    if let Some(client) = client_guard.take() {
+        client.shutdown().await;
+    }
+    if let Err(e) = telegram::install_session(&staged).await {
+        // The old session file is still in place; bring its client back before reporting
+        let _ = tokio::fs::remove_file(&staged).await;
+        match telegram::TelegramClient::new().await {
+            Ok(client) => *client_guard = Some(client),
+            Err(restore_error) => eprintln!("Warning: Failed to reopen the previous session: {}", restore_error),
         }
+        return Err(e.to_string());
     }
+
+    let client = telegram::TelegramClient::new().await.map_err(|e| e.to_string())?;
+    let authenticated = client.is_authenticated().await.unwrap_or(false);
+    *client_guard = Some(client);
+    Ok(authenticated)
+}
+
+// Every setting in one place, for the settings panel
+#[tauri::command]
+async fn get_config() -> Result<config::EffectiveConfig, String> {
+    config::AppConfig::effective().await.map_err(|e| e.to_string())
+}
+
+// Apply a partial settings change; nothing is saved if any field is invalid
+#[tauri::command]
+async fn set_config(update: config::ConfigUpdate) -> Result<config::EffectiveConfig, String> {
+    config::AppConfig::update(update).await.map_err(|e| e.to_string())?;
+    transfers::limits_changed();
+    config::AppConfig::effective().await.map_err(|e| e.to_string())
+}
+
+// Create or link a channel for root files; with neither, root goes back to Saved Messages
+#[tauri::command]
+async fn set_root_channel(
+    create_new: bool,
+    chat_id: Option<i64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<i64>, String> {
+    state.ensure_writable()?;
+
+    let client_ref = {
+        let client_guard = state.telegram_client.lock().await;
+        if let Some(ref client) = *client_guard {
+            client.get_client_ref()
+        } else {
+            return Err("Not authenticated".to_string());
+        }
+    }; // Lock released here
+
+    storage::set_root_channel(client_ref, create_new, chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn upload_delay_status() -> Result<transfers::UploadDelayStatus, String> {
+    Ok(transfers::upload_delay_status().await)
 }
 
 #[tauri::command]
@@ -497,26 +2805,154 @@ fn main() {
         tauri::Builder::default()
             .manage(AppState {
                 telegram_client: Mutex::new(None),
+                readonly: AtomicBool::new(false),
+            })
+            .setup(|app| {
+                tokio::spawn(maintenance_loop(app.handle()));
+                Ok(())
             })
             .invoke_handler(tauri::generate_handler![
+                get_last_phone,
+                clear_last_phone,
+                set_hash_last_phone,
                 check_api_keys_configured,
+                validate_api_keys,
                 save_api_keys,
                 initialize_client,
+                set_session_path,
+                get_session_path,
+                get_config,
+                export_session,
+                import_session,
+                set_config,
+                upload_delay_status,
+                set_root_channel,
                 telegram_login,
                 telegram_verify_code,
+                cancel_login,
+                export_login_token,
+                import_login_token,
                 telegram_check_auth,
                 upload_file,
+                upload_from_url,
+                recent_upload_failures,
                 download_file,
+                stream_file,
+                ack_stream_chunk,
+                cancel_stream,
+                resume_downloads,
+                upload_files,
+                upload_folder,
+                download_files,
                 download_thumbnail,
+                get_folder_avatar,
+                set_folder_avatar,
+                can_download,
+                preflight_download,
+                preview_text,
                 list_files,
+                list_recent,
+                find_file_by_message,
+                mime_type_breakdown,
+                list_files_by_size,
+                list_largest_files,
                 get_folder_stats,
+                get_folder_dedup_stats,
+                list_files_without_checksum,
+                backfill_checksums,
+                repair_vault,
+                set_file_order,
                 list_files_recursive,
                 create_folder,
+                ensure_folder,
+                precreate_folders,
+                convert_folder_channel,
+                check_folder_channel,
+                folder_permissions,
                 delete_file,
+                list_file_versions,
+                download_file_version,
+                set_file_description,
+                set_file_custom,
+                get_file_custom,
                 delete_folder,
+                delete_folder_preserving,
+                verify_vault,
+                scan_vault_health,
+                vault_health_report,
+                audit_file_sizes,
+                run_maintenance,
+                audit_file_locations,
+                find_duplicate_messages,
+                repair_duplicate_messages,
+                find_unreferenced_uploads,
+                import_unreferenced_uploads,
+                delete_unreferenced_uploads,
+                list_tombstones,
+                clear_tombstones,
+                list_orphaned_channels,
+                delete_orphaned_channels,
+                move_file,
+                copy_file,
+                copy_folder,
+                move_folder,
+                list_folders,
+                set_folder_appearance,
+                share_file_to_chat,
+                refresh_file_name,
+                clean_file_names,
+                relink_file,
+                repair_folder_metadata,
+                rebuild_metadata_from_telegram,
+                find_duplicate_folders,
+                audit_channels,
+                audit_folder_titles,
+                ping_telegram,
+                merge_folders,
                 get_storage_stats,
+                compact_metadata,
+                normalize_ids,
+                take_metadata_recovery,
+                schema_status,
+                merge_metadata,
+                export_metadata,
+                export_folder_manifest,
+                import_folder_manifest,
+                export_folder,
+                download_folder,
+                download_changed_since,
                 sync_metadata,
+                cancel_operation,
+                cancel_all,
+                set_queue_paused,
+                is_queue_paused,
+                list_queued_operations,
+                cancel_queued,
+                prioritize_operation,
+                resume_batch,
+                migration_status,
                 migrate_files_to_folders,
+                encrypt_existing_vault,
+                set_dialog_search_limits,
+                set_caption_prefix,
+                set_transfer_chunk_size,
+                get_transfer_chunk_size,
+                set_max_concurrent_downloads,
+                get_max_concurrent_downloads,
+                set_max_concurrent_uploads,
+                get_max_concurrent_uploads,
+                set_max_download_buffer_bytes,
+                get_max_download_buffer_bytes,
+                set_vault_quota,
+                get_vault_quota,
+                unlock_vault,
+                lock_vault,
+                is_vault_unlocked,
+                set_encryption_stream_threshold,
+                set_readonly,
+                is_readonly,
+                get_bandwidth_stats,
+                reset_bandwidth_stats,
             ])
             .run(tauri::generate_context!())
             .expect("error while running tauri application");
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use lazy_static::lazy_static;
+use tokio::sync::oneshot;
+
+lazy_static! {
+    // Cancellation flags of long-running operations, keyed by an id chosen by the caller
+    static ref OPERATIONS: std::sync::Mutex<HashMap<String, CancelToken>> = std::sync::Mutex::new(HashMap::new());
+    // Batches paused on their retry budget, waiting for the user to continue or stop
+    static ref PAUSED: std::sync::Mutex<HashMap<String, oneshot::Sender<bool>>> = std::sync::Mutex::new(HashMap::new());
+}
+
+// Bumped by cancel_all; transfers that started (or queued) before a bump stop at their next chunk
+static TRANSFER_EPOCH: AtomicU64 = AtomicU64::new(0);
+// Numbers the ids handed out by unique_id
+static NEXT_OPERATION: AtomicU64 = AtomicU64::new(1);
+
+// Shared flag a long-running operation polls between steps
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// A fresh id like "upload-3" for a caller that didn't pick one, so two runs of the same
+// kind don't share (and finish) each other's token
+pub fn unique_id(kind: &str) -> String {
+    format!("{}-{}", kind, NEXT_OPERATION.fetch_add(1, Ordering::SeqCst))
+}
+
+// Register an operation and get its token. Re-using an id replaces the old token.
+pub fn register(operation_id: &str) -> CancelToken {
+    let token = CancelToken::default();
+    if let Ok(mut operations) = OPERATIONS.lock() {
+        operations.insert(operation_id.to_string(), token.clone());
+    }
+    token
+}
+
+// Request cancellation. Returns false if no such operation is running.
+pub fn cancel(operation_id: &str) -> bool {
+    match OPERATIONS.lock().ok().and_then(|operations| operations.get(operation_id).cloned()) {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+// Forget a finished operation
+pub fn finish(operation_id: &str) {
+    if let Ok(mut operations) = OPERATIONS.lock() {
+        operations.remove(operation_id);
+    }
+}
+
+pub fn transfer_epoch() -> u64 {
+    TRANSFER_EPOCH.load(Ordering::SeqCst)
+}
+
+// Whether cancel_all ran since `epoch` was taken
+pub fn transfers_aborted_since(epoch: u64) -> bool {
+    transfer_epoch() != epoch
+}
+
+// Stop everything: cancel every registered operation, stop batches waiting on a retry
+// prompt, abort in-flight transfers, clear the queue and pause it, so nothing new starts
+// until transfers::set_queue_paused(false). Returns how many operations were cancelled;
+// single transfers outside an operation are aborted but not counted.
+pub fn cancel_all() -> usize {
+    crate::transfers::set_queue_paused(true);
+    TRANSFER_EPOCH.fetch_add(1, Ordering::SeqCst);
+    crate::transfers::clear_queue();
+
+    let tokens: HashMap<String, CancelToken> = OPERATIONS.lock()
+        .map(|operations| operations.clone())
+        .unwrap_or_default();
+    for token in tokens.values() {
+        token.cancel();
+    }
+
+    let paused: Vec<(String, oneshot::Sender<bool>)> = PAUSED.lock()
+        .map(|mut paused| paused.drain().collect())
+        .unwrap_or_default();
+    let mut cancelled = tokens.len();
+    for (operation_id, sender) in paused {
+        if !tokens.contains_key(&operation_id) {
+            cancelled += 1;
+        }
+        let _ = sender.send(false);
+    }
+
+    println!("Cancelled {} operations", cancelled);
+    cancelled
+}
+
+// Retries and flood-wait time shared by every file of one batch, so an outage can't make
+// each file burn through its own retries. Once either total crosses its limit the batch
+// pauses until the user decides whether to go on.
+#[derive(Debug)]
+pub struct RetryBudget {
+    pub operation_id: String,
+    pub max_retries: u32,
+    pub max_flood_wait_secs: u64,
+    retries: AtomicU32,
+    flood_wait_secs: AtomicU64,
+    stopped: AtomicBool,
+    prompt: tokio::sync::Mutex<()>,  // One pause prompt at a time
+}
+
+impl RetryBudget {
+    pub fn new(operation_id: &str, max_retries: u32, max_flood_wait_secs: u64) -> Self {
+        Self {
+            operation_id: operation_id.to_string(),
+            max_retries,
+            max_flood_wait_secs,
+            retries: AtomicU32::new(0),
+            flood_wait_secs: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+            prompt: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    // Count one retry, plus the flood wait Telegram asked for before it (0 if none)
+    pub fn record_retry(&self, flood_wait_secs: u64) {
+        self.retries.fetch_add(1, Ordering::SeqCst);
+        self.flood_wait_secs.fetch_add(flood_wait_secs, Ordering::SeqCst);
+    }
+
+    // (retries, flood-wait seconds) spent since the batch started or was last continued
+    pub fn spent(&self) -> (u32, u64) {
+        (self.retries.load(Ordering::SeqCst), self.flood_wait_secs.load(Ordering::SeqCst))
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        let (retries, flood_wait_secs) = self.spent();
+        retries >= self.max_retries || flood_wait_secs >= self.max_flood_wait_secs
+    }
+
+    // The user chose to stop the batch
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    // If the budget is used up, announce the pause via `on_pause` and wait for resume().
+    // Continuing starts a fresh budget. Returns false once the batch is stopped.
+    pub async fn checkpoint(&self, on_pause: impl FnOnce(u32, u64)) -> bool {
+        let _prompt = self.prompt.lock().await;
+        if self.is_stopped() {
+            return false;
+        }
+        if !self.is_exhausted() {
+            return true;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        if let Ok(mut paused) = PAUSED.lock() {
+            paused.insert(self.operation_id.clone(), sender);
+        }
+        let (retries, flood_wait_secs) = self.spent();
+        on_pause(retries, flood_wait_secs);
+
+        let proceed = receiver.await.unwrap_or(false);
+        if proceed {
+            self.retries.store(0, Ordering::SeqCst);
+            self.flood_wait_secs.store(0, Ordering::SeqCst);
+        } else {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+        proceed
+    }
+}
+
+// Answer a paused batch. Returns false if no batch with that id is waiting.
+pub fn resume(operation_id: &str, proceed: bool) -> bool {
+    let sender = PAUSED.lock().ok().and_then(|mut paused| paused.remove(operation_id));
+    match sender {
+        Some(sender) => sender.send(proceed).is_ok(),
+        None => false,
+    }
+}
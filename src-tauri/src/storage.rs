@@ -4,28 +4,50 @@ use anyhow::Result;
 use grammers_client::{Client, peer::Peer, media::Media, message::{Message, InputMessage}};
 use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock};
-use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use lazy_static::lazy_static;
 use tauri::Manager;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use crate::error::TvaultError;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 
 lazy_static! {
     static ref METADATA_CACHE: RwLock<Option<MetadataStore>> = RwLock::new(None);
+    static ref PENDING_DOWNLOADS_LOCK: Mutex<()> = Mutex::new(());
+    // Destinations of downloads running in this process; maintenance leaves their `.part` files alone
+    static ref ACTIVE_DOWNLOADS: std::sync::Mutex<std::collections::HashSet<String>> =
+        std::sync::Mutex::new(std::collections::HashSet::new());
+    // Set when a corrupt metadata.json was moved aside at load, until the UI picks it up
+    static ref METADATA_RECOVERY: RwLock<Option<MetadataRecovery>> = RwLock::new(None);
+    // Chunks each running stream may still send before the UI acknowledges one
+    static ref STREAM_CREDITS: std::sync::Mutex<std::collections::HashMap<String, Arc<tokio::sync::Semaphore>>> =
+        std::sync::Mutex::new(std::collections::HashMap::new());
+    // Bytes of uploads in flight, counted against the quota before they reach the metadata
+    static ref QUOTA_RESERVED: std::sync::Mutex<u64> = std::sync::Mutex::new(0);
+    // Held from reading the used bytes until the reservation is added
+    static ref QUOTA_CHECK_LOCK: Mutex<()> = Mutex::new(());
+    // Uploads that gave up for good, newest last
+    static ref RECENT_UPLOAD_FAILURES: std::sync::Mutex<std::collections::VecDeque<UploadFailure>> =
+        std::sync::Mutex::new(std::collections::VecDeque::new());
 }
 
-// Helper function to extract flood wait time from error message
+// Helper function to extract flood wait time from error message.
+// Handles both "flood_wait_300" and grammers' "flood_wait ... (value: 300)" forms.
 fn extract_flood_wait(error_str: &str) -> Option<u64> {
     use regex::Regex;
-    let re = Regex::new(r"flood_wait_(\d+)").ok()?;
+    let re = Regex::new(r"(?i)flood_wait(?:_(\d+)|[^(]*\(value:\s*(\d+)\))").ok()?;
     if let Some(caps) = re.captures(error_str) {
-        caps.get(1)?.as_str().parse().ok()
+        caps.get(1).or_else(|| caps.get(2))?.as_str().parse().ok()
     } else {
         None
     }
 }
 
+const FLOOD_WAIT_SHORT_CAP_SECS: u64 = 60;   // Waits up to this are handled as a normal retry
+const MAX_FLOOD_WAIT_SECS: u64 = 10 * 60;   // Longer waits are honoured up to this, then we give up
+
 // Check if error is transient and worth retrying
 fn is_retryable_error(error_str: &str) -> bool {
     let error_lower = error_str.to_lowercase();
@@ -42,6 +64,379 @@ fn is_retryable_error(error_str: &str) -> bool {
     error_lower.contains("broken pipe")
 }
 
+const MAX_RECENT_UPLOAD_FAILURES: usize = 50;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadFailure {
+    pub file: String,
+    pub folder: String,
+    pub kind: crate::error::UploadFailureKind,
+    pub remedy: String,
+    pub attempts: u32,
+    pub error: String,  // The last attempt's error
+    pub failed_at: i64,
+}
+
+// Sort a failed upload into something the user can act on. `history` holds the errors of
+// earlier attempts: an upload that kept hitting flood waits is rate limited even if its
+// last attempt died of a timeout.
+fn classify_upload_failure(error: &str, history: &[String]) -> crate::error::UploadFailureKind {
+    use crate::error::UploadFailureKind;
+
+    let upper = error.to_uppercase();
+    let lower = error.to_lowercase();
+    if lower.contains("too large") || upper.contains("FILE_PARTS_INVALID") || upper.contains("FILE_PART_TOO_BIG") {
+        return UploadFailureKind::FileTooLarge;
+    }
+    if ["AUTH_KEY_UNREGISTERED", "AUTH_KEY_INVALID", "SESSION_REVOKED", "SESSION_EXPIRED", "USER_DEACTIVATED", "NOT AUTHENTICATED"]
+        .iter()
+        .any(|code| upper.contains(code))
+    {
+        return UploadFailureKind::AuthExpired;
+    }
+    if ["CHANNEL_INVALID", "CHANNEL_PRIVATE", "PEER_ID_INVALID", "CHAT_WRITE_FORBIDDEN", "CHAT_ADMIN_REQUIRED", "USER_BANNED"]
+        .iter()
+        .any(|code| upper.contains(code))
+        || (lower.contains("chat with id") && lower.contains("not found"))
+    {
+        return UploadFailureKind::PeerGone;
+    }
+
+    let is_flood = |e: &str| {
+        let lower = e.to_lowercase();
+        extract_flood_wait(&lower).is_some() || lower.contains("flood_wait") || lower.contains("too many requests")
+    };
+    let floods = history.iter().filter(|e| is_flood(e)).count();
+    if is_flood(error) || floods * 2 > history.len() {
+        return UploadFailureKind::FloodLimited;
+    }
+    if is_retryable_error(error) || history.iter().any(|e| is_retryable_error(e)) {
+        return UploadFailureKind::NetworkUnstable;
+    }
+    UploadFailureKind::Unknown
+}
+
+// Give up on an upload: classify the failure, keep it for recent_upload_failures and
+// return the error the caller passes on
+fn upload_failed(file_name: &str, folder: &str, error: &str, history: &[String], attempts: u32) -> TvaultError {
+    record_upload_failure(file_name, folder, classify_upload_failure(error, history), error, attempts)
+}
+
+// upload_failed for a caller that already knows why the upload gave up
+fn record_upload_failure(file_name: &str, folder: &str, kind: crate::error::UploadFailureKind, error: &str, attempts: u32) -> TvaultError {
+    let failure = UploadFailure {
+        file: file_name.to_string(),
+        folder: folder.to_string(),
+        kind,
+        remedy: kind.remedy().to_string(),
+        attempts,
+        error: error.to_string(),
+        failed_at: chrono::Utc::now().timestamp(),
+    };
+    println!("Upload of {} failed ({:?}) after {} attempts: {}", file_name, kind, attempts, error);
+
+    let mut recent = RECENT_UPLOAD_FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+    recent.push_back(failure);
+    while recent.len() > MAX_RECENT_UPLOAD_FAILURES {
+        recent.pop_front();
+    }
+
+    TvaultError::UploadFailed { kind, attempts, detail: error.to_string() }
+}
+
+// Newest first
+pub fn recent_upload_failures() -> Vec<UploadFailure> {
+    let recent = RECENT_UPLOAD_FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+    recent.iter().rev().cloned().collect()
+}
+
+pub const DEFAULT_CAPTION_PREFIX: &str = "📁 ";  // Also all that uploads from before the marker existed carry
+const CAPTION_MARKER: &str = "#tvault";     // Bare marker line, used when no trailer fits
+const CAPTION_TRAILER_PREFIX: &str = "#tvault:v"; // Followed by "<version>:<base64 JSON>"
+const KEY_CHECK_PREFIX: &str = "#tvault:key:";    // Saved Messages note holding the vault's key check as base64 JSON
+const CAPTION_TRAILER_VERSION: u32 = 1;
+const MAX_TRAILER_CHARS: usize = 512; // Leaves at least half of the caption for the visible text
+const MAX_CAPTION_CHARS: usize = 1024; // Telegram caption limit for standard users
+const CAPTION_TRUNCATION_MARKER: &str = "…";
+const MAX_DESCRIPTION_CHARS: usize = 2000; // Longer ones are cut; the caption shows what fits
+const MAX_CUSTOM_METADATA_BYTES: usize = 256; // Keys plus values, so they fit in the trailer next to the rest
+
+// Metadata embedded in every upload caption so a vault can be rebuilt from Telegram alone.
+// Serialized as JSON and base64-encoded behind `#tvault:v1:` on the caption's last line;
+// bump CAPTION_TRAILER_VERSION when the meaning of a field changes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptionTrailer {
+    pub folder: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>, // Hex SHA-256 of the stored bytes (ciphertext for encrypted files)
+    pub encrypted: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>, // Kept date when it isn't the message's (e.g. the original mtime)
+}
+
+// Encode the trailer line. A long description is dropped first, then the name (both are still
+// in the caption body), then custom metadata (still kept locally); if even that doesn't fit,
+// the bare marker keeps the file recognizable.
+fn build_caption_trailer(trailer: &CaptionTrailer) -> String {
+    let candidates = [
+        trailer.clone(),
+        CaptionTrailer { description: None, ..trailer.clone() },
+        CaptionTrailer { description: None, name: None, ..trailer.clone() },
+        CaptionTrailer { description: None, name: None, custom: BTreeMap::new(), ..trailer.clone() },
+    ];
+
+    for candidate in candidates.iter() {
+        if let Ok(json) = serde_json::to_vec(candidate) {
+            let line = format!(
+                "{}{}:{}",
+                CAPTION_TRAILER_PREFIX, CAPTION_TRAILER_VERSION, URL_SAFE_NO_PAD.encode(json)
+            );
+            if line.chars().count() <= MAX_TRAILER_CHARS {
+                return line;
+            }
+        }
+    }
+
+    CAPTION_MARKER.to_string()
+}
+
+// Decode the trailer from a caption's last line. None for captions without one,
+// bare-marker captions and trailers written by a newer schema version.
+fn parse_caption_trailer(text: &str) -> Option<CaptionTrailer> {
+    let last_line = text.trim_end().lines().last()?.trim();
+    let (version, payload) = last_line.strip_prefix(CAPTION_TRAILER_PREFIX)?.split_once(':')?;
+    if version.parse::<u32>().ok()? != CAPTION_TRAILER_VERSION {
+        return None;
+    }
+
+    let json = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+// Whether a caption line is the T-Vault marker, bare or carrying a trailer
+fn is_marker_line(line: &str) -> bool {
+    line == CAPTION_MARKER || line.starts_with(CAPTION_TRAILER_PREFIX)
+}
+
+// Build the message caption: file name on the first line, optional description below,
+// and the machine-readable trailer as the last line
+fn build_caption(prefix: &str, file_name: &str, description: Option<&str>, trailer: &CaptionTrailer) -> String {
+    let trailer_line = build_caption_trailer(trailer);
+    let mut caption = format!("{}{}", prefix, file_name);
+
+    if let Some(description) = description.map(str::trim).filter(|d| !d.is_empty()) {
+        caption.push_str("\n\n");
+        caption.push_str(description);
+    }
+
+    // Truncate the body, never the trailer
+    let marker_chars = trailer_line.chars().count() + 2;
+    let max_body = MAX_CAPTION_CHARS - marker_chars;
+    if caption.chars().count() > max_body {
+        let keep = max_body - CAPTION_TRUNCATION_MARKER.chars().count();
+        caption = caption.chars().take(keep).collect::<String>() + CAPTION_TRUNCATION_MARKER;
+    }
+
+    caption.push_str("\n\n");
+    caption.push_str(&trailer_line);
+    caption
+}
+
+// Split a caption built by `build_caption` back into (file name, description).
+// Captions without the marker are only accepted with the default prefix.
+fn parse_caption(text: &str, prefix: &str) -> Option<(String, Option<String>)> {
+    let trimmed = text.trim_end();
+    let (body, last_line) = trimmed.rsplit_once('\n').unwrap_or(("", trimmed));
+    let rest = if is_marker_line(last_line.trim()) {
+        strip_caption_prefix(body.trim(), prefix)
+    } else {
+        trimmed.strip_prefix(DEFAULT_CAPTION_PREFIX)?
+    };
+
+    let (name, description) = match rest.split_once('\n') {
+        Some((name, description)) => (name, Some(description.trim().to_string())),
+        None => (rest, None),
+    };
+
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), description.filter(|d| !d.is_empty())))
+}
+
+// `text` without the configured or default caption prefix in front. Other leading emoji are
+// part of the name.
+fn strip_caption_prefix<'a>(text: &'a str, prefix: &str) -> &'a str {
+    for known_prefix in [prefix.trim(), DEFAULT_CAPTION_PREFIX.trim()] {
+        if !known_prefix.is_empty() {
+            // Some clients send the emoji with a variation selector after it
+            if let Some(rest) = text.strip_prefix(known_prefix) {
+                return rest.trim_start_matches('\u{fe0f}').trim_start();
+            }
+        }
+    }
+    text
+}
+
+// Turn caption-derived text into a bare file name: first line only, without the configured
+// or default prefix, or marker/trailer text glued onto it
+fn clean_file_name(raw: &str, prefix: &str) -> String {
+    let first_line = raw.trim().lines().next().unwrap_or("").trim();
+    if is_marker_line(first_line) {
+        return String::new();
+    }
+
+    let name = match first_line.find(CAPTION_MARKER) {
+        Some(pos) if pos > 0 && first_line[..pos].ends_with(char::is_whitespace) => first_line[..pos].trim_end(),
+        _ => first_line,
+    };
+
+    strip_caption_prefix(name, prefix).to_string()
+}
+
+// Options that tweak how a single upload is stored
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadOptions {
+    pub description: Option<String>,
+    pub supersedes: Option<String>,  // File id this upload becomes the new version of
+    #[serde(default)]
+    pub progress: ProgressConfig,
+    #[serde(skip)]
+    pub target: Option<UploadTarget>,  // Chat resolved up front for batch uploads
+    #[serde(default)]
+    pub encrypt: bool,  // Encrypt with the unlocked vault key before uploading
+    #[serde(skip)]
+    pub retry_budget: Option<Arc<crate::operations::RetryBudget>>,  // Shared by the files of a batch
+    #[serde(default)]
+    pub created_at: Option<i64>,  // Keep this timestamp (e.g. the original mtime) instead of the upload time
+    #[serde(default)]
+    pub as_photo: bool,  // Send an image as a compressed Telegram photo instead of the original file
+    #[serde(default)]
+    pub custom: BTreeMap<String, String>,  // Carried over by re-uploads; a new version inherits the old one's when empty
+}
+
+// A local file's modification time as a Unix timestamp, for imports that keep original dates
+pub fn modified_timestamp(meta: &std::fs::Metadata) -> Option<i64> {
+    let modified = meta.modified().ok()?;
+    let secs = modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    i64::try_from(secs).ok()
+}
+
+// A resolved destination chat for uploads into a folder (chat_id None = Saved Messages)
+#[derive(Debug, Clone)]
+pub struct UploadTarget {
+    pub chat: Peer,
+    pub chat_id: Option<i64>,
+}
+
+// Human-readable name for a media kind, used in errors and support reports
+fn media_kind(media: &Media) -> &'static str {
+    match media {
+        Media::Document(_) => "document",
+        Media::Photo(_) => "photo",
+        Media::Sticker(_) => "sticker",
+        Media::Contact(_) => "contact",
+        Media::Poll(_) => "poll",
+        Media::Geo(_) => "location",
+        Media::Venue(_) => "venue",
+        Media::Dice(_) => "dice",
+        _ => "unknown",
+    }
+}
+
+// Normalize media to something we can stream: stickers are backed by a document,
+// and audio/voice/video notes already arrive as `Media::Document`
+fn downloadable_media(media: Media) -> Result<Media, TvaultError> {
+    match media {
+        Media::Document(_) | Media::Photo(_) => Ok(media),
+        Media::Sticker(sticker) => Ok(Media::Document(sticker.document)),
+        other => Err(TvaultError::UnsupportedMedia(media_kind(&other).to_string())),
+    }
+}
+
+const MAX_PHOTO_BYTES: u64 = 10 * 1024 * 1024; // Telegram's limit for photos
+
+// Only unencrypted images small enough for Telegram can go up as photos
+fn check_photo_upload(file_name: &str, mime_type: &str, file_size: u64, encrypt: bool) -> Result<()> {
+    if encrypt {
+        return Err(anyhow::anyhow!("Encrypted files can't be sent as photos: {}", file_name));
+    }
+    if !mime_type.starts_with("image/") {
+        return Err(anyhow::anyhow!("Only images can be sent as photos: {} is {}", file_name, mime_type));
+    }
+    if file_size > MAX_PHOTO_BYTES {
+        return Err(anyhow::anyhow!(
+            "{} is too large to send as a photo ({} bytes, limit {})", file_name, file_size, MAX_PHOTO_BYTES
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultQuota {
+    pub max_bytes: u64,       // 0 = no limit
+    pub used_bytes: u64,
+    pub reserved_bytes: u64,  // Uploads underway
+}
+
+// Room for `size` more bytes? A limit of 0 means there is no quota.
+fn check_quota(size: u64, used: u64, reserved: u64, limit: u64) -> Result<(), TvaultError> {
+    if limit > 0 && used.saturating_add(reserved).saturating_add(size) > limit {
+        return Err(TvaultError::QuotaExceeded { size, used, reserved, limit });
+    }
+    Ok(())
+}
+
+// Counts an upload's size against the quota until it finishes; released when dropped
+struct QuotaReservation(u64);
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        let mut reserved = QUOTA_RESERVED.lock().unwrap_or_else(|e| e.into_inner());
+        *reserved = reserved.saturating_sub(self.0);
+    }
+}
+
+// The client currently held in the shared slot. Long operations call this again before
+// each attempt instead of keeping the clone they started with.
+async fn shared_client<C: Clone>(client_ref: &Mutex<Option<C>>) -> Result<C> {
+    let client_guard = client_ref.lock().await;
+    client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))
+}
+
+// Check and reserve in one step, so parallel uploads can't each see the same free space
+async fn reserve_quota(size: u64) -> Result<QuotaReservation> {
+    let _check = QUOTA_CHECK_LOCK.lock().await;
+    let limit = crate::config::AppConfig::load().await.max_vault_bytes;
+    // Reservations are read before the stats: an upload finishing in between is then counted
+    // twice rather than not at all. New ones can't appear meanwhile, we hold the check lock.
+    let reserved_before = *QUOTA_RESERVED.lock().unwrap_or_else(|e| e.into_inner());
+    let used = get_storage_stats().await?.total_size;
+    check_quota(size, used, reserved_before, limit)?;
+    *QUOTA_RESERVED.lock().unwrap_or_else(|e| e.into_inner()) += size;
+    Ok(QuotaReservation(size))
+}
+
+pub async fn vault_quota() -> Result<VaultQuota> {
+    Ok(VaultQuota {
+        max_bytes: crate::config::AppConfig::load().await.max_vault_bytes,
+        used_bytes: get_storage_stats().await?.total_size,
+        reserved_bytes: *QUOTA_RESERVED.lock().unwrap_or_else(|e| e.into_inner()),
+    })
+}
+
 // Helper function to attempt upload with proper error handling and resume support
 async fn attempt_upload(
     client: &grammers_client::Client,
@@ -49,6 +444,31 @@ async fn attempt_upload(
     file_path: &str,
     file_name: &str,
     file_size: u64,
+    caption: &str,
+    encryption: Option<(&crate::encryption::Encryptor, u64)>,  // Key and streaming threshold
+    thumbnail: Option<&Path>,
+    as_photo: bool,
+    progress_config: ProgressConfig,
+    on_progress: Box<dyn Fn(u32, u64, u64) + Send + Sync>,
+) -> Result<i32> {
+    let file = tokio::fs::File::open(file_path).await
+        .map_err(|e| anyhow::anyhow!("Failed to open file for upload: {}", e))?;
+    attempt_upload_reader(client, target_chat, file, file_name, file_size, caption, encryption, thumbnail, as_photo, progress_config, on_progress).await
+}
+
+// Upload `file_size` bytes from `file` and post them to `target_chat`. Local files and
+// URL bodies both come through here, so nothing has to be staged on disk first.
+async fn attempt_upload_reader<R: AsyncRead + Unpin + Send + 'static>(
+    client: &grammers_client::Client,
+    target_chat: &Peer,
+    file: R,
+    file_name: &str,
+    file_size: u64,
+    caption: &str,
+    encryption: Option<(&crate::encryption::Encryptor, u64)>,  // Key and streaming threshold
+    thumbnail: Option<&Path>,
+    as_photo: bool,
+    progress_config: ProgressConfig,
     on_progress: Box<dyn Fn(u32, u64, u64) + Send + Sync>,
 ) -> Result<i32> {
     // Calculate dynamic timeout based on file size
@@ -62,27 +482,43 @@ async fn attempt_upload(
 
     // Add timeout for the entire upload process
     let upload_future = async {
-        let file = tokio::fs::File::open(file_path).await
-            .map_err(|e| anyhow::anyhow!("Failed to open file for upload: {}", e))?;
+        // Encrypted uploads send the sealed bytes, which are slightly larger than the file
+        let (file, upload_size): (Box<dyn AsyncRead + Unpin + Send>, u64) = match encryption {
+            Some((encryptor, threshold)) => {
+                crate::encryption::encrypted_reader(encryptor, file, file_size, threshold).await?
+            }
+            None => (Box::new(file), file_size),
+        };
         // Wrap reader to emit throttled progress updates
-        let mut file = ProgressReader::new(file, file_size, on_progress);
+        let mut file = ProgressReader::with_config(file, upload_size, progress_config, on_progress);
 
         println!("Starting file stream upload...");
 
-        // Upload file directly to Telegram using the stream with timeout
+        // Upload file directly to Telegram using the stream with timeout.
+        // grammers picks the upload part size itself, so the chunk size setting only applies to downloads.
         let uploaded_file = tokio::time::timeout(
             tokio::time::Duration::from_secs(timeout_secs),
-            client.upload_stream(&mut file, file_size as usize, file_name.to_string())
+            client.upload_stream(&mut file, upload_size as usize, file_name.to_string())
         ).await
             .map_err(|e| anyhow::anyhow!("Upload timed out after {} seconds. Telegram may be slow or file is too large. Error: {}", timeout_secs, e))??;
         
         println!("File stream uploaded. Sending message to chat...");
 
         // Send to target chat (Saved Messages OR folder channel)
-        let caption = format!("📁 {}", file_name);
-        let input_message = InputMessage::new()
-            .text(&caption)
-            .document(uploaded_file);
+        let mut input_message = InputMessage::new().text(caption);
+        input_message = if as_photo {
+            input_message.photo(uploaded_file)
+        } else {
+            input_message.document(uploaded_file)
+        };
+
+        // A missing preview isn't worth failing the upload over (photos get Telegram's own)
+        if let Some(thumbnail) = thumbnail.filter(|_| !as_photo) {
+            match client.upload_file(thumbnail).await {
+                Ok(uploaded_thumb) => input_message = input_message.thumbnail(uploaded_thumb),
+                Err(e) => eprintln!("Warning: Failed to upload thumbnail for {}: {}", file_name, e),
+            }
+        }
         
         // Get PeerRef from Peer
         let peer_ref = target_chat.to_ref()
@@ -98,24 +534,79 @@ async fn attempt_upload(
     upload_future.await
 }
 
+// Throttling rules for progress callbacks emitted by ProgressReader/ProgressWriter
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ProgressConfig {
+    pub enabled: bool,           // false = no byte-level events at all (start/complete only)
+    pub min_interval_ms: u64,    // Minimum time between two updates
+    pub heartbeat_ms: u64,       // Send an update at least this often, even if progress is flat
+    pub min_step_percent: u32,   // Minimum progress change worth reporting
+}
+
+impl Default for ProgressConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_interval_ms: 1000,
+            heartbeat_ms: 5000,
+            min_step_percent: 5,
+        }
+    }
+}
+
+impl ProgressConfig {
+    // Quiet mode used by batch operations: no byte-level updates
+    pub fn silent() -> Self {
+        Self {
+            enabled: false,
+            ..Self::default()
+        }
+    }
+
+    fn should_report(&self, progress: u32, last_progress: u32, elapsed_ms: u128) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        let time_passed = elapsed_ms >= self.min_interval_ms as u128;
+        let stale = elapsed_ms >= self.heartbeat_ms as u128;
+        let significant_change = (progress as i32 - last_progress as i32).unsigned_abs() >= self.min_step_percent;
+        let is_milestone = progress == 100 || progress == 0;
+
+        is_milestone || (time_passed && (significant_change || stale))
+    }
+}
+
+fn transfer_cancelled() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, "Transfer cancelled")
+}
+
 pub struct ProgressReader<R> {
     inner: R,
     total_size: u64,
     current_size: u64,
     last_reported_progress: u32,
     last_reported_time: std::time::Instant,
+    config: ProgressConfig,
     on_progress: Box<dyn Fn(u32, u64, u64) + Send + Sync>, // progress %, current, total
+    abort_epoch: u64,  // See operations::cancel_all
 }
 
 impl<R: AsyncRead + Unpin> ProgressReader<R> {
     pub fn new(inner: R, total_size: u64, on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static) -> Self {
+        Self::with_config(inner, total_size, ProgressConfig::default(), on_progress)
+    }
+
+    pub fn with_config(inner: R, total_size: u64, config: ProgressConfig, on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static) -> Self {
         Self {
             inner,
             total_size,
             current_size: 0,
             last_reported_progress: 0,
             last_reported_time: std::time::Instant::now(),
+            config,
             on_progress: Box::new(on_progress),
+            abort_epoch: crate::operations::transfer_epoch(),
         }
     }
 }
@@ -126,25 +617,25 @@ impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
+        if crate::operations::transfers_aborted_since(self.abort_epoch) {
+            return Poll::Ready(Err(transfer_cancelled()));
+        }
         let prev_len = buf.filled().len();
         match Pin::new(&mut self.inner).poll_read(cx, buf) {
             Poll::Ready(Ok(())) => {
                 let read_len = buf.filled().len() - prev_len;
                 if read_len > 0 {
                     self.current_size += read_len as u64;
+                    crate::bandwidth::record(crate::bandwidth::Direction::Upload, read_len as u64);
                     
                     if self.total_size > 0 {
                         let progress = ((self.current_size as f64 / self.total_size as f64) * 100.0) as u32;
                         let now = std::time::Instant::now();
                         
-                        // Throttle updates, but send a heartbeat at least every 5s even if progress is flat
+                        // Throttle updates, but send a heartbeat even if progress is flat
                         let elapsed_ms = now.duration_since(self.last_reported_time).as_millis();
-                        let time_passed = elapsed_ms >= 1000; // 1 second
-                        let stale = elapsed_ms >= 5000;       // 5 second heartbeat
-                        let significant_change = (progress as i32 - self.last_reported_progress as i32).abs() >= 5; // 5% change
-                        let is_milestone = progress == 100 || progress == 0;
 
-                        if is_milestone || (time_passed && (significant_change || stale)) {
+                        if self.config.should_report(progress, self.last_reported_progress, elapsed_ms) {
                             self.last_reported_progress = progress;
                             self.last_reported_time = now;
                             println!("Upload progress: {}% ({}/{} bytes)", progress, self.current_size, self.total_size);
@@ -166,43 +657,70 @@ pub struct ProgressWriter<W> {
     current_size: u64,
     last_reported_progress: u32,
     last_reported_time: std::time::Instant,
+    config: ProgressConfig,
     on_progress: Box<dyn Fn(u32, u64, u64) + Send + Sync>,
+    abort_epoch: u64,
 }
 
 impl<W: tokio::io::AsyncWrite + Unpin> ProgressWriter<W> {
     pub fn new(inner: W, total_size: u64, on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static) -> Self {
+        Self::with_config(inner, total_size, ProgressConfig::default(), on_progress)
+    }
+
+    pub fn with_config(inner: W, total_size: u64, config: ProgressConfig, on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static) -> Self {
         Self {
             inner,
             total_size,
             current_size: 0,
             last_reported_progress: 0,
             last_reported_time: std::time::Instant::now(),
+            config,
             on_progress: Box::new(on_progress),
+            abort_epoch: crate::operations::transfer_epoch(),
         }
     }
 }
 
+impl<W> ProgressWriter<W> {
+    // Account for bytes already on disk when resuming a download
+    pub fn starting_at(mut self, offset: u64) -> Self {
+        self.current_size = offset;
+        self
+    }
+}
+
+// Seeking only moves the write position; progress keeps counting bytes written
+impl<W: AsyncSeek + Unpin> AsyncSeek for ProgressWriter<W> {
+    fn start_seek(mut self: Pin<&mut Self>, position: std::io::SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
 impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for ProgressWriter<W> {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
+        if crate::operations::transfers_aborted_since(self.abort_epoch) {
+            return Poll::Ready(Err(transfer_cancelled()));
+        }
         match Pin::new(&mut self.inner).poll_write(cx, buf) {
             Poll::Ready(Ok(n)) => {
                 if n > 0 {
                     self.current_size += n as u64;
+                    crate::bandwidth::record(crate::bandwidth::Direction::Download, n as u64);
                     if self.total_size > 0 {
                         let progress = ((self.current_size as f64 / self.total_size as f64) * 100.0) as u32;
                         let now = std::time::Instant::now();
-                        // Throttle updates, but send a heartbeat at least every 5s even if progress is flat
+                        // Throttle updates, but send a heartbeat even if progress is flat
                         let elapsed_ms = now.duration_since(self.last_reported_time).as_millis();
-                        let time_passed = elapsed_ms >= 1000; // 1 second
-                        let stale = elapsed_ms >= 5000;       // 5 second heartbeat
-                        let significant_change = (progress as i32 - self.last_reported_progress as i32).abs() >= 5; // 5% change
-                        let is_milestone = progress == 100 || progress == 0;
 
-                        if is_milestone || (time_passed && (significant_change || stale)) {
+                        if self.config.should_report(progress, self.last_reported_progress, elapsed_ms) {
                             self.last_reported_progress = progress;
                             self.last_reported_time = now;
                             // Emit throttled progress updates to the UI
@@ -239,6 +757,43 @@ pub struct FileMetadata {
     pub encrypted: bool,
     #[serde(default)]
     pub chat_id: Option<i64>,  // Telegram chat where file is stored (None = Saved Messages)
+    #[serde(default)]
+    pub description: Option<String>,  // User note, mirrored into the message caption
+    #[serde(default)]
+    pub versions: Vec<i32>,  // Message ids of earlier versions in the same chat, oldest first
+    #[serde(default)]
+    pub sha256: Option<String>,  // Hex SHA-256 of the file's content, when known
+    #[serde(default)]
+    pub sort_index: Option<i64>,  // Manual position within its folder (local only)
+    #[serde(default)]
+    pub health: Option<FileHealth>,  // Result of the last health scan (local only)
+    #[serde(default)]
+    pub as_photo: bool,  // Stored as a compressed photo: downloads get Telegram's copy, not the original bytes
+    #[serde(default)]
+    pub updated_at: Option<i64>,  // When the stored copy was last written (None = at created_at)
+    #[serde(default)]
+    pub custom: BTreeMap<String, String>,  // Free-form key-value data for integrations, mirrored into the trailer
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plain_versions: Vec<i32>,  // Entries of `versions` stored unencrypted although this copy is encrypted
+    #[serde(default)]
+    pub modified_at: Option<i64>,  // Last rename, move or caption edit (None = never edited)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy_of: Option<String>,  // Id of the entry this was copied from
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthStatus {
+    Ok,
+    Missing,       // Message gone or without media
+    SizeMismatch,  // Message there, but its file isn't the size the entry records
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileHealth {
+    pub status: HealthStatus,
+    pub checked_at: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -248,12 +803,27 @@ pub struct StorageStats {
     pub folder_count: u64,
 }
 
+// Type of Telegram chat backing a folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    #[default]
+    Broadcast,  // Private broadcast channel (original behaviour)
+    Megagroup,  // Supergroup
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FolderMetadata {
     pub path: String,                 // e.g., "/Documents" or "/Photos/Vacation"
     pub chat_id: Option<i64>,         // Telegram channel ID
     pub chat_title: Option<String>,   // e.g., "T-Vault: /Documents"
     pub created_at: i64,
+    #[serde(default)]
+    pub kind: ChannelKind,
+    #[serde(default)]
+    pub color: Option<String>,        // Display only, e.g. "#3b82f6"
+    #[serde(default)]
+    pub icon: Option<String>,         // Display only, icon name chosen in the UI
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -264,10 +834,31 @@ pub struct MetadataStore {
     pub folders: Vec<String>,  // Keep for backward compatibility
     #[serde(default)]
     pub folder_metadata: Vec<FolderMetadata>,  // Rich folder info with chat_id
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,  // Entries whose message disappeared from Telegram, oldest first
+    #[serde(default)]
+    pub health_scan_started_at: Option<i64>,  // Files checked before this are due in the current scan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<crate::encryption::KeyCheck>,  // Set by the first unlock; also kept in Saved Messages
+}
+
+// Record of a file that verify_vault found missing on Telegram (e.g. deleted from another device)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tombstone {
+    pub id: String,
+    pub name: String,
+    pub folder: String,
+    pub size: u64,
+    pub chat_id: Option<i64>,
+    pub message_id: Option<i32>,
+    pub detected_at: i64,
 }
 
+const MAX_TOMBSTONES: usize = 1000; // Oldest tombstones are dropped beyond this
+
 fn default_version() -> u32 {
-    2  // Current schema version
+    CURRENT_SCHEMA_VERSION
 }
 
 impl Default for MetadataStore {
@@ -279,16 +870,28 @@ impl Default for MetadataStore {
 impl MetadataStore {
     pub fn new() -> Self {
         Self {
-            version: 2,
+            version: CURRENT_SCHEMA_VERSION,
             files: Vec::new(),
             folders: vec!["/".to_string()],
             folder_metadata: Vec::new(),
+            tombstones: Vec::new(),
+            health_scan_started_at: None,
+            key_check: None,
         }
     }
 }
 
-fn normalize_file_ids(store: &mut MetadataStore) -> bool {
-    let mut changed = false;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdChange {
+    pub old_id: String,
+    pub new_id: String,
+    pub name: String,
+}
+
+// Rewrite file ids to "<chat>:<message>" (or a unique local id) and return what changed
+fn normalize_file_ids(store: &mut MetadataStore) -> Vec<IdChange> {
+    let mut changes = Vec::new();
     let mut seen: HashSet<String> = HashSet::new();
     let mut counter: u64 = 0;
 
@@ -311,14 +914,32 @@ fn normalize_file_ids(store: &mut MetadataStore) -> bool {
         }
 
         if file.id != new_id {
-            file.id = new_id.clone();
-            changed = true;
+            changes.push(IdChange {
+                old_id: std::mem::replace(&mut file.id, new_id.clone()),
+                new_id: new_id.clone(),
+                name: file.name.clone(),
+            });
         }
 
         seen.insert(new_id);
     }
 
-    changed
+    changes
+}
+
+// Run id normalization on the current store on demand, saving only if something changed
+pub async fn normalize_ids() -> Result<Vec<IdChange>> {
+    let mut metadata = load_metadata_copy().await?;
+    let changes = normalize_file_ids(&mut metadata);
+
+    if !changes.is_empty() {
+        for change in &changes {
+            println!("Normalized id of {}: {} -> {}", change.name, change.old_id, change.new_id);
+        }
+        save_metadata_local(&metadata).await?;
+    }
+
+    Ok(changes)
 }
 
 // Reserved for future encryption feature
@@ -327,7 +948,35 @@ const ENCRYPTION_PASSWORD: &str = "tvault_secure_key_2024";
 #[allow(dead_code)]
 const METADATA_TAG: &str = "#TVAULT_METADATA_V1";
 
-const MAX_FILE_SIZE: u64 = 2 * 1024 * 1024 * 1024; // 2GB limit for Telegram standard users
+// Largest upload Telegram accepts for standard accounts: 4000 parts of 512 KiB = 2000 MiB.
+// A file of exactly this size is allowed; anything above it is rejected.
+const MAX_FILE_SIZE: u64 = 4000 * 512 * 1024;
+
+// Format a byte count for error messages, e.g. 2097152000 => "1.95 GiB"
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+fn check_upload_size(file_name: &str, file_size: u64) -> Result<()> {
+    if file_size > MAX_FILE_SIZE {
+        return Err(anyhow::anyhow!(
+            "File is too large: {} is {} ({} bytes). Telegram's limit is {} ({} bytes).",
+            file_name, format_bytes(file_size), file_size, format_bytes(MAX_FILE_SIZE), MAX_FILE_SIZE
+        ));
+    }
+    Ok(())
+}
 
 async fn get_metadata_path() -> Result<std::path::PathBuf> {
     // Use app data directory instead of current directory to avoid triggering Tauri rebuilds
@@ -342,58 +991,291 @@ async fn get_metadata_path() -> Result<std::path::PathBuf> {
     Ok(data_dir.join("metadata.json"))
 }
 
-async fn ensure_metadata_loaded() -> Result<()> {
-    // Check if already loaded
-    let has_cache = METADATA_CACHE.read().await.is_some();
-    if has_cache {
-        return Ok(());
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataRecovery {
+    pub backup_path: String,
+    pub error: String,
+}
 
-    // Cache miss - load from disk
-    let path = get_metadata_path().await?;
-    let path_exists = path.exists();
-    let mut metadata = if path_exists {
-        let data = tokio::fs::read_to_string(&path).await?;
-        serde_json::from_str(&data)?
-    } else {
-        MetadataStore::new()
-    };
+// Move an unparseable metadata.json aside as metadata.corrupt.<ts>.json and start from an
+// empty store. Nothing is deleted; rebuild_metadata_from_telegram can repopulate the index.
+async fn recover_corrupt_metadata(path: &std::path::Path, error: &serde_json::Error) -> Result<MetadataStore> {
+    let backup_path = path.with_file_name(format!("metadata.corrupt.{}.json", chrono::Utc::now().timestamp()));
+    tokio::fs::rename(path, &backup_path).await
+        .map_err(|e| anyhow::anyhow!("metadata.json is corrupt ({}) and could not be moved aside: {}", error, e))?;
 
-    // Normalize IDs to avoid collisions across chats
-    let ids_changed = normalize_file_ids(&mut metadata);
-    // Update cache
-    let mut cache = METADATA_CACHE.write().await;
-    *cache = Some(metadata.clone());
-    drop(cache);
+    eprintln!("==========================================================");
+    eprintln!("ERROR: metadata.json is corrupt: {}", error);
+    eprintln!("ERROR: Moved it to {}", backup_path.display());
+    eprintln!("ERROR: Starting with an empty file index. Rebuild it from Telegram to recover.");
+    eprintln!("==========================================================");
 
-    // Persist normalized IDs once (after releasing cache lock)
-    if ids_changed {
-        save_metadata_local(&metadata).await?;
-    }
+    *METADATA_RECOVERY.write().await = Some(MetadataRecovery {
+        backup_path: backup_path.to_string_lossy().to_string(),
+        error: error.to_string(),
+    });
 
-    Ok(())
+    let store = MetadataStore::new();
+    save_metadata_local(&store).await?;
+    Ok(store)
 }
 
-async fn load_metadata_copy() -> Result<MetadataStore> {
-    ensure_metadata_loaded().await?;
-    let cache = METADATA_CACHE.read().await;
-    Ok(cache.as_ref().unwrap().clone())
+// Report (once) that the metadata was reset because the file on disk was corrupt
+pub async fn take_metadata_recovery() -> Option<MetadataRecovery> {
+    METADATA_RECOVERY.write().await.take()
 }
 
-async fn save_metadata_local(store: &MetadataStore) -> Result<()> {
-    // Update cache first
-    {
-        let mut cache = METADATA_CACHE.write().await;
-        *cache = Some(store.clone());
-    }
+type SchemaMigration = fn(&mut serde_json::Value) -> Result<()>;
 
-    let path = get_metadata_path().await?;
-    let data = serde_json::to_string_pretty(store)
-        .map_err(|e| anyhow::anyhow!("Failed to serialize metadata: {}", e))?;
-    
-    // Write atomically: write to temp file first, then rename
-    let temp_path = path.with_extension("tmp");
-    tokio::fs::write(&temp_path, data).await
+// Upgrade steps in order: entry i takes a store from version i + 1 to i + 2.
+// Steps work on raw JSON so an old layout never has to deserialize as the new one.
+const SCHEMA_MIGRATIONS: [(&str, SchemaMigration); 1] = [
+    ("Backfill folder_metadata for legacy folders", migrate_v1_to_v2),
+];
+const CURRENT_SCHEMA_VERSION: u32 = SCHEMA_MIGRATIONS.len() as u32 + 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaStep {
+    pub from: u32,
+    pub to: u32,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaStatus {
+    pub version: u32,            // Of the loaded store
+    pub current_version: u32,    // Written by this build
+    pub pending: Vec<SchemaStep>, // Still to run (empty once loaded, unless the store is newer)
+    pub steps: Vec<SchemaStep>,   // Every migration this build knows
+}
+
+// Stores written before the version field existed are legacy (1)
+fn stored_schema_version(store: &serde_json::Value) -> u32 {
+    store.get("version").and_then(|v| v.as_u64()).map_or(1, |v| v as u32)
+}
+
+fn schema_steps(from: u32) -> Vec<SchemaStep> {
+    SCHEMA_MIGRATIONS.iter()
+        .enumerate()
+        .map(|(i, (description, _))| SchemaStep {
+            from: i as u32 + 1,
+            to: i as u32 + 2,
+            description: description.to_string(),
+        })
+        .filter(|step| step.from >= from)
+        .collect()
+}
+
+// v1 only listed folder paths; v2 keeps a folder_metadata entry per folder. Legacy
+// folders get one without a channel, which is created on their next upload (see folder_upload_chat).
+fn migrate_v1_to_v2(store: &mut serde_json::Value) -> Result<()> {
+    let object = store.as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("Metadata is not a JSON object"))?;
+    let folders: Vec<String> = object.get("folders")
+        .and_then(|f| f.as_array())
+        .map(|paths| paths.iter().filter_map(|p| p.as_str().map(String::from)).collect())
+        .unwrap_or_default();
+    let mut folder_metadata: Vec<FolderMetadata> = match object.get("folder_metadata") {
+        Some(existing) => serde_json::from_value(existing.clone())?,
+        None => Vec::new(),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    for path in folders.into_iter().filter(|p| p != "/") {
+        if !folder_metadata.iter().any(|m| m.path == path) {
+            folder_metadata.push(FolderMetadata {
+                path,
+                chat_id: None,
+                chat_title: None,
+                created_at: now,
+                kind: ChannelKind::default(),
+                color: None,
+                icon: None,
+            });
+        }
+    }
+    object.insert("folder_metadata".to_string(), serde_json::to_value(folder_metadata)?);
+    Ok(())
+}
+
+// Run every step from the store's version up to the current one; returns the steps applied
+fn migrate_schema(store: &mut serde_json::Value) -> Result<Vec<SchemaStep>> {
+    let version = stored_schema_version(store);
+    let steps = schema_steps(version);
+    for step in &steps {
+        let (_, migrate) = SCHEMA_MIGRATIONS[step.from as usize - 1];
+        migrate(store)
+            .map_err(|e| anyhow::anyhow!("Schema migration {} -> {} failed: {}", step.from, step.to, e))?;
+        if let Some(object) = store.as_object_mut() {
+            object.insert("version".to_string(), serde_json::json!(step.to));
+        }
+        println!("Metadata schema migrated {} -> {}: {}", step.from, step.to, step.description);
+    }
+    Ok(steps)
+}
+
+// Parse metadata.json, upgrading older schemas after copying the original aside and
+// refusing newer ones. Returns the store and whether it changed on the way in.
+async fn read_metadata_file(path: &std::path::Path) -> Result<(MetadataStore, bool)> {
+    let data = tokio::fs::read(path).await?;
+    let mut raw: serde_json::Value = match serde_json::from_slice(&data) {
+        Ok(raw) => raw,
+        Err(e) => return Ok((recover_corrupt_metadata(path, &e).await?, false)),
+    };
+
+    let version = stored_schema_version(&raw);
+    // A newer app wrote this; loading it here would drop fields we don't know about on the next save
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "Metadata schema v{} is newer than this app supports (v{}). Update T-Vault to open this vault.",
+            version, CURRENT_SCHEMA_VERSION
+        ));
+    }
+    let migrated = version < CURRENT_SCHEMA_VERSION;
+    if migrated {
+        let backup_path = path.with_file_name(format!("metadata.v{}.{}.json", version, chrono::Utc::now().timestamp()));
+        tokio::fs::copy(path, &backup_path).await
+            .map_err(|e| anyhow::anyhow!("Could not back up metadata before migrating it: {}", e))?;
+        println!("Backed up schema v{} metadata to {}", version, backup_path.display());
+        migrate_schema(&mut raw)?;
+    }
+
+    match serde_json::from_value(raw) {
+        Ok(metadata) => Ok((metadata, migrated)),
+        Err(e) => Ok((recover_corrupt_metadata(path, &e).await?, false)),
+    }
+}
+
+pub async fn schema_status() -> Result<SchemaStatus> {
+    let version = load_metadata_copy().await?.version;
+    Ok(SchemaStatus {
+        version,
+        current_version: CURRENT_SCHEMA_VERSION,
+        pending: schema_steps(version),
+        steps: schema_steps(1),
+    })
+}
+
+async fn ensure_metadata_loaded() -> Result<()> {
+    // Check if already loaded
+    let has_cache = METADATA_CACHE.read().await.is_some();
+    if has_cache {
+        return Ok(());
+    }
+
+    // Cache miss - load from disk
+    let path = get_metadata_path().await?;
+    let path_exists = path.exists();
+    let (mut metadata, migrated) = if path_exists {
+        read_metadata_file(&path).await?
+    } else {
+        (MetadataStore::new(), false)
+    };
+
+    // Normalize IDs to avoid collisions across chats
+    let ids_changed = !normalize_file_ids(&mut metadata).is_empty() || migrated;
+    // Update cache
+    let mut cache = METADATA_CACHE.write().await;
+    *cache = Some(metadata.clone());
+    drop(cache);
+
+    // Persist migrated schema and normalized IDs once (after releasing cache lock)
+    if ids_changed {
+        save_metadata_local(&metadata).await?;
+    }
+
+    Ok(())
+}
+
+async fn load_metadata_copy() -> Result<MetadataStore> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    Ok(cache.as_ref().unwrap().clone())
+}
+
+// Metadata saves made while a batch is active are held back and written every
+// `every` saves (0 = only when the batch ends). The cache is always up to date.
+#[derive(Debug, Default)]
+struct SaveBatcher {
+    every: usize,
+    pending: usize,
+    writes: usize,
+}
+
+impl SaveBatcher {
+    fn new(every: usize) -> Self {
+        Self { every, ..Default::default() }
+    }
+
+    // Record a save; true if this one should reach the disk
+    fn record_save(&mut self) -> bool {
+        self.pending += 1;
+        if self.every > 0 && self.pending >= self.every {
+            self.pending = 0;
+            self.writes += 1;
+            return true;
+        }
+        false
+    }
+
+    // End of the batch: true if held-back saves still need writing
+    fn finish(&mut self) -> bool {
+        let waiting = self.pending > 0;
+        if waiting {
+            self.pending = 0;
+            self.writes += 1;
+        }
+        waiting
+    }
+}
+
+tokio::task_local! {
+    // Set for the duration of a batched operation (e.g. migration), so only saves made by
+    // that task are deferred and concurrent commands keep writing through
+    static METADATA_BATCH: std::cell::RefCell<SaveBatcher>;
+}
+
+async fn save_metadata_local(store: &MetadataStore) -> Result<()> {
+    // Update cache first
+    {
+        let mut cache = METADATA_CACHE.write().await;
+        *cache = Some(store.clone());
+    }
+
+    let write_now = METADATA_BATCH.try_with(|batch| batch.borrow_mut().record_save()).unwrap_or(true);
+    if !write_now {
+        return Ok(());
+    }
+    write_metadata_file(store).await
+}
+
+// Write whatever the current batch held back; returns the number of disk writes it made in total
+async fn finish_metadata_batch() -> Result<usize> {
+    let (waiting, writes) = METADATA_BATCH
+        .try_with(|batch| {
+            let mut batch = batch.borrow_mut();
+            (batch.finish(), batch.writes)
+        })
+        .unwrap_or((false, 0));
+
+    if waiting {
+        let store = load_metadata_copy().await?;
+        write_metadata_file(&store).await?;
+    }
+    Ok(writes)
+}
+
+async fn write_metadata_file(store: &MetadataStore) -> Result<()> {
+    let path = get_metadata_path().await?;
+    let data = serde_json::to_string_pretty(store)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize metadata: {}", e))?;
+    
+    // Write atomically: write to temp file first, then rename
+    let temp_path = path.with_extension("tmp");
+    tokio::fs::write(&temp_path, data).await
         .map_err(|e| anyhow::anyhow!("Failed to write metadata: {}", e))?;
     
     tokio::fs::rename(&temp_path, &path).await
@@ -402,11 +1284,361 @@ async fn save_metadata_local(store: &MetadataStore) -> Result<()> {
     Ok(())
 }
 
+// Where uploads into a (non-root) folder go, as far as the local metadata knows
+#[derive(Debug, PartialEq)]
+enum FolderChat {
+    Existing(i64),
+    Legacy(ChannelKind),  // Folder without a channel yet; one of this kind gets created
+}
+
+fn folder_upload_chat(metadata: &MetadataStore, folder: &str) -> Result<FolderChat> {
+    match metadata.folder_metadata.iter().find(|f| f.path == folder) {
+        Some(FolderMetadata { chat_id: Some(chat_id), .. }) => Ok(FolderChat::Existing(*chat_id)),
+        // Legacy folders get an entry without a chat from the v2 migration or when styled
+        Some(meta) => Ok(FolderChat::Legacy(meta.kind)),
+        None if metadata.folders.iter().any(|f| f == folder) => Ok(FolderChat::Legacy(ChannelKind::Broadcast)),
+        None => Err(anyhow::anyhow!("Folder not found: {}. Please create the folder first.", folder)),
+    }
+}
+
+// Resolve (and for legacy folders, create) the chat that uploads into `folder` go to
+async fn resolve_upload_target(client: &Client, folder: &str) -> Result<UploadTarget> {
+    if folder == "/" {
+        // Root files go to Saved Messages, or to the root channel if one is set
+        if let Some(chat_id) = crate::config::AppConfig::load().await.root_chat_id {
+            println!("Uploading to Root (channel {})", chat_id);
+            let chat = crate::telegram::get_chat_peer(client, chat_id).await?;
+            return Ok(UploadTarget { chat, chat_id: Some(chat_id) });
+        }
+        println!("Uploading to Root (Saved Messages)");
+        let me = client.get_me().await
+            .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
+        Ok(UploadTarget { chat: Peer::User(me), chat_id: None })
+    } else {
+        // Folder files go to dedicated channel
+        println!("Uploading to folder: {}", folder);
+        
+        // Reload metadata to be safe
+        let metadata = load_metadata_copy().await?;
+
+        let chat_id = match folder_upload_chat(&metadata, folder)? {
+            FolderChat::Existing(chat_id) => chat_id,
+            FolderChat::Legacy(kind) => {
+                println!("Auto-upgrading legacy folder: {}", folder);
+                upgrade_legacy_folder(client, folder, kind).await?
+            }
+        };
+        
+        println!("Resolving chat peer for ID: {}", chat_id);
+        let chat = crate::telegram::get_chat_peer(client, chat_id).await?;
+        println!("Chat peer resolved.");
+        Ok(UploadTarget { chat, chat_id: Some(chat_id) })
+    }
+}
+
+// Resolve a folder's upload chat once so a batch of uploads can reuse it
+pub async fn prepare_upload_target(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder: &str,
+) -> Result<UploadTarget> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+    resolve_upload_target(&client, folder).await
+}
+
+// Regular files directly inside a local directory, sorted by name (hidden files skipped)
+pub async fn list_local_files(local_dir: &str) -> Result<Vec<String>> {
+    let mut entries = tokio::fs::read_dir(local_dir).await
+        .map_err(|e| anyhow::anyhow!("Failed to read directory {}: {}", local_dir, e))?;
+
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let hidden = entry.file_name().to_str().map_or(true, |n| n.starts_with('.'));
+        if hidden || !entry.file_type().await?.is_file() {
+            continue;
+        }
+        if let Some(path) = entry.path().to_str() {
+            files.push(path.to_string());
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+// What a non-regular file is, for error messages
+fn special_file_kind(file_type: &std::fs::FileType) -> &'static str {
+    if file_type.is_dir() {
+        return "directory";
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_socket() {
+            return "socket";
+        }
+        if file_type.is_fifo() {
+            return "named pipe";
+        }
+        if file_type.is_block_device() || file_type.is_char_device() {
+            return "device";
+        }
+    }
+    "special file"
+}
+
+// Make sure `path` is a regular file we can read before any upload setup happens.
+// Symlinks are followed to their target unless the config says otherwise.
+async fn check_upload_source(path: &Path, follow_symlinks: bool) -> Result<std::fs::Metadata> {
+    let display = path.display().to_string();
+    let link_metadata = tokio::fs::symlink_metadata(path).await
+        .map_err(|_| anyhow::anyhow!("File does not exist: {}", display))?;
+    if link_metadata.file_type().is_symlink() && !follow_symlinks {
+        return Err(TvaultError::SymlinkRejected(display).into());
+    }
+
+    // Follows the link; a dangling one fails here
+    let metadata = tokio::fs::metadata(path).await
+        .map_err(|_| anyhow::anyhow!("File does not exist: {}", display))?;
+    if !metadata.is_file() {
+        return Err(TvaultError::NotAFile { path: display, kind: special_file_kind(&metadata.file_type()).to_string() }.into());
+    }
+
+    if let Err(e) = tokio::fs::File::open(path).await {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            return Err(TvaultError::NotReadable(display).into());
+        }
+        return Err(anyhow::anyhow!("Failed to open {}: {}", display, e));
+    }
+    Ok(metadata)
+}
+
+// One try of an operation that run_attempts repeats, and what happens between tries
+trait RetriedAttempts<C> {
+    type Output;
+
+    // The outer error ends the loop at once; the inner one is a failed attempt worth retrying
+    async fn attempt(&mut self, client: C, retry_count: u32) -> Result<Result<Self::Output>>;
+
+    // Called with the number of failed attempts so far. Waits before the next one, or gives up with an error.
+    async fn after_failure(&mut self, retry_count: u32, error: anyhow::Error) -> Result<()>;
+}
+
+// Repeat `attempts` until one succeeds or it gives up. The client is taken fresh for every
+// attempt, so one rebuilt by a reconnect in the meantime (see TelegramClient::rebuild_connection)
+// is used instead of the dead one.
+async fn run_attempts<C: Clone, A: RetriedAttempts<C>>(client_ref: &Mutex<Option<C>>, attempts: &mut A) -> Result<A::Output> {
+    let mut retry_count = 0;
+    loop {
+        let client = shared_client(client_ref).await?;
+        match attempts.attempt(client, retry_count).await? {
+            Ok(output) => return Ok(output),
+            Err(e) => {
+                retry_count += 1;
+                attempts.after_failure(retry_count, e).await?;
+            }
+        }
+    }
+}
+
+const MAX_UPLOAD_RETRIES: u32 = 5;
+
+// The retried part of upload_file: sending the file, and the waits and events between tries
+struct UploadAttempts<'a> {
+    file_path: &'a str,
+    file_name: &'a str,
+    folder: &'a str,
+    file_size: u64,
+    target_chat: &'a Peer,
+    caption: &'a str,
+    encryption: Option<(&'a crate::encryption::Encryptor, u64)>,
+    thumbnail: Option<&'a Path>,
+    options: &'a UploadOptions,
+    permit: &'a mut Option<crate::transfers::TransferPermit>,
+    app_handle: &'a tauri::AppHandle,
+    abort_epoch: u64,
+    last_error: Option<String>,  // Reported with every event so the UI sees the struggle
+    failures: Vec<String>,       // Every failed attempt's error, for classifying the final one
+}
+
+impl RetriedAttempts<Client> for UploadAttempts<'_> {
+    type Output = i32;
+
+    async fn attempt(&mut self, client: Client, retry_count: u32) -> Result<Result<i32>> {
+        // Nothing has been sent yet (or the last attempt failed), so stopping leaves no entry behind
+        if crate::operations::transfers_aborted_since(self.abort_epoch) {
+            return Err(anyhow::anyhow!("Upload cancelled"));
+        }
+
+        // Hard timeout per attempt to avoid indefinite hangs
+        let attempt_timeout_secs = std::cmp::min(
+            1200, // cap at 20 minutes
+            std::cmp::max(
+                180, // minimum 3 minutes
+                ((self.file_size / (20 * 1024 * 1024)).saturating_mul(60)) + 180 // scale with size
+            )
+        );
+
+        // Before each retry, verify the client connection is still valid
+        // This catches stale connections before wasting time on a failed upload
+        if retry_count > 0 {
+            println!("Verifying client connection before retry {}...", retry_count);
+            if !crate::telegram::test_client_connection(&client).await {
+                println!("Client connection appears stale, re-fetching chat peer...");
+                // Re-fetch chat peer in case the connection was dropped
+                let new_chat = if self.folder == "/" {
+                    let me = client.get_me().await
+                        .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
+                    Ok(Peer::User(me))
+                } else {
+                    let chat_id = {
+                        let metadata = load_metadata_copy().await?;
+                        let existing_meta = metadata.folder_metadata.iter()
+                            .find(|f| f.path == self.folder)
+                            .cloned()
+                            .ok_or_else(|| anyhow::anyhow!("Folder not found"))?;
+                        existing_meta.chat_id
+                            .ok_or_else(|| anyhow::anyhow!("Folder missing chat_id"))?
+                    };
+                    crate::telegram::get_chat_peer(&client, chat_id).await
+                };
+
+                match new_chat {
+                    Ok(_new_peer) => println!("Chat peer refreshed successfully"),
+                    Err(e) => println!("Failed to refresh chat peer: {}", e),
+                }
+            }
+        }
+
+        // Create a progress callback for UI updates
+        let file_path_clone = self.file_path.to_string();
+        let file_name_clone = self.file_name.to_string();
+        let folder_clone = self.folder.to_string();
+        let app_handle_clone = self.app_handle.clone();
+        let attempt = retry_count + 1;
+        let last_error_clone = self.last_error.clone();
+
+        let on_progress_clone = Box::new(move |progress: u32, current: u64, total: u64| {
+            app_handle_clone.emit_all("upload-progress", serde_json::json!({
+                "filePath": file_path_clone,
+                "file": file_name_clone,
+                "folder": folder_clone,
+                "status": "uploading",
+                "progress": progress,
+                "current": current,
+                "total": total,
+                "attempt": attempt,
+                "lastError": last_error_clone
+            })).ok();
+        });
+
+        // Run attempt with a timeout to avoid getting stuck forever
+        let result = tokio::time::timeout(
+            tokio::time::Duration::from_secs(attempt_timeout_secs),
+            attempt_upload(&client, self.target_chat, self.file_path, self.file_name, self.file_size, self.caption, self.encryption, self.thumbnail, self.options.as_photo, self.options.progress, on_progress_clone)
+        ).await.map_err(|e| anyhow::anyhow!("Upload attempt timed out after {}s: {}", attempt_timeout_secs, e))?;
+
+        if result.is_ok() {
+            println!("Upload successful on attempt {}", attempt);
+            // Sent before the metadata is saved; "completed" still follows once it is
+            self.app_handle.emit_all("upload-progress", serde_json::json!({
+                "filePath": self.file_path,
+                "file": self.file_name,
+                "folder": self.folder,
+                "status": "uploaded",
+                "progress": 100,
+                "attempts": attempt,
+                "lastError": self.last_error
+            })).ok();
+        }
+        Ok(result)
+    }
+
+    async fn after_failure(&mut self, retry_count: u32, e: anyhow::Error) -> Result<()> {
+        let (file_path, file_name, folder, file_size) = (self.file_path, self.file_name, self.folder, self.file_size);
+        let error_str = e.to_string();
+        self.last_error = Some(error_str.clone());
+
+        if retry_count >= MAX_UPLOAD_RETRIES {
+            return Err(upload_failed(file_name, folder, &error_str, &self.failures, retry_count).into());
+        }
+        self.failures.push(error_str.clone());
+
+        // Check for flood wait error - respect Telegram's rate limits
+        let error_str_lower = error_str.to_lowercase();
+        let wait_seconds = if error_str_lower.contains("flood_wait") {
+            // Use the exact wait time from Telegram. Short waits are a normal retry;
+            // long ones are honoured in full (with a countdown event) up to the maximum.
+            let requested = extract_flood_wait(&error_str_lower).unwrap_or(30);
+            if requested > MAX_FLOOD_WAIT_SECS {
+                let error = format!(
+                    "Telegram asked to wait {}s before uploading again (over the {}s limit).",
+                    requested, MAX_FLOOD_WAIT_SECS
+                );
+                let kind = crate::error::UploadFailureKind::FloodLimited;
+                return Err(record_upload_failure(file_name, folder, kind, &error, retry_count).into());
+            }
+            charge_retry_budget(self.options.retry_budget.as_deref(), requested, self.permit, file_path, file_name, self.app_handle).await?;
+            crate::transfers::record_flood_wait(requested).await;
+            if requested > FLOOD_WAIT_SHORT_CAP_SECS {
+                println!("Flood wait of {}s requested for {}. Waiting it out...", requested, file_name);
+                self.app_handle.emit_all("upload-progress", serde_json::json!({
+                    "filePath": file_path,
+                    "file": file_name,
+                    "folder": folder,
+                    "status": "flood_wait",
+                    "waitSeconds": requested,
+                    "progress": 0,
+                    "current": 0,
+                    "total": file_size,
+                    "attempt": retry_count,
+                    "lastError": self.last_error
+                })).ok();
+                tokio::time::sleep(tokio::time::Duration::from_secs(requested)).await;
+                return Ok(());
+            }
+            requested
+        } else if error_str_lower.contains("too many requests") {
+            // Respect "too many requests" with a longer wait
+            charge_retry_budget(self.options.retry_budget.as_deref(), 0, self.permit, file_path, file_name, self.app_handle).await?;
+            crate::transfers::record_flood_wait(30).await;
+            30
+        } else {
+            // Exponential backoff for other retryable errors: 1, 2, 4, 8, 16 seconds
+            charge_retry_budget(self.options.retry_budget.as_deref(), 0, self.permit, file_path, file_name, self.app_handle).await?;
+            std::cmp::min(2u64.saturating_pow(retry_count - 1), 30)
+        };
+
+        println!("Upload attempt {} of {} failed: {}. Retrying in {} seconds...",
+            retry_count, MAX_UPLOAD_RETRIES, e, wait_seconds);
+
+        // Emit progress update showing retry
+        self.app_handle.emit_all("upload-progress", serde_json::json!({
+            "filePath": file_path,
+            "file": file_name,
+            "folder": folder,
+            "status": "retrying",
+            "progress": 0,
+            "error": format!("Retrying in {}s... (attempt {}/{})", wait_seconds, retry_count, MAX_UPLOAD_RETRIES),
+            "current": 0,
+            "total": file_size,
+            "attempt": retry_count,
+            "lastError": self.last_error
+        })).ok();
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(wait_seconds)).await;
+        Ok(())
+    }
+}
+
 // Upload file to Telegram Saved Messages (unencrypted for viewing in Telegram)
 pub async fn upload_file(
     client_ref: Arc<Mutex<Option<Client>>>,
     file_path: &str,
     folder: &str,
+    options: UploadOptions,
     _on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static,
     app_handle: tauri::AppHandle,
 ) -> Result<String> {
@@ -419,10 +1651,9 @@ pub async fn upload_file(
 
     let path = Path::new(file_path);
     
-    // Check if file exists
-    if !path.exists() {
-        return Err(anyhow::anyhow!("File does not exist: {}", file_path));
-    }
+    // Check that it's a readable regular file before the long upload setup
+    let follow_symlinks = crate::config::AppConfig::load().await.follow_symlinks;
+    let file_metadata = check_upload_source(path, follow_symlinks).await?;
     
     let file_name = path
         .file_name()
@@ -431,25 +1662,62 @@ pub async fn upload_file(
 
     println!("File found: {}, size check...", file_name);
 
-    // Get file size
-    let file_metadata = tokio::fs::metadata(file_path).await
-        .map_err(|e| anyhow::anyhow!("Failed to read file metadata: {}", e))?;
     let file_size = file_metadata.len();
 
-    // Check if file exceeds 2GB limit
-    if file_size >= MAX_FILE_SIZE {
-        return Err(anyhow::anyhow!("File is too large ({}). Telegram has a 2GB limit for files.", file_name));
-    }
+    // Encrypted uploads need the vault unlocked before anything is sent
+    let encryptor = if options.encrypt {
+        Some(crate::encryption::session_encryptor().ok_or(TvaultError::VaultLocked)?)
+    } else {
+        None
+    };
+    let stream_threshold = crate::config::AppConfig::load().await.encryption_stream_threshold;
+
+    // Wait for a slot in the global upload budget before touching the network
+    let abort_epoch = crate::operations::transfer_epoch();
+    let mut permit = Some(crate::transfers::acquire(crate::bandwidth::Direction::Upload, file_path, file_name).await
+        .ok_or_else(|| anyhow::anyhow!("Removed from the upload queue"))?);
+
+    // Check against Telegram's upload limit (the encrypted blob is what gets stored)
+    let stored_size = if encryptor.is_some() {
+        crate::encryption::encrypted_size(file_size, stream_threshold)
+    } else {
+        file_size
+    };
+    check_upload_size(file_name, stored_size)
+        .map_err(|e| upload_failed(file_name, folder, &e.to_string(), &[], 0))?;
     
     // Check for zero-byte files
     if file_size == 0 {
         return Err(anyhow::anyhow!("Cannot upload empty file: {}", file_name));
     }
 
+    // Held until the entry is saved, so uploads running side by side can't overshoot the quota
+    let _quota = reserve_quota(file_size).await?;
+
     // Get mime type
     let mime_type = mime_guess::from_path(path)
         .first_or_octet_stream()
         .to_string();
+    if options.as_photo {
+        check_photo_upload(file_name, &mime_type, file_size, options.encrypt)?;
+    }
+
+    // Content hash for duplicate detection (and, for plain uploads, download verification)
+    let content_sha256 = file_sha256(path).await
+        .map_err(|e| anyhow::anyhow!("Failed to hash {}: {}", file_name, e))?;
+
+    // A preview would show Telegram what an encrypted file contains, so those never get one
+    let thumbnail = if !options.encrypt && crate::config::AppConfig::load().await.generate_thumbnails {
+        match thumbnail_path(&content_sha256).await {
+            Ok(destination) => generate_thumbnail(path, &mime_type, &destination).await,
+            Err(e) => {
+                eprintln!("Warning: No thumbnail directory: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
     println!("File validated. Getting client...");
 
@@ -461,268 +1729,121 @@ pub async fn upload_file(
 
     println!("Client obtained. Determining target chat...");
 
-    // Determine target chat based on folder
-    let (target_chat, target_chat_id): (Peer, Option<i64>) = if folder == "/" {
-        // Root files go to Saved Messages
-        println!("Uploading to Root (Saved Messages)");
-        let me = client.get_me().await
-            .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
-        (Peer::User(me), None)
-    } else {
-        // Folder files go to dedicated channel
-        println!("Uploading to folder: {}", folder);
-        
-        // Reload metadata to be safe
+    // Determine target chat based on folder (batch uploads resolve it once up front)
+    let UploadTarget { chat: target_chat, chat_id: target_chat_id } = match options.target.clone() {
+        Some(target) => target,
+        None => resolve_upload_target(&client, folder).await?,
+    };
+
+    println!("Target chat determined. Starting file upload stream...");
+
+    // A new version has to live next to the ones it replaces so the chain stays resolvable
+    let mut custom = options.custom.clone();
+    if let Some(ref old_id) = options.supersedes {
         let metadata = load_metadata_copy().await?;
-        
-        // Check for existing rich metadata
-        let existing_meta = metadata.folder_metadata.iter()
-            .find(|f| f.path == folder)
-            .cloned();
-            
-        let chat_id = if let Some(meta) = existing_meta {
-            println!("Found folder metadata. Chat ID: {:?}", meta.chat_id);
-            // Case 1: Metadata exists
-            if let Some(cid) = meta.chat_id {
-                cid
-            } else {
-                // Should not happen if created correctly, but if chat_id is missing, treat as legacy
-                return Err(anyhow::anyhow!("Folder metadata corrupted (missing chat_id) for {}", folder));
-            }
-        } else {
-            println!("No folder metadata found. Checking legacy folders list...");
-            // Case 2: No metadata. Check if it's a valid legacy folder
-            if metadata.folders.contains(&folder.to_string()) {
-                println!("Auto-upgrading legacy folder: {}", folder);
-                
-                // Create the channel now
-                let chat_title = format!("T-Vault: {}", folder);
-                let description = format!("Storage folder for: {}", folder);
-                
-                let (new_chat_id, chat_name) = crate::telegram::create_folder_channel(
-                    &client,
-                    &chat_title,
-                    &description
-                ).await?;
-                
-                println!("Channel created: ID={}, Name={}", new_chat_id, chat_name);
+        let previous = metadata.files.iter()
+            .find(|f| &f.id == old_id && !f.is_folder)
+            .ok_or_else(|| anyhow::anyhow!("File to supersede not found: {}", old_id))?;
+        if previous.chat_id != target_chat_id {
+            return Err(anyhow::anyhow!(
+                "Cannot add a version of {} in a different folder", previous.name
+            ));
+        }
+        if custom.is_empty() {
+            custom = previous.custom.clone();
+        }
+    }
 
-                // Add small delay
-                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-                
-                // Update metadata with new channel info
-                // Need to reload metadata again in case of race conditions? 
-                // For simplicity assuming single-user local access
-                let mut current_metadata = load_metadata_copy().await?;
-                
-                // Add to folder_metadata
-                current_metadata.folder_metadata.push(FolderMetadata {
-                    path: folder.to_string(),
-                    chat_id: Some(new_chat_id),
-                    chat_title: Some(chat_name),
-                    created_at: chrono::Utc::now().timestamp(),
-                });
-                
-                // Also update the virtual file entry for this folder
-                let path = Path::new(folder);
-                let name = path.file_name().unwrap_or_default().to_str().unwrap_or_default();
-                let parent = path.parent().map(|p| p.to_str().unwrap_or("/")).unwrap_or("/");
-                let parent_str = if parent.is_empty() { "/" } else { parent };
-
-                if let Some(entry) = current_metadata.files.iter_mut().find(|f| 
-                    f.is_folder && f.name == name && 
-                    (f.folder == parent_str || (parent_str == "/" && f.folder == "/"))
-                ) {
-                    entry.chat_id = Some(new_chat_id);
-                }
-                
-                save_metadata_local(&current_metadata).await?;
-                
-                new_chat_id
-            } else {
-                return Err(anyhow::anyhow!("Folder not found: {}. Please create the folder first.", folder));
-            }
-        };
-        
-        println!("Resolving chat peer for ID: {}", chat_id);
-        let chat = crate::telegram::get_chat_peer(&client, chat_id).await?;
-        println!("Chat peer resolved.");
-        (chat, Some(chat_id))
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+    let description = normalize_description(options.description.clone());
+    let trailer = CaptionTrailer {
+        folder: folder.to_string(),
+        name: Some(file_name.to_string()),
+        description: description.clone(),
+        sha256: Some(content_sha256.clone()).filter(|_| !options.encrypt && !options.as_photo),
+        encrypted: options.encrypt,
+        custom: custom.clone(),
+        created_at: options.created_at,
+        ..Default::default()
     };
-
-    println!("Target chat determined. Starting file upload stream...");
+    let caption = build_caption(&caption_prefix, file_name, description.as_deref(), &trailer);
 
     // Perform upload with retry logic - no more global cooldown blocking
-    let message_id = {
-        let mut retry_count = 0;
-        const MAX_RETRIES: u32 = 5;  // Increased retries
-        
-        loop {
-            // Hard timeout per attempt to avoid indefinite hangs
-            let attempt_timeout_secs = std::cmp::min(
-                1200, // cap at 20 minutes
-                std::cmp::max(
-                    180, // minimum 3 minutes
-                    ((file_size / (20 * 1024 * 1024)).saturating_mul(60)) + 180 // scale with size
-                )
-            );
-
-            // Before each attempt, verify the client connection is still valid
-            // This catches stale connections before wasting time on a failed upload
-            if retry_count > 0 {
-                println!("Verifying client connection before retry {}...", retry_count);
-                if !crate::telegram::test_client_connection(&client).await {
-                    println!("Client connection appears stale, re-fetching chat peer...");
-                    // Re-fetch chat peer in case the connection was dropped
-                    let new_chat = if folder == "/" {
-                        let me = client.get_me().await
-                            .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
-                        Ok(Peer::User(me))
-                    } else {
-                        let chat_id = {
-                            let metadata = load_metadata_copy().await?;
-                            let existing_meta = metadata.folder_metadata.iter()
-                                .find(|f| f.path == folder)
-                                .cloned()
-                                .ok_or_else(|| anyhow::anyhow!("Folder not found"))?;
-                            existing_meta.chat_id
-                                .ok_or_else(|| anyhow::anyhow!("Folder missing chat_id"))?
-                        };
-                        crate::telegram::get_chat_peer(&client, chat_id).await
-                    };
-                    
-                    match new_chat {
-                        Ok(_new_peer) => {
-                            println!("Chat peer refreshed successfully");
-                            // Update target_chat for the next attempt
-                            // We need to use a mutable reference, so we'll just note it
-                        }
-                        Err(e) => {
-                            println!("Failed to refresh chat peer: {}", e);
-                        }
-                    }
-                }
-            }
-
-            let result = {
-                // Create a progress callback for UI updates
-                let file_path_clone = file_path.to_string();
-                let file_name_clone = file_name.to_string();
-                let folder_clone = folder.to_string();
-                let app_handle_clone = app_handle.clone();
-                
-                let on_progress_clone = Box::new(move |progress: u32, current: u64, total: u64| {
-                    app_handle_clone.emit_all("upload-progress", serde_json::json!({
-                        "filePath": file_path_clone,
-                        "file": file_name_clone,
-                        "folder": folder_clone,
-                        "status": "uploading",
-                        "progress": progress,
-                        "current": current,
-                        "total": total
-                    })).ok();
-                });
-                
-                // Run attempt with a timeout to avoid getting stuck forever
-                tokio::time::timeout(
-                    tokio::time::Duration::from_secs(attempt_timeout_secs),
-                    attempt_upload(&client, &target_chat, file_path, file_name, file_size, on_progress_clone)
-                ).await.map_err(|e| anyhow::anyhow!("Upload attempt timed out after {}s: {}", attempt_timeout_secs, e))?
-            };
-            
-            match result {
-                Ok(id) => {
-                    println!("Upload successful on attempt {}", retry_count + 1);
-                    break id;
-                }
-                Err(e) => {
-                    retry_count += 1;
-                    let error_str = e.to_string();
-                    let is_retryable = is_retryable_error(&error_str);
-                    
-                    if retry_count >= MAX_RETRIES {
-                        if is_retryable {
-                            println!("Upload failed after {} attempts due to transient errors. File: {}", MAX_RETRIES, file_name);
-                            return Err(anyhow::anyhow!(
-                                "Upload failed after {} attempts. Telegram may be busy or network is unstable. Error: {}",
-                                MAX_RETRIES,
-                                e
-                            ));
-                        } else {
-                            return Err(anyhow::anyhow!("Upload failed: {}", e));
-                        }
-                    }
-                    
-                    // Check for flood wait error - respect Telegram's rate limits
-                    let error_str_lower = error_str.to_lowercase();
-                    let wait_seconds = if error_str_lower.contains("flood_wait") {
-                        // Use the exact wait time from Telegram, capped at 60 seconds
-                        std::cmp::min(extract_flood_wait(&error_str_lower).unwrap_or(30), 60)
-                    } else if error_str_lower.contains("too many requests") {
-                        // Respect "too many requests" with a longer wait
-                        30
-                    } else {
-                        // Exponential backoff for other retryable errors: 1, 2, 4, 8, 16 seconds
-                        std::cmp::min(2u64.saturating_pow(retry_count - 1), 30)
-                    };
-                    
-                    println!("Upload attempt {} of {} failed: {}. Retrying in {} seconds...", 
-                        retry_count, MAX_RETRIES, e, wait_seconds);
-                    
-                    // Emit progress update showing retry
-                    app_handle.emit_all("upload-progress", serde_json::json!({
-                        "filePath": file_path,
-                        "file": file_name,
-                        "folder": folder,
-                        "status": "retrying",
-                        "progress": 0,
-                        "error": format!("Retrying in {}s... (attempt {}/{})", wait_seconds, retry_count, MAX_RETRIES),
-                        "current": 0,
-                        "total": file_size
-                    })).ok();
-                    
-                    tokio::time::sleep(tokio::time::Duration::from_secs(wait_seconds)).await;
-                }
-            }
-        }
-    };
-    
-    // Add delay between operations to prevent overwhelming Telegram API
-    // Telegram has rate limits: ~30 messages per second for supergroups, 
-    // but for uploads we should be more conservative
-    // Use adaptive delay based on file size
-    let delay_ms = match file_size {
-        size if size > 500 * 1024 * 1024 => 3000,  // 500MB+ files: 3s delay
-        size if size > 100 * 1024 * 1024 => 2000,  // 100-500MB files: 2s delay
-        size if size > 10 * 1024 * 1024 => 1000,   // 10-100MB files: 1s delay
-        size if size > 1024 * 1024 => 500,         // 1-10MB files: 500ms delay
-        _ => 250,                                  // <1MB files: 250ms delay
+    let mut attempts = UploadAttempts {
+        file_path,
+        file_name,
+        folder,
+        file_size,
+        target_chat: &target_chat,
+        caption: &caption,
+        encryption: encryptor.as_ref().map(|e| (e, stream_threshold)),
+        thumbnail: thumbnail.as_deref(),
+        options: &options,
+        permit: &mut permit,
+        app_handle: &app_handle,
+        abort_epoch,
+        last_error: None,
+        failures: Vec::new(),
     };
+    let message_id = run_attempts(&client_ref, &mut attempts).await?;
     
-    // Add extra jitter to prevent synchronized bursts in batch uploads
-    let jitter_ms = rand::random::<u64>() % 500;
-    let total_delay_ms = delay_ms + jitter_ms;
-    
-    println!("Upload complete. Waiting {}ms before next operation...", total_delay_ms);
-    tokio::time::sleep(tokio::time::Duration::from_millis(total_delay_ms)).await;
+    if let Err(e) = crate::bandwidth::flush().await {
+        eprintln!("Warning: Failed to save bandwidth stats: {}", e);
+    }
+
+    // Pause between uploads to stay clear of Telegram's rate limits. The delay adapts:
+    // it grows after flood waits and shrinks while none occur (see transfers::next_upload_delay)
+    let delay = crate::transfers::next_upload_delay().await;
+    if !delay.is_zero() {
+        println!("Upload complete. Waiting {}ms before next operation...", delay.as_millis());
+        tokio::time::sleep(delay).await;
+    }
     
     // Update metadata
     let metadata_result = async {
         let mut metadata = load_metadata_copy().await?;
         let id_prefix = target_chat_id.map(|id| id.to_string()).unwrap_or_else(|| "saved".to_string());
         let unique_id = format!("{}:{}", id_prefix, message_id);
+
+        // The superseded entry is folded into the version chain; its message stays in Telegram
+        let mut versions = Vec::new();
+        let mut plain_versions = Vec::new();
+        if let Some(ref old_id) = options.supersedes {
+            if let Some(pos) = metadata.files.iter().position(|f| &f.id == old_id) {
+                let previous = metadata.files.remove(pos);
+                versions = previous.versions;
+                versions.extend(previous.message_id);
+                // An encrypted version on top of plain ones has to remember which are plain
+                if options.encrypt {
+                    plain_versions = if previous.encrypted { previous.plain_versions } else { versions.clone() };
+                }
+            }
+        }
+
         metadata.files.push(FileMetadata {
             id: unique_id,
             name: file_name.to_string(),
             size: file_size,
             mime_type,
-            created_at: chrono::Utc::now().timestamp(),
+            created_at: options.created_at.unwrap_or_else(|| chrono::Utc::now().timestamp()),
             folder: folder.to_string(),
             is_folder: false,
-            thumbnail: None,
+            thumbnail: thumbnail.as_ref().map(|p| p.to_string_lossy().to_string()),
             message_id: Some(message_id),
-            encrypted: false,
+            encrypted: options.encrypt,
             chat_id: target_chat_id,  // None for root, Some(id) for folders
+            description,
+            versions,
+            // A photo is Telegram's recompressed copy, so the original's hash doesn't describe it
+            sha256: Some(content_sha256.clone()).filter(|_| !options.as_photo),
+            sort_index: None,
+            health: None,
+            as_photo: options.as_photo,
+            updated_at: Some(chrono::Utc::now().timestamp()),
+            custom,
+            plain_versions,
+            modified_at: None,
+            copy_of: None,
         });
 
         // Save updated metadata locally
@@ -740,687 +1861,8050 @@ pub async fn upload_file(
     Ok(message_id.to_string())
 }
 
-// Download file from Telegram
-pub async fn download_file(
+const URL_UPLOAD_MAX_REDIRECTS: usize = 10;
+const URL_UPLOAD_CONNECT_TIMEOUT_SECS: u64 = 30;
+
+// Name for a file fetched from `url`: the server's Content-Disposition filename if it sent one,
+// else the last segment of the final URL, percent-decoded. None if neither gives a usable name.
+fn url_file_name(url: &reqwest::Url, content_disposition: Option<&str>) -> Option<String> {
+    let from_header = content_disposition
+        .and_then(|value| value.split(';').map(str::trim).find_map(|part| part.strip_prefix("filename=")))
+        .map(|name| name.trim_matches('"').to_string());
+    let from_url = || {
+        url.path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(|segment| percent_encoding::percent_decode_str(segment).decode_utf8_lossy().to_string())
+    };
+
+    // Only the last component, whatever the server put in front of it
+    from_header.or_else(from_url)
+        .and_then(|name| name.rsplit(['/', '\\']).next().map(|n| n.trim().to_string()))
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+}
+
+// Upload a remote file by streaming the HTTP response body straight into Telegram, so it never
+// touches the local disk. The server has to send a Content-Length, because Telegram needs the
+// size up front. A body can only be read once, so a failed upload isn't retried here.
+pub async fn upload_from_url(
     client_ref: Arc<Mutex<Option<Client>>>,
-    file_id: &str,
-    destination: &str,
+    url: &str,
+    folder: &str,
+    file_name: Option<&str>,
+    encrypt: bool,
     on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static,
-) -> Result<String> {
-    // Validate inputs
-    if file_id.trim().is_empty() {
-        return Err(anyhow::anyhow!("Invalid file ID"));
-    }
-    if destination.trim().is_empty() {
-        return Err(anyhow::anyhow!("Invalid destination path"));
+) -> Result<FileMetadata> {
+    let url = reqwest::Url::parse(url.trim())
+        .map_err(|e| anyhow::anyhow!("Invalid URL: {}", e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(anyhow::anyhow!("Only http and https URLs can be uploaded"));
     }
 
-    ensure_metadata_loaded().await?;
-    
-    let file_meta = {
-        let cache = METADATA_CACHE.read().await;
-        let metadata = cache.as_ref().ok_or_else(|| anyhow::anyhow!("Metadata not loaded"))?;
-        metadata.files.iter().find(|f| f.id == file_id).cloned()
+    // Encrypted uploads need the vault unlocked before anything is fetched
+    let encryptor = if encrypt {
+        Some(crate::encryption::session_encryptor().ok_or(TvaultError::VaultLocked)?)
+    } else {
+        None
     };
-    
-    let file_meta = file_meta.ok_or_else(|| anyhow::anyhow!("File not found"))?;
-    let file_size = file_meta.size;
+    let stream_threshold = crate::config::AppConfig::load().await.encryption_stream_threshold;
 
-    let message_id = file_meta
-        .message_id
-        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+    let queued_name = file_name.unwrap_or(url.as_str());
+    let _permit = crate::transfers::acquire(crate::bandwidth::Direction::Upload, url.as_str(), queued_name).await
+        .ok_or_else(|| anyhow::anyhow!("Removed from the upload queue"))?;
 
-    // Get client by cloning
-    let client = {
-        let client_guard = client_ref.lock().await;
-        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
-    }; // Lock released
+    let http = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(URL_UPLOAD_MAX_REDIRECTS))
+        .connect_timeout(std::time::Duration::from_secs(URL_UPLOAD_CONNECT_TIMEOUT_SECS))
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to set up HTTP client: {}", e))?;
+    let response = http.get(url.clone()).send().await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| anyhow::anyhow!("Failed to fetch {}: {}", url, e))?;
 
-    // Determine source chat based on chat_id
-    let chat: Peer = if let Some(chat_id) = file_meta.chat_id {
-        // File in folder channel
-        crate::telegram::get_chat_peer(&client, chat_id).await?
+    let header = |name: reqwest::header::HeaderName| {
+        response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string)
+    };
+    let file_name = match file_name.map(str::trim).filter(|n| !n.is_empty()) {
+        Some(name) => name.replace(['/', '\\'], "_"),
+        None => url_file_name(response.url(), header(reqwest::header::CONTENT_DISPOSITION).as_deref())
+            .ok_or_else(|| anyhow::anyhow!("Can't tell a file name from {}; please give one", url))?,
+    };
+    let file_size = response.content_length()
+        .ok_or_else(|| anyhow::anyhow!("{} didn't say how large it is; download it first and upload the file", url))?;
+    if file_size == 0 {
+        return Err(anyhow::anyhow!("Cannot upload empty file: {}", file_name));
+    }
+
+    let stored_size = if encryptor.is_some() {
+        crate::encryption::encrypted_size(file_size, stream_threshold)
     } else {
-        // File in Saved Messages (root or legacy)
-        let me = client.get_me().await
-            .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
-        Peer::User(me)
+        file_size
     };
-    
-    // Get PeerRef from Peer
-    let peer_ref = chat.to_ref()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
-    
-    // Get messages from the appropriate chat
-    let mut messages = client.iter_messages(peer_ref);
-    
-    // Find the specific message
-    while let Some(message) = messages.next().await? {
-        if message.id() == message_id {
-            if let Some(media) = message.media() {
-                // Download media with progress tracking (explicitly handle doc/photo)
-                let out_file = tokio::fs::File::create(destination).await
-                    .map_err(|e| anyhow::anyhow!("Failed to create destination file: {}", e))?;
+    check_upload_size(&file_name, stored_size)?;
+    let _quota = reserve_quota(file_size).await?;
 
-                match media {
-                    Media::Document(doc) => {
-                        let expected_size = if file_size > 0 {
-                            file_size
-                        } else {
-                            doc.size().unwrap_or(0) as u64
-                        };
-                        let mut progress_writer = ProgressWriter::new(out_file, expected_size, on_progress);
-                        let mut download_stream = client.iter_download(&doc);
-                        let mut downloaded_bytes: u64 = 0;
-
-                        while let Some(chunk) = download_stream.next().await? {
-                            downloaded_bytes += chunk.len() as u64;
-                            progress_writer.write_all(&chunk).await
-                                .map_err(|e| anyhow::anyhow!("Failed to write chunk: {}", e))?;
-                        }
-                        progress_writer.flush().await
-                            .map_err(|e| anyhow::anyhow!("Failed to flush file: {}", e))?;
-
-                        // Verify we received the full file; retry once with download_media if short
-                        if expected_size > 0 && downloaded_bytes < expected_size {
-                            eprintln!(
-                                "Warning: Downloaded {} of {} bytes. Retrying with download_media...",
-                                downloaded_bytes, expected_size
-                            );
-                            // Re-create file to ensure clean write
-                            let out_file = tokio::fs::File::create(destination).await
-                                .map_err(|e| anyhow::anyhow!("Failed to recreate destination file: {}", e))?;
-                            drop(out_file);
-                            client.download_media(&doc, destination).await
-                                .map_err(|e| anyhow::anyhow!("Failed to re-download file: {}", e))?;
-                        }
-                    }
-                    Media::Photo(photo) => {
-                        let mut progress_writer = ProgressWriter::new(out_file, file_size, on_progress);
-                        let mut download_stream = client.iter_download(&photo);
-                        let mut downloaded_bytes: u64 = 0;
-
-                        while let Some(chunk) = download_stream.next().await? {
-                            downloaded_bytes += chunk.len() as u64;
-                            progress_writer.write_all(&chunk).await
-                                .map_err(|e| anyhow::anyhow!("Failed to write chunk: {}", e))?;
-                        }
-                        progress_writer.flush().await
-                            .map_err(|e| anyhow::anyhow!("Failed to flush file: {}", e))?;
-
-                        if file_size > 0 && downloaded_bytes < file_size {
-                            eprintln!(
-                                "Warning: Downloaded {} of {} bytes. Retrying with download_media...",
-                                downloaded_bytes, file_size
-                            );
-                            let out_file = tokio::fs::File::create(destination).await
-                                .map_err(|e| anyhow::anyhow!("Failed to recreate destination file: {}", e))?;
-                            drop(out_file);
-                            client.download_media(&photo, destination).await
-                                .map_err(|e| anyhow::anyhow!("Failed to re-download file: {}", e))?;
-                        }
-                    }
-                    _ => {
-                        return Err(anyhow::anyhow!("Unsupported media type for download"));
-                    }
-                }
+    // The server's type when it names one, else a guess from the name
+    let mime_type = header(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.split(';').next().map(|t| t.trim().to_lowercase()))
+        .filter(|t| !t.is_empty() && t != "application/octet-stream")
+        .unwrap_or_else(|| mime_guess::from_path(&file_name).first_or_octet_stream().to_string());
 
-                // Add delay between operations to avoid rate limits
-                tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
+    let client = shared_client(&client_ref).await?;
+    let UploadTarget { chat: target_chat, chat_id: target_chat_id } = resolve_upload_target(&client, folder).await?;
 
-                // Remove macOS quarantine attributes
-                #[cfg(target_os = "macos")]
-                {
-                    use std::process::Command;
-                    use std::path::Path;
+    // The content hash isn't known until the body has gone through, so the trailer goes without
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+    let trailer = CaptionTrailer {
+        folder: folder.to_string(),
+        name: Some(file_name.clone()),
+        encrypted: encrypt,
+        ..Default::default()
+    };
+    let caption = build_caption(&caption_prefix, &file_name, None, &trailer);
 
-                    let dest_path = Path::new(destination);
-                    if dest_path.exists() && dest_path.is_file() {
-                        let _ = Command::new("xattr")
-                            .args(&["-d", "com.apple.quarantine", destination])
-                            .output();
-                    }
-                }
+    use futures::TryStreamExt;
+    let body = tokio_util::io::StreamReader::new(
+        response.bytes_stream().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    );
+    println!("Streaming {} ({} bytes) from {} into {}", file_name, file_size, url, folder);
+    let result = attempt_upload_reader(
+        &client, &target_chat, body, &file_name, file_size, &caption,
+        encryptor.as_ref().map(|e| (e, stream_threshold)), None, false, ProgressConfig::default(), Box::new(on_progress),
+    ).await;
 
-                return Ok(destination.to_string());
+    if let Err(e) = crate::bandwidth::flush().await {
+        eprintln!("Warning: Failed to save bandwidth stats: {}", e);
+    }
+    let message_id = match result {
+        Ok(id) => id,
+        Err(e) => {
+            if let Some(secs) = extract_flood_wait(&e.to_string().to_lowercase()) {
+                crate::transfers::record_flood_wait(secs).await;
             }
+            let error = format!("Upload from {} failed: {}", url, e);
+            return Err(upload_failed(&file_name, folder, &error, &[], 1).into());
         }
-    }
-    
-    Err(anyhow::anyhow!("Message with ID {} not found in Telegram", message_id))
-}
-
+    };
 
-// Download thumbnail from Telegram
-pub async fn download_thumbnail(
-    client_ref: Arc<Mutex<Option<Client>>>,
-    file_id: &str,
-    destination: &str,
-) -> Result<Option<String>> {
-    ensure_metadata_loaded().await?;
-    
-    // Scope the read lock
-    let file_meta = {
-        let cache = METADATA_CACHE.read().await;
-        let metadata = cache.as_ref().unwrap();
-        metadata.files.iter().find(|f| f.id == file_id).cloned()
+    let entry = FileMetadata {
+        id: message_file_id(target_chat_id, message_id),
+        name: file_name,
+        size: file_size,
+        mime_type,
+        created_at: chrono::Utc::now().timestamp(),
+        folder: folder.to_string(),
+        is_folder: false,
+        thumbnail: None,
+        message_id: Some(message_id),
+        encrypted: encrypt,
+        chat_id: target_chat_id,
+        description: None,
+        versions: Vec::new(),
+        sha256: None,
+        sort_index: None,
+        health: None,
+        as_photo: false,
+        updated_at: Some(chrono::Utc::now().timestamp()),
+        custom: BTreeMap::new(),
+        plain_versions: Vec::new(),
+        modified_at: None,
+        copy_of: None,
     };
+    let mut metadata = load_metadata_copy().await?;
+    metadata.files.push(entry.clone());
+    save_metadata_local(&metadata).await?;
 
-    let file_meta = file_meta.ok_or_else(|| anyhow::anyhow!("File not found"))?;
+    Ok(entry)
+}
 
-    // Only attempt download for images
-    // For videos, downloading the full file as a "thumbnail" is too heavy
-    if !file_meta.mime_type.starts_with("image/") {
-        return Ok(None);
-    }
+const THUMBNAIL_MAX_SIDE: u32 = 320;  // Telegram ignores document thumbnails larger than this
+const THUMBNAIL_TIMEOUT_SECS: u64 = 30;
 
-    let message_id = file_meta
-        .message_id
-        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+// Generated previews and folder avatars share one directory next to the metadata,
+// so the cache cap in run_maintenance covers both
+async fn thumbnail_cache_dir() -> Result<std::path::PathBuf> {
+    let dir = get_metadata_path().await?.with_file_name("thumbnails");
+    tokio::fs::create_dir_all(&dir).await?;
+    Ok(dir)
+}
 
-    // Get client by cloning
-    let client = {
-        let client_guard = client_ref.lock().await;
-        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
-    }; // Lock released
+// Generated upload thumbnails are named by content hash
+async fn thumbnail_path(content_sha256: &str) -> Result<std::path::PathBuf> {
+    Ok(thumbnail_cache_dir().await?.join(format!("{}.jpg", content_sha256)))
+}
 
-    // Determine source chat based on chat_id
-    let chat: Peer = if let Some(chat_id) = file_meta.chat_id {
-        // File in folder channel
-        crate::telegram::get_chat_peer(&client, chat_id).await?
+// Make a JPEG preview of an image or video at `destination`. Videos need ffmpeg on the PATH.
+// None for other files, or when generation fails for any reason.
+async fn generate_thumbnail(file_path: &Path, mime_type: &str, destination: &Path) -> Option<std::path::PathBuf> {
+    if destination.exists() {
+        return Some(destination.to_path_buf());
+    }
+
+    let result = if mime_type.starts_with("image/") {
+        let (source, target) = (file_path.to_path_buf(), destination.to_path_buf());
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let preview = image::open(&source)?
+                .thumbnail(THUMBNAIL_MAX_SIDE, THUMBNAIL_MAX_SIDE)
+                .to_rgb8();
+            preview.save_with_format(&target, image::ImageFormat::Jpeg)?;
+            Ok(())
+        }).await.map_err(|e| anyhow::anyhow!("Thumbnail task failed: {}", e)).and_then(|r| r)
+    } else if mime_type.starts_with("video/") {
+        let scale = format!(
+            "scale={0}:{0}:force_original_aspect_ratio=decrease",
+            THUMBNAIL_MAX_SIDE
+        );
+        let ffmpeg = tokio::process::Command::new("ffmpeg")
+            .args(["-y", "-loglevel", "error", "-ss", "1", "-i"])
+            .arg(file_path)
+            .args(["-frames:v", "1", "-vf", &scale])
+            .arg(destination)
+            .kill_on_drop(true)
+            .output();
+        match tokio::time::timeout(tokio::time::Duration::from_secs(THUMBNAIL_TIMEOUT_SECS), ffmpeg).await {
+            Ok(Ok(output)) if output.status.success() && destination.exists() => Ok(()),
+            Ok(Ok(output)) => Err(anyhow::anyhow!("ffmpeg failed: {}", String::from_utf8_lossy(&output.stderr).trim())),
+            Ok(Err(e)) => Err(anyhow::anyhow!("ffmpeg not available: {}", e)),
+            Err(_) => Err(anyhow::anyhow!("ffmpeg timed out")),
+        }
     } else {
-        // File in Saved Messages (root or legacy)
-        let me = client.get_me().await?;
-        Peer::User(me)
+        return None;
     };
-    
-    // Get PeerRef from Peer
-    let peer_ref = chat.to_ref()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
-    
-    let mut messages = client.iter_messages(peer_ref);
-    
-    while let Some(message) = messages.next().await? {
-        if message.id() == message_id {
-            if let Some(media) = message.media() {
-                // For images, download the media to the destination
-                // Check if destination exists first to avoid re-downloading
-                if !std::path::Path::new(destination).exists() {
-                    client.download_media(&media, destination).await?;
-                    
-                    // Remove macOS quarantine
-                    #[cfg(target_os = "macos")]
-                    {
-                        use std::process::Command;
-                        use std::path::Path;
-
-                        let dest_path = Path::new(destination);
-                        if dest_path.exists() && dest_path.is_file() {
-                            let _ = Command::new("xattr")
-                                .args(&["-d", "com.apple.quarantine", destination])
-                                .output();
-                        }
-                    }
-                }
-                
-                return Ok(Some(destination.to_string()));
-            }
+
+    match result {
+        Ok(()) => Some(destination.to_path_buf()),
+        Err(e) => {
+            println!("No thumbnail for {}: {}", file_path.display(), e);
+            let _ = tokio::fs::remove_file(destination).await;
+            None
         }
     }
-    Err(anyhow::anyhow!("Message not found"))
 }
 
-// List files in folder
-pub async fn list_files(folder: &str) -> Result<Vec<FileMetadata>> {
-    ensure_metadata_loaded().await?;
-    let cache = METADATA_CACHE.read().await;
-    let metadata = cache.as_ref().unwrap();
-    
-    let mut files: Vec<FileMetadata> = metadata.files.iter()
-        .filter(|f| f.folder == folder)
-        .cloned()
-        .collect();
-    
-    // Sort by created_at descending (newest first)
-    files.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-    
-    Ok(files)
+// Pause the batch if its retry budget is used up, telling the UI with a batch-paused event.
+// Returns false once the user chose to stop the batch.
+pub async fn retry_budget_checkpoint(budget: &crate::operations::RetryBudget, app_handle: &tauri::AppHandle) -> bool {
+    budget.checkpoint(|retries, flood_wait_secs| {
+        println!("Batch {} paused after {} retries and {}s of flood waits", budget.operation_id, retries, flood_wait_secs);
+        app_handle.emit_all("batch-paused", serde_json::json!({
+            "operationId": budget.operation_id,
+            "retries": retries,
+            "floodWaitSeconds": flood_wait_secs,
+            "maxRetries": budget.max_retries,
+            "maxFloodWaitSeconds": budget.max_flood_wait_secs
+        })).ok();
+    }).await
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct FolderStats {
-    pub file_count: u64,
-    pub total_size: u64,
+// Charge one retry of a batch upload (no-op outside a batch). Fails if the batch was stopped.
+// While the batch waits for the user, the upload's slot goes to other transfers and is
+// queued for again (as `file_path`) once the batch continues.
+async fn charge_retry_budget(
+    budget: Option<&crate::operations::RetryBudget>,
+    flood_wait_secs: u64,
+    permit: &mut Option<crate::transfers::TransferPermit>,
+    file_path: &str,
+    file_name: &str,
+    app_handle: &tauri::AppHandle,
+) -> Result<()> {
+    let Some(budget) = budget else {
+        return Ok(());
+    };
+    budget.record_retry(flood_wait_secs);
+    if budget.is_exhausted() {
+        *permit = None;
+    }
+    if !retry_budget_checkpoint(budget, app_handle).await {
+        return Err(anyhow::anyhow!("Batch stopped after repeated retries"));
+    }
+    if permit.is_none() {
+        let slot = crate::transfers::acquire(crate::bandwidth::Direction::Upload, file_path, file_name).await
+            .ok_or_else(|| anyhow::anyhow!("Removed from the upload queue"))?;
+        *permit = Some(slot);
+    }
+    Ok(())
 }
 
-// Get stats for a folder recursively
-pub async fn get_folder_stats(folder_path: &str) -> Result<FolderStats> {
-    ensure_metadata_loaded().await?;
-    let cache = METADATA_CACHE.read().await;
-    let metadata = cache.as_ref().unwrap();
-    
-    let folder_prefix = if folder_path == "/" {
-        "/".to_string()
-    } else {
-        format!("{}/", folder_path)
-    };
+const BATCH_QUIET_THRESHOLD: u64 = 5 * 1024 * 1024; // Smaller batch files only report start/complete
 
-    let mut file_count = 0;
-    let mut total_size = 0;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchFailure {
+    pub item: String,
+    pub error: String,
+}
 
-    for file in &metadata.files {
-        if !file.is_folder && (file.folder == folder_path || file.folder.starts_with(&folder_prefix)) {
-            file_count += 1;
-            total_size += file.size;
-        }
-    }
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchReport {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: Vec<BatchFailure>,
+}
 
-    Ok(FolderStats {
-        file_count,
-        total_size,
-    })
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchDownloadItem {
+    pub file_id: String,
+    pub destination: String,
 }
 
-// Get all files in a folder recursively
-pub async fn list_files_recursive(folder_path: &str) -> Result<Vec<FileMetadata>> {
+// Look up a file's stored size from the cache
+pub async fn get_file_size(file_id: &str) -> Result<u64> {
     ensure_metadata_loaded().await?;
     let cache = METADATA_CACHE.read().await;
     let metadata = cache.as_ref().unwrap();
-    
-    let folder_prefix = if folder_path == "/" {
-        "/".to_string()
+
+    metadata.files.iter()
+        .find(|f| f.id == file_id)
+        .map(|f| f.size)
+        .ok_or_else(|| anyhow::anyhow!("File not found"))
+}
+
+// Progress behaviour for a single file inside a batch operation
+pub fn batch_progress_config(file_size: u64, aggregate_only: bool) -> ProgressConfig {
+    if aggregate_only && file_size < BATCH_QUIET_THRESHOLD {
+        ProgressConfig::silent()
     } else {
-        format!("{}/", folder_path)
-    };
+        ProgressConfig::default()
+    }
+}
 
-    let mut files = Vec::new();
+pub const MAX_TRANSFER_CHUNK_SIZE: u64 = 512 * 1024; // Telegram's maximum part size; resume offsets are aligned to it
+pub const MIN_TRANSFER_CHUNK_SIZE: u64 = 4 * 1024;
 
-    for file in &metadata.files {
-        if !file.is_folder && (file.folder == folder_path || file.folder.starts_with(&folder_prefix)) {
-            files.push(file.clone());
-        }
+// Parallel document downloads: parts in flight at once, and the smallest download worth it
+const PARALLEL_DOWNLOAD_WORKERS: usize = 4;
+const PARALLEL_DOWNLOAD_MIN_CHUNKS: u64 = 8;
+
+// Telegram only accepts part sizes that are powers of two between 4KB and 512KB
+pub fn validate_chunk_size(chunk_size: u64) -> Result<()> {
+    if !chunk_size.is_power_of_two()
+        || chunk_size < MIN_TRANSFER_CHUNK_SIZE
+        || chunk_size > MAX_TRANSFER_CHUNK_SIZE
+    {
+        return Err(anyhow::anyhow!(
+            "Invalid chunk size {}: must be a power of two between {} and {}",
+            chunk_size, format_bytes(MIN_TRANSFER_CHUNK_SIZE), format_bytes(MAX_TRANSFER_CHUNK_SIZE)
+        ));
     }
+    Ok(())
+}
 
-    Ok(files)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingDownload {
+    pub file_id: String,
+    pub destination: String,
+    pub started_at: i64,
+    #[serde(default)]
+    pub downloaded_bytes: u64,
+    // What the `.part` file holds; entries from before these were recorded never match
+    #[serde(default)]
+    pub message_id: i32,
+    #[serde(default)]
+    pub expected_size: u64,
 }
 
-// Create folder
-pub async fn create_folder(
-    client_ref: Arc<Mutex<Option<Client>>>,
-    folder_name: &str,
-    parent_folder: &str,
-) -> Result<String> {
-    // Validate folder name
-    if folder_name.trim().is_empty() {
-        return Err(anyhow::anyhow!("Folder name cannot be empty"));
+impl PendingDownload {
+    // Whether this entry's `.part` file belongs to the given message and size
+    fn holds(&self, file_id: &str, message_id: i32, expected_size: u64) -> bool {
+        self.file_id == file_id
+            && self.message_id == message_id
+            && self.expected_size == expected_size
+            && expected_size > 0
     }
-    
-    // Sanitize folder name (remove invalid characters)
-    let sanitized_name = folder_name.trim().replace('/', "_").replace('\\', "_");
-    if sanitized_name.is_empty() {
-        return Err(anyhow::anyhow!("Invalid folder name"));
+}
+
+// Marks a destination as being downloaded until dropped
+struct ActiveDownload(String);
+
+impl ActiveDownload {
+    fn start(destination: &str) -> Self {
+        ACTIVE_DOWNLOADS.lock().unwrap_or_else(|e| e.into_inner()).insert(destination.to_string());
+        ActiveDownload(destination.to_string())
     }
-    
-    let full_path = if parent_folder == "/" {
-        format!("/{}", sanitized_name)
-    } else {
-        format!("{}/{}", parent_folder.trim_end_matches('/'), sanitized_name)
+}
+
+impl Drop for ActiveDownload {
+    fn drop(&mut self) {
+        ACTIVE_DOWNLOADS.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0);
+    }
+}
+
+fn partial_download_path(destination: &str) -> std::path::PathBuf {
+    std::path::PathBuf::from(format!("{}.part", destination))
+}
+
+// Bytes of an existing `.part` file we can keep, rounded down to a whole chunk
+async fn resumable_offset(part_path: &Path, expected_size: u64) -> u64 {
+    let existing = match tokio::fs::metadata(part_path).await {
+        Ok(meta) => meta.len(),
+        Err(_) => return 0,
     };
-    
-    let mut metadata = load_metadata_copy().await?;
-    
-    // Check if folder already exists
-    if metadata.folders.contains(&full_path) {
-        return Err(anyhow::anyhow!("Folder already exists"));
+
+    if expected_size > 0 && existing >= expected_size {
+        // Complete or oversized leftovers can't be trusted - start over
+        return 0;
     }
-    
-    // Check if a file/folder with this name already exists in the parent folder
-    let existing = metadata.files.iter().any(|f| 
-        f.folder == parent_folder && f.name == sanitized_name
-    );
-    if existing {
-        return Err(anyhow::anyhow!("A file or folder with this name already exists"));
+
+    // Every valid chunk size divides the maximum, so this offset works whatever the setting
+    (existing / MAX_TRANSFER_CHUNK_SIZE) * MAX_TRANSFER_CHUNK_SIZE
+}
+
+async fn open_partial_download(part_path: &Path, resume_from: u64) -> Result<tokio::fs::File> {
+    if resume_from == 0 {
+        return tokio::fs::File::create(part_path).await
+            .map_err(|e| anyhow::anyhow!("Failed to create destination file: {}", e));
     }
-    
-    // Create Telegram channel for this folder
-    let client = {
-        let guard = client_ref.lock().await;
-        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
-    };
-    
-    let chat_title = format!("T-Vault: {}", full_path);
-    let description = format!("Storage folder for: {}", full_path);
-    
-    let (chat_id, chat_name) = crate::telegram::create_folder_channel(
-        &client,
-        &chat_title,
-        &description,
-    ).await?;
-    
-    // Add small delay after channel creation
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-    
-    metadata.folders.push(full_path.clone());
-    
-    // Add to folder_metadata
-    metadata.folder_metadata.push(FolderMetadata {
-        path: full_path.clone(),
-        chat_id: Some(chat_id),
-        chat_title: Some(chat_name),
-        created_at: chrono::Utc::now().timestamp(),
-    });
-    
-    // Add folder as virtual entry
-    metadata.files.push(FileMetadata {
-        id: format!("folder_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0)),
-        name: sanitized_name.clone(),
-        size: 0,
-        mime_type: "folder".to_string(),
-        created_at: chrono::Utc::now().timestamp(),
-        folder: parent_folder.to_string(),
-        is_folder: true,
-        thumbnail: None,
-        message_id: None,
-        encrypted: false,
-        chat_id: Some(chat_id),
-    });
-    
-    save_metadata_local(&metadata).await?;
-    
-    Ok(full_path)
+
+    let file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(part_path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to reopen partial download: {}", e))?;
+    // Drop any trailing partial chunk so writes continue at a chunk boundary
+    file.set_len(resume_from).await
+        .map_err(|e| anyhow::anyhow!("Failed to truncate partial download: {}", e))?;
+    Ok(file)
 }
 
-// Delete file
-pub async fn delete_file(
-    client_ref: Arc<Mutex<Option<Client>>>,
-    file_id: &str,
-) -> Result<bool> {
-    let mut metadata = load_metadata_copy().await?;
-    
-    if let Some(pos) = metadata.files.iter().position(|f| f.id == file_id) {
-        let file_meta = &metadata.files[pos];
-        
-        // Get message_id and chat_id before removing from metadata
-        let message_id = file_meta.message_id;
-        let chat_id = file_meta.chat_id;
-        
-        // Delete the actual message from Telegram if we have a message_id
-        if let Some(msg_id) = message_id {
-            // Get client by cloning
-            let client = {
-                let client_guard = client_ref.lock().await;
-                client_guard.as_ref().cloned()
-            };
+// Preallocate the part file so parts can be written at any offset. False if the filesystem
+// refuses, in which case the download stays sequential.
+async fn prepare_positioned_writes(file: &mut tokio::fs::File, size: u64) -> bool {
+    file.seek(std::io::SeekFrom::Start(0)).await.is_ok() && file.set_len(size).await.is_ok()
+}
 
-            if let Some(client) = client {
-                // Determine which chat to delete from
-                let chat_result: Result<Peer> = if let Some(cid) = chat_id {
-                    // Delete from folder channel
-                    crate::telegram::get_chat_peer(&client, cid).await
-                } else {
-                    // Delete from Saved Messages
-                    client.get_me().await
-                        .map(|me| Peer::User(me))
-                        .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))
-                };
-                
-                if let Ok(chat) = chat_result {
-                    if let Some(peer_ref) = chat.to_ref() {
-                        let message_ids = vec![msg_id];
-                        if let Err(e) = client.delete_messages(peer_ref, &message_ids).await {
-                            eprintln!("Warning: Failed to delete message from Telegram: {:?}", e);
-                        }
-                    }
-                }
+// Fetch `total_size` bytes as `chunk_size` parts with at most `workers` requests in flight.
+// Each part is written at its own offset, so the order parts complete in doesn't matter.
+// A part counts against `buffer` from before its request until it's written, so parts
+// held in memory never add up past `max_buffered` (shared with other downloads).
+// Every part except the last must be exactly `chunk_size`; returns the bytes written.
+async fn assemble_chunks<W, F, Fut>(
+    writer: &mut W,
+    total_size: u64,
+    chunk_size: u64,
+    workers: usize,
+    buffer: &crate::transfers::ByteBudget,
+    max_buffered: u64,
+    fetch: F,
+) -> Result<u64>
+where
+    W: AsyncWrite + AsyncSeek + Unpin,
+    F: Fn(u64) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<u8>>>,
+{
+    use futures::stream::{self, StreamExt, TryStreamExt};
+
+    let chunk_count = total_size.div_ceil(chunk_size);
+    let mut parts = stream::iter(0..chunk_count)
+        .map(|index| {
+            let part = fetch(index);
+            let size = chunk_size.min(total_size - index * chunk_size);
+            async move {
+                let permit = buffer.acquire(size, max_buffered).await;
+                part.await.map(|bytes| (index, bytes, permit))
             }
+        })
+        .buffer_unordered(workers.max(1));
+
+    let mut written: u64 = 0;
+    while let Some((index, bytes, _permit)) = parts.try_next().await? {
+        let offset = index * chunk_size;
+        let expected = chunk_size.min(total_size - offset);
+        if bytes.len() as u64 != expected {
+            return Err(anyhow::anyhow!(
+                "Part {} came back with {} bytes, expected {}", index, bytes.len(), expected
+            ));
         }
-        
-        // Remove from local metadata
-        metadata.files.remove(pos);
-        save_metadata_local(&metadata).await?;
-        
-        Ok(true)
-    } else {
-        Ok(false)
+
+        writer.seek(std::io::SeekFrom::Start(offset)).await
+            .map_err(|e| anyhow::anyhow!("Failed to seek to part {}: {}", index, e))?;
+        writer.write_all(&bytes).await
+            .map_err(|e| anyhow::anyhow!("Failed to write part {}: {}", index, e))?;
+        written += expected;
     }
+    writer.flush().await
+        .map_err(|e| anyhow::anyhow!("Failed to flush file: {}", e))?;
+
+    Ok(written)
 }
 
-// Delete folder and its associated Telegram channel
-pub async fn delete_folder(
-    client_ref: Arc<Mutex<Option<Client>>>,
-    folder_path: &str,
-) -> Result<bool> {
-    let mut metadata = load_metadata_copy().await?;
-    
-    // Find folder metadata
-    let folder_meta = metadata.folder_metadata.iter()
-        .find(|f| f.path == folder_path)
-        .cloned();
-    
-    if let Some(folder_meta) = folder_meta {
-        // Delete Telegram channel if it exists
-        if let Some(chat_id) = folder_meta.chat_id {
-            let client = {
-                let guard = client_ref.lock().await;
-                guard.as_ref().cloned()
-            };
-            
-            if let Some(client) = client {
-                if let Err(e) = crate::telegram::delete_channel(&client, chat_id).await {
-                    eprintln!("Warning: Failed to delete Telegram channel: {:?}", e);
-                    // Continue anyway - we'll clean up local metadata
-                }
-            }
+// Hex SHA-256 of a file, read in 64KB blocks
+async fn file_sha256(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
         }
-        
-        // Remove from metadata
-        metadata.folder_metadata.retain(|f| f.path != folder_path);
-        metadata.folders.retain(|f| f != folder_path);
-        
-        // Remove all files in this folder (recursively)
-        let folder_prefix = format!("{}/", folder_path);
-        metadata.files.retain(|f| {
-            // 1. Remove files inside this folder
-            if f.folder == folder_path { return false; }
-            
-            // 2. Remove files in subfolders
-            if f.folder.starts_with(&folder_prefix) { return false; }
-            
-            // 3. Remove the folder entry itself (the virtual file representing this folder)
-            if f.is_folder {
-                let entry_full_path = if f.folder == "/" {
-                    format!("/{}", f.name)
-                } else {
-                    format!("{}/{}", f.folder, f.name)
-                };
-                
-                if entry_full_path == folder_path {
-                    return false;
-                }
-            }
-            
-            true
-        });
-        
-        save_metadata_local(&metadata).await?;
-        
-        Ok(true)
-    } else {
-        Ok(false)
+        hasher.update(&buffer[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Compare a finished download against the SHA-256 recorded in its caption trailer
+async fn verify_sha256(path: &Path, expected_hex: &str) -> Result<()> {
+    let actual = file_sha256(path).await?;
+    if !actual.eq_ignore_ascii_case(expected_hex) {
+        return Err(anyhow::anyhow!("Checksum mismatch (got {}, expected {})", actual, expected_hex));
     }
+    Ok(())
 }
 
-// Get storage stats
-pub async fn get_storage_stats() -> Result<StorageStats> {
-    ensure_metadata_loaded().await?;
-    let cache = METADATA_CACHE.read().await;
-    let metadata = cache.as_ref().unwrap();
-    
-    let total_size: u64 = metadata.files.iter().filter(|f| !f.is_folder).map(|f| f.size).sum();
-    let total_files = metadata.files.iter().filter(|f| !f.is_folder).count() as u64;
-    let folder_count = metadata.folders.len() as u64;
-    
-    Ok(StorageStats {
-        total_files,
-        total_size,
-        folder_count,
-    })
+async fn get_pending_downloads_path() -> Result<std::path::PathBuf> {
+    Ok(get_metadata_path().await?.with_file_name("pending_downloads.json"))
 }
 
-// Sync metadata by scanning Telegram Saved Messages
-pub async fn sync_from_telegram(client_ref: Arc<Mutex<Option<Client>>>) -> Result<usize> {
-    let client = {
-        let client_guard = client_ref.lock().await;
-        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+async fn load_pending_downloads() -> Vec<PendingDownload> {
+    let path = match get_pending_downloads_path().await {
+        Ok(path) => path,
+        Err(_) => return Vec::new(),
     };
 
-    let me = client.get_me().await?;
-    let chat = Peer::User(me);
-    
-    // Get PeerRef from Peer
-    let peer_ref = chat.to_ref()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
-    
-    let mut messages = client.iter_messages(peer_ref);
-    let mut new_files = Vec::new();
-    let mut found_folders = std::collections::HashSet::new();
-    found_folders.insert("/".to_string());
+    match tokio::fs::read_to_string(&path).await {
+        Ok(data) => serde_json::from_str(&data).unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
 
-    while let Some(message) = messages.next().await? {
-        if let Some(media) = message.media() {
-            let text = message.text();
-            if text.starts_with("📁 ") {
-                let name = text.trim_start_matches("📁 ").to_string();
-                
-                // Extract basic info from media
-                let (size, mime_type) = match media {
-                    Media::Document(doc) => {
-                        (doc.size().unwrap_or(0) as u64, doc.mime_type().unwrap_or("application/octet-stream").to_string())
-                    }
-                    Media::Photo(_) => {
-                        (0, "image/jpeg".to_string()) // Photos don't easily give size here
-                    }
-                    _ => (0, "application/octet-stream".to_string()),
-                };
+async fn save_pending_downloads(pending: &[PendingDownload]) -> Result<()> {
+    let path = get_pending_downloads_path().await?;
+    let data = serde_json::to_string_pretty(pending)?;
+    tokio::fs::write(&path, data).await?;
+    Ok(())
+}
 
-                let unique_id = format!("saved:{}", message.id());
-                new_files.push(FileMetadata {
-                    id: unique_id,
-                    name,
-                    size,
-                    mime_type,
-                    created_at: message.date().timestamp(),
-                    folder: "/".to_string(), // Default to root as folder structure isn't stored in TG
-                    is_folder: false,
-                    thumbnail: None,
-                    message_id: Some(message.id()),
-                    encrypted: false,
-                    chat_id: None,
-                });
-            }
+// Whether the `.part` file at `destination` was recorded for this exact message and size
+async fn pending_download_holds(file_id: &str, message_id: i32, expected_size: u64, destination: &str) -> bool {
+    let _guard = PENDING_DOWNLOADS_LOCK.lock().await;
+    load_pending_downloads().await.iter()
+        .any(|p| p.destination == destination && p.holds(file_id, message_id, expected_size))
+}
+
+async fn track_pending_download(file_id: &str, message_id: i32, expected_size: u64, destination: &str) {
+    let _guard = PENDING_DOWNLOADS_LOCK.lock().await;
+    let mut pending = load_pending_downloads().await;
+
+    if !pending.iter().any(|p| p.destination == destination && p.holds(file_id, message_id, expected_size)) {
+        pending.retain(|p| p.destination != destination);
+        pending.push(PendingDownload {
+            file_id: file_id.to_string(),
+            destination: destination.to_string(),
+            started_at: chrono::Utc::now().timestamp(),
+            downloaded_bytes: 0,
+            message_id,
+            expected_size,
+        });
+        if let Err(e) = save_pending_downloads(&pending).await {
+            eprintln!("Warning: Failed to record pending download: {}", e);
         }
     }
+}
 
-    if new_files.is_empty() {
-        return Ok(0);
+async fn untrack_pending_download(destination: &str) {
+    let _guard = PENDING_DOWNLOADS_LOCK.lock().await;
+    let mut pending = load_pending_downloads().await;
+    let before = pending.len();
+    pending.retain(|p| p.destination != destination);
+
+    if pending.len() != before {
+        if let Err(e) = save_pending_downloads(&pending).await {
+            eprintln!("Warning: Failed to update pending downloads: {}", e);
+        }
     }
+}
 
-    // Load existing to avoid duplicates
-    let mut store = load_metadata_copy().await.unwrap_or_else(|_| MetadataStore::new());
-    let count = new_files.len();
+// Startup scan: list interrupted downloads that can be resumed by calling download_file again
+pub async fn resume_downloads() -> Result<Vec<PendingDownload>> {
+    let _guard = PENDING_DOWNLOADS_LOCK.lock().await;
+    let pending = load_pending_downloads().await;
+    let mut resumable = Vec::new();
 
-    for file in new_files {
-        if !store.files.iter().any(|f| f.message_id == file.message_id) {
-            store.files.push(file);
+    for mut entry in pending.iter().cloned() {
+        // Forget entries whose partial file has disappeared
+        if let Ok(meta) = tokio::fs::metadata(partial_download_path(&entry.destination)).await {
+            entry.downloaded_bytes = meta.len();
+            resumable.push(entry);
         }
     }
 
-    save_metadata_local(&store).await?;
-    Ok(count)
+    if resumable.len() != pending.len() {
+        save_pending_downloads(&resumable).await?;
+    }
+
+    println!("Found {} resumable downloads", resumable.len());
+    Ok(resumable)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MigrationReport {
-    pub total: usize,
-    pub migrated: usize,
-    pub failed: usize,
-    pub skipped: usize,
+const TEMP_FILE_TTL_SECS: u64 = 24 * 60 * 60;             // Leftovers in our temp dirs older than this go
+const PARTIAL_DOWNLOAD_TTL_SECS: u64 = 7 * 24 * 60 * 60;  // Interrupted downloads nobody resumed
+const TEMP_WORK_DIRS: [&str; 4] = ["tvault_migration", "tvault_encrypt", "tvault_checksums", "tvault_share"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceReport {
+    pub thumbnails_removed: usize,
+    pub temp_files_removed: usize,
+    pub partial_downloads_removed: usize,
+    pub bytes_reclaimed: u64,
 }
 
-/// Migrate existing files from Saved Messages to folder-specific channels
-pub async fn migrate_files_to_folders(
+// Every file beneath `dir` with its size and last use (access time where the OS keeps it)
+async fn cached_files(dir: &Path) -> Vec<(std::path::PathBuf, u64, std::time::SystemTime)> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let Ok(mut entries) = tokio::fs::read_dir(&current).await else { continue };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Ok(meta) = entry.metadata().await else { continue };
+            if meta.is_dir() {
+                pending.push(entry.path());
+            } else if meta.is_file() {
+                let used = meta.accessed().or_else(|_| meta.modified()).unwrap_or(std::time::UNIX_EPOCH);
+                files.push((entry.path(), meta.len(), used));
+            }
+        }
+    }
+    files
+}
+
+// Least recently used files to drop so the rest fit in `cap` bytes
+fn lru_evictions(
+    mut files: Vec<(std::path::PathBuf, u64, std::time::SystemTime)>,
+    cap: u64,
+) -> Vec<(std::path::PathBuf, u64, std::time::SystemTime)> {
+    let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+    files.sort_by_key(|(_, _, used)| *used);
+    files.into_iter()
+        .take_while(|(_, size, _)| {
+            let evict = total > cap;
+            total = total.saturating_sub(*size);
+            evict
+        })
+        .collect()
+}
+
+fn older_than(time: std::time::SystemTime, secs: u64) -> bool {
+    time.elapsed().map_or(false, |age| age.as_secs() > secs)
+}
+
+// Keep disk use bounded: prune generated previews to the configured cap, clear stale files
+// from our temp dirs and drop interrupted downloads that were never resumed (or whose
+// file is gone from the vault)
+pub async fn run_maintenance() -> Result<MaintenanceReport> {
+    let mut report = MaintenanceReport::default();
+    let cap = crate::config::AppConfig::load().await.thumbnail_cache_max_bytes;
+
+    let thumbnails_dir = get_metadata_path().await?.with_file_name("thumbnails");
+    for (path, size, _) in lru_evictions(cached_files(&thumbnails_dir).await, cap) {
+        if tokio::fs::remove_file(&path).await.is_ok() {
+            report.thumbnails_removed += 1;
+            report.bytes_reclaimed += size;
+        }
+    }
+
+    for name in TEMP_WORK_DIRS {
+        let dir = std::env::temp_dir().join(name);
+        for (path, size, used) in cached_files(&dir).await {
+            if older_than(used, TEMP_FILE_TTL_SECS) && tokio::fs::remove_file(&path).await.is_ok() {
+                report.temp_files_removed += 1;
+                report.bytes_reclaimed += size;
+            }
+        }
+    }
+
+    {
+        let _guard = PENDING_DOWNLOADS_LOCK.lock().await;
+        let pending = load_pending_downloads().await;
+        let metadata = load_metadata_copy().await?;
+        let active = ACTIVE_DOWNLOADS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut kept = Vec::new();
+        for entry in &pending {
+            // A running download keeps writing its `.part`, so its age is when it last made progress
+            let part_path = partial_download_path(&entry.destination);
+            let last_written = tokio::fs::metadata(&part_path).await.and_then(|m| m.modified()).ok();
+            let orphaned = !active.contains(&entry.destination)
+                && (!metadata.files.iter().any(|f| f.id == entry.file_id)
+                    || last_written.map_or(true, |time| older_than(time, PARTIAL_DOWNLOAD_TTL_SECS)));
+            if !orphaned {
+                kept.push(entry.clone());
+                continue;
+            }
+            let size = tokio::fs::metadata(&part_path).await.map(|m| m.len()).unwrap_or(0);
+            if tokio::fs::remove_file(&part_path).await.is_ok() {
+                report.partial_downloads_removed += 1;
+                report.bytes_reclaimed += size;
+            }
+        }
+        if kept.len() != pending.len() {
+            save_pending_downloads(&kept).await?;
+        }
+    }
+
+    println!(
+        "Maintenance: removed {} previews, {} temp files and {} partial downloads ({} bytes)",
+        report.thumbnails_removed, report.temp_files_removed, report.partial_downloads_removed, report.bytes_reclaimed
+    );
+    Ok(report)
+}
+
+// Download file from Telegram
+pub async fn download_file(
     client_ref: Arc<Mutex<Option<Client>>>,
-    on_progress: impl Fn(String, u32, u32) + Send + Sync + 'static,
-    app_handle: tauri::AppHandle,
-) -> Result<MigrationReport> {
-    let metadata = load_metadata_copy().await?;
-    
-    // Collect files that need migration (in folders but no chat_id)
-    let files_to_migrate: Vec<FileMetadata> = metadata.files.iter()
-        .filter(|f| !f.is_folder && f.folder != "/" && f.chat_id.is_none())
-        .cloned()
-        .collect();
+    file_id: &str,
+    destination: &str,
+    progress_config: ProgressConfig,
+    on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static,
+) -> Result<String> {
+    // Validate inputs
+    if file_id.trim().is_empty() {
+        return Err(anyhow::anyhow!("Invalid file ID"));
+    }
+    if destination.trim().is_empty() {
+        return Err(anyhow::anyhow!("Invalid destination path"));
+    }
+
+    ensure_metadata_loaded().await?;
     
-    let total_files = files_to_migrate.len();
-    let mut migrated = 0;
-    let mut failed = 0;
-    let mut skipped = 0;
+    let file_meta = {
+        let cache = METADATA_CACHE.read().await;
+        let metadata = cache.as_ref().ok_or_else(|| anyhow::anyhow!("Metadata not loaded"))?;
+        metadata.files.iter().find(|f| f.id == file_id).cloned()
+    };
     
-    for (index, file) in files_to_migrate.iter().enumerate() {
-        on_progress(file.name.clone(), index as u32 + 1, total_files as u32);
-        
-        // Check if folder has a channel
-        let folder_has_channel = metadata.folder_metadata.iter()
-            .any(|fm| fm.path == file.folder && fm.chat_id.is_some());
-        
-        if !folder_has_channel {
-            // Folder doesn't have a channel yet - skip this file
-            eprintln!("Skipping {}: folder {} has no associated channel", file.name, file.folder);
-            skipped += 1;
-            continue;
+    let file_meta = file_meta.ok_or_else(|| anyhow::anyhow!("File not found"))?;
+    let encryptor = download_encryptor(&file_meta)?;
+
+    // The stored name stays as it is; only the file on disk gets a safe one
+    let destination = safe_download_path(destination);
+    let result = download_entry(client_ref, &file_meta, &destination, progress_config, on_progress).await?;
+    if let Some(ref encryptor) = encryptor {
+        decrypt_download(encryptor, &destination).await?;
+    }
+    Ok(result)
+}
+
+// Characters NTFS rejects in file names, and device names Windows reserves whatever the extension
+const WINDOWS_INVALID_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const WINDOWS_MAX_PATH: usize = 260;
+const WINDOWS_MAX_NAME_UNITS: usize = 255; // UTF-16 units per path component
+
+// A file name Windows will accept: invalid characters become '_', trailing dots/spaces go,
+// reserved device names get a leading '_' and over-long names are cut before the extension
+fn windows_safe_file_name(name: &str) -> String {
+    let mut safe: String = name.chars()
+        .map(|c| if WINDOWS_INVALID_CHARS.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+    let kept = safe.trim_end_matches(['.', ' ']).len();
+    safe.truncate(kept);
+    if safe.is_empty() {
+        return "file".to_string();
+    }
+
+    let stem = safe.split('.').next().unwrap_or("").trim_end();
+    if WINDOWS_RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        safe.insert(0, '_');
+    }
+
+    if safe.encode_utf16().count() <= WINDOWS_MAX_NAME_UNITS {
+        return safe;
+    }
+    let (stem, extension) = match safe.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() <= 16 => (stem.to_string(), format!(".{}", ext)),
+        _ => (safe.clone(), String::new()),
+    };
+    let mut budget = WINDOWS_MAX_NAME_UNITS - extension.encode_utf16().count();
+    let mut cut = String::new();
+    for c in stem.chars() {
+        if c.len_utf16() > budget {
+            break;
         }
-        
-        // Create temp directory for migration
-        let temp_dir = std::env::temp_dir().join("tvault_migration");
-        tokio::fs::create_dir_all(&temp_dir).await?;
-        let temp_path = temp_dir.join(&file.id);
-        let temp_path_str = temp_path.to_str().unwrap();
-        
-        // Download from Saved Messages
-        match download_file(client_ref.clone(), &file.id, temp_path_str, |_, _, _| {}).await {
-            Ok(_) => {
-                // Re-upload to folder channel
-                match upload_file(client_ref.clone(), temp_path_str, &file.folder, |_, _, _| {}, app_handle.clone()).await {
-                    Ok(_) => {
-                        // Delete old file from Saved Messages
-                        let _ = delete_file(client_ref.clone(), &file.id).await;
-                        migrated += 1;
-                        
-                        println!("Migrated: {} to folder {}", file.name, file.folder);
+        budget -= c.len_utf16();
+        cut.push(c);
+    }
+    cut + &extension
+}
+
+// Deep absolute paths need the \\?\ prefix to get past MAX_PATH (UNC shares use \\?\UNC\)
+fn with_long_path_prefix(path: &str) -> String {
+    if path.chars().count() < WINDOWS_MAX_PATH || path.starts_with(r"\\?\") {
+        return path.to_string();
+    }
+    let path = path.replace('/', "\\");
+    if let Some(share) = path.strip_prefix(r"\\") {
+        format!(r"\\?\UNC\{}", share)
+    } else if path.as_bytes().get(1) == Some(&b':') {
+        format!(r"\\?\{}", path)
+    } else {
+        // Relative paths can't take the prefix
+        path
+    }
+}
+
+// Where a download really goes: on Windows the name is made NTFS-safe and long paths prefixed
+fn safe_download_path(destination: &str) -> String {
+    if !cfg!(windows) {
+        return destination.to_string();
+    }
+
+    let (parent, name) = match destination.rfind(['/', '\\']) {
+        Some(pos) => destination.split_at(pos + 1),
+        None => ("", destination),
+    };
+    let safe_name = windows_safe_file_name(name);
+    if safe_name != name {
+        println!("Saving {} as {}", name, safe_name);
+    }
+    with_long_path_prefix(&format!("{}{}", parent, safe_name))
+}
+
+// Encrypted entries need the vault key; checked before anything is downloaded
+fn download_encryptor(file: &FileMetadata) -> Result<Option<crate::encryption::Encryptor>> {
+    if !file.encrypted {
+        return Ok(None);
+    }
+    crate::encryption::session_encryptor()
+        .map(Some)
+        .ok_or_else(|| TvaultError::VaultLocked.into())
+}
+
+// Replace the downloaded ciphertext at `destination` with the plaintext
+async fn decrypt_download(encryptor: &crate::encryption::Encryptor, destination: &str) -> Result<()> {
+    let sealed_path = std::path::PathBuf::from(format!("{}.sealed", destination));
+    tokio::fs::rename(destination, &sealed_path).await
+        .map_err(|e| anyhow::anyhow!("Failed to prepare decryption: {}", e))?;
+
+    let result = crate::encryption::decrypt_file(encryptor, &sealed_path, Path::new(destination)).await;
+    let _ = tokio::fs::remove_file(&sealed_path).await;
+    if result.is_err() {
+        // Don't leave half a plaintext behind
+        let _ = tokio::fs::remove_file(destination).await;
+    }
+    result.map(|_| ())
+}
+
+// Download the message referenced by a metadata entry to `destination`
+async fn download_entry(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_meta: &FileMetadata,
+    destination: &str,
+    progress_config: ProgressConfig,
+    on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static,
+) -> Result<String> {
+    let file_id = file_meta.id.as_str();
+    let file_size = file_meta.size;
+
+    // Wait for a slot in the global download budget
+    let abort_epoch = crate::operations::transfer_epoch();
+    let _permit = crate::transfers::acquire(crate::bandwidth::Direction::Download, &file_meta.id, &file_meta.name).await
+        .ok_or_else(|| anyhow::anyhow!("Removed from the download queue"))?;
+    if crate::operations::transfers_aborted_since(abort_epoch) {
+        return Err(anyhow::anyhow!("Download cancelled"));
+    }
+
+    let message_id = file_meta
+        .message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    // Get client by cloning
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    }; // Lock released
+
+    // Determine source chat based on chat_id
+    let chat: Peer = if let Some(chat_id) = file_meta.chat_id {
+        // File in folder channel
+        crate::telegram::get_chat_peer(&client, chat_id).await?
+    } else {
+        // File in Saved Messages (root or legacy)
+        let me = client.get_me().await
+            .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
+        Peer::User(me)
+    };
+
+    // Fetch the specific message directly
+    let message = find_message(&client, &chat, message_id).await?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID {} not found in Telegram", message_id))?;
+
+    if let Some(media) = message.media() {
+        // Reject unsupported media before touching the destination
+        let media = downloadable_media(media)?;
+
+        // Download media with progress tracking (explicitly handle doc/photo)
+        match media {
+            Media::Document(doc) => {
+                // Telegram's document size is authoritative; it's what a resumed file is checked against
+                let telegram_size = doc.size().unwrap_or(0) as u64;
+                let expected_size = if telegram_size > 0 {
+                    telegram_size
+                } else {
+                    file_size
+                };
+
+                // Download into a `.part` file next to the destination so an interrupted
+                // download (even across restarts) continues from where it stopped. Only a
+                // `.part` recorded for this message and size is kept; anything else starts over.
+                let part_path = partial_download_path(destination);
+                let _active = ActiveDownload::start(destination);
+                let resume_from = if pending_download_holds(file_id, message_id, expected_size, destination).await {
+                    resumable_offset(&part_path, expected_size).await
+                } else {
+                    untrack_pending_download(destination).await;
+                    0
+                };
+                let mut out_file = open_partial_download(&part_path, resume_from).await?;
+                let config = crate::config::AppConfig::load().await;
+                let chunk_size = config.transfer_chunk_size();
+                // Fresh, large downloads fetch several parts at once. An interrupted parallel
+                // download leaves a full-size part file, which resumable_offset won't trust.
+                let parallel = resume_from == 0
+                    && expected_size.div_ceil(chunk_size) >= PARALLEL_DOWNLOAD_MIN_CHUNKS
+                    && prepare_positioned_writes(&mut out_file, expected_size).await;
+                // Resuming after a restart goes through the file id, which only reaches the current version
+                let is_old_version = file_meta.versions.contains(&message_id);
+                if !is_old_version {
+                    track_pending_download(file_id, message_id, expected_size, destination).await;
+                }
+
+                if resume_from > 0 {
+                    println!("Resuming download of {} at {} of {} bytes", file_meta.name, resume_from, expected_size);
+                }
+
+                let mut progress_writer = ProgressWriter::with_config(out_file, expected_size, progress_config, on_progress)
+                    .starting_at(resume_from);
+                let downloaded_bytes = if parallel {
+                    let fetch = |index: u64| {
+                        let client = client.clone();
+                        let doc = doc.clone();
+                        async move {
+                            let mut part = client.iter_download(&doc)
+                                .chunk_size(chunk_size as i32)
+                                .skip_chunks(index as i32);
+                            Ok(part.next().await?.unwrap_or_default())
+                        }
+                    };
+                    let result = assemble_chunks(
+                        &mut progress_writer, expected_size, chunk_size, PARALLEL_DOWNLOAD_WORKERS,
+                        &crate::transfers::DOWNLOAD_BUFFER, config.max_download_buffer_bytes(), fetch
+                    ).await;
+                    drop(progress_writer);
+                    match result {
+                        Ok(written) => written,
+                        Err(e) => {
+                            let _ = tokio::fs::remove_file(&part_path).await;
+                            untrack_pending_download(destination).await;
+                            return Err(e);
+                        }
                     }
-                    Err(e) => {
-                        eprintln!("Failed to re-upload {}: {}", file.name, e);
-                        failed += 1;
+                } else {
+                    let mut download_stream = client.iter_download(&doc)
+                        .chunk_size(chunk_size as i32)
+                        .skip_chunks((resume_from / chunk_size) as i32);
+                    let mut downloaded_bytes: u64 = resume_from;
+
+                    while let Some(chunk) = download_stream.next().await? {
+                        downloaded_bytes += chunk.len() as u64;
+                        progress_writer.write_all(&chunk).await
+                            .map_err(|e| anyhow::anyhow!("Failed to write chunk: {}", e))?;
                     }
+                    progress_writer.flush().await
+                        .map_err(|e| anyhow::anyhow!("Failed to flush file: {}", e))?;
+                    drop(progress_writer);
+                    downloaded_bytes
+                };
+
+                // Verify we received the full file; retry once with download_media if short
+                if expected_size > 0 && downloaded_bytes < expected_size {
+                    eprintln!(
+                        "Warning: Downloaded {} of {} bytes. Retrying with download_media...",
+                        downloaded_bytes, expected_size
+                    );
+                    client.download_media(&doc, &part_path).await
+                        .map_err(|e| anyhow::anyhow!("Failed to re-download file: {}", e))?;
                 }
-                
-                // Clean up temp file
-                let _ = tokio::fs::remove_file(&temp_path).await;
+
+                // Verify the assembled file before it replaces the destination
+                let final_size = tokio::fs::metadata(&part_path).await
+                    .map_err(|e| anyhow::anyhow!("Failed to read downloaded file: {}", e))?
+                    .len();
+                if expected_size > 0 && final_size != expected_size {
+                    let _ = tokio::fs::remove_file(&part_path).await;
+                    untrack_pending_download(destination).await;
+                    return Err(anyhow::anyhow!(
+                        "Downloaded file is corrupt ({} bytes, expected {}). Please try again.",
+                        final_size, expected_size
+                    ));
+                }
+                let recorded_sha256 = parse_caption_trailer(message.text()).and_then(|trailer| trailer.sha256);
+                if let Some(expected_sha256) = recorded_sha256 {
+                    if let Err(e) = verify_sha256(&part_path, &expected_sha256).await {
+                        let _ = tokio::fs::remove_file(&part_path).await;
+                        untrack_pending_download(destination).await;
+                        return Err(anyhow::anyhow!("Downloaded file is corrupt: {}. Please try again.", e));
+                    }
+                }
+
+                tokio::fs::rename(&part_path, destination).await
+                    .map_err(|e| anyhow::anyhow!("Failed to move downloaded file into place: {}", e))?;
+                untrack_pending_download(destination).await;
             }
-            Err(e) => {
-                eprintln!("Failed to download {}: {}", file.name, e);
-                failed += 1;
+            Media::Photo(photo) => {
+                let out_file = tokio::fs::File::create(destination).await
+                    .map_err(|e| anyhow::anyhow!("Failed to create destination file: {}", e))?;
+                let mut progress_writer = ProgressWriter::with_config(out_file, file_size, progress_config, on_progress);
+                let mut download_stream = client.iter_download(&photo);
+                let mut downloaded_bytes: u64 = 0;
+
+                while let Some(chunk) = download_stream.next().await? {
+                    downloaded_bytes += chunk.len() as u64;
+                    progress_writer.write_all(&chunk).await
+                        .map_err(|e| anyhow::anyhow!("Failed to write chunk: {}", e))?;
+                }
+                progress_writer.flush().await
+                    .map_err(|e| anyhow::anyhow!("Failed to flush file: {}", e))?;
+
+                // Photos sent with as_photo come back recompressed, smaller than the original
+                if file_size > 0 && downloaded_bytes < file_size && !file_meta.as_photo {
+                    eprintln!(
+                        "Warning: Downloaded {} of {} bytes. Retrying with download_media...",
+                        downloaded_bytes, file_size
+                    );
+                    let out_file = tokio::fs::File::create(destination).await
+                        .map_err(|e| anyhow::anyhow!("Failed to recreate destination file: {}", e))?;
+                    drop(out_file);
+                    client.download_media(&photo, destination).await
+                        .map_err(|e| anyhow::anyhow!("Failed to re-download file: {}", e))?;
+                }
+            }
+            other => {
+                return Err(TvaultError::UnsupportedMedia(media_kind(&other).to_string()).into());
             }
         }
-        
-        // Add delay between migrations to avoid rate limits
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+        if let Err(e) = crate::bandwidth::flush().await {
+            eprintln!("Warning: Failed to save bandwidth stats: {}", e);
+        }
+
+        // Add delay between operations to avoid rate limits
+        tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
+
+        // Remove macOS quarantine attributes
+        #[cfg(target_os = "macos")]
+        {
+            use std::process::Command;
+            use std::path::Path;
+
+            let dest_path = Path::new(destination);
+            if dest_path.exists() && dest_path.is_file() {
+                let _ = Command::new("xattr")
+                    .args(&["-d", "com.apple.quarantine", destination])
+                    .output();
+            }
+        }
+
+        return Ok(destination.to_string());
+    }
+
+    Err(anyhow::anyhow!("Message with ID {} has no media to download", message_id))
+}
+
+// Stream an entry's stored bytes into any writer: no `.part` file, no resume, no temp files.
+// Encrypted entries come out still encrypted. Returns the number of bytes written.
+pub async fn download_to_writer<W: tokio::io::AsyncWrite + Unpin>(
+    client: &Client,
+    file_meta: &FileMetadata,
+    writer: W,
+    progress_config: ProgressConfig,
+    on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static,
+) -> Result<u64> {
+    let message_id = file_meta
+        .message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    let abort_epoch = crate::operations::transfer_epoch();
+    let _permit = crate::transfers::acquire(crate::bandwidth::Direction::Download, &file_meta.id, &file_meta.name).await
+        .ok_or_else(|| anyhow::anyhow!("Removed from the download queue"))?;
+    if crate::operations::transfers_aborted_since(abort_epoch) {
+        return Err(anyhow::anyhow!("Download cancelled"));
+    }
+    let chat = resolve_file_chat(client, file_meta.chat_id).await?;
+    let message = find_message(client, &chat, message_id).await?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID {} not found in Telegram", message_id))?;
+    let media = message.media()
+        .ok_or_else(|| anyhow::anyhow!("Message with ID {} has no media to download", message_id))?;
+
+    let chunk_size = crate::config::AppConfig::load().await.transfer_chunk_size();
+    let (mut download_stream, expected_size) = match downloadable_media(media)? {
+        Media::Document(doc) => {
+            let size = doc.size().map(|s| s as u64).filter(|&s| s > 0).unwrap_or(file_meta.size);
+            (client.iter_download(&doc).chunk_size(chunk_size as i32), size)
+        }
+        Media::Photo(photo) => (client.iter_download(&photo), file_meta.size),
+        other => return Err(TvaultError::UnsupportedMedia(media_kind(&other).to_string()).into()),
+    };
+
+    let mut progress_writer = ProgressWriter::with_config(writer, expected_size, progress_config, on_progress);
+    let mut downloaded_bytes: u64 = 0;
+    while let Some(chunk) = download_stream.next().await? {
+        downloaded_bytes += chunk.len() as u64;
+        progress_writer.write_all(&chunk).await
+            .map_err(|e| anyhow::anyhow!("Failed to write chunk: {}", e))?;
+    }
+    progress_writer.flush().await
+        .map_err(|e| anyhow::anyhow!("Failed to flush download: {}", e))?;
+
+    // There is no file to re-download into here, so a short read is an error
+    if expected_size > 0 && downloaded_bytes != expected_size {
+        return Err(anyhow::anyhow!(
+            "Download of {} is incomplete ({} of {} bytes). Please try again.",
+            file_meta.name, downloaded_bytes, expected_size
+        ));
+    }
+
+    Ok(downloaded_bytes)
+}
+
+const STREAM_EVENT_CHUNK: usize = 256 * 1024;  // Bytes per chunk handed to the UI
+const STREAM_MAX_IN_FLIGHT: usize = 8;          // Chunks the UI may hold unacknowledged
+const STREAM_ACK_TIMEOUT_SECS: u64 = 30;        // A UI that stops acknowledging ends the stream
+
+// Let a stream send one more chunk. Returns false if no such stream is running.
+pub fn ack_stream_chunk(operation_id: &str) -> bool {
+    match STREAM_CREDITS.lock().ok().and_then(|credits| credits.get(operation_id).cloned()) {
+        Some(credits) => {
+            credits.add_permits(1);
+            true
+        }
+        None => false,
+    }
+}
+
+// Stop a stream, including one waiting for acknowledgements. Returns false if none is running.
+pub fn cancel_stream(operation_id: &str) -> bool {
+    let cancelled = crate::operations::cancel(operation_id);
+    match STREAM_CREDITS.lock().ok().and_then(|credits| credits.get(operation_id).cloned()) {
+        Some(credits) => {
+            credits.close();
+            true
+        }
+        None => cancelled,
+    }
+}
+
+// Read until `buf` is full or the input ends
+async fn fill_buffer<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    use tokio::io::AsyncReadExt;
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+// Hand a file's content (decrypted if need be) to `on_chunk` piece by piece as it downloads,
+// with each piece's offset, for in-app previews. Nothing touches the disk. At most
+// STREAM_MAX_IN_FLIGHT chunks go out before the UI acknowledges them (ack_stream_chunk),
+// so a slow player holds the download back instead of piling up events.
+// Returns the number of bytes streamed.
+pub async fn stream_file(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    operation_id: &str,
+    cancel: &crate::operations::CancelToken,
+    on_chunk: impl Fn(u64, &[u8]),
+) -> Result<u64> {
+    let metadata = load_metadata_copy().await?;
+    let file_meta = find_file_entry(&metadata, file_id)?;
+    let encryptor = if file_meta.encrypted {
+        Some(crate::encryption::session_encryptor().ok_or(TvaultError::VaultLocked)?)
+    } else {
+        None
+    };
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let credits = Arc::new(tokio::sync::Semaphore::new(STREAM_MAX_IN_FLIGHT));
+    if let Ok(mut streams) = STREAM_CREDITS.lock() {
+        streams.insert(operation_id.to_string(), credits.clone());
+    }
+
+    let (mut content_tx, mut content_rx) = tokio::io::duplex(STREAM_EVENT_CHUNK);
+    let produce = async {
+        let result = match encryptor {
+            Some(ref encryptor) => {
+                // Ciphertext goes through a second bounded pipe into the decryptor
+                let (mut sealed_tx, sealed_rx) = tokio::io::duplex(EXPORT_PIPE_SIZE);
+                let download = async {
+                    let result = download_to_writer(&client, &file_meta, &mut sealed_tx, ProgressConfig::silent(), |_, _, _| {}).await;
+                    let _ = sealed_tx.shutdown().await;
+                    result
+                };
+                let decrypt = crate::encryption::decrypt_stream(encryptor, sealed_rx, &mut content_tx);
+                tokio::try_join!(download, decrypt).map(|_| ())
+            }
+            None => download_to_writer(&client, &file_meta, &mut content_tx, ProgressConfig::silent(), |_, _, _| {}).await.map(|_| ()),
+        };
+        let _ = content_tx.shutdown().await;
+        result
+    };
+    // Owns the reading end, so a consumer that gives up also stops the download
+    let consume = async move {
+        let mut buffer = vec![0u8; STREAM_EVENT_CHUNK];
+        let mut offset: u64 = 0;
+        loop {
+            let n = fill_buffer(&mut content_rx, &mut buffer).await?;
+            if n == 0 {
+                return Ok(offset);
+            }
+            if cancel.is_cancelled() {
+                return Err(anyhow::anyhow!("Stream cancelled"));
+            }
+            let permit = tokio::time::timeout(tokio::time::Duration::from_secs(STREAM_ACK_TIMEOUT_SECS), credits.acquire()).await
+                .map_err(|_| anyhow::anyhow!("Stream stalled: no chunk acknowledged for {}s", STREAM_ACK_TIMEOUT_SECS))?
+                .map_err(|_| anyhow::anyhow!("Stream cancelled"))?;
+            permit.forget();
+
+            on_chunk(offset, &buffer[..n]);
+            offset += n as u64;
+        }
+    };
+
+    let (produced, consumed): (Result<()>, Result<u64>) = tokio::join!(produce, consume);
+
+    if let Ok(mut streams) = STREAM_CREDITS.lock() {
+        streams.remove(operation_id);
+    }
+    let streamed = consumed?;
+    produced?;
+    Ok(streamed)
+}
+
+// Resolve the chat a file lives in: its folder channel, or Saved Messages when chat_id is None
+async fn resolve_file_chat(client: &Client, chat_id: Option<i64>) -> Result<Peer> {
+    if let Some(chat_id) = chat_id {
+        crate::telegram::get_chat_peer(client, chat_id).await
+    } else {
+        let me = client.get_me().await
+            .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
+        Ok(Peer::User(me))
+    }
+}
+
+// Locate a specific message in a chat. Fetches it by id in a single request and only
+// falls back to walking the history if Telegram returns nothing.
+async fn find_message(client: &Client, chat: &Peer, message_id: i32) -> Result<Option<Message>> {
+    let peer_ref = chat.to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+
+    match client.get_messages_by_id(peer_ref, &[message_id]).await {
+        Ok(messages) => {
+            if let Some(message) = messages.into_iter().flatten().find(|m| m.id() == message_id) {
+                return Ok(Some(message));
+            }
+        }
+        Err(e) => eprintln!("Warning: Fetching message {} by id failed, scanning history: {}", message_id, e),
+    }
+
+    let mut messages = client.iter_messages(peer_ref);
+    while let Some(message) = messages.next().await? {
+        if message.id() == message_id {
+            return Ok(Some(message));
+        }
+    }
+
+    Ok(None)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaSupport {
+    pub downloadable: bool,
+    pub media_kind: String,
+}
+
+// Report whether a file's backing message carries media we can download
+pub async fn can_download(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+) -> Result<MediaSupport> {
+    let file_meta = load_metadata_copy().await?
+        .files.into_iter()
+        .find(|f| f.id == file_id)
+        .ok_or_else(|| anyhow::anyhow!("File not found"))?;
+
+    let message_id = file_meta
+        .message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let chat = resolve_file_chat(&client, file_meta.chat_id).await?;
+    let message = find_message(&client, &chat, message_id).await?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID {} not found in Telegram", message_id))?;
+
+    Ok(match message.media() {
+        Some(media) => {
+            let kind = media_kind(&media).to_string();
+            MediaSupport {
+                downloadable: downloadable_media(media).is_ok(),
+                media_kind: kind,
+            }
+        }
+        None => MediaSupport {
+            downloadable: false,
+            media_kind: "none".to_string(),
+        },
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadPreflight {
+    pub available: bool,     // Message exists and carries downloadable media
+    pub media_kind: String,
+    pub size: u64,           // Authoritative size from Telegram (falls back to metadata for photos)
+    pub reason: Option<String>,
+}
+
+// Confirm a file can be downloaded before starting: fetches just its message by id
+pub async fn preflight_download(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+) -> Result<DownloadPreflight> {
+    let metadata = load_metadata_copy().await?;
+    let file_meta = find_file_entry(&metadata, file_id)?;
+
+    let unavailable = |media_kind: &str, reason: String| DownloadPreflight {
+        available: false,
+        media_kind: media_kind.to_string(),
+        size: 0,
+        reason: Some(reason),
+    };
+
+    let Some(message_id) = file_meta.message_id else {
+        return Ok(unavailable("none", "No message ID for file".to_string()));
+    };
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let chat = match resolve_file_chat(&client, file_meta.chat_id).await {
+        Ok(chat) => chat,
+        Err(e) => return Ok(unavailable("none", e.to_string())),
+    };
+
+    let Some(message) = find_message(&client, &chat, message_id).await? else {
+        return Ok(unavailable("none", format!("Message with ID {} not found in Telegram", message_id)));
+    };
+
+    let Some(media) = message.media() else {
+        return Ok(unavailable("none", "Message has no media".to_string()));
+    };
+
+    let kind = media_kind(&media).to_string();
+    match downloadable_media(media) {
+        Ok(Media::Document(doc)) => Ok(DownloadPreflight {
+            available: true,
+            media_kind: kind,
+            size: doc.size().map(|s| s as u64).unwrap_or(file_meta.size),
+            reason: None,
+        }),
+        Ok(_) => Ok(DownloadPreflight {
+            available: true,
+            media_kind: kind,
+            size: file_meta.size,
+            reason: None,
+        }),
+        Err(e) => Ok(unavailable(&kind, e.to_string())),
+    }
+}
+
+// Download thumbnail from Telegram
+pub async fn download_thumbnail(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    destination: &str,
+) -> Result<Option<String>> {
+    ensure_metadata_loaded().await?;
+    
+    // Scope the read lock
+    let file_meta = {
+        let cache = METADATA_CACHE.read().await;
+        let metadata = cache.as_ref().unwrap();
+        metadata.files.iter().find(|f| f.id == file_id).cloned()
+    };
+
+    let file_meta = file_meta.ok_or_else(|| anyhow::anyhow!("File not found"))?;
+
+    // Previews generated at upload time are already on disk
+    if let Some(ref local) = file_meta.thumbnail {
+        if Path::new(local).is_file() {
+            return Ok(Some(local.clone()));
+        }
+    }
+
+    // Images fall back to the full file; videos only have a preview if one was embedded
+    let is_image = file_meta.mime_type.starts_with("image/");
+    if !is_image && !file_meta.mime_type.starts_with("video/") {
+        return Ok(None);
+    }
+
+    let message_id = file_meta
+        .message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    // Get client by cloning
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    }; // Lock released
+
+    // Determine source chat based on chat_id
+    let chat: Peer = if let Some(chat_id) = file_meta.chat_id {
+        // File in folder channel
+        crate::telegram::get_chat_peer(&client, chat_id).await?
+    } else {
+        // File in Saved Messages (root or legacy)
+        let me = client.get_me().await?;
+        Peer::User(me)
+    };
+
+    // Fetch the specific message directly
+    let message = find_message(&client, &chat, message_id).await?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID {} not found in Telegram", message_id))?;
+
+    if let Some(media) = message.media() {
+        // The embedded thumb is a few KB; only images without one are fetched in full
+        let embedded_thumb = match media {
+            Media::Document(ref doc) => doc.thumbs().into_iter().last(),
+            _ => None,
+        };
+        if embedded_thumb.is_none() && !is_image {
+            return Ok(None);
+        }
+
+        // Check if destination exists first to avoid re-downloading
+        if !std::path::Path::new(destination).exists() {
+            match embedded_thumb {
+                Some(thumb) => client.download_media(&thumb, destination).await?,
+                None => client.download_media(&media, destination).await?,
+            }
+            
+            // Remove macOS quarantine
+            #[cfg(target_os = "macos")]
+            {
+                use std::process::Command;
+                use std::path::Path;
+
+                let dest_path = Path::new(destination);
+                if dest_path.exists() && dest_path.is_file() {
+                    let _ = Command::new("xattr")
+                        .args(&["-d", "com.apple.quarantine", destination])
+                        .output();
+                }
+            }
+        }
+        
+        return Ok(Some(destination.to_string()));
+    }
+    Err(anyhow::anyhow!("Message with ID {} has no media", message_id))
+}
+
+// Channel id for a folder's avatar; the root has none unless it has a channel
+async fn folder_avatar_chat(folder_path: &str) -> Result<Option<i64>> {
+    let metadata = load_metadata_copy().await?;
+    destination_chat_id(&metadata, folder_path).await
+}
+
+// Fetch a folder channel's small profile photo into the thumbnail cache.
+// Returns the cached path, or None when the channel has no photo.
+pub async fn get_folder_avatar(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+) -> Result<Option<String>> {
+    let Some(chat_id) = folder_avatar_chat(folder_path).await? else {
+        return Ok(None);
+    };
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let path = crate::telegram::download_channel_photo(&client, chat_id, &thumbnail_cache_dir().await?).await?;
+    Ok(path.map(|p| p.to_string_lossy().to_string()))
+}
+
+// Make `image_path` (JPEG or PNG) the folder channel's profile photo
+pub async fn set_folder_avatar(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+    image_path: &str,
+) -> Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let chat_id = folder_avatar_chat(folder_path).await?
+        .ok_or_else(|| anyhow::anyhow!("The root folder has no channel to set an avatar on"))?;
+
+    // Telegram only accepts still images here; check the bytes, not the extension
+    let mut file = tokio::fs::File::open(image_path).await
+        .map_err(|e| anyhow::anyhow!("Failed to open image: {}", e))?;
+    let mut header = Vec::with_capacity(SNIFF_BYTES);
+    (&mut file).take(SNIFF_BYTES as u64).read_to_end(&mut header).await
+        .map_err(|e| anyhow::anyhow!("Failed to read image: {}", e))?;
+    drop(file);
+    match sniff_mime(&header) {
+        Some(("image/jpeg", _)) | Some(("image/png", _)) => {}
+        _ => return Err(anyhow::anyhow!("Folder avatars must be JPEG or PNG images")),
+    }
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    crate::telegram::set_channel_photo(&client, chat_id, Path::new(image_path)).await
+}
+
+// Magic-byte signatures: (offset, bytes, mime type, canonical extension)
+const MAGIC_SIGNATURES: &[(usize, &[u8], &str, &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png", "png"),
+    (0, b"\xff\xd8\xff", "image/jpeg", "jpg"),
+    (0, b"GIF87a", "image/gif", "gif"),
+    (0, b"GIF89a", "image/gif", "gif"),
+    (8, b"WEBP", "image/webp", "webp"),
+    (0, b"%PDF-", "application/pdf", "pdf"),
+    (0, b"PK\x03\x04", "application/zip", "zip"),
+    (0, b"\x1f\x8b", "application/gzip", "gz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed", "7z"),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar", "rar"),
+    (257, b"ustar", "application/x-tar", "tar"),
+    (0, b"ID3", "audio/mpeg", "mp3"),
+    (0, b"fLaC", "audio/flac", "flac"),
+    (0, b"OggS", "audio/ogg", "ogg"),
+    (8, b"WAVE", "audio/wav", "wav"),
+    (4, b"ftyp", "video/mp4", "mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/x-matroska", "mkv"),
+    (0, b"SQLite format 3\0", "application/vnd.sqlite3", "sqlite"),
+];
+
+// Container formats whose contents legitimately look like a plain zip
+const ZIP_BASED_EXTENSIONS: &[&str] = &["docx", "xlsx", "pptx", "odt", "ods", "odp", "epub", "jar", "apk", "xpi"];
+
+const SNIFF_BYTES: usize = 512;
+
+// Detect a file's real type from its first bytes. Returns (mime type, extension).
+fn sniff_mime(header: &[u8]) -> Option<(&'static str, &'static str)> {
+    MAGIC_SIGNATURES.iter()
+        .find(|(offset, magic, _, _)| {
+            header.get(*offset..offset + magic.len()).map_or(false, |bytes| bytes == *magic)
+        })
+        .map(|(_, _, mime, ext)| (*mime, *ext))
+}
+
+// Whether a file named `file_name` is plausibly of the sniffed type
+fn extension_matches(file_name: &str, detected_mime: &str, detected_ext: &str) -> bool {
+    let Some(ext) = Path::new(file_name).extension().and_then(|e| e.to_str()) else {
+        return false;
+    };
+    let ext = ext.to_lowercase();
+
+    if ext == detected_ext {
+        return true;
+    }
+    if detected_mime == "application/zip" && ZIP_BASED_EXTENSIONS.contains(&ext.as_str()) {
+        return true;
+    }
+    // mp4 covers the whole ISO media family (m4a, mov, 3gp, heic...)
+    if detected_mime == "video/mp4" && matches!(ext.as_str(), "m4a" | "m4v" | "mov" | "3gp" | "heic" | "heif" | "avif") {
+        return true;
+    }
+    if detected_mime == "video/x-matroska" && ext == "webm" {
+        return true;
+    }
+    mime_guess::from_ext(&ext).iter().any(|m| m.essence_str() == detected_mime)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeMismatch {
+    pub stored_mime: String,
+    pub detected_mime: String,
+    pub detected_extension: String,
+    pub renamed_to: Option<String>,  // Set when the correct extension was appended
+}
+
+// Compare a downloaded file's magic bytes against its name and stored mime type.
+// Returns None when they agree or the content isn't recognised.
+pub async fn verify_download_type(
+    file_id: &str,
+    path: &str,
+    fix_extension: bool,
+) -> Result<Option<TypeMismatch>> {
+    use tokio::io::AsyncReadExt;
+
+    let metadata = load_metadata_copy().await?;
+    let stored_mime = metadata.files.iter()
+        .find(|f| f.id == file_id)
+        .map(|f| f.mime_type.clone())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let mut file = tokio::fs::File::open(path).await
+        .map_err(|e| anyhow::anyhow!("Failed to open downloaded file: {}", e))?;
+    let mut header = Vec::with_capacity(SNIFF_BYTES);
+    (&mut file).take(SNIFF_BYTES as u64).read_to_end(&mut header).await
+        .map_err(|e| anyhow::anyhow!("Failed to read downloaded file: {}", e))?;
+    drop(file);
+
+    let Some((detected_mime, detected_ext)) = sniff_mime(&header) else {
+        return Ok(None);
+    };
+
+    let file_name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    if extension_matches(file_name, detected_mime, detected_ext) {
+        return Ok(None);
+    }
+
+    let renamed_to = if fix_extension {
+        let new_path = format!("{}.{}", path, detected_ext);
+        tokio::fs::rename(path, &new_path).await
+            .map_err(|e| anyhow::anyhow!("Failed to rename downloaded file: {}", e))?;
+        Some(new_path)
+    } else {
+        None
+    };
+
+    println!("Type mismatch for {}: stored {}, detected {}", file_name, stored_mime, detected_mime);
+
+    Ok(Some(TypeMismatch {
+        stored_mime,
+        detected_mime: detected_mime.to_string(),
+        detected_extension: detected_ext.to_string(),
+        renamed_to,
+    }))
+}
+
+const MAX_PREVIEW_BYTES: usize = 256 * 1024; // Hard cap for in-app previews
+const MAX_ENCRYPTED_PREVIEW_SOURCE: u64 = 8 * 1024 * 1024; // Encrypted files are fetched whole to preview them
+const PREVIEW_TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "log", "csv", "tsv", "json", "xml", "yaml", "yml", "toml",
+    "ini", "cfg", "conf", "sh", "py", "rs", "js", "ts", "html", "css", "sql",
+];
+
+// Check whether a file can be previewed as text based on mime type or extension
+fn is_text_like(mime_type: &str, file_name: &str) -> bool {
+    if mime_type.starts_with("text/") {
+        return true;
+    }
+
+    if matches!(
+        mime_type,
+        "application/json" | "application/xml" | "application/javascript" | "application/x-yaml" | "application/toml" | "application/x-sh"
+    ) {
+        return true;
+    }
+
+    Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|ext| PREVIEW_TEXT_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+// Download only the first `max_bytes` of a text file and return it as a (lossy) UTF-8 string
+pub async fn preview_text(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    max_bytes: usize,
+) -> Result<String> {
+    ensure_metadata_loaded().await?;
+
+    let file_meta = {
+        let cache = METADATA_CACHE.read().await;
+        let metadata = cache.as_ref().ok_or_else(|| anyhow::anyhow!("Metadata not loaded"))?;
+        metadata.files.iter().find(|f| f.id == file_id).cloned()
+    };
+
+    let file_meta = file_meta.ok_or_else(|| anyhow::anyhow!("File not found"))?;
+
+    if !is_text_like(&file_meta.mime_type, &file_meta.name) {
+        return Err(anyhow::anyhow!("Preview is only available for text files ({})", file_meta.mime_type));
+    }
+
+    let max_bytes = max_bytes.clamp(1, MAX_PREVIEW_BYTES);
+
+    // Ciphertext only decrypts as a whole, so encrypted files are previewed from a full download
+    if let Some(encryptor) = download_encryptor(&file_meta)? {
+        if file_meta.size > MAX_ENCRYPTED_PREVIEW_SOURCE {
+            return Err(anyhow::anyhow!(
+                "Preview is only available for encrypted files up to {} bytes", MAX_ENCRYPTED_PREVIEW_SOURCE
+            ));
+        }
+        let temp_dir = std::env::temp_dir().join("tvault_preview");
+        tokio::fs::create_dir_all(&temp_dir).await?;
+        let temp_path = temp_dir.join(file_meta.id.replace(':', "_"));
+        let temp_path_str = temp_path.to_string_lossy().to_string();
+
+        let result = async {
+            download_entry(client_ref, &file_meta, &temp_path_str, ProgressConfig::silent(), |_, _, _| {}).await?;
+            decrypt_download(&encryptor, &temp_path_str).await?;
+            Ok::<_, anyhow::Error>(tokio::fs::read(&temp_path).await?)
+        }.await;
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        let mut buffer = result?;
+        buffer.truncate(max_bytes);
+        return Ok(String::from_utf8_lossy(&buffer).into_owned());
+    }
+
+    let message_id = file_meta
+        .message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    // Get client by cloning
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    }; // Lock released
+
+    // Determine source chat based on chat_id
+    let chat: Peer = if let Some(chat_id) = file_meta.chat_id {
+        crate::telegram::get_chat_peer(&client, chat_id).await?
+    } else {
+        let me = client.get_me().await
+            .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
+        Peer::User(me)
+    };
+
+    // Fetch the specific message directly
+    let message = find_message(&client, &chat, message_id).await?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID {} not found in Telegram", message_id))?;
+
+    let doc = match message.media() {
+        Some(Media::Document(doc)) => doc,
+        _ => return Err(anyhow::anyhow!("Message has no document to preview")),
+    };
+
+    // Request the smallest chunk size that covers the preview so we only pull what we need.
+    // Telegram requires chunk sizes to be multiples of 4KB, up to 512KB.
+    let chunk_size = max_bytes.next_power_of_two().clamp(4 * 1024, 512 * 1024) as i32;
+    let mut download_stream = client.iter_download(&doc).chunk_size(chunk_size);
+    let mut buffer: Vec<u8> = Vec::with_capacity(max_bytes);
+
+    while buffer.len() < max_bytes {
+        match download_stream.next().await? {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    buffer.truncate(max_bytes);
+
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+// List files in folder
+pub async fn list_files(folder: &str) -> Result<Vec<FileMetadata>> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    let metadata = cache.as_ref().unwrap();
+    
+    let mut files: Vec<FileMetadata> = metadata.files.iter()
+        .filter(|f| f.folder == folder)
+        .cloned()
+        .collect();
+    
+    files.sort_by(compare_listing);
+    
+    Ok(files)
+}
+
+// Manually ordered entries first, by sort_index; everything else newest first
+fn compare_listing(a: &FileMetadata, b: &FileMetadata) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    match (a.sort_index, b.sort_index) {
+        (Some(x), Some(y)) => x.cmp(&y),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => b.created_at.cmp(&a.created_at),
+    }
+}
+
+// Store a manual order for a folder: `ordered_ids` get positions 0, 1, 2, ...; entries of the
+// folder that aren't listed lose theirs and follow, newest first. Local only.
+pub async fn set_file_order(folder: &str, ordered_ids: &[String]) -> Result<usize> {
+    let mut metadata = load_metadata_copy().await?;
+
+    let mut seen = HashSet::new();
+    for id in ordered_ids {
+        if !seen.insert(id.as_str()) {
+            return Err(anyhow::anyhow!("Duplicate id in order: {}", id));
+        }
+        if !metadata.files.iter().any(|f| &f.id == id && f.folder == folder) {
+            return Err(anyhow::anyhow!("{} is not in folder {}", id, folder));
+        }
+    }
+
+    for file in metadata.files.iter_mut().filter(|f| f.folder == folder) {
+        file.sort_index = ordered_ids.iter().position(|id| *id == file.id).map(|pos| pos as i64);
+    }
+
+    save_metadata_local(&metadata).await?;
+    Ok(ordered_ids.len())
+}
+
+// List the newest files across all folders
+pub async fn list_recent(limit: usize) -> Result<Vec<FileMetadata>> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    let metadata = cache.as_ref().unwrap();
+
+    let mut files: Vec<&FileMetadata> = metadata.files.iter()
+        .filter(|f| !f.is_folder)
+        .collect();
+
+    if limit == 0 || files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Partial sort: move the `limit` newest files to the front, then only sort those
+    if files.len() > limit {
+        files.select_nth_unstable_by(limit - 1, |a, b| b.created_at.cmp(&a.created_at));
+        files.truncate(limit);
+    }
+    files.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+    Ok(files.into_iter().cloned().collect())
+}
+
+fn file_by_message(files: &[FileMetadata], chat_id: Option<i64>, message_id: i32) -> Option<&FileMetadata> {
+    files.iter().find(|f| !f.is_folder && f.chat_id == chat_id && f.message_id == Some(message_id))
+}
+
+// The entry stored in a given Telegram message (chat_id None = Saved Messages), from the cache only
+pub async fn find_file_by_message(chat_id: Option<i64>, message_id: i32) -> Result<Option<FileMetadata>> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    let metadata = cache.as_ref().unwrap();
+    Ok(file_by_message(&metadata.files, chat_id, message_id).cloned())
+}
+
+// Files with min_bytes <= size <= max_bytes, largest first, at most `limit` of them
+fn largest_files(files: &[FileMetadata], min_bytes: u64, max_bytes: Option<u64>, limit: usize) -> Vec<&FileMetadata> {
+    let mut files: Vec<&FileMetadata> = files.iter()
+        .filter(|f| !f.is_folder && f.size >= min_bytes && max_bytes.map_or(true, |max| f.size <= max))
+        .collect();
+
+    if limit == 0 || files.is_empty() {
+        return Vec::new();
+    }
+
+    // Same partial sort as list_recent: only the `limit` biggest get fully sorted
+    if files.len() > limit {
+        files.select_nth_unstable_by(limit - 1, |a, b| b.size.cmp(&a.size));
+        files.truncate(limit);
+    }
+    files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+    files
+}
+
+// List files in a size range across all folders, largest first
+pub async fn list_files_by_size(min_bytes: u64, max_bytes: Option<u64>, limit: usize) -> Result<Vec<FileMetadata>> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    let metadata = cache.as_ref().unwrap();
+
+    Ok(largest_files(&metadata.files, min_bytes, max_bytes, limit).into_iter().cloned().collect())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderStats {
+    pub file_count: u64,
+    pub total_size: u64,
+}
+
+// Get stats for a folder recursively
+pub async fn get_folder_stats(folder_path: &str) -> Result<FolderStats> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    let metadata = cache.as_ref().unwrap();
+    
+    let folder_prefix = if folder_path == "/" {
+        "/".to_string()
+    } else {
+        format!("{}/", folder_path)
+    };
+
+    let mut file_count = 0;
+    let mut total_size = 0;
+
+    for file in &metadata.files {
+        if !file.is_folder && (file.folder == folder_path || file.folder.starts_with(&folder_prefix)) {
+            file_count += 1;
+            total_size += file.size;
+        }
+    }
+
+    Ok(FolderStats {
+        file_count,
+        total_size,
+    })
+}
+
+// Get all files in a folder recursively
+pub async fn list_files_recursive(folder_path: &str) -> Result<Vec<FileMetadata>> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    let metadata = cache.as_ref().unwrap();
+    
+    let folder_prefix = if folder_path == "/" {
+        "/".to_string()
+    } else {
+        format!("{}/", folder_path)
+    };
+
+    let mut files = Vec::new();
+
+    for file in &metadata.files {
+        if !file.is_folder && (file.folder == folder_path || file.folder.starts_with(&folder_prefix)) {
+            files.push(file.clone());
+        }
+    }
+
+    Ok(files)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderDedupStats {
+    pub file_count: u64,
+    pub total_size: u64,
+    pub dedup_size: u64,        // Unique content only; files without a hash count as unique
+    pub duplicate_files: u64,   // Copies beyond the first of each hash
+    pub unhashed_files: u64,
+}
+
+fn dedup_stats(files: &[FileMetadata]) -> FolderDedupStats {
+    let mut stats = FolderDedupStats::default();
+    let mut seen: HashSet<&str> = HashSet::new();
+
+    for file in files.iter().filter(|f| !f.is_folder) {
+        stats.file_count += 1;
+        stats.total_size += file.size;
+        match file.sha256.as_deref() {
+            Some(hash) if !seen.insert(hash) => stats.duplicate_files += 1,
+            Some(_) => stats.dedup_size += file.size,
+            None => {
+                stats.unhashed_files += 1;
+                stats.dedup_size += file.size;
+            }
+        }
+    }
+
+    stats
+}
+
+// Raw vs. deduplicated size of a folder subtree, grouping files by content hash
+pub async fn get_folder_dedup_stats(folder_path: &str) -> Result<FolderDedupStats> {
+    let files = list_files_recursive(folder_path).await?;
+    Ok(dedup_stats(&files))
+}
+
+// Files with no recorded content hash (uploaded before hashes were kept)
+pub async fn list_files_without_checksum() -> Result<Vec<FileMetadata>> {
+    let metadata = load_metadata_copy().await?;
+    Ok(metadata.files.into_iter()
+        .filter(|f| !f.is_folder && f.sha256.is_none())
+        .collect())
+}
+
+// Download every file without a hash, hash its (decrypted) content and record it. Each hash
+// is saved as soon as it's known, so a cancelled or failed run resumes where it stopped.
+// Encrypted files need the vault unlocked.
+pub async fn backfill_checksums(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    cancel: &crate::operations::CancelToken,
+    on_progress: impl Fn(&str, usize, usize),
+) -> Result<BatchReport> {
+    let files = list_files_without_checksum().await?;
+    let mut report = BatchReport {
+        total: files.len(),
+        ..Default::default()
+    };
+    let temp_root = std::env::temp_dir().join("tvault_checksums");
+
+    for (index, file) in files.iter().enumerate() {
+        if cancel.is_cancelled() {
+            println!("Checksum backfill cancelled after {} of {} files", index, files.len());
+            break;
+        }
+        on_progress(&file.name, index, files.len());
+
+        let temp_dir = temp_root.join(file.id.replace(':', "_"));
+        let result = async {
+            tokio::fs::create_dir_all(&temp_dir).await?;
+            let temp_path = temp_dir.join("content");
+            let saved_as = download_file(client_ref.clone(), &file.id, &temp_path.to_string_lossy(), ProgressConfig::silent(), |_, _, _| {}).await?;
+            let hash = file_sha256(Path::new(&saved_as)).await?;
+
+            let mut metadata = load_metadata_copy().await?;
+            let entry = metadata.files.iter_mut()
+                .find(|f| f.id == file.id)
+                .ok_or_else(|| anyhow::anyhow!("File was removed while hashing"))?;
+            entry.sha256 = Some(hash);
+            save_metadata_local(&metadata).await
+        }.await;
+        let _ = tokio::fs::remove_dir_all(&temp_dir).await;
+
+        match result {
+            Ok(()) => report.succeeded += 1,
+            Err(e) => {
+                eprintln!("Failed to hash {}: {}", file.name, e);
+                report.failed.push(BatchFailure { item: file.id.clone(), error: e.to_string() });
+            }
+        }
+    }
+    on_progress("", report.succeeded + report.failed.len(), files.len());
+
+    if let Err(e) = crate::bandwidth::flush().await {
+        eprintln!("Warning: Failed to save bandwidth stats: {}", e);
+    }
+
+    Ok(report)
+}
+
+// Create folder
+pub async fn create_folder(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_name: &str,
+    parent_folder: &str,
+    kind: ChannelKind,
+) -> Result<String> {
+    // Validate folder name
+    if folder_name.trim().is_empty() {
+        return Err(anyhow::anyhow!("Folder name cannot be empty"));
+    }
+    
+    // Sanitize folder name (remove invalid characters)
+    let sanitized_name = folder_name.trim().replace('/', "_").replace('\\', "_");
+    if sanitized_name.is_empty() {
+        return Err(anyhow::anyhow!("Invalid folder name"));
+    }
+    
+    let full_path = if parent_folder == "/" {
+        format!("/{}", sanitized_name)
+    } else {
+        format!("{}/{}", parent_folder.trim_end_matches('/'), sanitized_name)
+    };
+    
+    let mut metadata = load_metadata_copy().await?;
+    
+    // Check if folder already exists
+    if metadata.folders.contains(&full_path) {
+        return Err(anyhow::anyhow!("Folder already exists"));
+    }
+    
+    // Check if a file/folder with this name already exists in the parent folder
+    let existing = metadata.files.iter().any(|f| 
+        f.folder == parent_folder && f.name == sanitized_name
+    );
+    if existing {
+        return Err(anyhow::anyhow!("A file or folder with this name already exists"));
+    }
+    
+    // Create Telegram channel for this folder
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+    
+    let chat_title = format!("T-Vault: {}", full_path);
+    let description = format!("Storage folder for: {}", full_path);
+    
+    let (chat_id, chat_name) = crate::telegram::create_folder_channel(
+        &client,
+        &chat_title,
+        &description,
+        kind == ChannelKind::Megagroup,
+    ).await?;
+    
+    // Add small delay after channel creation
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    
+    metadata.folders.push(full_path.clone());
+    
+    // Add to folder_metadata
+    metadata.folder_metadata.push(FolderMetadata {
+        path: full_path.clone(),
+        chat_id: Some(chat_id),
+        chat_title: Some(chat_name),
+        created_at: chrono::Utc::now().timestamp(),
+        kind,
+        color: None,
+        icon: None,
+    });
+    
+    // Add folder as virtual entry
+    metadata.files.push(FileMetadata {
+        id: format!("folder_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0)),
+        name: sanitized_name.clone(),
+        size: 0,
+        mime_type: "folder".to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+        folder: parent_folder.to_string(),
+        is_folder: true,
+        thumbnail: None,
+        message_id: None,
+        encrypted: false,
+        chat_id: Some(chat_id),
+        description: None,
+        versions: Vec::new(),
+        sha256: None,
+        sort_index: None,
+        health: None,
+        as_photo: false,
+        updated_at: None,
+        custom: BTreeMap::new(),
+        plain_versions: Vec::new(),
+        modified_at: None,
+        copy_of: None,
+    });
+    
+    save_metadata_local(&metadata).await?;
+    
+    Ok(full_path)
+}
+
+// Give a legacy folder (listed in `folders` but without a channel) its own channel
+// and link it in folder_metadata and the folder's virtual entry
+async fn upgrade_legacy_folder(client: &Client, folder: &str, kind: ChannelKind) -> Result<i64> {
+    let chat_title = format!("T-Vault: {}", folder);
+    let description = format!("Storage folder for: {}", folder);
+
+    let (new_chat_id, chat_name) = crate::telegram::create_folder_channel(
+        client,
+        &chat_title,
+        &description,
+        kind == ChannelKind::Megagroup,
+    ).await?;
+
+    println!("Channel created: ID={}, Name={}", new_chat_id, chat_name);
+
+    // Add small delay
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // Reload metadata since channel creation took a while
+    let mut metadata = load_metadata_copy().await?;
+
+    if let Some(meta) = metadata.folder_metadata.iter_mut().find(|f| f.path == folder) {
+        meta.chat_id = Some(new_chat_id);
+        meta.chat_title = Some(chat_name);
+        meta.kind = kind;
+    } else {
+        metadata.folder_metadata.push(FolderMetadata {
+            path: folder.to_string(),
+            chat_id: Some(new_chat_id),
+            chat_title: Some(chat_name),
+            created_at: chrono::Utc::now().timestamp(),
+            kind,
+            color: None,
+            icon: None,
+        });
+    }
+
+    // Also update the virtual file entry for this folder
+    if let Some(entry) = metadata.files.iter_mut().find(|f| f.is_folder && folder_entry_path(f) == folder) {
+        entry.chat_id = Some(new_chat_id);
+    }
+
+    save_metadata_local(&metadata).await?;
+
+    Ok(new_chat_id)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnsuredFolder {
+    pub path: String,
+    pub chat_id: Option<i64>,
+    pub created: bool,   // A new folder was created
+    pub upgraded: bool,  // An existing legacy folder got its channel
+}
+
+// Idempotent variant of `create_folder`: returns the existing folder when it already has
+// a channel, upgrades a legacy folder that lacks one, and creates it otherwise
+pub async fn ensure_folder(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_name: &str,
+    parent_folder: &str,
+    kind: ChannelKind,
+) -> Result<EnsuredFolder> {
+    let sanitized_name = folder_name.trim().replace('/', "_").replace('\\', "_");
+    let full_path = if parent_folder == "/" {
+        format!("/{}", sanitized_name)
+    } else {
+        format!("{}/{}", parent_folder.trim_end_matches('/'), sanitized_name)
+    };
+
+    let metadata = load_metadata_copy().await?;
+
+    if sanitized_name.is_empty() || !metadata.folders.contains(&full_path) {
+        let path = create_folder(client_ref, folder_name, parent_folder, kind).await?;
+        let chat_id = load_metadata_copy().await?
+            .folder_metadata.iter()
+            .find(|f| f.path == path)
+            .and_then(|f| f.chat_id);
+        return Ok(EnsuredFolder { path, chat_id, created: true, upgraded: false });
+    }
+
+    let existing_chat = metadata.folder_metadata.iter()
+        .find(|f| f.path == full_path)
+        .and_then(|f| f.chat_id);
+
+    if let Some(chat_id) = existing_chat {
+        return Ok(EnsuredFolder { path: full_path, chat_id: Some(chat_id), created: false, upgraded: false });
+    }
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    println!("Upgrading legacy folder: {}", full_path);
+    let chat_id = upgrade_legacy_folder(&client, &full_path, kind).await?;
+
+    Ok(EnsuredFolder { path: full_path, chat_id: Some(chat_id), created: false, upgraded: true })
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrecreateReport {
+    pub created: Vec<String>,
+    pub existing: Vec<String>,   // Already had a channel (legacy folders that got one are listed in created)
+    pub failed: Vec<BatchFailure>,
+}
+
+const FOLDER_CREATE_SPACING_MS: u64 = 2000; // Between two channel creations, which Telegram limits hard
+
+// Every folder a set of planned paths needs, ancestors included, parents before children
+fn planned_folder_paths(paths: &[String]) -> Vec<String> {
+    let mut planned = std::collections::BTreeSet::new();
+    for path in paths {
+        let mut current = String::new();
+        for part in path.split(['/', '\\']).map(str::trim).filter(|p| !p.is_empty()) {
+            current = format!("{}/{}", current, part);
+            planned.insert(current.clone());
+        }
+    }
+
+    // A parent is a prefix of its children, so sorting by depth keeps the order valid
+    let mut planned: Vec<String> = planned.into_iter().collect();
+    planned.sort_by_key(|p| p.matches('/').count());
+    planned
+}
+
+// Create the channels for a planned folder structure ahead of an import, spaced out so the
+// uploads that follow never have to stop and create one. Existing folders are left alone.
+pub async fn precreate_folders(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    paths: &[String],
+    kind: ChannelKind,
+) -> Result<PrecreateReport> {
+    let mut report = PrecreateReport::default();
+
+    for path in planned_folder_paths(paths) {
+        // Without its parent a folder can't be created either
+        if report.failed.iter().any(|f| path.starts_with(&format!("{}/", f.item))) {
+            report.failed.push(BatchFailure { item: path, error: "Parent folder could not be created".to_string() });
+            continue;
+        }
+
+        let (parent, name) = split_folder_path(&path);
+        let mut flood_waited = false;
+        let result = loop {
+            match ensure_folder(client_ref.clone(), &name, &parent, kind).await {
+                Err(e) if !flood_waited => {
+                    match extract_flood_wait(&e.to_string().to_lowercase()).filter(|&secs| secs <= MAX_FLOOD_WAIT_SECS) {
+                        Some(secs) => {
+                            println!("Waiting {}s for a flood wait before creating {}", secs, path);
+                            tokio::time::sleep(tokio::time::Duration::from_secs(secs)).await;
+                            flood_waited = true;
+                        }
+                        None => break Err(e),
+                    }
+                }
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(ensured) if ensured.created || ensured.upgraded => {
+                report.created.push(ensured.path);
+                tokio::time::sleep(tokio::time::Duration::from_millis(FOLDER_CREATE_SPACING_MS)).await;
+            }
+            Ok(ensured) => report.existing.push(ensured.path),
+            Err(e) => report.failed.push(BatchFailure { item: path, error: e.to_string() }),
+        }
+    }
+
+    println!(
+        "Pre-created {} folders ({} already existed, {} failed)",
+        report.created.len(), report.existing.len(), report.failed.len()
+    );
+    Ok(report)
+}
+
+// Delete file
+pub async fn delete_file(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    purge_versions: bool,
+) -> Result<bool> {
+    let mut metadata = load_metadata_copy().await?;
+    
+    if let Some(pos) = metadata.files.iter().position(|f| f.id == file_id) {
+        let file_meta = &metadata.files[pos];
+        
+        // Get message_id and chat_id before removing from metadata
+        let message_id = file_meta.message_id;
+        let chat_id = file_meta.chat_id;
+        
+        // Delete the actual message from Telegram if we have a message_id
+        if let Some(msg_id) = message_id {
+            // Get client by cloning
+            let client = {
+                let client_guard = client_ref.lock().await;
+                client_guard.as_ref().cloned()
+            };
+
+            if let Some(client) = client {
+                // Determine which chat to delete from
+                let chat_result: Result<Peer> = if let Some(cid) = chat_id {
+                    // Delete from folder channel
+                    crate::telegram::get_chat_peer(&client, cid).await
+                } else {
+                    // Delete from Saved Messages
+                    client.get_me().await
+                        .map(|me| Peer::User(me))
+                        .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))
+                };
+                
+                if let Ok(chat) = chat_result {
+                    if let Some(peer_ref) = chat.to_ref() {
+                        let mut message_ids = vec![msg_id];
+                        if purge_versions {
+                            message_ids.extend(file_meta.versions.iter().copied());
+                        }
+                        if let Err(e) = client.delete_messages(peer_ref, &message_ids).await {
+                            eprintln!("Warning: Failed to delete message from Telegram: {:?}", e);
+                        }
+                    }
+                }
+            }
+        }
+        
+        // Remove from local metadata
+        metadata.files.remove(pos);
+        save_metadata_local(&metadata).await?;
+        
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileVersion {
+    pub index: usize,       // 0 = oldest; the current version has the highest index
+    pub message_id: i32,
+    pub is_current: bool,
+}
+
+fn find_file_entry(metadata: &MetadataStore, file_id: &str) -> Result<FileMetadata> {
+    metadata.files.iter()
+        .find(|f| f.id == file_id && !f.is_folder)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("File not found"))
+}
+
+// List every stored version of a file, oldest first, ending with the current one
+pub async fn list_file_versions(file_id: &str) -> Result<Vec<FileVersion>> {
+    let metadata = load_metadata_copy().await?;
+    let file = find_file_entry(&metadata, file_id)?;
+
+    let current = file.message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    let mut versions: Vec<FileVersion> = file.versions.iter()
+        .enumerate()
+        .map(|(index, &message_id)| FileVersion { index, message_id, is_current: false })
+        .collect();
+    versions.push(FileVersion { index: file.versions.len(), message_id: current, is_current: true });
+
+    Ok(versions)
+}
+
+// Download a specific version of a file (see `list_file_versions` for the indices)
+pub async fn download_file_version(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    version_index: usize,
+    destination: &str,
+    progress_config: ProgressConfig,
+    on_progress: impl Fn(u32, u64, u64) + Send + Sync + 'static,
+) -> Result<String> {
+    let metadata = load_metadata_copy().await?;
+    let mut file = find_file_entry(&metadata, file_id)?;
+
+    if version_index < file.versions.len() {
+        // Older versions may differ in size; let the download use Telegram's size instead
+        let message_id = file.versions[version_index];
+        file.message_id = Some(message_id);
+        file.size = 0;
+        // A version from before the file was encrypted downloads as it is
+        file.encrypted &= !file.plain_versions.contains(&message_id);
+    } else if version_index > file.versions.len() {
+        return Err(anyhow::anyhow!(
+            "Version {} does not exist ({} versions stored)", version_index, file.versions.len() + 1
+        ));
+    }
+    let encryptor = download_encryptor(&file)?;
+
+    let destination = safe_download_path(destination);
+    let result = download_entry(client_ref, &file, &destination, progress_config, on_progress).await?;
+    if let Some(ref encryptor) = encryptor {
+        decrypt_download(encryptor, &destination).await?;
+    }
+    Ok(result)
+}
+
+// Set (or clear) a file's description and mirror it into the Telegram caption
+pub async fn set_file_description(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    description: Option<String>,
+) -> Result<FileMetadata> {
+    let mut metadata = load_metadata_copy().await?;
+
+    let pos = metadata.files.iter()
+        .position(|f| f.id == file_id && !f.is_folder)
+        .ok_or_else(|| anyhow::anyhow!("File not found"))?;
+
+    let file_meta = FileMetadata {
+        description: normalize_description(description),
+        modified_at: Some(chrono::Utc::now().timestamp()),
+        ..metadata.files[pos].clone()
+    };
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+    edit_file_caption(&client_ref, &file_meta, file_caption(&caption_prefix, &file_meta)).await?;
+
+    metadata.files[pos] = file_meta.clone();
+    save_metadata_local(&metadata).await?;
+
+    Ok(file_meta)
+}
+
+// Trimmed and capped at MAX_DESCRIPTION_CHARS; blank descriptions become None
+fn normalize_description(description: Option<String>) -> Option<String> {
+    let description = description?;
+    let description = description.trim();
+    if description.is_empty() {
+        return None;
+    }
+    Some(description.chars().take(MAX_DESCRIPTION_CHARS).collect::<String>().trim_end().to_string())
+}
+
+// The caption an entry's message should carry, trailer included
+fn file_caption(prefix: &str, file: &FileMetadata) -> String {
+    let trailer = CaptionTrailer {
+        folder: file.folder.clone(),
+        name: Some(file.name.clone()),
+        description: file.description.clone(),
+        sha256: file.sha256.clone().filter(|_| !file.encrypted),
+        encrypted: file.encrypted,
+        custom: file.custom.clone(),
+        // The entry's date may differ from the message's, and a rebuild should keep it
+        created_at: Some(file.created_at),
+        ..Default::default()
+    };
+    build_caption(prefix, &file.name, file.description.as_deref(), &trailer)
+}
+
+// Replace the caption of an entry's message. Entries without a message have nothing to edit.
+async fn edit_file_caption(
+    client_ref: &Arc<Mutex<Option<Client>>>,
+    file: &FileMetadata,
+    caption: String,
+) -> Result<()> {
+    let Some(message_id) = file.message_id else {
+        return Ok(());
+    };
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let chat: Peer = if let Some(chat_id) = file.chat_id {
+        crate::telegram::get_chat_peer(&client, chat_id).await?
+    } else {
+        let me = client.get_me().await
+            .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
+        Peer::User(me)
+    };
+
+    let peer_ref = chat.to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+
+    client.edit_message(peer_ref, message_id, InputMessage::new().text(caption)).await
+        .map_err(|e| anyhow::anyhow!("Failed to update caption on Telegram: {}", e))?;
+    Ok(())
+}
+
+// Keys must be non-blank and everything together small enough for the caption trailer
+fn check_custom_metadata(custom: &BTreeMap<String, String>) -> Result<()> {
+    if custom.keys().any(|k| k.trim().is_empty()) {
+        return Err(anyhow::anyhow!("Custom metadata keys can't be empty"));
+    }
+    let size: usize = custom.iter().map(|(k, v)| k.len() + v.len()).sum();
+    if size > MAX_CUSTOM_METADATA_BYTES {
+        return Err(anyhow::anyhow!(
+            "Custom metadata is {} bytes, the limit is {}", size, MAX_CUSTOM_METADATA_BYTES
+        ));
+    }
+    Ok(())
+}
+
+// Replace a file's custom key-value metadata. With `embed`, the caption trailer gets it too so
+// a sync from Telegram restores it; otherwise it's local until the caption is next rewritten,
+// and any custom data an earlier call embedded is taken out of the trailer.
+pub async fn set_file_custom(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    custom: BTreeMap<String, String>,
+    embed: bool,
+) -> Result<FileMetadata> {
+    check_custom_metadata(&custom)?;
+
+    let mut metadata = load_metadata_copy().await?;
+    let pos = metadata.files.iter()
+        .position(|f| f.id == file_id && !f.is_folder)
+        .ok_or_else(|| anyhow::anyhow!("File not found"))?;
+    let file_meta = FileMetadata {
+        custom,
+        modified_at: Some(chrono::Utc::now().timestamp()),
+        ..metadata.files[pos].clone()
+    };
+
+    if embed {
+        let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+        let caption = file_caption(&caption_prefix, &file_meta);
+        // A long folder path can still crowd it out of the trailer
+        let embedded = parse_caption_trailer(&caption).map(|t| t.custom);
+        if embedded.as_ref() != Some(&file_meta.custom) {
+            return Err(anyhow::anyhow!("Custom metadata doesn't fit in the caption of {}", file_meta.name));
+        }
+        edit_file_caption(&client_ref, &file_meta, caption).await?;
+    } else if !metadata.files[pos].custom.is_empty() {
+        // The old values may be in the trailer, where a sync would bring them back
+        let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+        let without_custom = FileMetadata { custom: BTreeMap::new(), ..file_meta.clone() };
+        edit_file_caption(&client_ref, &file_meta, file_caption(&caption_prefix, &without_custom)).await?;
+    }
+
+    metadata.files[pos] = file_meta.clone();
+    save_metadata_local(&metadata).await?;
+
+    Ok(file_meta)
+}
+
+pub async fn get_file_custom(file_id: &str) -> Result<BTreeMap<String, String>> {
+    let metadata = load_metadata_copy().await?;
+    metadata.files.iter()
+        .find(|f| f.id == file_id && !f.is_folder)
+        .map(|f| f.custom.clone())
+        .ok_or_else(|| anyhow::anyhow!("File not found"))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NameFix {
+    pub id: String,
+    pub old_name: String,
+    pub new_name: String,
+}
+
+// One-shot local repair: strip caption prefixes and marker/trailer text that earlier syncs
+// left in file names. Entries that would end up with an empty name are left alone.
+pub async fn clean_file_names() -> Result<Vec<NameFix>> {
+    let mut metadata = load_metadata_copy().await?;
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+    let mut fixes = Vec::new();
+
+    for file in metadata.files.iter_mut().filter(|f| !f.is_folder) {
+        let cleaned = clean_file_name(&file.name, &caption_prefix);
+        if !cleaned.is_empty() && cleaned != file.name {
+            fixes.push(NameFix {
+                id: file.id.clone(),
+                old_name: std::mem::replace(&mut file.name, cleaned.clone()),
+                new_name: cleaned,
+            });
+            file.modified_at = Some(chrono::Utc::now().timestamp());
+        }
+    }
+
+    if !fixes.is_empty() {
+        println!("Cleaned {} file names", fixes.len());
+        save_metadata_local(&metadata).await?;
+    }
+    Ok(fixes)
+}
+
+// Re-read a file's name from its Telegram document attributes and store it if it differs.
+// Entries without a filename attribute (e.g. photos) keep their current name.
+pub async fn refresh_file_name(client_ref: Arc<Mutex<Option<Client>>>, file_id: &str) -> Result<FileMetadata> {
+    let mut metadata = load_metadata_copy().await?;
+
+    let pos = metadata.files.iter()
+        .position(|f| f.id == file_id && !f.is_folder)
+        .ok_or_else(|| anyhow::anyhow!("File not found"))?;
+    let file_meta = metadata.files[pos].clone();
+    let message_id = file_meta.message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let chat = resolve_file_chat(&client, file_meta.chat_id).await?;
+    let message = find_message(&client, &chat, message_id).await?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID {} not found in Telegram", message_id))?;
+
+    match message.media().as_ref().and_then(document_file_name) {
+        Some(name) if name != file_meta.name => {
+            println!("Renaming {} to {} from its document attributes", file_meta.name, name);
+            metadata.files[pos].name = name;
+            metadata.files[pos].modified_at = Some(chrono::Utc::now().timestamp());
+            let updated = metadata.files[pos].clone();
+            save_metadata_local(&metadata).await?;
+            Ok(updated)
+        }
+        _ => Ok(file_meta),
+    }
+}
+
+// Normalized file id for a message: "{chat_id}:{message_id}", or "saved:{message_id}" for Saved Messages
+fn message_file_id(chat_id: Option<i64>, message_id: i32) -> String {
+    let chat_part = chat_id.map(|id| id.to_string()).unwrap_or_else(|| "saved".to_string());
+    format!("{}:{}", chat_part, message_id)
+}
+
+// Point a file entry at a different Telegram message (manual recovery, no Telegram writes)
+pub async fn relink_file(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    new_message_id: i32,
+    chat_id: Option<i64>,
+) -> Result<FileMetadata> {
+    let metadata = load_metadata_copy().await?;
+
+    if !metadata.files.iter().any(|f| f.id == file_id && !f.is_folder) {
+        return Err(anyhow::anyhow!("File not found"));
+    }
+
+    let new_id = message_file_id(chat_id, new_message_id);
+    if new_id != file_id && metadata.files.iter().any(|f| f.id == new_id) {
+        return Err(anyhow::anyhow!("Message {} is already linked to another file ({})", new_message_id, new_id));
+    }
+
+    // Validate the target message exists and carries media before accepting the relink
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let chat = resolve_file_chat(&client, chat_id).await?;
+    let message = find_message(&client, &chat, new_message_id).await?
+        .ok_or_else(|| anyhow::anyhow!("Message with ID {} not found in Telegram", new_message_id))?;
+    if message.media().is_none() {
+        return Err(anyhow::anyhow!("Message {} has no media attached", new_message_id));
+    }
+
+    // Re-load after the network round trip so we don't clobber concurrent changes
+    let mut metadata = load_metadata_copy().await?;
+    let entry = metadata.files.iter_mut()
+        .find(|f| f.id == file_id && !f.is_folder)
+        .ok_or_else(|| anyhow::anyhow!("File not found"))?;
+
+    println!("Relinking {} -> {}", entry.id, new_id);
+    entry.id = new_id;
+    entry.message_id = Some(new_message_id);
+    entry.chat_id = chat_id;
+    let updated = entry.clone();
+
+    save_metadata_local(&metadata).await?;
+    Ok(updated)
+}
+
+// Delete folder and its associated Telegram channel
+pub async fn delete_folder(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+) -> Result<bool> {
+    let mut metadata = load_metadata_copy().await?;
+    
+    // Find folder metadata
+    let folder_meta = metadata.folder_metadata.iter()
+        .find(|f| f.path == folder_path)
+        .cloned();
+    
+    if let Some(folder_meta) = folder_meta {
+        // Delete Telegram channel if it exists
+        if let Some(chat_id) = folder_meta.chat_id {
+            let client = {
+                let guard = client_ref.lock().await;
+                guard.as_ref().cloned()
+            };
+            
+            if let Some(client) = client {
+                if let Err(e) = crate::telegram::delete_channel(&client, chat_id).await {
+                    eprintln!("Warning: Failed to delete Telegram channel: {:?}", e);
+                    // Continue anyway - we'll clean up local metadata
+                }
+            }
+        }
+        
+        // Remove from metadata
+        metadata.folder_metadata.retain(|f| f.path != folder_path);
+        metadata.folders.retain(|f| f != folder_path);
+        
+        // Remove all files in this folder (recursively)
+        let folder_prefix = format!("{}/", folder_path);
+        metadata.files.retain(|f| {
+            // 1. Remove files inside this folder
+            if f.folder == folder_path { return false; }
+            
+            // 2. Remove files in subfolders
+            if f.folder.starts_with(&folder_prefix) { return false; }
+            
+            // 3. Remove the folder entry itself (the virtual file representing this folder)
+            if f.is_folder {
+                let entry_full_path = if f.folder == "/" {
+                    format!("/{}", f.name)
+                } else {
+                    format!("{}/{}", f.folder, f.name)
+                };
+                
+                if entry_full_path == folder_path {
+                    return false;
+                }
+            }
+            
+            true
+        });
+        
+        save_metadata_local(&metadata).await?;
+        
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+// Full path of a virtual folder entry, e.g. parent "/Photos" + name "Trips" => "/Photos/Trips"
+fn folder_entry_path(entry: &FileMetadata) -> String {
+    if entry.folder == "/" {
+        format!("/{}", entry.name)
+    } else {
+        format!("{}/{}", entry.folder, entry.name)
+    }
+}
+
+// Split a folder path into (parent, name), e.g. "/Photos/Trips" => ("/Photos", "Trips")
+fn split_folder_path(folder_path: &str) -> (String, String) {
+    let path = Path::new(folder_path);
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+    let parent = path.parent().and_then(|p| p.to_str()).unwrap_or("/");
+    let parent = if parent.is_empty() { "/" } else { parent };
+    (parent.to_string(), name)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FolderRepairReport {
+    pub added_to_folders: Vec<String>,     // Paths that had metadata or an entry but were missing from `folders`
+    pub added_entries: Vec<String>,        // Paths that were missing their virtual `is_folder` entry
+    pub fixed_chat_ids: Vec<String>,       // Virtual entries whose chat_id disagreed with folder_metadata
+    pub removed_duplicates: usize,         // Duplicate paths dropped from `folders`
+    pub needs_upgrade: Vec<String>,        // Legacy folders with no channel yet (upgraded on next upload)
+}
+
+impl FolderRepairReport {
+    pub fn changed(&self) -> bool {
+        !self.added_to_folders.is_empty()
+            || !self.added_entries.is_empty()
+            || !self.fixed_chat_ids.is_empty()
+            || self.removed_duplicates > 0
+    }
+}
+
+// Reconcile `folders`, `folder_metadata` and the virtual folder entries in place
+fn reconcile_folders(store: &mut MetadataStore) -> FolderRepairReport {
+    let mut report = FolderRepairReport::default();
+
+    // 1. Drop duplicate paths (and make sure root is always present)
+    let mut seen: HashSet<String> = HashSet::new();
+    let before = store.folders.len();
+    store.folders.retain(|p| seen.insert(p.clone()));
+    report.removed_duplicates = before - store.folders.len();
+    if seen.insert("/".to_string()) {
+        store.folders.insert(0, "/".to_string());
+    }
+
+    // 2. Every path with rich metadata or a virtual entry must be in `folders`
+    let mut known_paths: Vec<String> = store.folder_metadata.iter().map(|f| f.path.clone()).collect();
+    known_paths.extend(store.files.iter().filter(|f| f.is_folder).map(folder_entry_path));
+    for path in known_paths {
+        if seen.insert(path.clone()) {
+            store.folders.push(path.clone());
+            report.added_to_folders.push(path);
+        }
+    }
+
+    // 3. Every non-root folder needs a virtual entry whose chat_id matches its metadata
+    let mut counter: i64 = 0;
+    for path in store.folders.clone() {
+        if path == "/" {
+            continue;
+        }
+
+        let chat_id = store.folder_metadata.iter()
+            .find(|f| f.path == path)
+            .and_then(|f| f.chat_id);
+
+        if chat_id.is_none() {
+            report.needs_upgrade.push(path.clone());
+        }
+
+        match store.files.iter_mut().find(|f| f.is_folder && folder_entry_path(f) == path) {
+            Some(entry) => {
+                if chat_id.is_some() && entry.chat_id != chat_id {
+                    entry.chat_id = chat_id;
+                    report.fixed_chat_ids.push(path.clone());
+                }
+            }
+            None => {
+                counter += 1;
+                let (parent, name) = split_folder_path(&path);
+                store.files.push(FileMetadata {
+                    id: format!("folder_{}_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0), counter),
+                    name,
+                    size: 0,
+                    mime_type: "folder".to_string(),
+                    created_at: chrono::Utc::now().timestamp(),
+                    folder: parent,
+                    is_folder: true,
+                    thumbnail: None,
+                    message_id: None,
+                    encrypted: false,
+                    chat_id,
+                    description: None,
+                    versions: Vec::new(),
+                    sha256: None,
+                    sort_index: None,
+                    health: None,
+                    as_photo: false,
+                    updated_at: None,
+                    custom: BTreeMap::new(),
+                    plain_versions: Vec::new(),
+                    modified_at: None,
+                    copy_of: None,
+                });
+                report.added_entries.push(path.clone());
+            }
+        }
+    }
+
+    report
+}
+
+// Reconcile the folder lists and persist any fixes
+pub async fn repair_folder_metadata() -> Result<FolderRepairReport> {
+    let mut metadata = load_metadata_copy().await?;
+    let report = reconcile_folders(&mut metadata);
+
+    if report.changed() {
+        println!(
+            "Folder repair: {} added to folders, {} entries added, {} chat ids fixed, {} duplicates removed",
+            report.added_to_folders.len(), report.added_entries.len(), report.fixed_chat_ids.len(), report.removed_duplicates
+        );
+        save_metadata_local(&metadata).await?;
+    }
+
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompactReport {
+    pub removed_files: usize,            // Duplicate file entries (same message)
+    pub removed_folder_entries: usize,   // Duplicate virtual folder entries and folder_metadata rows
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub bytes_saved: u64,
+}
+
+// Rebuild the store without duplicates: one entry per stored message, one virtual entry per
+// folder path, one folder_metadata row per (path, chat). Ids are normalized and folders
+// reconciled afterwards. Returns (files removed, folder entries removed).
+fn compact_store(store: &mut MetadataStore) -> (usize, usize) {
+    let files_before = store.files.len();
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    let mut seen_folders: HashSet<String> = HashSet::new();
+    let mut removed_folder_entries = 0;
+    let mut files = Vec::with_capacity(store.files.len());
+
+    for file in store.files.drain(..) {
+        if file.is_folder {
+            if !seen_folders.insert(folder_entry_path(&file)) {
+                removed_folder_entries += 1;
+                continue;
+            }
+        } else {
+            // Two entries for the same message are the same file, whatever their ids say
+            let key = match file.message_id {
+                Some(message_id) => format!("{:?}:{}", file.chat_id, message_id),
+                None => file.id.clone(),
+            };
+            if !seen_ids.insert(key) {
+                continue;
+            }
+        }
+        files.push(file);
+    }
+    let removed_files = files_before - files.len() - removed_folder_entries;
+
+    let mut seen_meta: HashSet<(String, Option<i64>)> = HashSet::new();
+    let meta_before = store.folder_metadata.len();
+    store.folder_metadata.retain(|f| seen_meta.insert((f.path.clone(), f.chat_id)));
+    removed_folder_entries += meta_before - store.folder_metadata.len();
+
+    store.files = files;
+    normalize_file_ids(store);
+    reconcile_folders(store);
+
+    store.files.shrink_to_fit();
+    store.folders.shrink_to_fit();
+    store.folder_metadata.shrink_to_fit();
+
+    (removed_files, removed_folder_entries)
+}
+
+// Rebuild the metadata store from scratch after bulk deletes and write it back atomically
+pub async fn compact_metadata() -> Result<CompactReport> {
+    let path = get_metadata_path().await?;
+    let bytes_before = tokio::fs::metadata(&path).await.map(|m| m.len()).unwrap_or(0);
+
+    let mut metadata = load_metadata_copy().await?;
+    let (removed_files, removed_folder_entries) = compact_store(&mut metadata);
+    save_metadata_local(&metadata).await?;
+
+    let bytes_after = tokio::fs::metadata(&path).await.map(|m| m.len()).unwrap_or(0);
+
+    println!(
+        "Metadata compacted: {} duplicate files, {} duplicate folder entries removed, {} -> {} bytes",
+        removed_files, removed_folder_entries, bytes_before, bytes_after
+    );
+
+    Ok(CompactReport {
+        removed_files,
+        removed_folder_entries,
+        bytes_before,
+        bytes_after,
+        bytes_saved: bytes_before.saturating_sub(bytes_after),
+    })
+}
+
+// One row of the metadata export
+#[derive(Debug, Clone, Serialize)]
+struct ExportRow<'a> {
+    id: &'a str,
+    name: &'a str,
+    size: u64,
+    mime_type: &'a str,
+    folder: &'a str,
+    created_at: i64,
+    chat_id: Option<i64>,
+    encrypted: bool,
+}
+
+impl<'a> From<&'a FileMetadata> for ExportRow<'a> {
+    fn from(f: &'a FileMetadata) -> Self {
+        Self {
+            id: &f.id,
+            name: &f.name,
+            size: f.size,
+            mime_type: &f.mime_type,
+            folder: &f.folder,
+            created_at: f.created_at,
+            chat_id: f.chat_id,
+            encrypted: f.encrypted,
+        }
+    }
+}
+
+// Quote a CSV field when it contains separators, quotes or line breaks (RFC 4180)
+fn csv_escape(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') || field.contains('\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Write the file listing to `destination` as "csv" or "json". Works offline from the cache.
+pub async fn export_metadata(format: &str, destination: &str) -> Result<usize> {
+    if destination.trim().is_empty() {
+        return Err(anyhow::anyhow!("Invalid destination path"));
+    }
+
+    let metadata = load_metadata_copy().await?;
+    let rows: Vec<ExportRow> = metadata.files.iter()
+        .filter(|f| !f.is_folder)
+        .map(ExportRow::from)
+        .collect();
+
+    let content = match format.to_lowercase().as_str() {
+        "json" => serde_json::to_string_pretty(&rows)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize export: {}", e))?,
+        "csv" => {
+            let mut out = String::from("id,name,size,mime_type,folder,created_at,chat_id,encrypted\n");
+            for row in &rows {
+                out.push_str(&format!(
+                    "{},{},{},{},{},{},{},{}\n",
+                    csv_escape(row.id),
+                    csv_escape(row.name),
+                    row.size,
+                    csv_escape(row.mime_type),
+                    csv_escape(row.folder),
+                    row.created_at,
+                    row.chat_id.map(|id| id.to_string()).unwrap_or_default(),
+                    row.encrypted,
+                ));
+            }
+            out
+        }
+        other => return Err(anyhow::anyhow!("Unsupported export format: {} (use \"csv\" or \"json\")", other)),
+    };
+
+    tokio::fs::write(destination, content).await
+        .map_err(|e| anyhow::anyhow!("Failed to write export: {}", e))?;
+
+    Ok(rows.len())
+}
+
+const FOLDER_MANIFEST_VERSION: u32 = 1;
+
+// A folder subtree's metadata, for moving it between installs without the rest of the vault
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderManifest {
+    pub version: u32,
+    pub root: String,
+    pub exported_at: i64,
+    pub folders: Vec<FolderMetadata>,  // Channels of the root and every folder beneath it
+    pub entries: Vec<FileMetadata>,    // Folder entries and files
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestImportReport {
+    pub folders_added: usize,
+    pub files_added: usize,
+    pub already_present: usize,
+    pub missing: Vec<String>,            // Files whose message couldn't be found; not imported
+    pub folder_conflicts: Vec<String>,   // Folders that exist here with another channel; kept as they are
+    pub failed_chats: Vec<BatchFailure>, // Chats this account can't read; their files are not imported
+}
+
+// Everything at or beneath `root`. Local-only details (previews, order, health) stay behind.
+fn folder_manifest(metadata: &MetadataStore, root: &str) -> Result<FolderManifest> {
+    if root == "/" {
+        return Err(anyhow::anyhow!("Export the root with export_metadata instead"));
+    }
+    if !metadata.folders.iter().any(|f| f == root) {
+        return Err(anyhow::anyhow!("Folder not found: {}", root));
+    }
+    let in_tree = |path: &str| rebase_path(path, root, root).is_some();
+
+    let folders = metadata.folder_metadata.iter()
+        .filter(|m| in_tree(&m.path))
+        .cloned()
+        .collect();
+    let entries = metadata.files.iter()
+        .filter(|f| if f.is_folder { in_tree(&folder_entry_path(f)) } else { in_tree(&f.folder) })
+        .map(|f| FileMetadata { thumbnail: None, sort_index: None, health: None, ..f.clone() })
+        .collect();
+
+    Ok(FolderManifest {
+        version: FOLDER_MANIFEST_VERSION,
+        root: root.to_string(),
+        exported_at: chrono::Utc::now().timestamp(),
+        folders,
+        entries,
+    })
+}
+
+// Merge a manifest into the store. Only files whose message is in `present` are added;
+// folders that already exist keep their local channel and appearance.
+fn merge_folder_manifest(
+    metadata: &mut MetadataStore,
+    manifest: &FolderManifest,
+    present: &HashSet<(Option<i64>, i32)>,
+    report: &mut ManifestImportReport,
+) {
+    let mut paths: Vec<String> = manifest.entries.iter()
+        .filter(|f| f.is_folder)
+        .map(folder_entry_path)
+        .chain(std::iter::once(manifest.root.clone()))
+        .collect();
+    paths.sort();
+    paths.dedup();
+
+    for path in &paths {
+        let meta = manifest.folders.iter().find(|m| &m.path == path);
+        if metadata.folders.contains(path) {
+            let local_chat = metadata.folder_metadata.iter().find(|m| &m.path == path).and_then(|m| m.chat_id);
+            if meta.map_or(false, |m| m.chat_id != local_chat) {
+                report.folder_conflicts.push(path.clone());
+            }
+            continue;
+        }
+
+        metadata.folders.push(path.clone());
+        if let Some(meta) = meta {
+            metadata.folder_metadata.push(meta.clone());
+        }
+        if let Some(entry) = manifest.entries.iter().find(|f| f.is_folder && &folder_entry_path(f) == path) {
+            metadata.files.push(entry.clone());
+        }
+        report.folders_added += 1;
+    }
+
+    for file in manifest.entries.iter().filter(|f| !f.is_folder) {
+        let Some(message_id) = file.message_id else {
+            report.missing.push(file.name.clone());
+            continue;
+        };
+        if is_referenced(metadata, file.chat_id, message_id) {
+            report.already_present += 1;
+        } else if present.contains(&(file.chat_id, message_id)) {
+            metadata.files.push(FileMetadata { id: message_file_id(file.chat_id, message_id), ..file.clone() });
+            report.files_added += 1;
+        } else {
+            report.missing.push(file.name.clone());
+        }
+    }
+}
+
+// Write the manifest of `folder_path` and everything beneath it to `destination` (JSON)
+pub async fn export_folder_manifest(folder_path: &str, destination: &str) -> Result<usize> {
+    if destination.trim().is_empty() {
+        return Err(anyhow::anyhow!("Invalid destination path"));
+    }
+
+    let metadata = load_metadata_copy().await?;
+    let manifest = folder_manifest(&metadata, folder_path)?;
+    let content = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize manifest: {}", e))?;
+    tokio::fs::write(destination, content).await
+        .map_err(|e| anyhow::anyhow!("Failed to write manifest: {}", e))?;
+
+    Ok(manifest.entries.iter().filter(|f| !f.is_folder).count())
+}
+
+// Merge a manifest written by export_folder_manifest into the local store. Each file's
+// message is looked up first; files this account can't find aren't imported.
+pub async fn import_folder_manifest(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    path: &str,
+) -> Result<ManifestImportReport> {
+    let content = tokio::fs::read(path).await
+        .map_err(|e| anyhow::anyhow!("Failed to read manifest: {}", e))?;
+    let manifest: FolderManifest = serde_json::from_slice(&content)
+        .map_err(|e| anyhow::anyhow!("Not a folder manifest: {}", e))?;
+    if manifest.version > FOLDER_MANIFEST_VERSION {
+        return Err(anyhow::anyhow!("Manifest version {} is newer than this app supports", manifest.version));
+    }
+
+    let (parent, _) = split_folder_path(&manifest.root);
+    let metadata = load_metadata_copy().await?;
+    if !metadata.folders.contains(&parent) {
+        return Err(anyhow::anyhow!("Parent folder {} doesn't exist; create it first", parent));
+    }
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+
+    let mut by_chat: std::collections::BTreeMap<Option<i64>, Vec<&FileMetadata>> = std::collections::BTreeMap::new();
+    for file in manifest.entries.iter().filter(|f| !f.is_folder && f.message_id.is_some()) {
+        by_chat.entry(file.chat_id).or_default().push(file);
+    }
+
+    let mut report = ManifestImportReport::default();
+    let mut present = HashSet::new();
+    for (chat_id, files) in &by_chat {
+        let ids: Vec<i32> = files.iter().filter_map(|f| f.message_id).collect();
+        match fetch_file_messages(&client, *chat_id, &ids, &caption_prefix).await {
+            Ok(found) => present.extend(files.iter()
+                .filter_map(|f| f.message_id.filter(|id| found.get(id).map_or(false, |m| message_matches_entry(m, f))))
+                .map(|id| (*chat_id, id))),
+            Err(e) => report.failed_chats.push(BatchFailure {
+                item: chat_id.map(|id| id.to_string()).unwrap_or_else(|| "Saved Messages".to_string()),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    // Re-read so changes made while we were checking aren't lost
+    let mut metadata = load_metadata_copy().await?;
+    merge_folder_manifest(&mut metadata, &manifest, &present, &mut report);
+    save_metadata_local(&metadata).await?;
+
+    println!(
+        "Imported manifest of {}: {} folders, {} files added, {} already present, {} missing",
+        manifest.root, report.folders_added, report.files_added, report.already_present, report.missing.len()
+    );
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataMergeReport {
+    pub files_added: usize,
+    pub conflicts: usize,               // Same message, different entry on each side
+    pub replaced: usize,                // Conflicts the other side won by being newer
+    pub folders_added: usize,
+    pub folders_linked: usize,          // Folders here that took their channel from the other side
+    pub folder_conflicts: Vec<String>,  // Same folder, another channel; kept as it is here
+    pub key_check_added: bool,          // The other side had unlocked the vault, this one hadn't
+}
+
+// An entry without what only means something on the device it came from
+fn without_local_details(file: &FileMetadata) -> FileMetadata {
+    FileMetadata { thumbnail: None, sort_index: None, health: None, ..file.clone() }
+}
+
+// Latest change to an entry from either an upload or an edit, for picking a merge winner
+fn edited_at(file: &FileMetadata) -> i64 {
+    file.modified_at.unwrap_or(0).max(changed_at(file))
+}
+
+// Fold `other` (metadata.json from another device) into `metadata`. Files are matched by their
+// message; when both sides changed an entry, the newer one wins and ties keep ours. A message
+// that one side has as an older version stays folded into that version chain, and messages
+// either side found deleted aren't brought back.
+fn merge_metadata_stores(metadata: &mut MetadataStore, other: &MetadataStore) -> MetadataMergeReport {
+    let mut report = MetadataMergeReport::default();
+
+    if metadata.key_check.is_none() && other.key_check.is_some() {
+        metadata.key_check = other.key_check.clone();
+        report.key_check_added = true;
+    }
+
+    let mut paths: Vec<&String> = other.folders.iter().collect();
+    paths.sort();
+    for path in paths {
+        let meta = other.folder_metadata.iter().find(|m| &m.path == path);
+        if metadata.folders.contains(path) {
+            let local = metadata.folder_metadata.iter().position(|m| &m.path == path);
+            match (local, meta.filter(|m| m.chat_id.is_some())) {
+                // A legacy folder here that got its channel on the other device
+                (Some(i), Some(meta)) if metadata.folder_metadata[i].chat_id.is_none() => {
+                    metadata.folder_metadata[i].chat_id = meta.chat_id;
+                    report.folders_linked += 1;
+                }
+                (None, Some(meta)) => {
+                    metadata.folder_metadata.push(meta.clone());
+                    report.folders_linked += 1;
+                }
+                (Some(i), Some(meta)) if metadata.folder_metadata[i].chat_id != meta.chat_id => {
+                    report.folder_conflicts.push(path.clone());
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        metadata.folders.push(path.clone());
+        if let Some(meta) = meta {
+            metadata.folder_metadata.push(meta.clone());
+        }
+        if let Some(entry) = other.files.iter().find(|f| f.is_folder && &folder_entry_path(f) == path) {
+            metadata.files.push(without_local_details(entry));
+        }
+        report.folders_added += 1;
+    }
+
+    let deleted: HashSet<(Option<i64>, Option<i32>)> = metadata.tombstones.iter()
+        .chain(other.tombstones.iter())
+        .map(|t| (t.chat_id, t.message_id))
+        .collect();
+
+    for file in other.files.iter().filter(|f| !f.is_folder) {
+        if file.message_id.is_some() && deleted.contains(&(file.chat_id, file.message_id)) {
+            continue;
+        }
+
+        let same_message = |f: &FileMetadata| match file.message_id {
+            Some(_) => f.chat_id == file.chat_id && f.message_id == file.message_id,
+            None => f.message_id.is_none() && f.id == file.id,
+        };
+        let pos = metadata.files.iter().position(|f| !f.is_folder && same_message(f))
+            // The other side uploaded a newer version of one of ours
+            .or_else(|| metadata.files.iter().position(|f| {
+                !f.is_folder && f.chat_id == file.chat_id && f.message_id.map_or(false, |id| file.versions.contains(&id))
+            }));
+
+        match pos {
+            Some(pos) => {
+                let local = &metadata.files[pos];
+                // When an edit happened doesn't make the entries differ by itself
+                let comparable = |f: &FileMetadata| FileMetadata { modified_at: None, ..without_local_details(f) };
+                let differs = serde_json::to_value(comparable(local)).ok()
+                    != serde_json::to_value(comparable(file)).ok();
+                if !differs {
+                    continue;
+                }
+                report.conflicts += 1;
+                let superseded = local.message_id != file.message_id;
+                if superseded || edited_at(file) > edited_at(local) {
+                    let local = metadata.files[pos].clone();
+                    metadata.files[pos] = FileMetadata {
+                        thumbnail: local.thumbnail.filter(|_| !superseded),
+                        sort_index: local.sort_index,
+                        health: None,
+                        ..file.clone()
+                    };
+                    report.replaced += 1;
+                }
+            }
+            // Already an older version of one of ours
+            None if file.message_id.map_or(false, |id| is_referenced(metadata, file.chat_id, id)) => {}
+            None => {
+                metadata.files.push(without_local_details(file));
+                report.files_added += 1;
+            }
+        }
+    }
+
+    report
+}
+
+// Merge the metadata.json of another device into ours, e.g. after both uploaded while apart
+pub async fn merge_metadata(other_json: &str) -> Result<MetadataMergeReport> {
+    let mut raw: serde_json::Value = serde_json::from_str(other_json)
+        .map_err(|e| anyhow::anyhow!("Not valid metadata: {}", e))?;
+    let version = stored_schema_version(&raw);
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(anyhow::anyhow!("Metadata schema v{} is newer than this app supports", version));
+    }
+    migrate_schema(&mut raw)?;
+    let other: MetadataStore = serde_json::from_value(raw)
+        .map_err(|e| anyhow::anyhow!("Not valid metadata: {}", e))?;
+
+    let mut metadata = load_metadata_copy().await?;
+    let report = merge_metadata_stores(&mut metadata, &other);
+    if report.files_added + report.replaced + report.folders_added + report.folders_linked > 0 || report.key_check_added {
+        save_metadata_local(&metadata).await?;
+    }
+
+    println!(
+        "Merged metadata: {} files added, {} conflicts ({} replaced), {} folders added, {} linked, {} folder conflicts",
+        report.files_added, report.conflicts, report.replaced, report.folders_added, report.folders_linked,
+        report.folder_conflicts.len()
+    );
+    Ok(report)
+}
+
+const EXPORT_PIPE_SIZE: usize = 256 * 1024; // Buffer between download and decryption when exporting
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderExportReport {
+    pub files: usize,
+    pub bytes: u64,  // Uncompressed bytes written into the archive
+}
+
+// Directory of `file` inside an export of `root` ("" for files directly in it),
+// or None if the file lies outside the exported folder
+fn export_entry_dir(root: &str, file: &FileMetadata) -> Option<String> {
+    relative_folder_dir(root, &file.folder)
+}
+
+// Same as export_entry_dir, for a folder path
+fn relative_folder_dir(root: &str, folder: &str) -> Option<String> {
+    if folder == root {
+        return Some(String::new());
+    }
+    let relative = if root == "/" {
+        folder.strip_prefix('/')
+    } else {
+        folder.strip_prefix(root).and_then(|rest| rest.strip_prefix('/'))
+    }?;
+    Some(format!("{}/", relative))
+}
+
+// Archive name for a file, numbered like "a (2).txt" if the folder already has one by that name
+fn unique_entry_name(used: &mut HashSet<String>, dir: &str, file_name: &str) -> String {
+    let file_name = file_name.replace(['/', '\\'], "_");
+    let candidate = format!("{}{}", dir, file_name);
+    if used.insert(candidate.clone()) {
+        return candidate;
+    }
+
+    let (stem, ext) = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+        _ => (file_name.clone(), String::new()),
+    };
+    (2..)
+        .map(|n| format!("{}{} ({}){}", dir, stem, n, ext))
+        .find(|name| used.insert(name.clone()))
+        .unwrap_or(candidate)
+}
+
+// Already-compressed media gains nothing from deflate
+fn zip_compression(mime_type: &str) -> async_zip::Compression {
+    let stored = mime_type.starts_with("image/")
+        || mime_type.starts_with("video/")
+        || mime_type.starts_with("audio/")
+        || matches!(mime_type, "application/zip" | "application/gzip" | "application/x-7z-compressed" | "application/x-rar-compressed");
+    if stored {
+        async_zip::Compression::Stored
+    } else {
+        async_zip::Compression::Deflate
+    }
+}
+
+// Download every file under `folder_path` (recursively) straight into a zip at `destination`.
+// Each file streams from Telegram through decryption into its archive entry, so nothing is
+// staged on disk and memory stays bounded. A failure removes the partial archive.
+pub async fn export_folder(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+    destination: &str,
+    on_progress: impl Fn(usize, usize, &str),
+) -> Result<FolderExportReport> {
+    let metadata = load_metadata_copy().await?;
+    if folder_path != "/" && !metadata.folders.iter().any(|f| f == folder_path) {
+        return Err(anyhow::anyhow!("Folder not found: {}", folder_path));
+    }
+
+    let mut used = HashSet::new();
+    let files: Vec<(String, FileMetadata)> = metadata.files.iter()
+        .filter(|f| !f.is_folder)
+        .filter_map(|f| {
+            let dir = export_entry_dir(folder_path, f)?;
+            Some((unique_entry_name(&mut used, &dir, &f.name), f.clone()))
+        })
+        .collect();
+
+    // Check the vault key before writing anything
+    let encryptor = if files.iter().any(|(_, f)| f.encrypted) {
+        Some(crate::encryption::session_encryptor().ok_or(TvaultError::VaultLocked)?)
+    } else {
+        None
+    };
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let out_file = tokio::fs::File::create(destination).await
+        .map_err(|e| anyhow::anyhow!("Failed to create archive: {}", e))?;
+    let result = write_export_zip(&client, &files, encryptor.as_ref(), out_file, &on_progress).await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(destination).await;
+    }
+
+    if let Err(e) = crate::bandwidth::flush().await {
+        eprintln!("Warning: Failed to save bandwidth stats: {}", e);
+    }
+
+    result
+}
+
+async fn write_export_zip(
+    client: &Client,
+    files: &[(String, FileMetadata)],
+    encryptor: Option<&crate::encryption::Encryptor>,
+    out_file: tokio::fs::File,
+    on_progress: &impl Fn(usize, usize, &str),
+) -> Result<FolderExportReport> {
+    use tokio_util::compat::FuturesAsyncWriteCompatExt;
+
+    let mut zip = async_zip::tokio::write::ZipFileWriter::with_tokio(out_file);
+    let mut report = FolderExportReport::default();
+
+    for (index, (entry_name, file)) in files.iter().enumerate() {
+        on_progress(index, files.len(), &file.name);
+
+        let builder = async_zip::ZipEntryBuilder::new(entry_name.clone().into(), zip_compression(&file.mime_type));
+        let mut entry = zip.write_entry_stream(builder).await
+            .map_err(|e| anyhow::anyhow!("Failed to start archive entry {}: {}", entry_name, e))?
+            .compat_write();
+
+        let written = match encryptor.filter(|_| file.encrypted) {
+            Some(encryptor) => {
+                // Ciphertext goes through a bounded in-memory pipe into the decryptor
+                let (mut sealed_tx, sealed_rx) = tokio::io::duplex(EXPORT_PIPE_SIZE);
+                let download = async {
+                    let result = download_to_writer(client, file, &mut sealed_tx, ProgressConfig::silent(), |_, _, _| {}).await;
+                    let _ = sealed_tx.shutdown().await;
+                    result
+                };
+                let decrypt = crate::encryption::decrypt_stream(encryptor, sealed_rx, &mut entry);
+                tokio::try_join!(download, decrypt)?.1
+            }
+            None => download_to_writer(client, file, &mut entry, ProgressConfig::silent(), |_, _, _| {}).await?,
+        };
+
+        entry.into_inner().close().await
+            .map_err(|e| anyhow::anyhow!("Failed to finish archive entry {}: {}", entry_name, e))?;
+        report.files += 1;
+        report.bytes += written;
+    }
+
+    zip.close().await
+        .map_err(|e| anyhow::anyhow!("Failed to finish archive: {}", e))?;
+    on_progress(files.len(), files.len(), "");
+
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderDownloadReport {
+    pub total: usize,
+    pub downloaded: Vec<String>,  // Local paths written
+    pub skipped: Vec<String>,     // Already on disk with a matching checksum
+    pub failed: Vec<BatchFailure>,
+}
+
+enum FolderDownloadOutcome {
+    Downloaded(String),
+    Skipped(String),
+}
+
+// Local path of an export entry ("Trips/a.jpg") under `dest_dir`. Every component is kept
+// inside `dest_dir`, and on Windows made NTFS-safe, not just the file name.
+fn folder_download_path(dest_dir: &Path, entry_name: &str) -> std::path::PathBuf {
+    entry_name.split('/')
+        .filter(|part| !part.is_empty())
+        .map(|part| if part == "." || part == ".." { "_" } else { part })
+        .fold(dest_dir.to_path_buf(), |path, part| {
+            if cfg!(windows) {
+                path.join(windows_safe_file_name(part))
+            } else {
+                path.join(part)
+            }
+        })
+}
+
+// Whether `path` already holds this file's content. Entries without a recorded hash
+// can't be checked and are downloaded again.
+async fn already_downloaded(path: &str, file: &FileMetadata) -> bool {
+    let Some(ref expected) = file.sha256 else {
+        return false;
+    };
+    if !Path::new(path).is_file() {
+        return false;
+    }
+    match file_sha256(Path::new(path)).await {
+        Ok(actual) => actual.eq_ignore_ascii_case(expected),
+        Err(_) => false,
+    }
+}
+
+type FolderDownloadResult = (String, FileMetadata, Result<FolderDownloadOutcome>);
+
+// Download every file under `folder_path` (recursively) into `dest_dir`, recreating the
+// folder structure. Files run in parallel within the transfer limits; encrypted ones are
+// decrypted. One failed file doesn't stop the rest.
+// `on_progress` gets (files done, file count, bytes done, total bytes).
+pub async fn download_folder(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+    dest_dir: &str,
+    on_progress: impl Fn(usize, usize, u64, u64) + Send + Sync + 'static,
+) -> Result<FolderDownloadReport> {
+    let results = download_tree(client_ref, folder_path, dest_dir, |_| true, on_progress).await?;
+    let report = folder_download_report(results);
+    println!(
+        "Folder download of {}: {} downloaded, {} skipped, {} failed",
+        folder_path, report.downloaded.len(), report.skipped.len(), report.failed.len()
+    );
+    Ok(report)
+}
+
+fn folder_download_report(results: Vec<FolderDownloadResult>) -> FolderDownloadReport {
+    let mut report = FolderDownloadReport {
+        total: results.len(),
+        ..Default::default()
+    };
+    for (path, _, result) in results {
+        match result {
+            Ok(FolderDownloadOutcome::Downloaded(saved_as)) => report.downloaded.push(saved_as),
+            Ok(FolderDownloadOutcome::Skipped(existing)) => report.skipped.push(existing),
+            Err(e) => report.failed.push(BatchFailure { item: path, error: e.to_string() }),
+        }
+    }
+    report
+}
+
+// Download the files under `folder_path` that `include` accepts, laid out as in download_folder
+async fn download_tree(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+    dest_dir: &str,
+    include: impl Fn(&FileMetadata) -> bool,
+    on_progress: impl Fn(usize, usize, u64, u64) + Send + Sync + 'static,
+) -> Result<Vec<FolderDownloadResult>> {
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    let metadata = load_metadata_copy().await?;
+    if folder_path != "/" && !metadata.folders.iter().any(|f| f == folder_path) {
+        return Err(anyhow::anyhow!("Folder not found: {}", folder_path));
+    }
+    if dest_dir.trim().is_empty() {
+        return Err(anyhow::anyhow!("Invalid destination path"));
+    }
+    let dest_dir = Path::new(dest_dir);
+
+    let mut used = HashSet::new();
+    let files: Vec<(String, FileMetadata)> = metadata.files.iter()
+        .filter(|f| !f.is_folder && include(f))
+        .filter_map(|f| {
+            let dir = export_entry_dir(folder_path, f)?;
+            let path = folder_download_path(dest_dir, &unique_entry_name(&mut used, &dir, &f.name));
+            Some((path.to_string_lossy().to_string(), f.clone()))
+        })
+        .collect();
+
+    // Check the vault key before creating anything
+    if files.iter().any(|(_, f)| f.encrypted) && crate::encryption::session_encryptor().is_none() {
+        return Err(TvaultError::VaultLocked.into());
+    }
+
+    // Empty folders are recreated too
+    tokio::fs::create_dir_all(dest_dir).await
+        .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", dest_dir.display(), e))?;
+    for folder in &metadata.folders {
+        if let Some(dir) = relative_folder_dir(folder_path, folder) {
+            let path = folder_download_path(dest_dir, &dir);
+            tokio::fs::create_dir_all(&path).await
+                .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))?;
+        }
+    }
+
+    let total = files.len();
+    let total_bytes: u64 = files.iter().map(|(_, f)| f.size).sum();
+    let file_bytes: Arc<Vec<AtomicU64>> = Arc::new(files.iter().map(|_| AtomicU64::new(0)).collect());
+    let files_done = Arc::new(AtomicUsize::new(0));
+    let on_progress = Arc::new(on_progress);
+    let report_progress = {
+        let file_bytes = file_bytes.clone();
+        let files_done = files_done.clone();
+        let on_progress = on_progress.clone();
+        move || {
+            let bytes_done = file_bytes.iter().map(|b| b.load(Ordering::Relaxed)).sum();
+            on_progress(files_done.load(Ordering::Relaxed), total, bytes_done, total_bytes);
+        }
+    };
+    report_progress();
+
+    // The shared transfer limiter decides how many of these actually run at once
+    let results: Vec<FolderDownloadResult> = futures::stream::iter(files.into_iter().enumerate())
+        .map(|(index, (path, file))| {
+            let client_ref = client_ref.clone();
+            let file_bytes = file_bytes.clone();
+            let files_done = files_done.clone();
+            let report_progress = report_progress.clone();
+            async move {
+                let result = if already_downloaded(&safe_download_path(&path), &file).await {
+                    Ok(FolderDownloadOutcome::Skipped(path.clone()))
+                } else {
+                    let on_file_progress = {
+                        let file_bytes = file_bytes.clone();
+                        let report_progress = report_progress.clone();
+                        move |_progress: u32, current: u64, _total: u64| {
+                            file_bytes[index].store(current, Ordering::Relaxed);
+                            report_progress();
+                        }
+                    };
+                    download_file(client_ref, &file.id, &path, ProgressConfig::default(), on_file_progress).await
+                        .map(FolderDownloadOutcome::Downloaded)
+                };
+
+                file_bytes[index].store(file.size, Ordering::Relaxed);
+                files_done.fetch_add(1, Ordering::Relaxed);
+                report_progress();
+                (path, file, result)
+            }
+        })
+        .buffer_unordered(crate::transfers::MAX_CONCURRENT_TRANSFERS)
+        .collect()
+        .await;
+
+    if let Err(e) = crate::bandwidth::flush().await {
+        eprintln!("Warning: Failed to save bandwidth stats: {}", e);
+    }
+
+    Ok(results)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangedDownloadReport {
+    #[serde(flatten)]
+    pub files: FolderDownloadReport,
+    pub high_water: i64,  // Pass as `since` next time
+}
+
+// When an entry last changed, as far as an incremental download is concerned
+fn changed_at(file: &FileMetadata) -> i64 {
+    file.updated_at.unwrap_or(file.created_at)
+}
+
+// The `since` for the next run: everything up to it made it to disk. A failed file holds the
+// mark just below itself so it's retried, and the current second stays open because more
+// uploads can still land in it.
+fn download_high_water(since: i64, results: &[(i64, bool)], now: i64) -> i64 {
+    let newest = results.iter().map(|&(at, _)| at).max().unwrap_or(since);
+    let first_failed = results.iter().filter(|&&(_, ok)| !ok).map(|&(at, _)| at - 1).min();
+    newest.min(first_failed.unwrap_or(i64::MAX)).min(now - 1).max(since)
+}
+
+// Incremental backup: download every file uploaded or rewritten after `since` (unix seconds)
+// into `dest_dir`, mirroring the vault's folders, as download_folder does for "/".
+pub async fn download_changed_since(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    since: i64,
+    dest_dir: &str,
+    on_progress: impl Fn(usize, usize, u64, u64) + Send + Sync + 'static,
+) -> Result<ChangedDownloadReport> {
+    let started_at = chrono::Utc::now().timestamp();
+    let results = download_tree(client_ref, "/", dest_dir, |f| changed_at(f) > since, on_progress).await?;
+
+    let outcomes: Vec<(i64, bool)> = results.iter().map(|(_, f, r)| (changed_at(f), r.is_ok())).collect();
+    let report = ChangedDownloadReport {
+        files: folder_download_report(results),
+        high_water: download_high_water(since, &outcomes, started_at),
+    };
+    println!(
+        "Changed since {}: {} downloaded, {} skipped, {} failed, next since {}",
+        since, report.files.downloaded.len(), report.files.skipped.len(), report.files.failed.len(), report.high_water
+    );
+    Ok(report)
+}
+
+// Get storage stats
+pub async fn get_storage_stats() -> Result<StorageStats> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    let metadata = cache.as_ref().unwrap();
+    
+    let total_size: u64 = metadata.files.iter().filter(|f| !f.is_folder).map(|f| f.size).sum();
+    let total_files = metadata.files.iter().filter(|f| !f.is_folder).count() as u64;
+    let folder_count = metadata.folders.len() as u64;
+    
+    Ok(StorageStats {
+        total_files,
+        total_size,
+        folder_count,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MimeTypeUsage {
+    pub mime_type: String,  // Or just the top-level type ("image") when grouped
+    pub count: usize,
+    pub total_size: u64,
+}
+
+// Count and bytes per mime type, largest first
+fn mime_type_usage(files: &[FileMetadata], top_level: bool) -> Vec<MimeTypeUsage> {
+    let mut usage: std::collections::HashMap<&str, (usize, u64)> = std::collections::HashMap::new();
+    for file in files.iter().filter(|f| !f.is_folder) {
+        let mime_type = if top_level {
+            file.mime_type.split('/').next().unwrap_or(&file.mime_type)
+        } else {
+            file.mime_type.as_str()
+        };
+        let entry = usage.entry(mime_type).or_default();
+        entry.0 += 1;
+        entry.1 += file.size;
+    }
+
+    let mut usage: Vec<MimeTypeUsage> = usage.into_iter()
+        .map(|(mime_type, (count, total_size))| MimeTypeUsage { mime_type: mime_type.to_string(), count, total_size })
+        .collect();
+    usage.sort_by(|a, b| b.total_size.cmp(&a.total_size).then_with(|| a.mime_type.cmp(&b.mime_type)));
+    usage
+}
+
+// What's taking up space, by mime type (or top-level type such as "image" with `top_level`)
+pub async fn mime_type_breakdown(top_level: bool) -> Result<Vec<MimeTypeUsage>> {
+    ensure_metadata_loaded().await?;
+    let cache = METADATA_CACHE.read().await;
+    let metadata = cache.as_ref().unwrap();
+    Ok(mime_type_usage(&metadata.files, top_level))
+}
+
+// The file name Telegram keeps with a document (DocumentAttributeFilename), if present
+fn document_file_name(media: &Media) -> Option<String> {
+    match media {
+        Media::Document(doc) => doc.name()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+// Metadata entry for a T-Vault upload found in `chat_id` (None = Saved Messages).
+// The folder comes from the caption trailer and is empty for captions without one.
+fn file_from_message(message: &Message, chat_id: Option<i64>, caption_prefix: &str) -> Option<FileMetadata> {
+    let media = message.media()?;
+    // The caption decides whether this is a T-Vault upload. For the name, the document's own
+    // attribute wins, then the trailer, then whatever is left of the caption's first line.
+    let (caption_name, description) = parse_caption(message.text(), caption_prefix)?;
+    // The trailer is authoritative; the body description may have been truncated
+    let trailer = parse_caption_trailer(message.text()).unwrap_or_default();
+    let name = document_file_name(&media)
+        .or_else(|| trailer.name.clone().filter(|n| !n.trim().is_empty()))
+        .unwrap_or_else(|| clean_file_name(&caption_name, caption_prefix));
+    if name.is_empty() {
+        return None;
+    }
+
+    // Extract basic info from media
+    let as_photo = matches!(media, Media::Photo(_));
+    let (size, mime_type) = match media {
+        Media::Document(doc) => {
+            (doc.size().unwrap_or(0) as u64, doc.mime_type().unwrap_or("application/octet-stream").to_string())
+        }
+        Media::Photo(_) => {
+            (0, "image/jpeg".to_string()) // Photos don't easily give size here
+        }
+        _ => (0, "application/octet-stream".to_string()),
+    };
+
+    Some(FileMetadata {
+        id: message_file_id(chat_id, message.id()),
+        name,
+        size,
+        mime_type,
+        created_at: trailer.created_at.unwrap_or_else(|| message.date().timestamp()),
+        folder: trailer.folder,
+        is_folder: false,
+        thumbnail: None,
+        message_id: Some(message.id()),
+        encrypted: trailer.encrypted,
+        chat_id,
+        description: trailer.description.or(description),
+        versions: Vec::new(),
+        // The trailer hashes the stored bytes, which is the content only for plain uploads
+        sha256: trailer.sha256.filter(|_| !trailer.encrypted),
+        sort_index: None,
+        health: None,
+        as_photo,
+        updated_at: None,
+        custom: trailer.custom,
+        plain_versions: Vec::new(),
+        modified_at: None,
+        copy_of: None,
+    })
+}
+
+// Sync metadata by scanning Telegram Saved Messages
+const SYNC_PROGRESS_EVERY: usize = 100; // Messages scanned between two sync progress reports
+
+// Rebuild metadata from T-Vault captions in Saved Messages.
+// Reports (messages scanned, files found) periodically; when cancelled, whatever was
+// found so far is still merged and counted.
+pub async fn sync_from_telegram(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    cancel: &crate::operations::CancelToken,
+    on_progress: impl Fn(usize, usize),
+) -> Result<usize> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let me = client.get_me().await?;
+    let chat = Peer::User(me);
+    
+    // Get PeerRef from Peer
+    let peer_ref = chat.to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+    
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+    let mut messages = client.iter_messages(peer_ref);
+    let mut new_files = Vec::new();
+
+    let mut scanned = 0;
+
+    while let Some(message) = messages.next().await? {
+        scanned += 1;
+        if scanned % SYNC_PROGRESS_EVERY == 0 {
+            on_progress(scanned, new_files.len());
+        }
+        if cancel.is_cancelled() {
+            println!("Sync cancelled after {} messages", scanned);
+            break;
+        }
+
+        if let Some(file) = file_from_message(&message, None, &caption_prefix) {
+            new_files.push(file);
+        }
+    }
+
+    on_progress(scanned, new_files.len());
+
+    if new_files.is_empty() {
+        return Ok(0);
+    }
+
+    // Load existing to avoid duplicates
+    let mut store = load_metadata_copy().await.unwrap_or_else(|_| MetadataStore::new());
+    let count = new_files.len();
+
+    for mut file in new_files {
+        // Older captions carry no folder, and a folder may have been deleted since
+        if file.folder != "/" && !store.folders.contains(&file.folder) {
+            file.folder = "/".to_string();
+        }
+        let known = store.files.iter().any(|f| {
+            f.message_id == file.message_id
+                || (f.chat_id.is_none() && file.message_id.map_or(false, |id| f.versions.contains(&id)))
+        });
+        if !known {
+            store.files.push(file);
+        }
+    }
+
+    save_metadata_local(&store).await?;
+    Ok(count)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RebuildReport {
+    pub files: usize,
+    pub folders: usize,                      // Not counting root
+    pub channels_scanned: usize,
+    pub failed_channels: Vec<BatchFailure>,  // Channels that couldn't be read; their previous entries are kept
+}
+
+const ROOT_CHANNEL_TITLE: &str = "T-Vault: /";  // Never parsed as a folder, see below
+
+// Folder path encoded in a folder channel title, e.g. "T-Vault: /Photos" => "/Photos"
+fn folder_path_from_title(title: &str) -> Option<String> {
+    let path = title.strip_prefix(crate::telegram::FOLDER_CHANNEL_PREFIX)?.trim();
+    if !path.starts_with('/') || path == "/" {
+        return None;
+    }
+    Some(path.trim_end_matches('/').to_string())
+}
+
+// Whether a channel title is the root channel's, which folder_path_from_title skips
+fn is_root_channel_title(title: &str) -> bool {
+    title.strip_prefix(crate::telegram::FOLDER_CHANNEL_PREFIX)
+        .map_or(false, |path| path.trim() == "/")
+}
+
+// Keep what the previous store knew about chats that couldn't be scanned, rather than
+// dropping their files from a rebuild over a passing network error
+fn carry_over_failed_chats(store: &mut MetadataStore, previous: &MetadataStore, failed_chats: &HashSet<i64>) {
+    let failed = |chat_id: Option<i64>| chat_id.map_or(false, |id| failed_chats.contains(&id));
+    for meta in previous.folder_metadata.iter().filter(|m| failed(m.chat_id)) {
+        if !store.folder_metadata.iter().any(|m| m.path == meta.path) {
+            store.folder_metadata.push(meta.clone());
+        }
+    }
+    for file in previous.files.iter().filter(|f| !f.is_folder && failed(f.chat_id)) {
+        if !store.files.iter().any(|f| f.id == file.id) {
+            store.files.push(file.clone());
+        }
+    }
+}
+
+// Disaster recovery: rebuild the whole store from Telegram alone. Saved Messages and every
+// "T-Vault:" channel are scanned and the local metadata is replaced. Only folder colors and
+// icons, which never reach Telegram, are carried over, plus the previous entries of any channel
+// that couldn't be read. Running it twice gives the same store.
+pub async fn rebuild_metadata_from_telegram(client_ref: Arc<Mutex<Option<Client>>>) -> Result<RebuildReport> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let config = crate::config::AppConfig::load().await;
+    let caption_prefix = config.caption_prefix;
+    let mut store = MetadataStore::new();
+    let mut report = RebuildReport::default();
+    let mut failed_chats: HashSet<i64> = HashSet::new();
+
+    // 1. Saved Messages: folder comes from the trailer (older captions land in root)
+    let me = client.get_me().await?;
+    let peer_ref = Peer::User(me).to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+    let mut messages = client.iter_messages(peer_ref);
+    while let Some(message) = messages.next().await? {
+        if let Some(file) = file_from_message(&message, None, &caption_prefix) {
+            store.files.push(file);
+        } else if let Some(check) = key_check_from_text(message.text()) {
+            // Newest first, so the oldest note ends up winning
+            store.key_check = Some(check);
+        }
+    }
+
+    // 1b. Root channel: the configured one, and any titled like one (the config may be gone too)
+    let folder_channels = crate::telegram::list_folder_channels(&client).await?;
+    let mut root_chats: Vec<i64> = config.root_chat_id.into_iter().collect();
+    root_chats.extend(folder_channels.iter().filter(|(_, title)| is_root_channel_title(title)).map(|(id, _)| *id));
+    root_chats.sort();
+    root_chats.dedup();
+    for root_chat_id in root_chats {
+        report.channels_scanned += 1;
+        let scanned: Result<()> = async {
+            let chat = crate::telegram::get_chat_peer(&client, root_chat_id).await?;
+            let peer_ref = chat.to_ref()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+            let mut messages = client.iter_messages(peer_ref);
+            while let Some(message) = messages.next().await? {
+                if let Some(mut file) = file_from_message(&message, Some(root_chat_id), &caption_prefix) {
+                    file.folder = "/".to_string();
+                    store.files.push(file);
+                }
+            }
+            Ok(())
+        }.await;
+        if let Err(e) = scanned {
+            eprintln!("Warning: Failed to scan root channel {}: {}", root_chat_id, e);
+            report.failed_channels.push(BatchFailure { item: ROOT_CHANNEL_TITLE.to_string(), error: e.to_string() });
+            failed_chats.insert(root_chat_id);
+        }
+    }
+
+    // 2. Folder channels: the channel decides the folder, whatever the trailer says
+    for (chat_id, title) in folder_channels {
+        let Some(path) = folder_path_from_title(&title) else {
+            continue;
+        };
+        report.channels_scanned += 1;
+
+        let scanned: Result<ChannelKind> = async {
+            let chat = crate::telegram::get_chat_peer(&client, chat_id).await?;
+            let kind = if matches!(chat, Peer::Group(_)) { ChannelKind::Megagroup } else { ChannelKind::Broadcast };
+            let peer_ref = chat.to_ref()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+
+            let mut messages = client.iter_messages(peer_ref);
+            while let Some(message) = messages.next().await? {
+                if let Some(mut file) = file_from_message(&message, Some(chat_id), &caption_prefix) {
+                    file.folder = path.clone();
+                    store.files.push(file);
+                }
+            }
+            Ok(kind)
+        }.await;
+
+        match scanned {
+            Ok(kind) => store.folder_metadata.push(FolderMetadata {
+                path: path.clone(),
+                chat_id: Some(chat_id),
+                chat_title: Some(title),
+                created_at: chrono::Utc::now().timestamp(),
+                kind,
+                color: None,
+                icon: None,
+            }),
+            Err(e) => {
+                eprintln!("Warning: Failed to scan channel {} ({}): {}", title, chat_id, e);
+                report.failed_channels.push(BatchFailure { item: title, error: e.to_string() });
+                failed_chats.insert(chat_id);
+            }
+        }
+    }
+
+    let previous = load_metadata_copy().await.ok();
+    if let Some(ref previous) = previous {
+        carry_over_failed_chats(&mut store, previous, &failed_chats);
+        if previous.key_check.is_some() {
+            store.key_check = previous.key_check.clone();
+        }
+    }
+
+    // 3. Folders: every channel path plus its ancestors; Saved Messages files need a known folder
+    let mut paths: HashSet<String> = HashSet::new();
+    for meta in &store.folder_metadata {
+        let mut path = meta.path.clone();
+        while path != "/" && paths.insert(path.clone()) {
+            path = split_folder_path(&path).0;
+        }
+    }
+    for file in store.files.iter_mut() {
+        if file.folder != "/" && !paths.contains(&file.folder) {
+            file.folder = "/".to_string();
+        }
+    }
+    let mut paths: Vec<String> = paths.into_iter().collect();
+    paths.sort();
+    store.folders.extend(paths);
+
+    // Keep local-only settings: folder appearance, manual file order, backfilled checksums
+    // and the tombstone history
+    if let Some(previous) = previous {
+        let previous_files: std::collections::HashMap<&str, &FileMetadata> = previous.files.iter()
+            .map(|f| (f.id.as_str(), f))
+            .collect();
+        for file in store.files.iter_mut() {
+            let old = previous_files.get(file.id.as_str());
+            file.sort_index = old.and_then(|f| f.sort_index);
+            if file.sha256.is_none() {
+                file.sha256 = old.and_then(|f| f.sha256.clone());
+            }
+        }
+        for meta in store.folder_metadata.iter_mut() {
+            if let Some(old) = previous.folder_metadata.iter().find(|m| m.path == meta.path) {
+                meta.color = old.color.clone();
+                meta.icon = old.icon.clone();
+            }
+        }
+        store.tombstones = previous.tombstones;
+    }
+
+    // Virtual entries for every folder (legacy ancestors without a channel included)
+    reconcile_folders(&mut store);
+
+    report.files = store.files.iter().filter(|f| !f.is_folder).count();
+    report.folders = store.folders.len() - 1;
+    println!(
+        "Rebuilt metadata from Telegram: {} files in {} folders ({} channels, {} failed)",
+        report.files, report.folders, report.channels_scanned, report.failed_channels.len()
+    );
+
+    save_metadata_local(&store).await?;
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub missing: Vec<Tombstone>,
+    pub failed_chats: Vec<BatchFailure>,  // Chats that couldn't be checked; their files are kept
+}
+
+// Move the given entries from the file list into the store's tombstones
+fn bury_missing_files(store: &mut MetadataStore, missing_ids: &HashSet<String>, detected_at: i64) -> Vec<Tombstone> {
+    let mut buried = Vec::new();
+    store.files.retain(|f| {
+        if f.is_folder || !missing_ids.contains(&f.id) {
+            return true;
+        }
+        buried.push(Tombstone {
+            id: f.id.clone(),
+            name: f.name.clone(),
+            folder: f.folder.clone(),
+            size: f.size,
+            chat_id: f.chat_id,
+            message_id: f.message_id,
+            detected_at,
+        });
+        false
+    });
+
+    store.tombstones.extend(buried.iter().cloned());
+    if store.tombstones.len() > MAX_TOMBSTONES {
+        let excess = store.tombstones.len() - MAX_TOMBSTONES;
+        store.tombstones.drain(..excess);
+    }
+    buried
+}
+
+// Check every file's message on Telegram (by id, 100 per request). Entries whose message is
+// gone or no longer carries media are removed and recorded as tombstones.
+pub async fn verify_vault(client_ref: Arc<Mutex<Option<Client>>>) -> Result<VerifyReport> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let metadata = load_metadata_copy().await?;
+    let mut by_chat: std::collections::BTreeMap<Option<i64>, Vec<(i32, String)>> = std::collections::BTreeMap::new();
+    for file in metadata.files.iter().filter(|f| !f.is_folder) {
+        if let Some(message_id) = file.message_id {
+            by_chat.entry(file.chat_id).or_default().push((message_id, file.id.clone()));
+        }
+    }
+
+    let mut report = VerifyReport::default();
+    let mut missing_ids = HashSet::new();
+
+    for (chat_id, entries) in &by_chat {
+        let chat_label = chat_id.map(|id| id.to_string()).unwrap_or_else(|| "Saved Messages".to_string());
+        let peer_ref = match resolve_file_chat(&client, *chat_id).await.map(|chat| chat.to_ref()) {
+            Ok(Some(peer_ref)) => peer_ref,
+            Ok(None) => {
+                report.failed_chats.push(BatchFailure { item: chat_label, error: "Failed to get peer reference".to_string() });
+                continue;
+            }
+            Err(e) => {
+                report.failed_chats.push(BatchFailure { item: chat_label, error: e.to_string() });
+                continue;
+            }
+        };
+
+        for batch in entries.chunks(100) {
+            let ids: Vec<i32> = batch.iter().map(|(message_id, _)| *message_id).collect();
+            let messages = match client.get_messages_by_id(peer_ref, &ids).await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    report.failed_chats.push(BatchFailure { item: chat_label.clone(), error: e.to_string() });
+                    break;
+                }
+            };
+
+            for ((_, file_id), message) in batch.iter().zip(messages) {
+                report.checked += 1;
+                if message.and_then(|m| m.media()).is_none() {
+                    missing_ids.insert(file_id.clone());
+                }
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        }
+    }
+
+    if !missing_ids.is_empty() {
+        // Re-read so changes made while we were checking aren't lost
+        let mut metadata = load_metadata_copy().await?;
+        report.missing = bury_missing_files(&mut metadata, &missing_ids, chrono::Utc::now().timestamp());
+        save_metadata_local(&metadata).await?;
+    }
+
+    println!(
+        "Verified {} files: {} missing, {} chats unreachable",
+        report.checked, report.missing.len(), report.failed_chats.len()
+    );
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocationFix {
+    pub id: String,
+    pub new_id: String,
+    pub name: String,
+    pub recorded_chat_id: Option<i64>,
+    pub actual_chat_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocationAudit {
+    pub checked: usize,
+    pub misplaced: Vec<LocationFix>,
+    pub not_found: Vec<String>,  // Ids found in none of the known chats
+    pub failed_chats: Vec<BatchFailure>,
+    pub repaired: bool,
+}
+
+// Message ids repeat across chats, so a message elsewhere only counts as the entry's if the
+// name matches and so does the size (when both sides know it)
+fn message_matches_entry(found: &FileMetadata, entry: &FileMetadata) -> bool {
+    found.name == entry.name && (found.size == 0 || entry.size == 0 || found.size == entry.size)
+}
+
+// T-Vault files among the given message ids of one chat, keyed by message id
+async fn fetch_file_messages(
+    client: &Client,
+    chat_id: Option<i64>,
+    message_ids: &[i32],
+    caption_prefix: &str,
+) -> Result<std::collections::HashMap<i32, FileMetadata>> {
+    let chat = resolve_file_chat(client, chat_id).await?;
+    let peer_ref = chat.to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+
+    let mut found = std::collections::HashMap::new();
+    for batch in message_ids.chunks(100) {
+        let messages = client.get_messages_by_id(peer_ref, batch).await
+            .map_err(|e| anyhow::anyhow!("Failed to fetch messages: {}", e))?;
+        for message in messages.into_iter().flatten() {
+            if let Some(file) = file_from_message(&message, chat_id, caption_prefix) {
+                found.insert(message.id(), file);
+            }
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+    }
+    Ok(found)
+}
+
+// Check that files live in the chat their metadata records (all of them, or a random sample)
+// and look for the ones that don't in the other T-Vault chats. With `repair`, found files get
+// their chat_id (and id) corrected.
+pub async fn audit_file_locations(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    sample: Option<usize>,
+    repair: bool,
+) -> Result<LocationAudit> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+    let config = crate::config::AppConfig::load().await;
+    let caption_prefix = config.caption_prefix;
+    let metadata = load_metadata_copy().await?;
+
+    let mut files: Vec<FileMetadata> = metadata.files.iter()
+        .filter(|f| !f.is_folder && f.message_id.is_some())
+        .cloned()
+        .collect();
+    if let Some(sample) = sample.filter(|&n| n < files.len()) {
+        use rand::seq::SliceRandom;
+        files.shuffle(&mut rand::thread_rng());
+        files.truncate(sample);
+    }
+
+    let mut known_chats: Vec<Option<i64>> = vec![None];
+    known_chats.extend(config.root_chat_id.map(Some));
+    known_chats.extend(metadata.folder_metadata.iter().filter_map(|m| m.chat_id).map(Some));
+    known_chats.sort();
+    known_chats.dedup();
+
+    let mut report = LocationAudit { checked: files.len(), ..Default::default() };
+
+    // 1. Is each file where the metadata says?
+    let mut by_chat: std::collections::BTreeMap<Option<i64>, Vec<FileMetadata>> = std::collections::BTreeMap::new();
+    for file in files {
+        by_chat.entry(file.chat_id).or_default().push(file);
+    }
+
+    let mut lost: Vec<FileMetadata> = Vec::new();
+    for (chat_id, entries) in &by_chat {
+        let ids: Vec<i32> = entries.iter().filter_map(|f| f.message_id).collect();
+        match fetch_file_messages(&client, *chat_id, &ids, &caption_prefix).await {
+            Ok(found) => lost.extend(entries.iter()
+                .filter(|f| !f.message_id.and_then(|id| found.get(&id)).map_or(false, |m| message_matches_entry(m, f)))
+                .cloned()),
+            Err(e) => report.failed_chats.push(BatchFailure {
+                item: chat_id.map(|id| id.to_string()).unwrap_or_else(|| "Saved Messages".to_string()),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    // 2. Look for the rest in every other known chat
+    let lost_ids: Vec<i32> = lost.iter().filter_map(|f| f.message_id).collect();
+    if !lost.is_empty() {
+        for chat_id in &known_chats {
+            if lost.iter().all(|f| report.misplaced.iter().any(|fix| fix.id == f.id)) {
+                break;
+            }
+            let found = match fetch_file_messages(&client, *chat_id, &lost_ids, &caption_prefix).await {
+                Ok(found) => found,
+                Err(e) => {
+                    eprintln!("Warning: Could not search chat {:?}: {}", chat_id, e);
+                    continue;
+                }
+            };
+            for file in lost.iter().filter(|f| f.chat_id != *chat_id) {
+                if report.misplaced.iter().any(|fix| fix.id == file.id) {
+                    continue;
+                }
+                let Some(message_id) = file.message_id else { continue };
+                if found.get(&message_id).map_or(false, |m| message_matches_entry(m, file)) {
+                    report.misplaced.push(LocationFix {
+                        id: file.id.clone(),
+                        new_id: message_file_id(*chat_id, message_id),
+                        name: file.name.clone(),
+                        recorded_chat_id: file.chat_id,
+                        actual_chat_id: *chat_id,
+                    });
+                }
+            }
+        }
+    }
+    report.not_found = lost.iter()
+        .filter(|f| !report.misplaced.iter().any(|fix| fix.id == f.id))
+        .map(|f| f.id.clone())
+        .collect();
+
+    if repair && !report.misplaced.is_empty() {
+        let mut metadata = load_metadata_copy().await?;
+        for fix in &report.misplaced {
+            if let Some(entry) = metadata.files.iter_mut().find(|f| f.id == fix.id) {
+                entry.chat_id = fix.actual_chat_id;
+                entry.id = fix.new_id.clone();
+            }
+        }
+        save_metadata_local(&metadata).await?;
+        report.repaired = true;
+    }
+
+    println!(
+        "Checked {} file locations: {} misplaced, {} not found{}",
+        report.checked, report.misplaced.len(), report.not_found.len(),
+        if report.repaired { " (repaired)" } else { "" }
+    );
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateMessageGroup {
+    pub chat_id: Option<i64>,
+    pub message_id: i32,
+    pub entries: Vec<FileMetadata>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateRepairReport {
+    pub removed: Vec<String>,          // Redundant copies of the entry that keeps the message
+    pub relinked: Vec<LocationFix>,    // Different files, pointed at the message that really holds them
+    pub unresolved: Vec<String>,       // Different files whose own message wasn't found; left as they are
+    pub failed_chats: Vec<BatchFailure>,
+}
+
+// Entries pointing at the same message. Ids are built from chat + message id, so all but one
+// of them end up with a stand-in local id and the file can't be reached by its proper one.
+fn duplicate_message_groups(metadata: &MetadataStore) -> Vec<DuplicateMessageGroup> {
+    let mut by_message: std::collections::BTreeMap<(Option<i64>, i32), Vec<FileMetadata>> = std::collections::BTreeMap::new();
+    for file in metadata.files.iter().filter(|f| !f.is_folder) {
+        if let Some(message_id) = file.message_id {
+            by_message.entry((file.chat_id, message_id)).or_default().push(file.clone());
+        }
+    }
+    by_message.into_iter()
+        .filter(|(_, entries)| entries.len() > 1)
+        .map(|((chat_id, message_id), entries)| DuplicateMessageGroup { chat_id, message_id, entries })
+        .collect()
+}
+
+// Whether two entries describe the same file (by hash when both have one)
+fn same_file_content(a: &FileMetadata, b: &FileMetadata) -> bool {
+    match (&a.sha256, &b.sha256) {
+        (Some(a_hash), Some(b_hash)) => a_hash == b_hash,
+        _ => a.name == b.name && a.size == b.size,
+    }
+}
+
+pub async fn find_duplicate_messages() -> Result<Vec<DuplicateMessageGroup>> {
+    Ok(duplicate_message_groups(&load_metadata_copy().await?))
+}
+
+// A recent T-Vault message in the chat that matches `entry` and no other entry references
+async fn find_entry_message(
+    client: &Client,
+    metadata: &MetadataStore,
+    entry: &FileMetadata,
+    claimed: &HashSet<(Option<i64>, i32)>,
+    caption_prefix: &str,
+) -> Result<Option<i32>> {
+    let chat = resolve_file_chat(client, entry.chat_id).await?;
+    let peer_ref = chat.to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+
+    let mut messages = client.iter_messages(peer_ref).limit(UNREFERENCED_SCAN_DEPTH);
+    while let Some(message) = messages.next().await? {
+        if is_referenced(metadata, entry.chat_id, message.id()) || claimed.contains(&(entry.chat_id, message.id())) {
+            continue;
+        }
+        if file_from_message(&message, entry.chat_id, caption_prefix).map_or(false, |found| message_matches_entry(&found, entry)) {
+            return Ok(Some(message.id()));
+        }
+    }
+    Ok(None)
+}
+
+// Sort out entries that share a message. The entry the message actually holds keeps it;
+// copies of that same file are removed, and different files are relinked to their own
+// message when one turns up among the chat's recent uploads.
+pub async fn repair_duplicate_messages(client_ref: Arc<Mutex<Option<Client>>>) -> Result<DuplicateRepairReport> {
+    // Entries are told apart by id below, so make sure no two share one
+    let mut metadata = load_metadata_copy().await?;
+    if !normalize_file_ids(&mut metadata).is_empty() {
+        save_metadata_local(&metadata).await?;
+    }
+    let groups = duplicate_message_groups(&metadata);
+    let mut report = DuplicateRepairReport::default();
+    if groups.is_empty() {
+        return Ok(report);
+    }
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+
+    let mut claimed: HashSet<(Option<i64>, i32)> = HashSet::new();
+    for group in &groups {
+        let chat_label = group.chat_id.map(|id| id.to_string()).unwrap_or_else(|| "Saved Messages".to_string());
+        let found = match fetch_file_messages(&client, group.chat_id, &[group.message_id], &caption_prefix).await {
+            Ok(found) => found,
+            Err(e) => {
+                report.failed_chats.push(BatchFailure { item: chat_label, error: e.to_string() });
+                continue;
+            }
+        };
+
+        // The entry the message holds, else the one with the proper id, else the first
+        let keeper = found.get(&group.message_id)
+            .and_then(|m| group.entries.iter().position(|e| message_matches_entry(m, e)))
+            .or_else(|| group.entries.iter().position(|e| e.id == message_file_id(group.chat_id, group.message_id)))
+            .unwrap_or(0);
+        let keep = &group.entries[keeper];
+
+        for (index, entry) in group.entries.iter().enumerate() {
+            if index == keeper {
+                continue;
+            }
+            if same_file_content(entry, keep) {
+                report.removed.push(entry.id.clone());
+                continue;
+            }
+            match find_entry_message(&client, &metadata, entry, &claimed, &caption_prefix).await {
+                Ok(Some(message_id)) => {
+                    claimed.insert((entry.chat_id, message_id));
+                    report.relinked.push(LocationFix {
+                        id: entry.id.clone(),
+                        new_id: message_file_id(entry.chat_id, message_id),
+                        name: entry.name.clone(),
+                        recorded_chat_id: entry.chat_id,
+                        actual_chat_id: entry.chat_id,
+                    });
+                }
+                Ok(None) => report.unresolved.push(entry.id.clone()),
+                Err(e) => {
+                    report.failed_chats.push(BatchFailure { item: chat_label.clone(), error: e.to_string() });
+                    report.unresolved.push(entry.id.clone());
+                }
+            }
+        }
+    }
+
+    if !report.removed.is_empty() || !report.relinked.is_empty() {
+        // Re-read so changes made while we were looking aren't lost
+        let mut metadata = load_metadata_copy().await?;
+        metadata.files.retain(|f| !report.removed.contains(&f.id));
+        for fix in &report.relinked {
+            if let Some(entry) = metadata.files.iter_mut().find(|f| f.id == fix.id) {
+                entry.message_id = fix.new_id.rsplit(':').next().and_then(|id| id.parse().ok());
+                entry.id = fix.new_id.clone();
+                entry.health = None;
+            }
+        }
+        save_metadata_local(&metadata).await?;
+    }
+
+    println!(
+        "Repaired {} shared messages: {} removed, {} relinked, {} unresolved",
+        groups.len(), report.removed.len(), report.relinked.len(), report.unresolved.len()
+    );
+    Ok(report)
+}
+
+// Messages checked per chat by find_unreferenced_uploads unless the caller asks otherwise
+pub const UNREFERENCED_SCAN_DEPTH: usize = 200;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnreferencedScan {
+    pub chats_scanned: usize,
+    pub uploads: Vec<FileMetadata>,  // Ready to import as they are
+    pub failed_chats: Vec<BatchFailure>,
+}
+
+// Whether any entry (or one of its older versions) lives in this message
+fn is_referenced(metadata: &MetadataStore, chat_id: Option<i64>, message_id: i32) -> bool {
+    metadata.files.iter().any(|f| {
+        !f.is_folder
+            && f.chat_id == chat_id
+            && (f.message_id == Some(message_id) || f.versions.contains(&message_id))
+    })
+}
+
+// Folder an unreferenced upload would be imported into: the one its caption names if that
+// still exists, else the folder the chat belongs to, else root
+fn unreferenced_upload_folder(metadata: &MetadataStore, file: &FileMetadata) -> String {
+    if file.folder != "/" && metadata.folders.contains(&file.folder) {
+        return file.folder.clone();
+    }
+    file.chat_id
+        .and_then(|chat_id| metadata.folder_metadata.iter().find(|m| m.chat_id == Some(chat_id)))
+        .map(|m| m.path.clone())
+        .filter(|path| metadata.folders.contains(path))
+        .unwrap_or_else(|| "/".to_string())
+}
+
+// Look through the most recent `depth` messages of Saved Messages and every folder chat for
+// T-Vault uploads the metadata doesn't know about, e.g. left behind by an upload that was
+// interrupted before its entry was saved. Nothing is changed.
+pub async fn find_unreferenced_uploads(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    depth: usize,
+) -> Result<UnreferencedScan> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+    let metadata = load_metadata_copy().await?;
+
+    let mut chats: Vec<Option<i64>> = vec![None];
+    chats.extend(crate::config::AppConfig::load().await.root_chat_id.map(Some));
+    chats.extend(metadata.folder_metadata.iter().filter_map(|m| m.chat_id).map(Some));
+    chats.sort();
+    chats.dedup();
+
+    let mut report = UnreferencedScan::default();
+    for chat_id in chats {
+        let chat_label = chat_id.map(|id| id.to_string()).unwrap_or_else(|| "Saved Messages".to_string());
+        let peer_ref = match resolve_file_chat(&client, chat_id).await.map(|chat| chat.to_ref()) {
+            Ok(Some(peer_ref)) => peer_ref,
+            Ok(None) => {
+                report.failed_chats.push(BatchFailure { item: chat_label, error: "Failed to get peer reference".to_string() });
+                continue;
+            }
+            Err(e) => {
+                report.failed_chats.push(BatchFailure { item: chat_label, error: e.to_string() });
+                continue;
+            }
+        };
+
+        let mut messages = client.iter_messages(peer_ref).limit(depth);
+        loop {
+            let message = match messages.next().await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    report.failed_chats.push(BatchFailure { item: chat_label.clone(), error: e.to_string() });
+                    break;
+                }
+            };
+            if is_referenced(&metadata, chat_id, message.id()) {
+                continue;
+            }
+            if let Some(mut file) = file_from_message(&message, chat_id, &caption_prefix) {
+                file.folder = unreferenced_upload_folder(&metadata, &file);
+                report.uploads.push(file);
+            }
+        }
+        report.chats_scanned += 1;
+    }
+
+    println!(
+        "Scanned {} chats: {} unreferenced uploads, {} chats unreachable",
+        report.chats_scanned, report.uploads.len(), report.failed_chats.len()
+    );
+    Ok(report)
+}
+
+// Add uploads found by find_unreferenced_uploads to the metadata. Ones that got an entry in
+// the meantime are skipped; returns the entries actually added.
+pub async fn import_unreferenced_uploads(uploads: Vec<FileMetadata>) -> Result<Vec<FileMetadata>> {
+    let mut metadata = load_metadata_copy().await?;
+    let mut imported = Vec::new();
+    for mut file in uploads {
+        let Some(message_id) = file.message_id.filter(|_| !file.is_folder) else { continue };
+        if is_referenced(&metadata, file.chat_id, message_id) {
+            continue;
+        }
+        file.id = message_file_id(file.chat_id, message_id);
+        file.folder = unreferenced_upload_folder(&metadata, &file);
+        file.versions.clear();
+        file.plain_versions.clear();
+        file.sort_index = None;
+        metadata.files.push(file.clone());
+        imported.push(file);
+    }
+
+    if !imported.is_empty() {
+        save_metadata_local(&metadata).await?;
+    }
+    Ok(imported)
+}
+
+// Delete the messages of uploads found by find_unreferenced_uploads. Anything the metadata
+// references by now is left alone.
+pub async fn delete_unreferenced_uploads(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    uploads: Vec<FileMetadata>,
+) -> Result<BatchReport> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+    let metadata = load_metadata_copy().await?;
+
+    let mut by_chat: std::collections::BTreeMap<Option<i64>, Vec<i32>> = std::collections::BTreeMap::new();
+    for file in &uploads {
+        if let Some(message_id) = file.message_id {
+            if !is_referenced(&metadata, file.chat_id, message_id) {
+                by_chat.entry(file.chat_id).or_default().push(message_id);
+            }
+        }
+    }
+
+    let mut report = BatchReport { total: by_chat.values().map(Vec::len).sum(), ..Default::default() };
+    for (chat_id, message_ids) in by_chat {
+        let result = async {
+            let chat = resolve_file_chat(&client, chat_id).await?;
+            let peer_ref = chat.to_ref()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+            client.delete_messages(peer_ref, &message_ids).await
+                .map_err(|e| anyhow::anyhow!("Failed to delete messages: {}", e))
+        }.await;
+
+        match result {
+            Ok(_) => report.succeeded += message_ids.len(),
+            Err(e) => report.failed.extend(message_ids.iter().map(|id| BatchFailure {
+                item: message_file_id(chat_id, *id),
+                error: e.to_string(),
+            })),
+        }
+    }
+    Ok(report)
+}
+
+const HEALTH_SCAN_BATCH: usize = 100;
+const HEALTH_SCAN_PAUSE_MS: u64 = 1000; // Between batches, so a long scan stays well below the rate limits
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthScanReport {
+    pub checked: usize,
+    pub ok: usize,
+    pub missing: usize,
+    pub size_mismatch: usize,
+    pub remaining: usize,  // Still due; a resumed scan picks these up
+    pub failed_chats: Vec<BatchFailure>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultHealthReport {
+    pub scan_started_at: Option<i64>,
+    pub total: usize,
+    pub unchecked: usize,  // Not yet checked by the current scan
+    pub ok: usize,
+    pub missing: Vec<FileMetadata>,
+    pub size_mismatch: Vec<FileMetadata>,
+}
+
+// Fetch one batch of messages for a scan, sitting out flood waits up to the usual limit.
+// An error means the rest of the chat should be skipped.
+async fn fetch_message_batch(
+    client: &Client,
+    chat: &Peer,
+    ids: &[i32],
+    cancel: &crate::operations::CancelToken,
+) -> std::result::Result<Vec<Option<Message>>, String> {
+    let peer_ref = chat.to_ref().ok_or_else(|| "Failed to get peer reference".to_string())?;
+    loop {
+        match client.get_messages_by_id(peer_ref, ids).await {
+            Ok(messages) => return Ok(messages),
+            Err(e) => {
+                let error = e.to_string();
+                match extract_flood_wait(&error.to_lowercase()).filter(|&secs| secs <= MAX_FLOOD_WAIT_SECS) {
+                    Some(secs) if !cancel.is_cancelled() => {
+                        println!("Scan waiting {}s for a flood wait", secs);
+                        tokio::time::sleep(tokio::time::Duration::from_secs(secs)).await;
+                    }
+                    _ => return Err(error),
+                }
+            }
+        }
+    }
+}
+
+// Whether a file still has to be checked by the scan that started at `scan_started_at`
+fn health_check_due(file: &FileMetadata, scan_started_at: i64) -> bool {
+    !file.is_folder && file.health.as_ref().map_or(true, |h| h.checked_at < scan_started_at)
+}
+
+// Encrypted entries record the plain size, so the stored blob may be either encrypted form of it
+fn stored_size_matches(entry: &FileMetadata, stored_size: u64) -> bool {
+    if entry.size == 0 || stored_size == entry.size {
+        return true;
+    }
+    entry.encrypted
+        && (stored_size == crate::encryption::encrypted_size(entry.size, 0)
+            || stored_size == crate::encryption::encrypted_size(entry.size, u64::MAX))
+}
+
+fn message_health(entry: &FileMetadata, message: Option<&Message>) -> HealthStatus {
+    match message.and_then(|m| m.media()) {
+        None => HealthStatus::Missing,
+        Some(Media::Document(doc)) => match doc.size() {
+            Some(size) if !stored_size_matches(entry, size as u64) => HealthStatus::SizeMismatch,
+            _ => HealthStatus::Ok,
+        },
+        Some(_) => HealthStatus::Ok,  // Photos carry no size to compare
+    }
+}
+
+// Store scan results in the entries they belong to (re-read, so other changes aren't lost)
+async fn record_health(results: &[(String, HealthStatus)]) -> Result<()> {
+    let checked_at = chrono::Utc::now().timestamp();
+    let mut metadata = load_metadata_copy().await?;
+    for (id, status) in results {
+        if let Some(entry) = metadata.files.iter_mut().find(|f| &f.id == id) {
+            entry.health = Some(FileHealth { status: *status, checked_at });
+        }
+    }
+    save_metadata_local(&metadata).await
+}
+
+// Check, batch by batch, that every file's message still exists and holds a file of the recorded
+// size, and store the result in the entry. Nothing is deleted; see vault_health_report.
+// Progress is saved after every batch, so a cancelled scan continues where it stopped when
+// run again with `resume` (a fresh scan re-checks everything).
+// Reports (files checked, files due) as it goes.
+pub async fn scan_vault_health(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    resume: bool,
+    cancel: &crate::operations::CancelToken,
+    on_progress: impl Fn(usize, usize),
+) -> Result<HealthScanReport> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let mut metadata = load_metadata_copy().await?;
+    let scan_started_at = match metadata.health_scan_started_at.filter(|_| resume) {
+        Some(started_at) => started_at,
+        None => {
+            let started_at = chrono::Utc::now().timestamp();
+            metadata.health_scan_started_at = Some(started_at);
+            save_metadata_local(&metadata).await?;
+            started_at
+        }
+    };
+
+    let due: Vec<FileMetadata> = metadata.files.iter()
+        .filter(|f| health_check_due(f, scan_started_at))
+        .cloned()
+        .collect();
+    let total = due.len();
+    let mut report = HealthScanReport { remaining: total, ..Default::default() };
+
+    let count = |report: &mut HealthScanReport, results: &[(String, HealthStatus)]| {
+        for (_, status) in results {
+            match status {
+                HealthStatus::Ok => report.ok += 1,
+                HealthStatus::Missing => report.missing += 1,
+                HealthStatus::SizeMismatch => report.size_mismatch += 1,
+            }
+        }
+        report.checked += results.len();
+        report.remaining -= results.len();
+    };
+
+    // Entries without a message can't be downloaded at all
+    let unlinked: Vec<(String, HealthStatus)> = due.iter()
+        .filter(|f| f.message_id.is_none())
+        .map(|f| (f.id.clone(), HealthStatus::Missing))
+        .collect();
+    if !unlinked.is_empty() {
+        record_health(&unlinked).await?;
+        count(&mut report, &unlinked);
+    }
+
+    let mut by_chat: std::collections::BTreeMap<Option<i64>, Vec<FileMetadata>> = std::collections::BTreeMap::new();
+    for file in due.into_iter().filter(|f| f.message_id.is_some()) {
+        by_chat.entry(file.chat_id).or_default().push(file);
+    }
+
+    'chats: for (chat_id, entries) in &by_chat {
+        let chat_label = chat_id.map(|id| id.to_string()).unwrap_or_else(|| "Saved Messages".to_string());
+        let chat = match resolve_file_chat(&client, *chat_id).await {
+            Ok(chat) => chat,
+            Err(e) => {
+                report.failed_chats.push(BatchFailure { item: chat_label, error: e.to_string() });
+                continue;
+            }
+        };
+
+        for batch in entries.chunks(HEALTH_SCAN_BATCH) {
+            if cancel.is_cancelled() {
+                break 'chats;
+            }
+
+            let ids: Vec<i32> = batch.iter().filter_map(|f| f.message_id).collect();
+            let messages = match fetch_message_batch(&client, &chat, &ids, cancel).await {
+                Ok(messages) => messages,
+                Err(error) => {
+                    report.failed_chats.push(BatchFailure { item: chat_label.clone(), error });
+                    continue 'chats;
+                }
+            };
+
+            let results: Vec<(String, HealthStatus)> = batch.iter().zip(messages)
+                .map(|(entry, message)| (entry.id.clone(), message_health(entry, message.as_ref())))
+                .collect();
+            record_health(&results).await?;
+            count(&mut report, &results);
+            on_progress(report.checked, total);
+
+            tokio::time::sleep(tokio::time::Duration::from_millis(HEALTH_SCAN_PAUSE_MS)).await;
+        }
+    }
+
+    println!(
+        "Health scan checked {} files: {} ok, {} missing, {} size mismatches, {} remaining",
+        report.checked, report.ok, report.missing, report.size_mismatch, report.remaining
+    );
+    Ok(report)
+}
+
+// The health of every file as recorded by the latest scan
+pub async fn vault_health_report() -> Result<VaultHealthReport> {
+    let metadata = load_metadata_copy().await?;
+    let mut report = VaultHealthReport {
+        scan_started_at: metadata.health_scan_started_at,
+        ..Default::default()
+    };
+
+    for file in metadata.files.into_iter().filter(|f| !f.is_folder) {
+        report.total += 1;
+        if metadata.health_scan_started_at.map_or(true, |started_at| health_check_due(&file, started_at)) {
+            report.unchecked += 1;
+            continue;
+        }
+        match file.health.as_ref().map(|h| h.status) {
+            Some(HealthStatus::Missing) => report.missing.push(file),
+            Some(HealthStatus::SizeMismatch) => report.size_mismatch.push(file),
+            _ => report.ok += 1,
+        }
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SizeMismatch {
+    pub file_id: String,
+    pub name: String,
+    pub recorded_size: u64,
+    pub telegram_size: u64,
+    pub repaired: bool,  // Encrypted entries record the plain size and are only reported
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SizeAuditReport {
+    pub checked: usize,
+    pub mismatches: Vec<SizeMismatch>,
+    pub failed_chats: Vec<BatchFailure>,
+}
+
+// Whether a recorded size is wrong for the document Telegram holds. Unlike the health
+// scan, an unknown (0) size counts: it's what breaks progress bars and dedup.
+fn size_disagrees(entry: &FileMetadata, telegram_size: u64) -> bool {
+    telegram_size > 0 && (entry.size == 0 || !stored_size_matches(entry, telegram_size))
+}
+
+// Compare the recorded size of the given files (all when empty) with their documents on
+// Telegram, batch by batch. With `repair`, unencrypted entries take Telegram's size.
+// Photos carry no size to compare and are skipped.
+pub async fn audit_file_sizes(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_ids: &[String],
+    repair: bool,
+    cancel: &crate::operations::CancelToken,
+) -> Result<SizeAuditReport> {
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let metadata = load_metadata_copy().await?;
+    let mut by_chat: std::collections::BTreeMap<Option<i64>, Vec<FileMetadata>> = std::collections::BTreeMap::new();
+    for file in metadata.files.into_iter()
+        .filter(|f| !f.is_folder && f.message_id.is_some())
+        .filter(|f| file_ids.is_empty() || file_ids.contains(&f.id))
+    {
+        by_chat.entry(file.chat_id).or_default().push(file);
+    }
+
+    let mut report = SizeAuditReport::default();
+    'chats: for (chat_id, entries) in &by_chat {
+        let chat_label = chat_id.map(|id| id.to_string()).unwrap_or_else(|| "Saved Messages".to_string());
+        let chat = match resolve_file_chat(&client, *chat_id).await {
+            Ok(chat) => chat,
+            Err(e) => {
+                report.failed_chats.push(BatchFailure { item: chat_label, error: e.to_string() });
+                continue;
+            }
+        };
+
+        for batch in entries.chunks(HEALTH_SCAN_BATCH) {
+            if cancel.is_cancelled() {
+                break 'chats;
+            }
+
+            let ids: Vec<i32> = batch.iter().filter_map(|f| f.message_id).collect();
+            let messages = match fetch_message_batch(&client, &chat, &ids, cancel).await {
+                Ok(messages) => messages,
+                Err(error) => {
+                    report.failed_chats.push(BatchFailure { item: chat_label.clone(), error });
+                    continue 'chats;
+                }
+            };
+
+            for (entry, message) in batch.iter().zip(messages) {
+                report.checked += 1;
+                let telegram_size = match message.as_ref().and_then(|m| m.media()) {
+                    Some(Media::Document(doc)) => doc.size().unwrap_or(0) as u64,
+                    _ => continue,  // Missing messages are the health scan's business
+                };
+                if size_disagrees(entry, telegram_size) {
+                    report.mismatches.push(SizeMismatch {
+                        file_id: entry.id.clone(),
+                        name: entry.name.clone(),
+                        recorded_size: entry.size,
+                        telegram_size,
+                        repaired: repair && !entry.encrypted,
+                    });
+                }
+            }
+
+            tokio::time::sleep(tokio::time::Duration::from_millis(HEALTH_SCAN_PAUSE_MS)).await;
+        }
+    }
+
+    let fixes: Vec<&SizeMismatch> = report.mismatches.iter().filter(|m| m.repaired).collect();
+    if !fixes.is_empty() {
+        // Re-read so changes made during the audit aren't lost
+        let mut metadata = load_metadata_copy().await?;
+        for fix in &fixes {
+            if let Some(entry) = metadata.files.iter_mut().find(|f| f.id == fix.file_id) {
+                entry.size = fix.telegram_size;
+            }
+        }
+        save_metadata_local(&metadata).await?;
+    }
+
+    println!(
+        "Size audit checked {} files: {} mismatches, {} repaired",
+        report.checked, report.mismatches.len(), fixes.len()
+    );
+    Ok(report)
+}
+
+// Hashes the caption trailers of `found` (by message id) offer for entries that lack one.
+// Only plain uploads qualify: an encrypted file's trailer hashes the ciphertext.
+fn checksums_from_captions(
+    entries: &[FileMetadata],
+    found: &std::collections::HashMap<i32, FileMetadata>,
+) -> Vec<(String, String)> {
+    entries.iter()
+        .filter(|e| e.sha256.is_none() && !e.encrypted && !e.as_photo)
+        .filter_map(|entry| {
+            let message = found.get(&entry.message_id?)?;
+            let hash = message.sha256.clone().filter(|_| message_matches_entry(message, entry))?;
+            Some((entry.id.clone(), hash))
+        })
+        .collect()
+}
+
+// Fill in missing hashes from caption trailers, which only takes fetching the messages.
+// Returns how many entries got one.
+async fn backfill_checksums_from_captions(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    report_failure: &mut Vec<BatchFailure>,
+) -> Result<usize> {
+    let client = shared_client(&client_ref).await?;
+    let caption_prefix = crate::config::AppConfig::load().await.caption_prefix;
+
+    let mut by_chat: std::collections::BTreeMap<Option<i64>, Vec<FileMetadata>> = std::collections::BTreeMap::new();
+    for file in list_files_without_checksum().await?.into_iter().filter(|f| f.message_id.is_some()) {
+        by_chat.entry(file.chat_id).or_default().push(file);
+    }
+
+    let mut hashes = Vec::new();
+    for (chat_id, entries) in &by_chat {
+        let ids: Vec<i32> = entries.iter().filter_map(|f| f.message_id).collect();
+        match fetch_file_messages(&client, *chat_id, &ids, &caption_prefix).await {
+            Ok(found) => hashes.extend(checksums_from_captions(entries, &found)),
+            Err(e) => report_failure.push(BatchFailure {
+                item: chat_id.map(|id| id.to_string()).unwrap_or_else(|| "Saved Messages".to_string()),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    if hashes.is_empty() {
+        return Ok(0);
+    }
+    let mut metadata = load_metadata_copy().await?;
+    let mut filled = 0;
+    for (id, hash) in hashes {
+        if let Some(entry) = metadata.files.iter_mut().find(|f| f.id == id && f.sha256.is_none()) {
+            entry.sha256 = Some(hash);
+            filled += 1;
+        }
+    }
+    save_metadata_local(&metadata).await?;
+    Ok(filled)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultRepairReport {
+    pub backup_path: String,
+    pub ids_normalized: Vec<IdChange>,
+    pub folders: FolderRepairReport,
+    pub duplicates: DuplicateRepairReport,
+    pub orphans: VerifyReport,              // Entries whose message is gone, now tombstones
+    pub sizes: SizeAuditReport,
+    pub checksums_filled: usize,            // From caption trailers
+    pub still_unhashed: usize,              // Need backfill_checksums, which downloads them
+    pub failed_steps: Vec<BatchFailure>,    // Steps that couldn't run; the others still did
+    pub cancelled: bool,
+}
+
+// The steps of repair_vault, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairStep {
+    Ids,
+    Folders,
+    Duplicates,
+    Orphans,
+    Sizes,
+    Checksums,
+}
+
+impl RepairStep {
+    // Reported to on_progress and in failed_steps
+    pub fn name(&self) -> &'static str {
+        match self {
+            RepairStep::Ids => "ids",
+            RepairStep::Folders => "folders",
+            RepairStep::Duplicates => "duplicates",
+            RepairStep::Orphans => "orphans",
+            RepairStep::Sizes => "sizes",
+            RepairStep::Checksums => "checksums",
+        }
+    }
+}
+
+pub const REPAIR_STEPS: [RepairStep; 6] = [
+    RepairStep::Ids,
+    RepairStep::Folders,
+    RepairStep::Duplicates,
+    RepairStep::Orphans,
+    RepairStep::Sizes,
+    RepairStep::Checksums,
+];
+
+// Run every metadata repair in one go, after copying metadata.json aside: id normalization,
+// folder reconciliation, shared-message cleanup, tombstoning entries whose message is gone,
+// and size and hash backfill from Telegram where that needs no downloads. Each step only
+// fixes what it finds, so running it again changes nothing. A failing step is recorded and
+// the next one still runs. `on_progress` gets (step, step index, step count).
+pub async fn repair_vault(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    cancel: &crate::operations::CancelToken,
+    on_progress: impl Fn(&str, usize, usize),
+) -> Result<VaultRepairReport> {
+    let mut report = VaultRepairReport::default();
+
+    let path = get_metadata_path().await?;
+    let backup_path = path.with_file_name(format!("metadata.repair.{}.json", chrono::Utc::now().timestamp()));
+    if path.exists() {
+        tokio::fs::copy(&path, &backup_path).await
+            .map_err(|e| anyhow::anyhow!("Could not back up metadata before repairing it: {}", e))?;
+        report.backup_path = backup_path.to_string_lossy().to_string();
+    }
+
+    let step_failed = |report: &mut VaultRepairReport, step: &str, e: anyhow::Error| {
+        eprintln!("Vault repair step {} failed: {}", step, e);
+        report.failed_steps.push(BatchFailure { item: step.to_string(), error: e.to_string() });
+    };
+
+    for (index, step) in REPAIR_STEPS.iter().copied().enumerate() {
+        if cancel.is_cancelled() {
+            report.cancelled = true;
+            break;
+        }
+        let name = step.name();
+        on_progress(name, index, REPAIR_STEPS.len());
+
+        match step {
+            RepairStep::Ids => match normalize_ids().await {
+                Ok(changes) => report.ids_normalized = changes,
+                Err(e) => step_failed(&mut report, name, e),
+            },
+            RepairStep::Folders => match repair_folder_metadata().await {
+                Ok(folders) => report.folders = folders,
+                Err(e) => step_failed(&mut report, name, e),
+            },
+            RepairStep::Duplicates => match repair_duplicate_messages(client_ref.clone()).await {
+                Ok(duplicates) => report.duplicates = duplicates,
+                Err(e) => step_failed(&mut report, name, e),
+            },
+            RepairStep::Orphans => match verify_vault(client_ref.clone()).await {
+                Ok(orphans) => report.orphans = orphans,
+                Err(e) => step_failed(&mut report, name, e),
+            },
+            RepairStep::Sizes => match audit_file_sizes(client_ref.clone(), &[], true, cancel).await {
+                Ok(sizes) => report.sizes = sizes,
+                Err(e) => step_failed(&mut report, name, e),
+            },
+            RepairStep::Checksums => {
+                let mut failed_chats = Vec::new();
+                match backfill_checksums_from_captions(client_ref.clone(), &mut failed_chats).await {
+                    Ok(filled) => report.checksums_filled = filled,
+                    Err(e) => step_failed(&mut report, name, e),
+                }
+                for failure in failed_chats {
+                    step_failed(&mut report, name, anyhow::anyhow!("{}: {}", failure.item, failure.error));
+                }
+            }
+        }
+    }
+    on_progress("", REPAIR_STEPS.len(), REPAIR_STEPS.len());
+
+    report.still_unhashed = list_files_without_checksum().await?.len();
+    println!(
+        "Vault repair: {} ids, {} folder fixes, {} duplicates removed, {} orphans, {} sizes, {} hashes, {} steps failed{}",
+        report.ids_normalized.len(),
+        report.folders.added_to_folders.len() + report.folders.added_entries.len() + report.folders.fixed_chat_ids.len(),
+        report.duplicates.removed.len(),
+        report.orphans.missing.len(),
+        report.sizes.mismatches.iter().filter(|m| m.repaired).count(),
+        report.checksums_filled,
+        report.failed_steps.len(),
+        if report.cancelled { " (cancelled)" } else { "" }
+    );
+    Ok(report)
+}
+
+pub async fn list_tombstones() -> Result<Vec<Tombstone>> {
+    Ok(load_metadata_copy().await?.tombstones)
+}
+
+// Forget the tombstone history; returns how many were removed
+pub async fn clear_tombstones() -> Result<usize> {
+    let mut metadata = load_metadata_copy().await?;
+    let cleared = metadata.tombstones.len();
+    if cleared > 0 {
+        metadata.tombstones.clear();
+        save_metadata_local(&metadata).await?;
+    }
+    Ok(cleared)
+}
+
+// Move a file's message into the chat of `target_folder`: forwarded server-side when possible,
+// downloaded and uploaded again only when the source chat blocks forwarding
+async fn move_file_to_chat(
+    client: &Client,
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file: &FileMetadata,
+    target_folder: &str,
+    target_chat_id: Option<i64>,
+    app_handle: tauri::AppHandle,
+) -> Result<FileMetadata> {
+    match forward_file_to_chat(client, file, target_chat_id).await {
+        Ok(moved) => Ok(moved),
+        Err(e) if crate::telegram::is_forward_restricted(&e.to_string()) => {
+            println!("Forwarding restricted for {}, falling back to download + re-upload", file.name);
+            reupload_file_to_folder(client_ref, file, target_folder, app_handle).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Slow path of a move: download the file, upload it into `target_folder` and delete the
+// original. Older versions can't come along and stay behind in the old chat.
+async fn reupload_file_to_folder(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file: &FileMetadata,
+    target_folder: &str,
+    app_handle: tauri::AppHandle,
+) -> Result<FileMetadata> {
+    let updated = upload_copy_to_folder(client_ref.clone(), file, target_folder, None, app_handle).await?;
+
+    if let Err(e) = delete_file(client_ref, &file.id, false).await {
+        eprintln!("Warning: Failed to delete {} after re-uploading it: {}", file.name, e);
+    }
+    Ok(updated)
+}
+
+// Download a file and upload it again into `target_folder` as a new entry with the same date.
+// `copy_of` is recorded on the new entry when it is a copy rather than the file moving.
+async fn upload_copy_to_folder(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file: &FileMetadata,
+    target_folder: &str,
+    copy_of: Option<&str>,
+    app_handle: tauri::AppHandle,
+) -> Result<FileMetadata> {
+    // A folder per file keeps the real name, which the upload takes over
+    let temp_dir = std::env::temp_dir().join("tvault_migration").join(file.id.replace(':', "_"));
+    tokio::fs::create_dir_all(&temp_dir).await?;
+    let temp_path = temp_dir.join(&file.name);
+
+    let result = async {
+        let saved_as = download_file(client_ref.clone(), &file.id, &temp_path.to_string_lossy(), ProgressConfig::silent(), |_, _, _| {}).await
+            .map_err(|e| anyhow::anyhow!("Download failed: {}", e))?;
+
+        let options = UploadOptions {
+            description: file.description.clone(),
+            progress: ProgressConfig::silent(),
+            encrypt: file.encrypted,
+            created_at: Some(file.created_at),
+            custom: file.custom.clone(),
+            ..Default::default()
+        };
+        upload_file(client_ref.clone(), &saved_as, target_folder, options, |_, _, _| {}, app_handle).await
+            .map_err(|e| anyhow::anyhow!("Upload failed: {}", e))
+    }.await;
+    let _ = tokio::fs::remove_dir_all(&temp_dir).await;
+    let new_message_id: i32 = result?.parse()?;
+
+    // The new message keeps the entry's original date
+    let mut metadata = load_metadata_copy().await?;
+    let target_chat_id = destination_chat_id(&metadata, target_folder).await?;
+    let entry = metadata.files.iter_mut()
+        .find(|f| f.chat_id == target_chat_id && f.message_id == Some(new_message_id))
+        .ok_or_else(|| anyhow::anyhow!("Re-uploaded file is missing from the metadata"))?;
+    entry.created_at = file.created_at;
+    entry.copy_of = copy_of.map(str::to_string);
+    let updated = entry.clone();
+    save_metadata_local(&metadata).await?;
+    Ok(updated)
+}
+
+// Move a file's message into another chat (None = Saved Messages) by forwarding it server-side,
+// update the metadata entry in place, then delete the original
+async fn forward_file_to_chat(
+    client: &Client,
+    file: &FileMetadata,
+    target_chat_id: Option<i64>,
+) -> Result<FileMetadata> {
+    let message_id = file.message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    let source = resolve_file_chat(client, file.chat_id).await?;
+    let destination = resolve_file_chat(client, target_chat_id).await?;
+
+    let new_message_id = crate::telegram::forward_message(client, &source, &destination, message_id).await?;
+
+    // Older versions travel with the file; any that fail to forward stay behind and drop out of the chain
+    let mut moved_ids = vec![message_id];
+    let mut new_versions = Vec::with_capacity(file.versions.len());
+    let mut new_plain_versions = Vec::new();
+    for &version_id in &file.versions {
+        match crate::telegram::forward_message(client, &source, &destination, version_id).await {
+            Ok(id) => {
+                new_versions.push(id);
+                if file.plain_versions.contains(&version_id) {
+                    new_plain_versions.push(id);
+                }
+                moved_ids.push(version_id);
+            }
+            Err(e) => eprintln!("Warning: Failed to forward version {} of {}: {}", version_id, file.name, e),
+        }
+    }
+
+    let mut metadata = load_metadata_copy().await?;
+    let entry = metadata.files.iter_mut()
+        .find(|f| f.id == file.id)
+        .ok_or_else(|| anyhow::anyhow!("File not found"))?;
+
+    entry.id = message_file_id(target_chat_id, new_message_id);
+    entry.message_id = Some(new_message_id);
+    entry.chat_id = target_chat_id;
+    entry.versions = new_versions;
+    entry.plain_versions = new_plain_versions;
+    let updated = entry.clone();
+
+    save_metadata_local(&metadata).await?;
+
+    // The copies are safe in the destination and the metadata points at them - remove the originals
+    if let Some(peer_ref) = source.to_ref() {
+        if let Err(e) = client.delete_messages(peer_ref, &moved_ids).await {
+            eprintln!("Warning: Failed to delete original message after forwarding: {:?}", e);
+        }
+    }
+    Ok(updated)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateFolder {
+    pub path: String,
+    pub chat_ids: Vec<i64>,
+    pub file_counts: Vec<usize>,  // Files stored in each chat, same order as chat_ids
+}
+
+// Group folder_metadata by path and return every path backed by more than one channel
+fn duplicate_folders(store: &MetadataStore) -> Vec<DuplicateFolder> {
+    let mut by_path: Vec<(String, Vec<i64>)> = Vec::new();
+    for meta in &store.folder_metadata {
+        let Some(chat_id) = meta.chat_id else { continue };
+        match by_path.iter_mut().find(|(path, _)| path == &meta.path) {
+            Some((_, chat_ids)) if !chat_ids.contains(&chat_id) => chat_ids.push(chat_id),
+            Some(_) => {}
+            None => by_path.push((meta.path.clone(), vec![chat_id])),
+        }
+    }
+
+    by_path.into_iter()
+        .filter(|(_, chat_ids)| chat_ids.len() > 1)
+        .map(|(path, chat_ids)| {
+            let file_counts = chat_ids.iter()
+                .map(|id| store.files.iter().filter(|f| !f.is_folder && f.chat_id == Some(*id)).count())
+                .collect();
+            DuplicateFolder { path, chat_ids, file_counts }
+        })
+        .collect()
+}
+
+// Folder paths that ended up with more than one channel (e.g. from racing legacy upgrades)
+pub async fn find_duplicate_folders() -> Result<Vec<DuplicateFolder>> {
+    let metadata = load_metadata_copy().await?;
+    Ok(duplicate_folders(&metadata))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelAudit {
+    pub path: String,
+    pub metadata_channels: Vec<i64>,  // folder_metadata rows for this path
+    pub entry_channel: Option<i64>,   // chat_id on the virtual folder entry
+    pub dialog_channels: Vec<i64>,    // Channels in the dialogs titled for this path
+    pub duplicate: bool,              // More than one distinct channel backs the path
+    pub mismatch: bool,               // Metadata, folder entry and dialogs disagree
+}
+
+// Compare every folder path's channels in metadata against the folder channels found in the dialogs
+fn audit_folder_channels(store: &MetadataStore, dialog_channels: &[(i64, String)]) -> Vec<ChannelAudit> {
+    let mut paths: Vec<String> = store.folders.iter()
+        .filter(|p| p.as_str() != "/")
+        .cloned()
+        .collect();
+    paths.extend(dialog_channels.iter().filter_map(|(_, title)| folder_path_from_title(title)));
+    paths.sort();
+    paths.dedup();
+
+    paths.into_iter().map(|path| {
+        let mut metadata_channels: Vec<i64> = store.folder_metadata.iter()
+            .filter(|m| m.path == path)
+            .filter_map(|m| m.chat_id)
+            .collect();
+        metadata_channels.sort();
+        metadata_channels.dedup();
+        let entry_channel = store.files.iter()
+            .find(|f| f.is_folder && folder_entry_path(f) == path)
+            .and_then(|f| f.chat_id);
+        let found: Vec<i64> = dialog_channels.iter()
+            .filter(|(_, title)| folder_path_from_title(title).as_deref() == Some(path.as_str()))
+            .map(|(chat_id, _)| *chat_id)
+            .collect();
+
+        let mut all: Vec<i64> = metadata_channels.iter().chain(found.iter()).chain(entry_channel.iter()).copied().collect();
+        all.sort();
+        all.dedup();
+
+        let known: HashSet<i64> = metadata_channels.iter().copied().collect();
+        let in_dialogs: HashSet<i64> = found.iter().copied().collect();
+        let mismatch = known != in_dialogs
+            || entry_channel.map_or(false, |id| !known.contains(&id));
+
+        ChannelAudit {
+            path,
+            metadata_channels,
+            entry_channel,
+            dialog_channels: found,
+            duplicate: all.len() > 1,
+            mismatch,
+        }
+    }).collect()
+}
+
+// Read-only diagnostic: for every folder path, which channels back it according to the
+// metadata and according to a dialog scan. Input for merge_folders and repair_folder_metadata.
+pub async fn audit_channels(client_ref: Arc<Mutex<Option<Client>>>) -> Result<Vec<ChannelAudit>> {
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let dialog_channels = crate::telegram::list_folder_channels(&client).await?;
+    let metadata = load_metadata_copy().await?;
+    Ok(audit_folder_channels(&metadata, &dialog_channels))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderTitleMismatch {
+    pub path: String,
+    pub chat_id: i64,
+    pub title: String,     // What the channel is called now
+    pub expected: String,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderTitleReport {
+    pub checked: usize,
+    pub mismatches: Vec<FolderTitleMismatch>,
+    pub failed: Vec<BatchFailure>,
+}
+
+fn expected_folder_title(path: &str) -> String {
+    format!("{} {}", crate::telegram::FOLDER_CHANNEL_PREFIX, path)
+}
+
+// Compare every folder channel's title with the "T-Vault: {path}" it should carry, e.g. after
+// a rename in the Telegram app. With `repair`, drifted channels get their title back.
+pub async fn audit_folder_titles(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    repair: bool,
+) -> Result<FolderTitleReport> {
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let metadata = load_metadata_copy().await?;
+    let mut report = FolderTitleReport::default();
+
+    for folder in metadata.folder_metadata.iter() {
+        let Some(chat_id) = folder.chat_id else {
+            continue;
+        };
+        report.checked += 1;
+
+        let title = match crate::telegram::fetch_channel_title(&client, chat_id).await {
+            Ok(title) => title,
+            Err(e) => {
+                report.failed.push(BatchFailure { item: folder.path.clone(), error: e.to_string() });
+                continue;
+            }
+        };
+        let expected = expected_folder_title(&folder.path);
+        if title != expected {
+            let repaired = if repair {
+                match crate::telegram::set_channel_title(&client, chat_id, &expected).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        report.failed.push(BatchFailure { item: folder.path.clone(), error: e.to_string() });
+                        false
+                    }
+                }
+            } else {
+                false
+            };
+            report.mismatches.push(FolderTitleMismatch { path: folder.path.clone(), chat_id, title, expected, repaired });
+        }
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+    }
+
+    let repaired: Vec<&FolderTitleMismatch> = report.mismatches.iter().filter(|m| m.repaired).collect();
+    if !repaired.is_empty() {
+        let mut metadata = load_metadata_copy().await?;
+        for mismatch in repaired {
+            for folder in metadata.folder_metadata.iter_mut().filter(|f| f.chat_id == Some(mismatch.chat_id)) {
+                folder.chat_title = Some(mismatch.expected.clone());
+            }
+        }
+        save_metadata_local(&metadata).await?;
+    }
+
+    Ok(report)
+}
+
+// Collapse a duplicated folder onto one channel: forward the files from every other channel
+// into `keep_chat_id`, then drop the extra metadata entries and delete the emptied channels
+pub async fn merge_folders(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+    keep_chat_id: i64,
+) -> Result<usize> {
+    let metadata = load_metadata_copy().await?;
+    let duplicate = duplicate_folders(&metadata).into_iter()
+        .find(|d| d.path == folder_path)
+        .ok_or_else(|| anyhow::anyhow!("Folder {} has no duplicate channels", folder_path))?;
+
+    if !duplicate.chat_ids.contains(&keep_chat_id) {
+        return Err(anyhow::anyhow!("Chat {} does not belong to folder {}", keep_chat_id, folder_path));
+    }
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let losers: Vec<i64> = duplicate.chat_ids.into_iter().filter(|id| *id != keep_chat_id).collect();
+    let mut moved = 0;
+
+    for &loser in &losers {
+        let files: Vec<FileMetadata> = metadata.files.iter()
+            .filter(|f| !f.is_folder && f.chat_id == Some(loser))
+            .cloned()
+            .collect();
+
+        for file in &files {
+            forward_file_to_chat(&client, file, Some(keep_chat_id)).await
+                .map_err(|e| anyhow::anyhow!(
+                    "Merge stopped after {} files ({}): {}", moved, file.name, e
+                ))?;
+            moved += 1;
+            tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        }
+    }
+
+    let mut metadata = load_metadata_copy().await?;
+    let mut kept_one = false;
+    metadata.folder_metadata.retain(|f| {
+        if f.path != folder_path {
+            return true;
+        }
+        // Keep a single entry for the surviving channel
+        if f.chat_id == Some(keep_chat_id) && !kept_one {
+            kept_one = true;
+            return true;
+        }
+        false
+    });
+    if let Some(entry) = metadata.files.iter_mut().find(|f| f.is_folder && folder_entry_path(f) == folder_path) {
+        entry.chat_id = Some(keep_chat_id);
+    }
+    save_metadata_local(&metadata).await?;
+
+    for loser in losers {
+        if let Err(e) = crate::telegram::delete_channel(&client, loser).await {
+            eprintln!("Warning: Failed to delete merged channel {}: {:?}", loser, e);
+        }
+    }
+
+    Ok(moved)
+}
+
+// Switch a folder's backing chat between a broadcast channel and a supergroup.
+// Telegram can't convert a channel in place, so this creates a chat of the requested kind,
+// forwards every file into it and then deletes the old channel.
+pub async fn convert_folder_channel(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+    to_megagroup: bool,
+) -> Result<FolderMetadata> {
+    let target_kind = if to_megagroup { ChannelKind::Megagroup } else { ChannelKind::Broadcast };
+
+    let metadata = load_metadata_copy().await?;
+    let folder_meta = metadata.folder_metadata.iter()
+        .find(|f| f.path == folder_path)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Folder not found: {}", folder_path))?;
+
+    if folder_meta.kind == target_kind {
+        return Ok(folder_meta);
+    }
+
+    let old_chat_id = folder_meta.chat_id
+        .ok_or_else(|| anyhow::anyhow!("Folder {} has no channel yet", folder_path))?;
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let chat_title = format!("T-Vault: {}", folder_path);
+    let description = format!("Storage folder for: {}", folder_path);
+    let (new_chat_id, chat_name) = crate::telegram::create_folder_channel(
+        &client,
+        &chat_title,
+        &description,
+        to_megagroup,
+    ).await?;
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // Move every file stored in the old channel
+    let files: Vec<FileMetadata> = metadata.files.iter()
+        .filter(|f| !f.is_folder && f.chat_id == Some(old_chat_id))
+        .cloned()
+        .collect();
+
+    for (index, file) in files.iter().enumerate() {
+        if let Err(e) = forward_file_to_chat(&client, file, Some(new_chat_id)).await {
+            // Files already moved are recorded per-file, so metadata stays consistent.
+            // Keep the old channel so the remaining files stay reachable.
+            return Err(anyhow::anyhow!(
+                "Conversion stopped after {} of {} files ({}): {}",
+                index, files.len(), file.name, e
+            ));
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+    }
+
+    let mut metadata = load_metadata_copy().await?;
+    let updated = {
+        let entry = metadata.folder_metadata.iter_mut()
+            .find(|f| f.path == folder_path)
+            .ok_or_else(|| anyhow::anyhow!("Folder not found: {}", folder_path))?;
+        entry.chat_id = Some(new_chat_id);
+        entry.chat_title = Some(chat_name);
+        entry.kind = target_kind;
+        entry.clone()
+    };
+
+    if let Some(entry) = metadata.files.iter_mut().find(|f| f.is_folder && folder_entry_path(f) == folder_path) {
+        entry.chat_id = Some(new_chat_id);
+    }
+
+    save_metadata_local(&metadata).await?;
+
+    if let Err(e) = crate::telegram::delete_channel(&client, old_chat_id).await {
+        eprintln!("Warning: Failed to delete old channel {}: {:?}", old_chat_id, e);
+    }
+
+    Ok(updated)
+}
+
+// Chat that stores a folder's files (None = Saved Messages for the root)
+fn folder_chat_id(metadata: &MetadataStore, folder: &str) -> Result<Option<i64>> {
+    if folder == "/" {
+        return Ok(None);
+    }
+    match metadata.folder_metadata.iter().find(|f| f.path == folder) {
+        Some(meta) => meta.chat_id
+            .map(Some)
+            .ok_or_else(|| anyhow::anyhow!("Folder {} has no channel yet", folder)),
+        None if metadata.folders.iter().any(|f| f == folder) => {
+            Err(anyhow::anyhow!("Folder {} has no channel yet. Upload a file to it first.", folder))
+        }
+        None => Err(anyhow::anyhow!("Folder not found: {}", folder)),
+    }
+}
+
+// Chat that new files in `folder` go to: like folder_chat_id, but root files go to the
+// root channel when one is set
+async fn destination_chat_id(metadata: &MetadataStore, folder: &str) -> Result<Option<i64>> {
+    if folder == "/" {
+        return Ok(crate::config::AppConfig::load().await.root_chat_id);
+    }
+    folder_chat_id(metadata, folder)
+}
+
+// Make root uploads go to a channel instead of Saved Messages: a new one when `create_new`
+// is set, else `chat_id` if given, else back to Saved Messages. Files already in Saved
+// Messages stay there and keep working; returns the root channel now in use.
+pub async fn set_root_channel(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    create_new: bool,
+    chat_id: Option<i64>,
+) -> Result<Option<i64>> {
+    let root_chat_id = if create_new {
+        let client = {
+            let guard = client_ref.lock().await;
+            guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+        };
+        let (chat_id, _) = crate::telegram::create_folder_channel(
+            &client,
+            ROOT_CHANNEL_TITLE,
+            "Storage for T-Vault root files",
+            false,
+        ).await?;
+        Some(chat_id)
+    } else if let Some(chat_id) = chat_id {
+        let client = {
+            let guard = client_ref.lock().await;
+            guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+        };
+        // Make sure the channel is reachable before anything is sent there
+        crate::telegram::get_chat_peer(&client, chat_id).await?;
+        Some(chat_id)
+    } else {
+        None
+    };
+
+    crate::config::AppConfig::set_root_chat_id(root_chat_id).await?;
+    println!("Root files now go to {}", root_chat_id.map(|id| format!("channel {}", id)).unwrap_or_else(|| "Saved Messages".to_string()));
+    Ok(root_chat_id)
+}
+
+// Re-root `path` from `from` to `to` if it is `from` itself or lies beneath it,
+// e.g. ("/A/B/C", "/A/B", "/X/B") => "/X/B/C"
+fn rebase_path(path: &str, from: &str, to: &str) -> Option<String> {
+    if path == from {
+        return Some(to.to_string());
+    }
+    path.strip_prefix(from)
+        .filter(|rest| rest.starts_with('/'))
+        .map(|rest| format!("{}{}", to, rest))
+}
+
+// Probe whether uploads into a folder will succeed. Root (Saved Messages) is always writable,
+// and legacy folders get their channel created on first upload, so both report Ok.
+pub async fn check_folder_channel(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+) -> Result<crate::telegram::ChannelStatus> {
+    use crate::telegram::ChannelStatus;
+
+    let metadata = load_metadata_copy().await?;
+    let chat_id = match destination_chat_id(&metadata, folder_path).await {
+        Ok(Some(chat_id)) => chat_id,
+        Ok(None) => return Ok(ChannelStatus::Ok),
+        Err(_) if metadata.folders.iter().any(|f| f == folder_path) => return Ok(ChannelStatus::Ok),
+        Err(e) => return Err(e),
+    };
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    Ok(crate::telegram::check_channel_access(&client, chat_id).await)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderPermissions {
+    pub path: String,
+    pub chat_id: i64,
+    #[serde(flatten)]
+    pub permissions: crate::telegram::ChannelPermissions,
+}
+
+// Whether we still own or administer a folder's channel, and with which rights. Explains
+// deletes and renames that fail with CHAT_ADMIN_REQUIRED before the user runs into them.
+pub async fn folder_permissions(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+) -> Result<FolderPermissions> {
+    let metadata = load_metadata_copy().await?;
+    if !metadata.folders.iter().any(|f| f == folder_path) {
+        return Err(anyhow::anyhow!("Folder not found: {}", folder_path));
+    }
+    let chat_id = metadata.folder_metadata.iter()
+        .find(|f| f.path == folder_path)
+        .and_then(|f| f.chat_id)
+        .ok_or_else(|| anyhow::anyhow!("Folder {} has no channel yet", folder_path))?;
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let permissions = crate::telegram::channel_permissions(&client, chat_id).await?;
+    Ok(FolderPermissions {
+        path: folder_path.to_string(),
+        chat_id,
+        permissions,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanedChannel {
+    pub chat_id: i64,
+    pub title: String,
+}
+
+// T-Vault folder channels on Telegram that no folder in the metadata points to,
+// typically left behind after the local metadata was reset
+pub async fn list_orphaned_channels(client_ref: Arc<Mutex<Option<Client>>>) -> Result<Vec<OrphanedChannel>> {
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let metadata = load_metadata_copy().await?;
+    let root_chat_id = crate::config::AppConfig::load().await.root_chat_id;
+    let referenced: HashSet<i64> = metadata.folder_metadata.iter()
+        .filter_map(|f| f.chat_id)
+        .chain(metadata.files.iter().filter_map(|f| f.chat_id))
+        .chain(root_chat_id)
+        .collect();
+
+    let orphaned = crate::telegram::list_folder_channels(&client).await?
+        .into_iter()
+        .filter(|(chat_id, _)| !referenced.contains(chat_id))
+        .map(|(chat_id, title)| OrphanedChannel { chat_id, title })
+        .collect();
+
+    Ok(orphaned)
+}
+
+// Delete the given orphaned channels. Ids that are no longer orphaned T-Vault channels are refused.
+pub async fn delete_orphaned_channels(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    chat_ids: &[i64],
+) -> Result<BatchReport> {
+    let orphaned: HashSet<i64> = list_orphaned_channels(client_ref.clone()).await?
+        .into_iter()
+        .map(|c| c.chat_id)
+        .collect();
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let mut report = BatchReport { total: chat_ids.len(), ..Default::default() };
+
+    for &chat_id in chat_ids {
+        if !orphaned.contains(&chat_id) {
+            report.failed.push(BatchFailure {
+                item: chat_id.to_string(),
+                error: "Not an orphaned T-Vault channel".to_string(),
+            });
+            continue;
+        }
+
+        match crate::telegram::delete_channel(&client, chat_id).await {
+            Ok(()) => report.succeeded += 1,
+            Err(e) => report.failed.push(BatchFailure { item: chat_id.to_string(), error: e.to_string() }),
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    }
+
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedFile {
+    pub message_id: i32,   // Message id in the target chat
+    pub forwarded: bool,   // False when content protection or encryption forced a download and re-send
+}
+
+// Send a stored file to any chat (contact, group or channel) without keeping a local copy.
+// Forwards the backing message; if the source chat blocks forwarding, downloads and re-sends it.
+// Encrypted files are always sent as a decrypted copy, which needs the vault unlocked.
+pub async fn share_file_to_chat(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    target_chat_id: i64,
+) -> Result<SharedFile> {
+    let metadata = load_metadata_copy().await?;
+    let file = find_file_entry(&metadata, file_id)?;
+    let message_id = file.message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let encryptor = download_encryptor(&file)?;
+    let target = crate::telegram::get_dialog_peer(&client, target_chat_id).await?;
+
+    if encryptor.is_none() {
+        let source = resolve_file_chat(&client, file.chat_id).await?;
+        match crate::telegram::forward_message(&client, &source, &target, message_id).await {
+            Ok(id) => return Ok(SharedFile { message_id: id, forwarded: true }),
+            Err(e) if crate::telegram::is_forward_restricted(&e.to_string()) => {
+                println!("Forwarding {} is restricted, sending a copy instead", file.name);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let temp_dir = std::env::temp_dir().join("tvault_share");
+    tokio::fs::create_dir_all(&temp_dir).await?;
+    let temp_path = temp_dir.join(file.id.replace(':', "_"));
+    let temp_path_str = temp_path.to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid temp path"))?
+        .to_string();
+
+    let result = async {
+        download_entry(client_ref.clone(), &file, &temp_path_str, ProgressConfig::silent(), |_, _, _| {}).await?;
+        if let Some(ref encryptor) = encryptor {
+            decrypt_download(encryptor, &temp_path_str).await?;
+        }
+        let size = tokio::fs::metadata(&temp_path).await?.len();
+        attempt_upload(&client, &target, &temp_path_str, &file.name, size, &file.name, None, None, file.as_photo, ProgressConfig::silent(), Box::new(|_, _, _| {})).await
+    }.await;
+
+    let _ = tokio::fs::remove_file(&temp_path).await;
+
+    result.map(|id| SharedFile { message_id: id, forwarded: false })
+}
+
+// Move a single file into another folder, forwarding its message to that folder's chat
+pub async fn move_file(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    target_folder: &str,
+    app_handle: tauri::AppHandle,
+) -> Result<FileMetadata> {
+    let metadata = load_metadata_copy().await?;
+    let file = find_file_entry(&metadata, file_id)?;
+
+    if file.folder == target_folder {
+        return Ok(file);
+    }
+
+    let target_chat_id = destination_chat_id(&metadata, target_folder).await?;
+
+    let moved = if file.chat_id == target_chat_id {
+        file
+    } else {
+        let client = {
+            let guard = client_ref.lock().await;
+            guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+        };
+        move_file_to_chat(&client, client_ref.clone(), &file, target_folder, target_chat_id, app_handle).await?
+    };
+
+    let mut metadata = load_metadata_copy().await?;
+    let entry = metadata.files.iter_mut()
+        .find(|f| f.id == moved.id)
+        .ok_or_else(|| anyhow::anyhow!("File not found"))?;
+    entry.folder = target_folder.to_string();
+    entry.sort_index = None;  // A position in the old folder means nothing in the new one
+    entry.modified_at = Some(chrono::Utc::now().timestamp());
+    let updated = entry.clone();
+
+    save_metadata_local(&metadata).await?;
+    Ok(updated)
+}
+
+// Move a folder (with everything beneath it) under a new parent.
+// Each folder keeps its own channel, so this only rewrites paths in the metadata.
+pub async fn move_folder(folder_path: &str, new_parent: &str) -> Result<String> {
+    let mut metadata = load_metadata_copy().await?;
+
+    if !metadata.folders.iter().any(|f| f == folder_path) {
+        return Err(anyhow::anyhow!("Folder not found: {}", folder_path));
+    }
+    if new_parent != "/" && !metadata.folders.iter().any(|f| f == new_parent) {
+        return Err(anyhow::anyhow!("Destination folder not found: {}", new_parent));
+    }
+    if rebase_path(new_parent, folder_path, folder_path).is_some() {
+        return Err(anyhow::anyhow!("Cannot move {} into itself", folder_path));
+    }
+
+    let (parent, name) = split_folder_path(folder_path);
+    if parent == new_parent {
+        return Ok(folder_path.to_string());
+    }
+
+    let new_path = if new_parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", new_parent, name)
+    };
+    if metadata.folders.iter().any(|f| f == &new_path) {
+        return Err(anyhow::anyhow!("Folder already exists: {}", new_path));
+    }
+
+    for folder in metadata.folders.iter_mut() {
+        if let Some(rebased) = rebase_path(folder, folder_path, &new_path) {
+            *folder = rebased;
+        }
+    }
+    for meta in metadata.folder_metadata.iter_mut() {
+        if let Some(rebased) = rebase_path(&meta.path, folder_path, &new_path) {
+            meta.path = rebased;
+        }
+    }
+    let now = chrono::Utc::now().timestamp();
+    for file in metadata.files.iter_mut() {
+        if file.is_folder && folder_entry_path(file) == folder_path {
+            file.folder = new_parent.to_string();
+        } else if let Some(rebased) = rebase_path(&file.folder, folder_path, &new_path) {
+            file.folder = rebased;
+        } else {
+            continue;
+        }
+        file.modified_at = Some(now);
+    }
+
+    save_metadata_local(&metadata).await?;
+    Ok(new_path)
+}
+
+// Copy a file into `target_folder` as a new entry. The message is forwarded server-side
+// (which also works within one chat); only a chat that blocks forwarding makes it download
+// and upload again. Only the current version is copied.
+pub async fn copy_file(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file_id: &str,
+    target_folder: &str,
+    app_handle: tauri::AppHandle,
+) -> Result<FileMetadata> {
+    let metadata = load_metadata_copy().await?;
+    let file = find_file_entry(&metadata, file_id)?;
+    let message_id = file.message_id
+        .ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+    let target_chat_id = destination_chat_id(&metadata, target_folder).await?;
+
+    let client = {
+        let guard = client_ref.lock().await;
+        guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let source = resolve_file_chat(&client, file.chat_id).await?;
+    let destination = resolve_file_chat(&client, target_chat_id).await?;
+    // Held until the copy is saved; the re-upload fallback reserves on its own
+    let quota = reserve_quota(file.size).await?;
+    let new_message_id = match crate::telegram::forward_message(&client, &source, &destination, message_id).await {
+        Ok(id) => id,
+        Err(e) if crate::telegram::is_forward_restricted(&e.to_string()) => {
+            println!("Forwarding restricted for {}, copying by download + re-upload", file.name);
+            drop(quota);
+            return upload_copy_to_folder(client_ref, &file, target_folder, Some(&file.id), app_handle).await;
+        }
+        Err(e) => return Err(e),
+    };
+
+    let copy = FileMetadata {
+        id: message_file_id(target_chat_id, new_message_id),
+        message_id: Some(new_message_id),
+        chat_id: target_chat_id,
+        folder: target_folder.to_string(),
+        versions: Vec::new(),
+        plain_versions: Vec::new(),
+        modified_at: None,
+        copy_of: Some(file.id.clone()),
+        sort_index: None,
+        health: None,
+        updated_at: Some(chrono::Utc::now().timestamp()),
+        ..file
+    };
+    let mut metadata = load_metadata_copy().await?;
+    metadata.files.push(copy.clone());
+    save_metadata_local(&metadata).await?;
+    Ok(copy)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderCopyReport {
+    pub path: String,            // The copy's root folder
+    pub folders_created: usize,
+    pub copied: usize,
+    pub skipped: usize,          // Already copied there, e.g. by an earlier run
+    pub failed: Vec<BatchFailure>,
+}
+
+const MAX_COPY_ATTEMPTS: u32 = 3;
+
+// Whether `folder` already holds a copy of the entry `source_id`
+fn has_copy_in(metadata: &MetadataStore, source_id: &str, folder: &str) -> bool {
+    metadata.files.iter()
+        .any(|f| !f.is_folder && f.folder == folder && f.copy_of.as_deref() == Some(source_id))
+}
+
+// Recreate `folder_path` and everything beneath it under `target_parent`, copying every file
+// with copy_file. Copying into a folder that already exists merges into it, so running it
+// again after a cancel or failure picks up the files still missing.
+// Reports (files done, total) as it goes.
+pub async fn copy_folder(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+    target_parent: &str,
+    cancel: &crate::operations::CancelToken,
+    on_progress: impl Fn(usize, usize),
+    app_handle: tauri::AppHandle,
+) -> Result<FolderCopyReport> {
+    let metadata = load_metadata_copy().await?;
+
+    if !metadata.folders.iter().any(|f| f == folder_path) || folder_path == "/" {
+        return Err(anyhow::anyhow!("Folder not found: {}", folder_path));
+    }
+    if target_parent != "/" && !metadata.folders.iter().any(|f| f == target_parent) {
+        return Err(anyhow::anyhow!("Destination folder not found: {}", target_parent));
+    }
+    if rebase_path(target_parent, folder_path, folder_path).is_some() {
+        return Err(anyhow::anyhow!("Cannot copy {} into itself", folder_path));
+    }
+
+    let (_, name) = split_folder_path(folder_path);
+    let new_root = if target_parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", target_parent, name)
+    };
+    if new_root == folder_path {
+        return Err(anyhow::anyhow!("{} is already in {}", folder_path, target_parent));
+    }
+    let mut report = FolderCopyReport { path: new_root.clone(), ..Default::default() };
+
+    // Parents sort before their children, so each folder's parent exists when it's created
+    let mut folders: Vec<(String, String)> = metadata.folders.iter()
+        .filter_map(|f| rebase_path(f, folder_path, &new_root).map(|copy| (f.clone(), copy)))
+        .collect();
+    folders.sort_by(|a, b| a.1.cmp(&b.1));
+    for (source, copy) in &folders {
+        let kind = metadata.folder_metadata.iter()
+            .find(|m| &m.path == source)
+            .map(|m| m.kind)
+            .unwrap_or_default();
+        let (parent, name) = split_folder_path(copy);
+        let ensured = ensure_folder(client_ref.clone(), &name, &parent, kind).await
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", copy, e))?;
+        if ensured.created {
+            report.folders_created += 1;
+        }
+    }
+
+    let files: Vec<(FileMetadata, String)> = metadata.files.iter()
+        .filter(|f| !f.is_folder)
+        .filter_map(|f| rebase_path(&f.folder, folder_path, &new_root).map(|copy| (f.clone(), copy)))
+        .collect();
+    let total = files.len();
+
+    for (index, (file, target_folder)) in files.iter().enumerate() {
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        if has_copy_in(&load_metadata_copy().await?, &file.id, target_folder) {
+            report.skipped += 1;
+            on_progress(index + 1, total);
+            continue;
+        }
+
+        let mut attempt = 0;
+        let result = loop {
+            attempt += 1;
+            match copy_file(client_ref.clone(), &file.id, target_folder, app_handle.clone()).await {
+                Err(e) if attempt < MAX_COPY_ATTEMPTS && !cancel.is_cancelled() => {
+                    // Slow down for flood waits the same way uploads do, then try again
+                    match extract_flood_wait(&e.to_string().to_lowercase()).filter(|&secs| secs <= MAX_FLOOD_WAIT_SECS) {
+                        Some(secs) => {
+                            crate::transfers::record_flood_wait(secs).await;
+                            tokio::time::sleep(tokio::time::Duration::from_secs(secs)).await;
+                        }
+                        None => break Err(e),
+                    }
+                }
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(_) => report.copied += 1,
+            Err(e) => report.failed.push(BatchFailure {
+                item: format!("{}/{}", file.folder.trim_end_matches('/'), file.name),
+                error: e.to_string(),
+            }),
+        }
+        on_progress(index + 1, total);
+
+        tokio::time::sleep(crate::transfers::next_upload_delay().await).await;
+    }
+
+    println!(
+        "Copied {} to {}: {} files copied, {} skipped, {} failed",
+        folder_path, new_root, report.copied, report.skipped, report.failed.len()
+    );
+    Ok(report)
+}
+
+// All folders with their channel and appearance, sorted by path
+pub async fn list_folders() -> Result<Vec<FolderMetadata>> {
+    let metadata = load_metadata_copy().await?;
+
+    let mut folders: Vec<FolderMetadata> = metadata.folders.iter()
+        .map(|path| {
+            metadata.folder_metadata.iter()
+                .find(|m| &m.path == path)
+                .cloned()
+                .unwrap_or_else(|| FolderMetadata {
+                    path: path.clone(),
+                    chat_id: None,
+                    chat_title: None,
+                    created_at: 0,
+                    kind: ChannelKind::default(),
+                    color: None,
+                    icon: None,
+                })
+        })
+        .collect();
+    folders.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(folders)
+}
+
+// Set a folder's color and icon. Purely local; empty values clear them.
+pub async fn set_folder_appearance(
+    folder_path: &str,
+    color: Option<String>,
+    icon: Option<String>,
+) -> Result<FolderMetadata> {
+    let mut metadata = load_metadata_copy().await?;
+    let updated = apply_folder_appearance(&mut metadata, folder_path, color, icon)?;
+
+    save_metadata_local(&metadata).await?;
+    Ok(updated)
+}
+
+fn apply_folder_appearance(
+    metadata: &mut MetadataStore,
+    folder_path: &str,
+    color: Option<String>,
+    icon: Option<String>,
+) -> Result<FolderMetadata> {
+    if !metadata.folders.iter().any(|f| f == folder_path) {
+        return Err(anyhow::anyhow!("Folder not found: {}", folder_path));
+    }
+
+    let color = color.map(|c| c.trim().to_string()).filter(|c| !c.is_empty());
+    let icon = icon.map(|i| i.trim().to_string()).filter(|i| !i.is_empty());
+
+    // Legacy folders have no folder_metadata entry yet; give them one without a channel.
+    // Uploads still treat the folder as legacy and create its channel (folder_upload_chat).
+    let pos = match metadata.folder_metadata.iter().position(|m| m.path == folder_path) {
+        Some(pos) => pos,
+        None => {
+            metadata.folder_metadata.push(FolderMetadata {
+                path: folder_path.to_string(),
+                chat_id: None,
+                chat_title: None,
+                created_at: chrono::Utc::now().timestamp(),
+                kind: ChannelKind::default(),
+                color: None,
+                icon: None,
+            });
+            metadata.folder_metadata.len() - 1
+        }
+    };
+
+    let meta = &mut metadata.folder_metadata[pos];
+    meta.color = color;
+    meta.icon = icon;
+    Ok(meta.clone())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PreservingDeleteReport {
+    pub files_relocated: usize,
+    pub folders_relocated: usize,
+    pub failed: Vec<BatchFailure>,
+    pub deleted: bool,  // False if anything failed to move; the folder is kept so nothing is lost
+}
+
+// Delete a folder but keep its contents: files directly inside are moved into `move_to`,
+// subfolders are re-parented under it, and only then is the emptied folder and its channel removed
+pub async fn delete_folder_preserving(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    folder_path: &str,
+    move_to: &str,
+    app_handle: tauri::AppHandle,
+) -> Result<PreservingDeleteReport> {
+    if folder_path == "/" {
+        return Err(anyhow::anyhow!("Cannot delete the root folder"));
+    }
+    if rebase_path(move_to, folder_path, folder_path).is_some() {
+        return Err(anyhow::anyhow!("Cannot move contents of {} into itself", folder_path));
+    }
+
+    let metadata = load_metadata_copy().await?;
+    if !metadata.folders.iter().any(|f| f == folder_path) {
+        return Err(anyhow::anyhow!("Folder not found: {}", folder_path));
+    }
+    // Fail early rather than half-way through the moves
+    folder_chat_id(&metadata, move_to)?;
+
+    let files: Vec<FileMetadata> = metadata.files.iter()
+        .filter(|f| !f.is_folder && f.folder == folder_path)
+        .cloned()
+        .collect();
+    let subfolders: Vec<String> = metadata.files.iter()
+        .filter(|f| f.is_folder && f.folder == folder_path)
+        .map(folder_entry_path)
+        .collect();
+
+    let mut report = PreservingDeleteReport::default();
+
+    for file in &files {
+        match move_file(client_ref.clone(), &file.id, move_to, app_handle.clone()).await {
+            Ok(_) => report.files_relocated += 1,
+            Err(e) => report.failed.push(BatchFailure { item: file.name.clone(), error: e.to_string() }),
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+    }
+
+    for subfolder in &subfolders {
+        match move_folder(subfolder, move_to).await {
+            Ok(_) => report.folders_relocated += 1,
+            Err(e) => report.failed.push(BatchFailure { item: subfolder.clone(), error: e.to_string() }),
+        }
+    }
+
+    if report.failed.is_empty() {
+        report.deleted = delete_folder(client_ref, folder_path).await?;
+    }
+
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderMigrationStatus {
+    pub path: String,
+    pub files: usize,        // Files of this folder still in Saved Messages
+    pub bytes: u64,
+    pub has_channel: bool,   // false = migrate_files_to_folders will skip these until one exists
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationStatus {
+    pub folders: Vec<FolderMigrationStatus>,  // Sorted by path
+    pub total_files: usize,
+    pub total_bytes: u64,
+}
+
+// Folders whose files still sit in Saved Messages, counted the way migrate_files picks them
+fn pending_migration(store: &MetadataStore) -> MigrationStatus {
+    let mut folders: std::collections::BTreeMap<&str, FolderMigrationStatus> = std::collections::BTreeMap::new();
+    for file in store.files.iter().filter(|f| !f.is_folder && f.folder != "/" && f.chat_id.is_none()) {
+        let entry = folders.entry(file.folder.as_str()).or_insert_with(|| FolderMigrationStatus {
+            path: file.folder.clone(),
+            has_channel: store.folder_metadata.iter().any(|m| m.path == file.folder && m.chat_id.is_some()),
+            ..Default::default()
+        });
+        entry.files += 1;
+        entry.bytes += file.size;
+    }
+
+    let folders: Vec<FolderMigrationStatus> = folders.into_values().collect();
+    MigrationStatus {
+        total_files: folders.iter().map(|f| f.files).sum(),
+        total_bytes: folders.iter().map(|f| f.bytes).sum(),
+        folders,
+    }
+}
+
+// What a migration would have to move. Read from the local metadata only.
+pub async fn migration_status() -> Result<MigrationStatus> {
+    let metadata = load_metadata_copy().await?;
+    Ok(pending_migration(&metadata))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationReport {
+    pub total: usize,
+    pub migrated: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+// Migration checkpoints metadata to disk this often by default instead of after every file
+pub const MIGRATION_SAVE_EVERY: usize = 25;
+
+/// Migrate existing files from Saved Messages to folder-specific channels.
+/// Metadata is written every `save_every` files (0 = once at the end); the in-memory
+/// store is current throughout and anything held back is written even if migration fails.
+pub async fn migrate_files_to_folders(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    on_progress: impl Fn(String, u32, u32) + Send + Sync + 'static,
+    app_handle: tauri::AppHandle,
+    save_every: usize,
+) -> Result<MigrationReport> {
+    METADATA_BATCH.scope(std::cell::RefCell::new(SaveBatcher::new(save_every)), async move {
+        let result = migrate_files(client_ref, on_progress, app_handle).await;
+        let flushed = finish_metadata_batch().await;
+        if let Ok(writes) = flushed {
+            println!("Migration wrote metadata {} time(s)", writes);
+        }
+        let report = result?;
+        flushed?;
+        Ok(report)
+    }).await
+}
+
+async fn migrate_files(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    on_progress: impl Fn(String, u32, u32) + Send + Sync + 'static,
+    app_handle: tauri::AppHandle,
+) -> Result<MigrationReport> {
+    let metadata = load_metadata_copy().await?;
+    
+    // Collect files that need migration (in folders but no chat_id)
+    let files_to_migrate: Vec<FileMetadata> = metadata.files.iter()
+        .filter(|f| !f.is_folder && f.folder != "/" && f.chat_id.is_none())
+        .cloned()
+        .collect();
+    
+    let total_files = files_to_migrate.len();
+    if total_files == 0 {
+        return Ok(MigrationReport { total: 0, migrated: 0, failed: 0, skipped: 0 });
+    }
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+
+    let mut migrated = 0;
+    let mut failed = 0;
+    let mut skipped = 0;
+    
+    for (index, file) in files_to_migrate.iter().enumerate() {
+        on_progress(file.name.clone(), index as u32 + 1, total_files as u32);
+        
+        // Check if folder has a channel
+        let folder_chat_id = metadata.folder_metadata.iter()
+            .find(|fm| fm.path == file.folder)
+            .and_then(|fm| fm.chat_id);
+        
+        let folder_chat_id = match folder_chat_id {
+            Some(id) => id,
+            None => {
+                // Folder doesn't have a channel yet - skip this file
+                eprintln!("Skipping {}: folder {} has no associated channel", file.name, file.folder);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        // Forwarded server-side where possible, downloaded and re-uploaded where that's blocked
+        match move_file_to_chat(&client, client_ref.clone(), file, &file.folder, Some(folder_chat_id), app_handle.clone()).await {
+            Ok(_) => {
+                migrated += 1;
+                println!("Migrated: {} to folder {}", file.name, file.folder);
+            }
+            Err(e) => {
+                eprintln!("Failed to migrate {}: {}", file.name, e);
+                failed += 1;
+            }
+        }
+        
+        // Add delay between migrations to avoid rate limits
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    }
+    
+    Ok(MigrationReport {
+        total: total_files,
+        migrated,
+        failed,
+        skipped,
+    })
+}
+
+fn key_check_text(check: &crate::encryption::KeyCheck) -> Result<String> {
+    Ok(format!("{}{}", KEY_CHECK_PREFIX, URL_SAFE_NO_PAD.encode(serde_json::to_vec(check)?)))
+}
+
+fn key_check_from_text(text: &str) -> Option<crate::encryption::KeyCheck> {
+    let payload = text.trim().strip_prefix(KEY_CHECK_PREFIX)?;
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+}
+
+// Whether `encryptor` decrypts the stored copy of `file`
+async fn decrypts_entry(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file: &FileMetadata,
+    encryptor: &crate::encryption::Encryptor,
+) -> Result<bool> {
+    let temp_dir = std::env::temp_dir().join("tvault_key_check");
+    tokio::fs::create_dir_all(&temp_dir).await?;
+    let sealed_path = temp_dir.join(file.id.replace(':', "_"));
+    let plain_path = sealed_path.with_extension("plain");
+
+    let result = async {
+        download_entry(client_ref, file, &sealed_path.to_string_lossy(), ProgressConfig::silent(), |_, _, _| {}).await?;
+        Ok::<_, anyhow::Error>(crate::encryption::decrypt_file(encryptor, &sealed_path, &plain_path).await.is_ok())
+    }.await;
+    let _ = tokio::fs::remove_file(&sealed_path).await;
+    let _ = tokio::fs::remove_file(&plain_path).await;
+    result
+}
+
+// The vault key `password` gives, refused unless it passes the vault's key check. The first
+// unlock sets the check up: a vault without encrypted files gets a fresh salted key, one with
+// files encrypted before the check existed keeps its unsalted key once it decrypts the
+// smallest of them. The check goes to Saved Messages too, so a rebuild brings it back.
+pub async fn vault_key(client_ref: Option<Arc<Mutex<Option<Client>>>>, password: &str) -> Result<crate::encryption::Encryptor> {
+    let metadata = load_metadata_copy().await?;
+    if let Some(ref check) = metadata.key_check {
+        return check.open(password);
+    }
+
+    let client_ref = client_ref
+        .ok_or_else(|| anyhow::anyhow!("Log in first: the vault password is checked against Telegram the first time"))?;
+    let sample = metadata.files.iter()
+        .filter(|f| !f.is_folder && f.encrypted && f.message_id.is_some())
+        .min_by_key(|f| f.size)
+        .cloned();
+    let (check, encryptor) = match sample {
+        Some(sample) => {
+            let encryptor = crate::encryption::Encryptor::new(password);
+            if !decrypts_entry(client_ref.clone(), &sample, &encryptor).await? {
+                return Err(anyhow::anyhow!("Wrong vault password: it doesn't decrypt {}", sample.name));
+            }
+            (crate::encryption::KeyCheck::legacy(&encryptor)?, encryptor)
+        }
+        None => crate::encryption::KeyCheck::create(password)?,
+    };
+
+    let client = shared_client(&client_ref).await?;
+    let me = client.get_me().await
+        .map_err(|e| anyhow::anyhow!("Failed to get user info: {}", e))?;
+    let peer_ref = Peer::User(me).to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+    client.send_message(peer_ref, InputMessage::new().text(key_check_text(&check)?)).await
+        .map_err(|e| anyhow::anyhow!("Failed to store the key check on Telegram: {}", e))?;
+
+    let mut metadata = load_metadata_copy().await?;
+    metadata.key_check = Some(check);
+    save_metadata_local(&metadata).await?;
+    println!("Vault key check set up");
+    Ok(encryptor)
+}
+
+// Re-upload every unencrypted file encrypted with the unlocked vault key, replacing its
+// message in the same chat once the new copy has been read back and decrypted. Each file
+// is committed on its own, so running it again after a cancel or a failure picks up the
+// files still left. Older versions stay as they were and are listed in plain_versions,
+// so they still download without decryption.
+pub async fn encrypt_existing_vault(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    cancel: &crate::operations::CancelToken,
+    retry_budget: Arc<crate::operations::RetryBudget>,
+    on_progress: impl Fn(&str, usize, usize),
+    app_handle: tauri::AppHandle,
+) -> Result<BatchReport> {
+    if crate::encryption::session_encryptor().is_none() {
+        return Err(TvaultError::VaultLocked.into());
+    }
+
+    let metadata = load_metadata_copy().await?;
+    let files: Vec<FileMetadata> = metadata.files.iter()
+        .filter(|f| !f.is_folder && !f.encrypted && f.message_id.is_some())
+        .cloned()
+        .collect();
+
+    let mut report = BatchReport {
+        total: files.len(),
+        ..Default::default()
+    };
+    if files.is_empty() {
+        return Ok(report);
+    }
+
+    let client = {
+        let client_guard = client_ref.lock().await;
+        client_guard.as_ref().cloned().ok_or_else(|| anyhow::anyhow!("Client not initialized"))?
+    };
+    let temp_root = std::env::temp_dir().join("tvault_encrypt");
+
+    for (index, file) in files.iter().enumerate() {
+        if cancel.is_cancelled() {
+            println!("Vault encryption cancelled after {} of {} files", index, files.len());
+            break;
+        }
+        if !retry_budget_checkpoint(&retry_budget, &app_handle).await {
+            break;
+        }
+        on_progress(&file.name, index, files.len());
+
+        // One directory per file keeps the original name, which the upload takes from the path
+        let temp_dir = temp_root.join(file.id.replace(':', "_"));
+        let result = encrypt_file_in_place(&client, client_ref.clone(), file, &temp_dir, retry_budget.clone(), app_handle.clone()).await;
+        let _ = tokio::fs::remove_dir_all(&temp_dir).await;
+
+        match result {
+            Ok(()) => report.succeeded += 1,
+            Err(e) => {
+                eprintln!("Failed to encrypt {}: {}", file.name, e);
+                report.failed.push(BatchFailure { item: file.id.clone(), error: e.to_string() });
+            }
+        }
+    }
+    on_progress("", report.succeeded + report.failed.len(), files.len());
+
+    if let Err(e) = crate::bandwidth::flush().await {
+        eprintln!("Warning: Failed to save bandwidth stats: {}", e);
+    }
+
+    Ok(report)
+}
+
+async fn encrypt_file_in_place(
+    client: &Client,
+    client_ref: Arc<Mutex<Option<Client>>>,
+    file: &FileMetadata,
+    temp_dir: &Path,
+    retry_budget: Arc<crate::operations::RetryBudget>,
+    app_handle: tauri::AppHandle,
+) -> Result<()> {
+    let old_message_id = file.message_id.ok_or_else(|| anyhow::anyhow!("No message ID for file"))?;
+    let chat: Peer = match file.chat_id {
+        Some(chat_id) => crate::telegram::get_chat_peer(client, chat_id).await?,
+        None => Peer::User(client.get_me().await?),
+    };
+
+    tokio::fs::create_dir_all(temp_dir).await?;
+    let temp_path = temp_dir.join(&file.name);
+    let saved_as = download_file(client_ref.clone(), &file.id, &temp_path.to_string_lossy(), ProgressConfig::silent(), |_, _, _| {}).await
+        .map_err(|e| anyhow::anyhow!("Download failed: {}", e))?;
+
+    let options = UploadOptions {
+        description: file.description.clone(),
+        progress: ProgressConfig::silent(),
+        target: Some(UploadTarget { chat: chat.clone(), chat_id: file.chat_id }),
+        encrypt: true,
+        retry_budget: Some(retry_budget),
+        created_at: Some(file.created_at),
+        custom: file.custom.clone(),
+        ..Default::default()
+    };
+    let new_message_id = upload_file(client_ref.clone(), &saved_as, &file.folder, options, |_, _, _| {}, app_handle).await
+        .map_err(|e| anyhow::anyhow!("Upload failed: {}", e))?;
+    let new_id = format!("{}:{}", file.chat_id.map(|id| id.to_string()).unwrap_or_else(|| "saved".to_string()), new_message_id);
+    let peer_ref = chat.to_ref().ok_or_else(|| anyhow::anyhow!("Failed to get peer reference"))?;
+
+    // Read the encrypted copy back before trusting it; a copy that doesn't decrypt to the
+    // same bytes is dropped and the plaintext original stays
+    if let Err(e) = confirm_encrypted_copy(client_ref, &new_id, &saved_as, temp_dir).await {
+        let mut metadata = load_metadata_copy().await?;
+        metadata.files.retain(|f| f.id != new_id);
+        save_metadata_local(&metadata).await?;
+        if let Ok(message_id) = new_message_id.parse::<i32>() {
+            if let Err(delete_error) = client.delete_messages(peer_ref, &[message_id]).await {
+                eprintln!("Warning: Failed to delete unconfirmed encrypted copy {}: {}", message_id, delete_error);
+            }
+        }
+        return Err(anyhow::anyhow!("Encrypted copy could not be confirmed: {}", e));
+    }
+
+    // The encrypted copy takes over the entry's place, order and history
+    let mut metadata = load_metadata_copy().await?;
+    if let Some(entry) = metadata.files.iter_mut().find(|f| f.id == new_id) {
+        entry.name = file.name.clone();
+        entry.created_at = file.created_at;
+        entry.sort_index = file.sort_index;
+        // Those versions are still the plaintext uploads
+        entry.versions = file.versions.clone();
+        entry.plain_versions = file.versions.clone();
+    }
+    metadata.files.retain(|f| f.id != file.id);
+    save_metadata_local(&metadata).await?;
+
+    // Only now drop the plaintext message; if that fails the user has to remove it by hand
+    client.delete_messages(peer_ref, &[old_message_id]).await
+        .map_err(|e| anyhow::anyhow!(
+            "Encrypted copy saved, but the unencrypted message {} could not be deleted: {}", old_message_id, e
+        ))?;
+
+    Ok(())
+}
+
+// Download a freshly encrypted upload and check it decrypts to the plaintext it came from
+async fn confirm_encrypted_copy(
+    client_ref: Arc<Mutex<Option<Client>>>,
+    new_id: &str,
+    plaintext: &str,
+    temp_dir: &Path,
+) -> Result<()> {
+    let check_dir = temp_dir.join("check");
+    tokio::fs::create_dir_all(&check_dir).await?;
+    let file_name = Path::new(plaintext).file_name().ok_or_else(|| anyhow::anyhow!("Invalid upload path"))?;
+    let check_path = check_dir.join(file_name);
+    let read_back = download_file(client_ref, new_id, &check_path.to_string_lossy(), ProgressConfig::silent(), |_, _, _| {}).await
+        .map_err(|e| anyhow::anyhow!("Download failed: {}", e))?;
+
+    let expected = file_sha256(Path::new(plaintext)).await?;
+    verify_sha256(Path::new(&read_back), &expected).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, folder: &str) -> FileMetadata {
+        FileMetadata {
+            id: format!("saved:{}", name),
+            name: name.to_string(),
+            size: 1,
+            mime_type: "text/plain".to_string(),
+            created_at: 0,
+            folder: folder.to_string(),
+            is_folder: false,
+            thumbnail: None,
+            message_id: None,
+            encrypted: false,
+            chat_id: None,
+            description: None,
+            versions: Vec::new(),
+            sha256: None,
+            sort_index: None,
+            health: None,
+            as_photo: false,
+            updated_at: None,
+            custom: BTreeMap::new(),
+            plain_versions: Vec::new(),
+            modified_at: None,
+            copy_of: None,
+        }
+    }
+
+    fn folder_entry(path: &str, chat_id: Option<i64>) -> FileMetadata {
+        let (parent, name) = split_folder_path(path);
+        FileMetadata {
+            id: format!("folder_{}", name),
+            mime_type: "folder".to_string(),
+            is_folder: true,
+            chat_id,
+            size: 0,
+            ..file(&name, &parent)
+        }
+    }
+
+    fn folder_meta(path: &str, chat_id: Option<i64>) -> FolderMetadata {
+        FolderMetadata {
+            path: path.to_string(),
+            chat_id,
+            chat_title: None,
+            created_at: 0,
+            kind: ChannelKind::Broadcast,
+            color: None,
+            icon: None,
+        }
+    }
+
+    #[test]
+    fn test_reconcile_adds_missing_folder_path() {
+        let mut store = MetadataStore::new();
+        store.folder_metadata.push(folder_meta("/Docs", Some(1)));
+        store.files.push(folder_entry("/Docs", Some(1)));
+
+        let report = reconcile_folders(&mut store);
+
+        assert_eq!(report.added_to_folders, vec!["/Docs".to_string()]);
+        assert!(store.folders.contains(&"/Docs".to_string()));
+    }
+
+    #[test]
+    fn test_reconcile_adds_missing_virtual_entry() {
+        let mut store = MetadataStore::new();
+        store.folders.push("/Photos/Trips".to_string());
+        store.folder_metadata.push(folder_meta("/Photos/Trips", Some(7)));
+
+        let report = reconcile_folders(&mut store);
+
+        assert_eq!(report.added_entries, vec!["/Photos/Trips".to_string()]);
+        let entry = store.files.iter().find(|f| f.is_folder).unwrap();
+        assert_eq!(entry.folder, "/Photos");
+        assert_eq!(entry.name, "Trips");
+        assert_eq!(entry.chat_id, Some(7));
+    }
+
+    #[test]
+    fn test_reconcile_fixes_entry_chat_id() {
+        let mut store = MetadataStore::new();
+        store.folders.push("/Docs".to_string());
+        store.folder_metadata.push(folder_meta("/Docs", Some(2)));
+        store.files.push(folder_entry("/Docs", Some(1)));
+
+        let report = reconcile_folders(&mut store);
+
+        assert_eq!(report.fixed_chat_ids, vec!["/Docs".to_string()]);
+        assert_eq!(store.files[0].chat_id, Some(2));
+    }
+
+    #[test]
+    fn test_reconcile_flags_legacy_folder_and_dedupes() {
+        let mut store = MetadataStore::new();
+        store.folders.push("/Old".to_string());
+        store.folders.push("/Old".to_string());
+        store.files.push(folder_entry("/Old", None));
+
+        let report = reconcile_folders(&mut store);
+
+        assert_eq!(report.removed_duplicates, 1);
+        assert_eq!(report.needs_upgrade, vec!["/Old".to_string()]);
+        assert_eq!(store.folders, vec!["/".to_string(), "/Old".to_string()]);
+    }
+
+    #[test]
+    fn test_reconcile_consistent_store_is_unchanged() {
+        let mut store = MetadataStore::new();
+        store.folders.push("/Docs".to_string());
+        store.folder_metadata.push(folder_meta("/Docs", Some(1)));
+        store.files.push(folder_entry("/Docs", Some(1)));
+        store.files.push(file("a.txt", "/Docs"));
+
+        let report = reconcile_folders(&mut store);
+
+        assert!(!report.changed());
+        assert!(report.needs_upgrade.is_empty());
+    }
+
+    #[test]
+    fn test_caption_round_trip() {
+        let trailer = CaptionTrailer { folder: "/Docs".to_string(), ..Default::default() };
+        let caption = build_caption("🗄 ", "notes.txt", Some("weekly log"), &trailer);
+        assert!(caption.lines().last().unwrap().starts_with("#tvault:v1:"));
+        assert_eq!(
+            parse_caption(&caption, "🗄 "),
+            Some(("notes.txt".to_string(), Some("weekly log".to_string())))
+        );
+        assert_eq!(
+            parse_caption(&build_caption("", "a.bin", None, &CaptionTrailer::default()), ""),
+            Some(("a.bin".to_string(), None))
+        );
+        // Captions written before the trailer existed end in the bare marker
+        assert_eq!(parse_caption("🗄 old.txt\n\n#tvault", "🗄 "), Some(("old.txt".to_string(), None)));
+        // Made under the default prefix before it was changed, or sent with a variation selector
+        let default_caption = build_caption(DEFAULT_CAPTION_PREFIX, "plan.md", None, &CaptionTrailer::default());
+        assert_eq!(parse_caption(&default_caption, "🗄 "), Some(("plan.md".to_string(), None)));
+        assert_eq!(parse_caption("🗄\u{fe0f} old.txt\n\n#tvault", "🗄 "), Some(("old.txt".to_string(), None)));
+    }
+
+    #[test]
+    fn test_folder_path_from_title() {
+        assert_eq!(folder_path_from_title("T-Vault: /Photos/Trips"), Some("/Photos/Trips".to_string()));
+        assert_eq!(folder_path_from_title("T-Vault:/Docs/"), Some("/Docs".to_string()));
+        assert_eq!(folder_path_from_title("T-Vault: /"), None);
+        assert_eq!(folder_path_from_title("Family chat"), None);
+    }
+
+    #[test]
+    fn test_caption_trailer_round_trip() {
+        let trailer = CaptionTrailer {
+            folder: "/Photos/2024".to_string(),
+            name: Some("img.jpg".into()),
+            tags: vec!["holiday".to_string()],
+            description: Some("beach".to_string()),
+            sha256: Some("ab".repeat(32)),
+            encrypted: true,
+            custom: BTreeMap::from([("camera".to_string(), "x100".to_string())]),
+            created_at: Some(1_600_000_000),
+        };
+        let caption = build_caption(DEFAULT_CAPTION_PREFIX, "img.jpg", Some("beach"), &trailer);
+        assert_eq!(parse_caption_trailer(&caption), Some(trailer));
+    }
+
+    #[test]
+    fn test_caption_trailer_missing_or_unknown() {
+        assert_eq!(parse_caption_trailer("📁 old.pdf"), None);
+        assert_eq!(parse_caption_trailer("📁 old.pdf\n\n#tvault"), None);
+        assert_eq!(parse_caption_trailer("📁 a.bin\n\n#tvault:v2:e30"), None);
+        assert_eq!(parse_caption_trailer("📁 a.bin\n\n#tvault:v1:not base64!"), None);
+    }
+
+    #[test]
+    fn test_caption_trailer_drops_long_description() {
+        let trailer = CaptionTrailer {
+            folder: "/".to_string(),
+            description: Some("y".repeat(900)),
+            ..Default::default()
+        };
+        let caption = build_caption("", "a.txt", trailer.description.as_deref(), &trailer);
+        assert!(caption.chars().count() <= MAX_CAPTION_CHARS);
+        assert_eq!(parse_caption_trailer(&caption).unwrap().description, None);
+    }
+
+    #[test]
+    fn test_parse_caption_legacy_and_unrelated() {
+        assert_eq!(parse_caption("📁 old.pdf", "🗄 "), Some(("old.pdf".to_string(), None)));
+        assert_eq!(parse_caption("random note", "🗄 "), None);
+        assert_eq!(parse_caption("talking about #tvault today", "🗄 "), None);
+    }
+
+    #[test]
+    fn test_clean_file_name() {
+        assert_eq!(clean_file_name("report.pdf", "🗄 "), "report.pdf");
+        assert_eq!(clean_file_name("📁 report.pdf", "🗄 "), "report.pdf");
+        assert_eq!(clean_file_name("🗄 report.pdf", "🗄 "), "report.pdf");
+        assert_eq!(clean_file_name("📁\u{fe0f}  report.pdf", "🗄 "), "report.pdf");
+        assert_eq!(clean_file_name("[TV] report.pdf", "[TV] "), "report.pdf");
+        assert_eq!(clean_file_name("report.pdf #tvault", "🗄 "), "report.pdf");
+        assert_eq!(clean_file_name("report.pdf\n\nnotes\n#tvault:v1:abc", "🗄 "), "report.pdf");
+        assert_eq!(clean_file_name("#tvault:v1:abc", "🗄 "), "");
+        // Real names are left alone
+        assert_eq!(clean_file_name("Ünïcode 中文.txt", "🗄 "), "Ünïcode 中文.txt");
+        assert_eq!(clean_file_name("my#tvault.txt", "🗄 "), "my#tvault.txt");
+        assert_eq!(clean_file_name("★ favourites.txt", "🗄 "), "★ favourites.txt");
+        assert_eq!(clean_file_name("🎵 song.mp3", "🗄 "), "🎵 song.mp3");
+    }
+
+    #[test]
+    fn test_caption_trailer_drops_name_before_marker() {
+        let trailer = CaptionTrailer {
+            folder: "/".to_string(),
+            name: Some("n".repeat(500)),
+            ..Default::default()
+        };
+        let line = build_caption_trailer(&trailer);
+        assert!(line.starts_with("#tvault:v1:"));
+        assert_eq!(parse_caption_trailer(&line).unwrap().name, None);
+    }
+
+    #[test]
+    fn test_caption_truncation_keeps_marker() {
+        let trailer = CaptionTrailer { folder: "/".to_string(), ..Default::default() };
+        let caption = build_caption(DEFAULT_CAPTION_PREFIX, "big.txt", Some(&"x".repeat(2000)), &trailer);
+        assert_eq!(caption.chars().count(), MAX_CAPTION_CHARS);
+        assert_eq!(parse_caption_trailer(&caption), Some(trailer));
+    }
+
+    #[test]
+    fn test_sniff_mime() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n...."), Some(("image/png", "png")));
+        assert_eq!(sniff_mime(b"%PDF-1.7"), Some(("application/pdf", "pdf")));
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8 "), Some(("image/webp", "webp")));
+        assert_eq!(sniff_mime(b"hello world"), None);
+        assert_eq!(sniff_mime(b""), None);
+    }
+
+    #[test]
+    fn test_extension_matches() {
+        assert!(extension_matches("photo.PNG", "image/png", "png"));
+        assert!(extension_matches("photo.jpeg", "image/jpeg", "jpg"));
+        assert!(extension_matches("report.docx", "application/zip", "zip"));
+        assert!(!extension_matches("photo", "image/png", "png"));
+        assert!(!extension_matches("notes.txt", "application/pdf", "pdf"));
+    }
+
+    #[test]
+    fn test_extract_flood_wait() {
+        assert_eq!(extract_flood_wait("rpc error: flood_wait_42"), Some(42));
+        assert_eq!(extract_flood_wait("rpc error 420: FLOOD_WAIT caused by upload.saveBigFilePart (value: 310)"), Some(310));
+        assert_eq!(extract_flood_wait("connection reset"), None);
+    }
+
+    #[test]
+    fn test_upload_size_limit() {
+        assert!(check_upload_size("a.bin", MAX_FILE_SIZE - 1).is_ok());
+        assert!(check_upload_size("a.bin", MAX_FILE_SIZE).is_ok());
+        let err = check_upload_size("a.bin", MAX_FILE_SIZE + 1).unwrap_err().to_string();
+        assert!(err.contains("2097152001 bytes"));
+        assert!(err.contains("2097152000 bytes"));
+    }
+
+    #[test]
+    fn test_validate_chunk_size() {
+        assert!(validate_chunk_size(4 * 1024).is_ok());
+        assert!(validate_chunk_size(128 * 1024).is_ok());
+        assert!(validate_chunk_size(512 * 1024).is_ok());
+        assert!(validate_chunk_size(2 * 1024).is_err());
+        assert!(validate_chunk_size(1024 * 1024).is_err());
+        assert!(validate_chunk_size(100 * 1024).is_err());
+        assert!(validate_chunk_size(0).is_err());
+    }
+
+    #[test]
+    fn test_largest_files() {
+        let mut files = vec![file("a", "/"), file("b", "/Docs"), file("c", "/"), file("d", "/")];
+        for (f, size) in files.iter_mut().zip([10, 500, 50, 200]) {
+            f.size = size;
+        }
+        files.push(folder_entry("/Docs", Some(1)));
+
+        let names = |found: Vec<&FileMetadata>| found.iter().map(|f| f.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(largest_files(&files, 0, None, 2)), ["b", "d"]);
+        assert_eq!(names(largest_files(&files, 20, Some(200), 10)), ["d", "c"]);
+        assert!(largest_files(&files, 0, None, 0).is_empty());
+    }
+
+    #[test]
+    fn test_export_entry_names() {
+        assert_eq!(export_entry_dir("/Photos", &file("a.jpg", "/Photos")), Some(String::new()));
+        assert_eq!(export_entry_dir("/Photos", &file("a.jpg", "/Photos/Trips")), Some("Trips/".to_string()));
+        assert_eq!(export_entry_dir("/Photos", &file("a.jpg", "/PhotosOld")), None);
+        assert_eq!(export_entry_dir("/", &file("a.jpg", "/Docs")), Some("Docs/".to_string()));
+
+        let mut used = HashSet::new();
+        assert_eq!(unique_entry_name(&mut used, "Trips/", "a.jpg"), "Trips/a.jpg");
+        assert_eq!(unique_entry_name(&mut used, "Trips/", "a.jpg"), "Trips/a (2).jpg");
+        assert_eq!(unique_entry_name(&mut used, "", "notes"), "notes");
+        assert_eq!(unique_entry_name(&mut used, "", "notes"), "notes (2)");
+    }
+
+    #[test]
+    fn test_folder_download_path() {
+        let dest = Path::new("out");
+        assert_eq!(folder_download_path(dest, "Trips/a.jpg"), dest.join("Trips").join("a.jpg"));
+        assert_eq!(folder_download_path(dest, "Trips/"), dest.join("Trips"));
+        assert_eq!(folder_download_path(dest, ""), dest.to_path_buf());
+        // Folder names can't climb out of the destination
+        assert_eq!(folder_download_path(dest, "../a.jpg"), dest.join("_").join("a.jpg"));
+    }
+
+    #[tokio::test]
+    async fn test_assemble_chunks_out_of_order() {
+        use rand::{SeedableRng, seq::SliceRandom};
+
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let chunk_size = 1024u64;
+        let mut order: Vec<u64> = (0..(data.len() as u64).div_ceil(chunk_size)).collect();
+        order.shuffle(&mut rand::rngs::StdRng::seed_from_u64(7));
+        let completed = std::sync::Mutex::new(Vec::new());
+        let (done_tx, _) = tokio::sync::watch::channel(0usize);
+
+        let path = std::env::temp_dir().join(format!("tvault_assemble_{}.part", std::process::id()));
+        let mut out = tokio::fs::File::create(&path).await.unwrap();
+        assert!(prepare_positioned_writes(&mut out, data.len() as u64).await);
+
+        let buffer = crate::transfers::ByteBudget::default();
+        let written = assemble_chunks(&mut out, data.len() as u64, chunk_size, order.len(), &buffer, u64::MAX, |index| {
+            let start = (index * chunk_size) as usize;
+            let part = data[start..(start + chunk_size as usize).min(data.len())].to_vec();
+            let rank = order.iter().position(|&i| i == index).unwrap();
+            let (completed, done_tx) = (&completed, &done_tx);
+            async move {
+                // Each part finishes right after the one before it in `order`
+                done_tx.subscribe().wait_for(|&done| done == rank).await.unwrap();
+                completed.lock().unwrap().push(index);
+                done_tx.send_modify(|done| *done += 1);
+                Ok(part)
+            }
+        }).await.unwrap();
+        drop(out);
+
+        let completed = completed.into_inner().unwrap();
+        assert_eq!(completed, order);
+        assert!(completed.windows(2).any(|w| w[0] > w[1]), "parts completed in order");
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), data);
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[tokio::test]
+    async fn test_assemble_chunks_rejects_short_part() {
+        let mut out = std::io::Cursor::new(Vec::new());
+        let buffer = crate::transfers::ByteBudget::default();
+        let result = assemble_chunks(&mut out, 3000, 1024, 2, &buffer, u64::MAX, |index| async move {
+            Ok(vec![0u8; if index == 1 { 10 } else { 1024 }])
+        }).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_save_batcher_reduces_writes() {
+        // A migration of 200 files saves twice per file (upload + delete)
+        let writes = |every: usize| {
+            let mut batch = SaveBatcher::new(every);
+            let written_now = (0..400).filter(|_| batch.record_save()).count();
+            let written_at_end = usize::from(batch.finish());
+            assert_eq!(batch.writes, written_now + written_at_end);
+            batch.writes
+        };
+
+        assert_eq!(writes(1), 400);
+        assert_eq!(writes(MIGRATION_SAVE_EVERY), 16);
+        assert_eq!(writes(300), 2);
+        assert_eq!(writes(0), 1);
+    }
+
+    #[test]
+    fn test_windows_safe_file_name() {
+        assert_eq!(windows_safe_file_name("report.pdf"), "report.pdf");
+        assert_eq!(windows_safe_file_name("a:b?c*.txt"), "a_b_c_.txt");
+        assert_eq!(windows_safe_file_name("notes. "), "notes");
+        assert_eq!(windows_safe_file_name("con.txt"), "_con.txt");
+        assert_eq!(windows_safe_file_name("Console.txt"), "Console.txt");
+        assert_eq!(windows_safe_file_name("..."), "file");
+
+        let long = format!("{}.jpeg", "é".repeat(300));
+        let safe = windows_safe_file_name(&long);
+        assert_eq!(safe.encode_utf16().count(), WINDOWS_MAX_NAME_UNITS);
+        assert!(safe.ends_with(".jpeg"));
+    }
+
+    #[test]
+    fn test_with_long_path_prefix() {
+        assert_eq!(with_long_path_prefix(r"C:\Users\a.txt"), r"C:\Users\a.txt");
+
+        let deep = format!(r"C:\{}\a.txt", "d".repeat(300));
+        assert_eq!(with_long_path_prefix(&deep), format!(r"\\?\{}", deep));
+        assert_eq!(with_long_path_prefix(&format!(r"\\?\{}", deep)), format!(r"\\?\{}", deep));
+
+        let share = format!(r"\\server\share\{}.txt", "s".repeat(300));
+        assert_eq!(with_long_path_prefix(&share), format!(r"\\?\UNC\server\share\{}.txt", "s".repeat(300)));
+    }
+
+    #[test]
+    fn test_compare_listing() {
+        let entry = |name: &str, created_at: i64, sort_index: Option<i64>| FileMetadata {
+            created_at,
+            sort_index,
+            ..file(name, "/")
+        };
+        let mut files = vec![
+            entry("old", 1, None),
+            entry("second", 5, Some(1)),
+            entry("new", 9, None),
+            entry("first", 2, Some(0)),
+        ];
+        files.sort_by(compare_listing);
+
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["first", "second", "new", "old"]);
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.50 KiB");
+        assert_eq!(format_bytes(MAX_FILE_SIZE), "1.95 GiB");
+    }
+
+    #[test]
+    fn test_rebase_path() {
+        assert_eq!(rebase_path("/A/B", "/A/B", "/X/B"), Some("/X/B".to_string()));
+        assert_eq!(rebase_path("/A/B/C", "/A/B", "/X/B"), Some("/X/B/C".to_string()));
+        assert_eq!(rebase_path("/A/BC", "/A/B", "/X/B"), None);
+        assert_eq!(rebase_path("/A", "/A/B", "/X/B"), None);
+    }
+
+    #[test]
+    fn test_duplicate_folders() {
+        let mut store = MetadataStore::new();
+        store.folder_metadata.push(folder_meta("/Docs", Some(1)));
+        store.folder_metadata.push(folder_meta("/Docs", Some(2)));
+        store.folder_metadata.push(folder_meta("/Docs", Some(2)));
+        store.folder_metadata.push(folder_meta("/Pics", Some(3)));
+        store.files.push(FileMetadata { chat_id: Some(2), ..file("a.txt", "/Docs") });
+
+        let duplicates = duplicate_folders(&store);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].path, "/Docs");
+        assert_eq!(duplicates[0].chat_ids, vec![1, 2]);
+        assert_eq!(duplicates[0].file_counts, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_audit_folder_channels() {
+        let mut store = MetadataStore::new();
+        store.folders = vec!["/".to_string(), "/Docs".to_string(), "/Pics".to_string(), "/Music".to_string()];
+        store.folder_metadata = vec![
+            folder_meta("/Docs", Some(1)),
+            folder_meta("/Pics", Some(2)),
+            folder_meta("/Music", Some(6)),
+            folder_meta("/Music", Some(5)),
+            folder_meta("/Music", Some(6)),
+        ];
+        store.files = vec![folder_entry("/Docs", Some(1)), folder_entry("/Pics", Some(2))];
+        let dialogs = vec![
+            (1, "T-Vault: /Docs".to_string()),
+            (2, "T-Vault: /Pics".to_string()),
+            (3, "T-Vault: /Pics".to_string()),
+            (4, "T-Vault: /Lost".to_string()),
+        ];
+
+        let audit = audit_folder_channels(&store, &dialogs);
+        let by_path = |path: &str| audit.iter().find(|a| a.path == path).unwrap();
+
+        assert!(!by_path("/Docs").duplicate && !by_path("/Docs").mismatch);
+        assert!(by_path("/Pics").duplicate && by_path("/Pics").mismatch);
+        assert_eq!(by_path("/Pics").dialog_channels, [2, 3]);
+        assert!(by_path("/Lost").mismatch && by_path("/Lost").metadata_channels.is_empty());
+        // Repeated records of a channel count once, wherever they sit
+        assert_eq!(by_path("/Music").metadata_channels, [5, 6]);
+    }
+
+    #[test]
+    fn test_compact_store_removes_duplicates() {
+        let mut store = MetadataStore::new();
+        store.folders.push("/Docs".to_string());
+        store.folder_metadata.push(folder_meta("/Docs", Some(7)));
+        store.folder_metadata.push(folder_meta("/Docs", Some(7)));
+        store.files.push(folder_entry("/Docs", Some(7)));
+        store.files.push(folder_entry("/Docs", Some(7)));
+        store.files.push(FileMetadata { message_id: Some(1), ..file("a.txt", "/") });
+        store.files.push(FileMetadata { message_id: Some(1), ..file("a.txt", "/") });
+
+        let (removed_files, removed_folder_entries) = compact_store(&mut store);
+        assert_eq!(removed_files, 1);
+        assert_eq!(removed_folder_entries, 2);
+        assert_eq!(store.files.len(), 2);
+        assert_eq!(store.folder_metadata.len(), 1);
+    }
+
+    #[test]
+    fn test_normalize_file_ids_reports_changes() {
+        let mut store = MetadataStore::new();
+        store.files.push(FileMetadata { message_id: Some(5), chat_id: Some(9), ..file("a.txt", "/Docs") });
+        store.files.push(FileMetadata { id: "saved:3".to_string(), message_id: Some(3), ..file("b.txt", "/") });
+        store.files.push(FileMetadata { id: "saved:3".to_string(), ..file("c.txt", "/") });
+
+        let changes = normalize_file_ids(&mut store);
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].old_id.as_str(), changes[0].new_id.as_str()), ("saved:a.txt", "9:5"));
+        assert_eq!(changes[1].name, "c.txt");
+        assert!(changes[1].new_id.starts_with("local:"));
+
+        assert!(normalize_file_ids(&mut store).is_empty());
+    }
+
+    #[test]
+    fn test_pending_migration() {
+        let mut store = MetadataStore::new();
+        store.folder_metadata.push(folder_meta("/Docs", Some(7)));
+        store.folder_metadata.push(folder_meta("/Photos", None));
+        store.files.push(folder_entry("/Docs", Some(7)));
+        store.files.push(FileMetadata { size: 3, ..file("a.txt", "/Docs") });
+        store.files.push(FileMetadata { size: 4, ..file("b.txt", "/Docs") });
+        store.files.push(FileMetadata { chat_id: Some(7), ..file("moved.txt", "/Docs") });
+        store.files.push(file("c.jpg", "/Photos"));
+        store.files.push(file("root.txt", "/"));
+
+        let status = pending_migration(&store);
+        assert_eq!((status.total_files, status.total_bytes), (3, 8));
+        assert_eq!(status.folders.len(), 2);
+        assert_eq!((status.folders[0].path.as_str(), status.folders[0].files, status.folders[0].bytes), ("/Docs", 2, 7));
+        assert!(status.folders[0].has_channel);
+        assert!(!status.folders[1].has_channel);
+    }
+
+    #[test]
+    fn test_bury_missing_files() {
+        let mut store = MetadataStore::new();
+        store.files.push(folder_entry("/Docs", Some(7)));
+        store.files.push(FileMetadata { message_id: Some(1), ..file("kept.txt", "/") });
+        store.files.push(FileMetadata { message_id: Some(2), chat_id: Some(7), ..file("gone.txt", "/Docs") });
+
+        let missing: HashSet<String> = ["saved:gone.txt".to_string(), "folder_Docs".to_string()].into_iter().collect();
+        let buried = bury_missing_files(&mut store, &missing, 42);
+
+        assert_eq!(buried.len(), 1);
+        assert_eq!((buried[0].name.as_str(), buried[0].folder.as_str(), buried[0].detected_at), ("gone.txt", "/Docs", 42));
+        assert_eq!(store.files.len(), 2);
+        assert_eq!(store.tombstones.len(), 1);
+
+        let ids: HashSet<String> = (0..MAX_TOMBSTONES + 5).map(|i| format!("x{}", i)).collect();
+        store.files = ids.iter().map(|id| FileMetadata { id: id.clone(), ..file("x", "/") }).collect();
+        bury_missing_files(&mut store, &ids, 43);
+        assert_eq!(store.tombstones.len(), MAX_TOMBSTONES);
+        assert_eq!(store.tombstones[0].detected_at, 43);
+    }
+
+    #[test]
+    fn test_message_matches_entry() {
+        let entry = FileMetadata { size: 10, ..file("a.txt", "/") };
+        assert!(message_matches_entry(&FileMetadata { size: 10, ..file("a.txt", "/Docs") }, &entry));
+        assert!(message_matches_entry(&FileMetadata { size: 0, ..file("a.txt", "/") }, &entry));
+        assert!(!message_matches_entry(&FileMetadata { size: 11, ..file("a.txt", "/") }, &entry));
+        assert!(!message_matches_entry(&FileMetadata { size: 10, ..file("b.txt", "/") }, &entry));
+    }
+
+    #[test]
+    fn test_dedup_stats() {
+        let hashed = |name: &str, size: u64, hash: &str| FileMetadata { size, sha256: Some(hash.to_string()), ..file(name, "/") };
+        let files = vec![
+            hashed("a.txt", 10, "aa"),
+            hashed("a copy.txt", 10, "aa"),
+            hashed("a again.txt", 10, "aa"),
+            hashed("b.txt", 5, "bb"),
+            FileMetadata { size: 7, ..file("old.txt", "/") },
+            folder_entry("/Docs", None),
+        ];
+
+        let stats = dedup_stats(&files);
+        assert_eq!(stats.file_count, 5);
+        assert_eq!(stats.total_size, 42);
+        assert_eq!(stats.dedup_size, 22);
+        assert_eq!(stats.duplicate_files, 2);
+        assert_eq!(stats.unhashed_files, 1);
+    }
+
+    #[test]
+    fn test_csv_escape() {
+        assert_eq!(csv_escape("plain.txt"), "plain.txt");
+        assert_eq!(csv_escape("a,b.txt"), "\"a,b.txt\"");
+        assert_eq!(csv_escape("say \"hi\".txt"), "\"say \"\"hi\"\".txt\"");
+        assert_eq!(csv_escape("line\nbreak"), "\"line\nbreak\"");
+    }
+
+    #[tokio::test]
+    async fn test_check_upload_source() {
+        let dir = std::env::temp_dir().join(format!("tvault-source-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let regular = dir.join("a.txt");
+        std::fs::write(&regular, b"data").unwrap();
+
+        assert_eq!(check_upload_source(&regular, true).await.unwrap().len(), 4);
+        let err = check_upload_source(&dir, true).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<TvaultError>(), Some(TvaultError::NotAFile { .. })));
+        assert!(check_upload_source(&dir.join("missing.txt"), true).await.is_err());
+
+        #[cfg(unix)]
+        {
+            let link = dir.join("link.txt");
+            std::os::unix::fs::symlink(&regular, &link).unwrap();
+            assert!(check_upload_source(&link, true).await.is_ok());
+            let err = check_upload_source(&link, false).await.unwrap_err();
+            assert!(matches!(err.downcast_ref::<TvaultError>(), Some(TvaultError::SymlinkRejected(_))));
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_health_check_due() {
+        let checked = |checked_at| FileMetadata {
+            health: Some(FileHealth { status: HealthStatus::Ok, checked_at }),
+            ..file("a.txt", "/")
+        };
+        assert!(health_check_due(&file("a.txt", "/"), 100));
+        assert!(health_check_due(&checked(99), 100));
+        assert!(!health_check_due(&checked(100), 100));
+        assert!(!health_check_due(&folder_entry("/Docs", None), 100));
+    }
+
+    #[test]
+    fn test_stored_size_matches() {
+        let plain = FileMetadata { size: 1000, ..file("a.txt", "/") };
+        assert!(stored_size_matches(&plain, 1000));
+        assert!(!stored_size_matches(&plain, 999));
+        assert!(stored_size_matches(&FileMetadata { size: 0, ..plain.clone() }, 999));
+
+        let encrypted = FileMetadata { encrypted: true, ..plain };
+        assert!(stored_size_matches(&encrypted, crate::encryption::encrypted_size(1000, 0)));
+        assert!(stored_size_matches(&encrypted, crate::encryption::encrypted_size(1000, u64::MAX)));
+        assert!(!stored_size_matches(&encrypted, 1000 + 1));
+    }
+
+    #[test]
+    fn test_duplicate_message_groups() {
+        let mut metadata = MetadataStore::new();
+        let entry = |name: &str, id: &str, message_id| FileMetadata {
+            id: id.to_string(),
+            message_id: Some(message_id),
+            ..file(name, "/")
+        };
+        // A double append: both entries carry the proper id for message 7
+        metadata.files.push(entry("a.txt", "saved:7", 7));
+        metadata.files.push(entry("a.txt", "saved:7", 7));
+        metadata.files.push(entry("b.txt", "saved:8", 8));
+        metadata.files.push(FileMetadata { chat_id: Some(-100), ..entry("b.txt", "-100:8", 8) });
+
+        // Normalizing leaves one of them with the proper id and gives the other a stand-in
+        let changes = normalize_file_ids(&mut metadata);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].new_id.starts_with("local:"));
+
+        // Same message id in different chats is no collision
+        let groups = duplicate_message_groups(&metadata);
+        assert_eq!(groups.len(), 1);
+        assert_eq!((groups[0].chat_id, groups[0].message_id), (None, 7));
+        assert_eq!(groups[0].entries.len(), 2);
+        assert!(same_file_content(&groups[0].entries[0], &groups[0].entries[1]));
+    }
+
+    #[test]
+    fn test_same_file_content() {
+        let hashed = |name: &str, hash: &str| FileMetadata { sha256: Some(hash.to_string()), ..file(name, "/") };
+        assert!(same_file_content(&hashed("a.txt", "aa"), &hashed("renamed.txt", "aa")));
+        assert!(!same_file_content(&hashed("a.txt", "aa"), &hashed("a.txt", "bb")));
+        assert!(same_file_content(&file("a.txt", "/"), &hashed("a.txt", "aa")));
+        assert!(!same_file_content(&file("a.txt", "/"), &FileMetadata { size: 2, ..file("a.txt", "/") }));
+    }
+
+    #[test]
+    fn test_folder_manifest_round_trip() {
+        let mut source = MetadataStore::new();
+        for (path, chat_id) in [("/Photos", Some(-100)), ("/Photos/Trips", Some(-200)), ("/Docs", Some(-300))] {
+            source.folders.push(path.to_string());
+            source.folder_metadata.push(folder_meta(path, chat_id));
+            source.files.push(folder_entry(path, chat_id));
+        }
+        let stored = |name: &str, folder: &str, chat_id, message_id| FileMetadata {
+            id: message_file_id(chat_id, message_id),
+            chat_id,
+            message_id: Some(message_id),
+            sort_index: Some(3),
+            ..file(name, folder)
+        };
+        source.files.push(stored("a.jpg", "/Photos", Some(-100), 1));
+        source.files.push(stored("b.jpg", "/Photos/Trips", Some(-200), 1));
+        source.files.push(stored("gone.jpg", "/Photos/Trips", Some(-200), 2));
+        source.files.push(stored("c.pdf", "/Docs", Some(-300), 1));
+
+        let manifest = folder_manifest(&source, "/Photos").unwrap();
+        assert_eq!(manifest.folders.len(), 2);
+        assert_eq!(manifest.entries.iter().filter(|f| !f.is_folder).count(), 3);
+        assert!(manifest.entries.iter().all(|f| f.sort_index.is_none()));
+        assert!(folder_manifest(&source, "/Missing").is_err());
+
+        let mut target = MetadataStore::new();
+        let present: HashSet<(Option<i64>, i32)> = [(Some(-100), 1), (Some(-200), 1)].into_iter().collect();
+        let mut report = ManifestImportReport::default();
+        merge_folder_manifest(&mut target, &manifest, &present, &mut report);
+        assert_eq!(report.folders_added, 2);
+        assert_eq!(report.files_added, 2);
+        assert_eq!(report.missing, vec!["gone.jpg".to_string()]);
+        assert!(target.folders.contains(&"/Photos/Trips".to_string()));
+        assert_eq!(folder_chat_id(&target, "/Photos/Trips").unwrap(), Some(-200));
+
+        // Importing again adds nothing
+        let mut again = ManifestImportReport::default();
+        merge_folder_manifest(&mut target, &manifest, &present, &mut again);
+        assert_eq!((again.folders_added, again.files_added, again.already_present), (0, 0, 2));
+        assert!(again.folder_conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_normalize_description() {
+        assert_eq!(normalize_description(None), None);
+        assert_eq!(normalize_description(Some("  \n ".to_string())), None);
+        assert_eq!(normalize_description(Some(" beach trip \n".to_string())).as_deref(), Some("beach trip"));
+        let long = normalize_description(Some("é".repeat(MAX_DESCRIPTION_CHARS + 10))).unwrap();
+        assert_eq!(long.chars().count(), MAX_DESCRIPTION_CHARS);
+    }
+
+    #[test]
+    fn test_pending_download_holds_only_its_own_part() {
+        let pending = PendingDownload {
+            file_id: "saved:7".to_string(),
+            destination: "/tmp/report.pdf".to_string(),
+            started_at: 0,
+            downloaded_bytes: 0,
+            message_id: 7,
+            expected_size: 4096,
+        };
+        assert!(pending.holds("saved:7", 7, 4096));
+        assert!(!pending.holds("saved:8", 7, 4096));
+        assert!(!pending.holds("saved:7", 5, 4096));
+        assert!(!pending.holds("saved:7", 7, 8192));
+
+        // Entries written before the message and size were recorded can't be trusted
+        let legacy: PendingDownload = serde_json::from_str(
+            r#"{"file_id":"saved:7","destination":"/tmp/report.pdf","started_at":0}"#
+        ).unwrap();
+        assert!(!legacy.holds("saved:7", 7, 4096));
+    }
+
+    #[test]
+    fn test_key_check_travels_with_the_vault() {
+        let check = crate::encryption::KeyCheck::legacy(&crate::encryption::Encryptor::new("pw")).unwrap();
+        let note = key_check_text(&check).unwrap();
+        assert_eq!(key_check_from_text(&note), Some(check.clone()));
+        assert_eq!(key_check_from_text("#tvault:key:not base64!"), None);
+
+        // A device that never unlocked takes the check from one that did, but never replaces its own
+        let mut local = MetadataStore::new();
+        let mut other = MetadataStore::new();
+        other.key_check = Some(check.clone());
+        assert!(merge_metadata_stores(&mut local, &other).key_check_added);
+        assert_eq!(local.key_check, Some(check));
+        other.key_check = Some(crate::encryption::KeyCheck::legacy(&crate::encryption::Encryptor::new("other")).unwrap());
+        assert!(!merge_metadata_stores(&mut local, &other).key_check_added);
+        assert_ne!(local.key_check, other.key_check);
+    }
+
+    #[test]
+    fn test_copy_matched_by_source_entry() {
+        let mut metadata = MetadataStore::new();
+        let mut first = file("notes.txt", "/Docs");
+        first.id = "-100:1".into();
+        let mut second = file("notes.txt", "/Docs");
+        second.id = "-100:2".into();
+        let mut copy = file("notes.txt", "/Backup/Docs");
+        copy.copy_of = Some(first.id.clone());
+        metadata.files.extend([first.clone(), second.clone(), copy]);
+
+        // A same-named file that hasn't been copied yet still needs copying
+        assert!(has_copy_in(&metadata, &first.id, "/Backup/Docs"));
+        assert!(!has_copy_in(&metadata, &second.id, "/Backup/Docs"));
+        assert!(!has_copy_in(&metadata, &first.id, "/Docs"));
+    }
+
+    #[test]
+    fn test_rebuild_keeps_unreadable_channels() {
+        let mut previous = MetadataStore::new();
+        previous.folder_metadata.push(folder_meta("/Docs", Some(-100)));
+        previous.folder_metadata.push(folder_meta("/Photos", Some(-200)));
+        previous.files.push(FileMetadata { id: "-100:1".to_string(), chat_id: Some(-100), ..file("a.txt", "/Docs") });
+        previous.files.push(FileMetadata { id: "-200:1".to_string(), chat_id: Some(-200), ..file("b.jpg", "/Photos") });
+
+        // /Photos was scanned fine, /Docs couldn't be read
+        let mut rebuilt = MetadataStore::new();
+        rebuilt.folder_metadata.push(folder_meta("/Photos", Some(-200)));
+        rebuilt.files.push(FileMetadata { id: "-200:1".to_string(), chat_id: Some(-200), ..file("b.jpg", "/Photos") });
+        carry_over_failed_chats(&mut rebuilt, &previous, &HashSet::from([-100]));
+
+        let mut ids: Vec<&str> = rebuilt.files.iter().map(|f| f.id.as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, ["-100:1", "-200:1"]);
+        assert_eq!(rebuilt.folder_metadata.len(), 2);
+        assert!(rebuilt.folder_metadata.iter().any(|m| m.path == "/Docs" && m.chat_id == Some(-100)));
+    }
+
+    #[test]
+    fn test_styled_legacy_folder_still_uploads() {
+        let mut store = MetadataStore::new();
+        store.folders.push("/Old".to_string());
+
+        let styled = apply_folder_appearance(&mut store, "/Old", Some(" #3b82f6 ".into()), Some("star".into())).unwrap();
+        assert_eq!(styled.color.as_deref(), Some("#3b82f6"));
+        assert_eq!(styled.chat_id, None);
+        assert_eq!(folder_upload_chat(&store, "/Old").unwrap(), FolderChat::Legacy(ChannelKind::Broadcast));
+        assert!(apply_folder_appearance(&mut store, "/Nope", None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_buffer_stays_under_cap() {
+        use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+        let data: Vec<u8> = (0..20_480u32).map(|i| (i % 253) as u8).collect();
+        let chunk_size = 1024u64;
+        let cap = 3000u64; // Room for two parts, with 16 workers asking
+        let buffer = crate::transfers::ByteBudget::default();
+        let peak_buffered = AtomicU64::new(0);
+        let (running, peak_running) = (AtomicUsize::new(0), AtomicUsize::new(0));
+
+        let mut out = std::io::Cursor::new(vec![0u8; data.len()]);
+        let written = assemble_chunks(&mut out, data.len() as u64, chunk_size, 16, &buffer, cap, |index| {
+            let start = (index * chunk_size) as usize;
+            let part = data[start..(start + chunk_size as usize).min(data.len())].to_vec();
+            let (buffer, peak_buffered, running, peak_running) = (&buffer, &peak_buffered, &running, &peak_running);
+            async move {
+                peak_running.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                peak_buffered.fetch_max(buffer.in_use(), Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(3 + index % 4)).await;
+                peak_buffered.fetch_max(buffer.in_use(), Ordering::SeqCst);
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(part)
+            }
+        }).await.unwrap();
+
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(out.into_inner(), data);
+        assert!(peak_buffered.into_inner() <= cap);
+        assert_eq!(peak_running.into_inner(), 2);
+        assert_eq!(buffer.in_use(), 0);
+    }
+
+    #[test]
+    fn test_checksums_from_captions() {
+        let hash = |c: char| c.to_string().repeat(64);
+        let entries = vec![
+            FileMetadata { id: "saved:1".to_string(), message_id: Some(1), ..file("a.txt", "/") },
+            FileMetadata { id: "saved:2".to_string(), message_id: Some(2), encrypted: true, ..file("b.txt", "/") },
+            FileMetadata { id: "saved:3".to_string(), message_id: Some(3), ..file("c.txt", "/") },
+            FileMetadata { id: "saved:4".to_string(), message_id: Some(4), ..file("d.txt", "/") },
+        ];
+        let found: std::collections::HashMap<i32, FileMetadata> = [
+            (1, FileMetadata { sha256: Some(hash('a')), ..file("a.txt", "/") }),
+            (2, FileMetadata { sha256: Some(hash('b')), ..file("b.txt", "/") }),
+            (3, FileMetadata { sha256: Some(hash('c')), ..file("other.txt", "/") }),
+            (4, file("d.txt", "/")),
+        ].into_iter().collect();
+
+        // Encrypted, mismatched and hashless messages are left alone
+        assert_eq!(checksums_from_captions(&entries, &found), vec![("saved:1".to_string(), hash('a'))]);
+    }
+
+    #[test]
+    fn test_classify_upload_failure() {
+        use crate::error::UploadFailureKind;
+
+        let history = |errors: &[&str]| errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(classify_upload_failure("File is too large: a.bin is 3.00 GiB", &[]), UploadFailureKind::FileTooLarge);
+        assert_eq!(classify_upload_failure("rpc error 401: AUTH_KEY_UNREGISTERED", &[]), UploadFailureKind::AuthExpired);
+        assert_eq!(classify_upload_failure("rpc error 400: CHANNEL_INVALID", &history(&["timed out"])), UploadFailureKind::PeerGone);
+        assert_eq!(classify_upload_failure("rpc error 420: FLOOD_WAIT_900", &[]), UploadFailureKind::FloodLimited);
+        // Mostly flood waits before a final timeout: still rate limiting
+        assert_eq!(
+            classify_upload_failure("Upload attempt timed out", &history(&["FLOOD_WAIT_30", "FLOOD_WAIT_45", "connection reset"])),
+            UploadFailureKind::FloodLimited
+        );
+        assert_eq!(
+            classify_upload_failure("Upload attempt timed out", &history(&["connection reset", "FLOOD_WAIT_30", "broken pipe"])),
+            UploadFailureKind::NetworkUnstable
+        );
+        assert_eq!(classify_upload_failure("MEDIA_EMPTY", &history(&["broken pipe"])), UploadFailureKind::NetworkUnstable);
+        assert_eq!(classify_upload_failure("MEDIA_EMPTY", &[]), UploadFailureKind::Unknown);
+    }
+
+    #[test]
+    fn test_url_file_name() {
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+        assert_eq!(url_file_name(&url("https://example.com/files/My%20Report.pdf?dl=1"), None).as_deref(), Some("My Report.pdf"));
+        assert_eq!(
+            url_file_name(&url("https://example.com/download?id=3"), Some("attachment; filename=\"data.csv\"")).as_deref(),
+            Some("data.csv")
+        );
+        assert_eq!(url_file_name(&url("https://example.com/x"), Some("attachment; filename=\"../../etc/passwd\"")).as_deref(), Some("passwd"));
+        assert_eq!(url_file_name(&url("https://example.com/"), None), None);
+        assert_eq!(url_file_name(&url("https://example.com/a/%2E%2E"), None), None);
+    }
+
+    #[test]
+    fn test_merge_disjoint_stores() {
+        let mut local = MetadataStore::new();
+        local.folders.push("/Docs".to_string());
+        local.folder_metadata.push(folder_meta("/Docs", Some(1)));
+        local.files.push(folder_entry("/Docs", Some(1)));
+        local.files.push(FileMetadata { id: "1:5".to_string(), message_id: Some(5), chat_id: Some(1), ..file("a.txt", "/Docs") });
+
+        let mut other = MetadataStore::new();
+        other.folders.push("/Photos".to_string());
+        other.folder_metadata.push(folder_meta("/Photos", Some(2)));
+        other.files.push(folder_entry("/Photos", Some(2)));
+        other.files.push(FileMetadata {
+            id: "2:7".to_string(),
+            message_id: Some(7),
+            chat_id: Some(2),
+            thumbnail: Some("/elsewhere/thumb.jpg".to_string()),
+            ..file("b.jpg", "/Photos")
+        });
+
+        let report = merge_metadata_stores(&mut local, &other);
+        assert_eq!((report.files_added, report.folders_added, report.conflicts), (1, 1, 0));
+        assert!(local.folders.contains(&"/Photos".to_string()));
+        assert!(local.folder_metadata.iter().any(|m| m.path == "/Photos" && m.chat_id == Some(2)));
+        let added = local.files.iter().find(|f| f.id == "2:7").unwrap();
+        assert!(added.thumbnail.is_none());
+        assert_eq!(local.files.len(), 4);
+
+        // Merging the same thing again changes nothing
+        let report = merge_metadata_stores(&mut local, &other);
+        assert_eq!((report.files_added, report.folders_added, report.conflicts), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_merge_overlapping_stores() {
+        // Renames only touch modified_at; the upload time stays the same on both sides
+        let entry = |name: &str, message_id: i32, modified_at: Option<i64>| FileMetadata {
+            id: format!("saved:{}", message_id),
+            message_id: Some(message_id),
+            updated_at: Some(5),
+            modified_at,
+            ..file(name, "/")
+        };
+
+        let mut local = MetadataStore::new();
+        local.folders.push("/Docs".to_string());
+        local.folder_metadata.push(folder_meta("/Docs", Some(1)));
+        local.folders.push("/Old".to_string());
+        local.folder_metadata.push(folder_meta("/Old", None));
+        local.files.push(FileMetadata { sort_index: Some(3), ..entry("same.txt", 1, Some(10)) });
+        local.files.push(entry("renamed-there.txt", 2, Some(10)));
+        local.files.push(entry("renamed-here.txt", 3, Some(20)));
+        local.files.push(entry("report.pdf", 4, Some(10)));
+
+        let mut other = MetadataStore::new();
+        other.folders.push("/Docs".to_string());
+        other.folder_metadata.push(folder_meta("/Docs", Some(9)));
+        other.folders.push("/Old".to_string());
+        other.folder_metadata.push(folder_meta("/Old", Some(7)));
+        other.files.push(entry("same.txt", 1, Some(10)));
+        other.files.push(entry("renamed.txt", 2, Some(20)));
+        other.files.push(entry("old-name.txt", 3, Some(10)));
+        other.files.push(FileMetadata { versions: vec![4], ..entry("report.pdf", 6, Some(30)) });
+        other.files.push(entry("new.txt", 5, Some(30)));
+
+        let report = merge_metadata_stores(&mut local, &other);
+        assert_eq!(report.files_added, 1);
+        assert_eq!(report.conflicts, 3);
+        assert_eq!(report.replaced, 2);
+        assert_eq!(report.folders_linked, 1);
+        assert_eq!(report.folder_conflicts, vec!["/Docs".to_string()]);
+
+        let name_of = |id: &str| local.files.iter().find(|f| f.id == id).map(|f| f.name.clone());
+        assert_eq!(name_of("saved:2").as_deref(), Some("renamed.txt"));
+        assert_eq!(name_of("saved:3").as_deref(), Some("renamed-here.txt"));
+        assert_eq!(name_of("saved:4"), None);
+        assert_eq!(name_of("saved:6").as_deref(), Some("report.pdf"));
+        assert_eq!(local.files.iter().find(|f| f.id == "saved:1").unwrap().sort_index, Some(3));
+        assert_eq!(local.folder_metadata[0].chat_id, Some(1));
+        assert_eq!(local.folder_metadata[1].chat_id, Some(7));
+
+        // Our side's old version isn't brought back as a file of its own
+        let mut stale = MetadataStore::new();
+        stale.files.push(entry("report.pdf", 4, Some(10)));
+        assert_eq!(merge_metadata_stores(&mut local, &stale).files_added, 0);
+    }
+
+    #[test]
+    fn test_custom_metadata_in_trailer() {
+        let custom: BTreeMap<String, String> = [("source", "crm"), ("ticket", "4711")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert!(check_custom_metadata(&custom).is_ok());
+        assert!(check_custom_metadata(&BTreeMap::from([(" ".to_string(), "x".to_string())])).is_err());
+        assert!(check_custom_metadata(&BTreeMap::from([("k".to_string(), "x".repeat(300))])).is_err());
+
+        let entry = FileMetadata { custom: custom.clone(), ..file("a.txt", "/Docs") };
+        let trailer = parse_caption_trailer(&file_caption("", &entry)).unwrap();
+        assert_eq!(trailer.custom, custom);
+        assert_eq!(trailer.folder, "/Docs");
+
+        // Too big for the trailer: dropped there before the folder is
+        let crowded = FileMetadata { folder: format!("/{}", "f".repeat(320)), ..entry };
+        let trailer = parse_caption_trailer(&file_caption("", &crowded)).unwrap();
+        assert!(trailer.custom.is_empty());
+        assert_eq!(trailer.folder, crowded.folder);
+    }
+
+    #[test]
+    fn test_download_high_water() {
+        // Nothing changed: stays put
+        assert_eq!(download_high_water(100, &[], 500), 100);
+        // All fine: newest change
+        assert_eq!(download_high_water(100, &[(150, true), (120, true)], 500), 150);
+        // A failure holds the mark below it
+        assert_eq!(download_high_water(100, &[(150, true), (130, false), (120, true)], 500), 129);
+        assert_eq!(download_high_water(100, &[(101, false)], 500), 100);
+        // The running second stays open
+        assert_eq!(download_high_water(100, &[(500, true)], 500), 499);
+    }
+
+    #[test]
+    fn test_planned_folder_paths() {
+        let paths = vec![
+            "/Photos/2023/Summer".to_string(),
+            "Photos/2024/".to_string(),
+            "/Docs".to_string(),
+            "/Photos/2023".to_string(),
+            "/".to_string(),
+        ];
+        assert_eq!(planned_folder_paths(&paths), vec![
+            "/Docs", "/Photos", "/Photos/2023", "/Photos/2024", "/Photos/2023/Summer",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_shared_client_follows_swap() {
+        let client_ref = Arc::new(Mutex::new(Some(1u32)));
+        assert_eq!(shared_client(&client_ref).await.unwrap(), 1);
+
+        // A reconnect replaces the client between two attempts
+        *client_ref.lock().await = Some(2);
+        assert_eq!(shared_client(&client_ref).await.unwrap(), 2);
+
+        *client_ref.lock().await = None;
+        assert!(shared_client(&client_ref).await.is_err());
+    }
+
+    // Fails on the first client and swaps in another while waiting to retry, like a reconnect
+    struct SwappedClientAttempts {
+        client_ref: Arc<Mutex<Option<u32>>>,
+        used: Vec<u32>,
+    }
+
+    impl RetriedAttempts<u32> for SwappedClientAttempts {
+        type Output = u32;
+
+        async fn attempt(&mut self, client: u32, _retry_count: u32) -> Result<Result<u32>> {
+            self.used.push(client);
+            if client == 1 {
+                return Ok(Err(anyhow::anyhow!("Connection closed")));
+            }
+            Ok(Ok(client))
+        }
+
+        async fn after_failure(&mut self, retry_count: u32, error: anyhow::Error) -> Result<()> {
+            if retry_count >= 3 {
+                return Err(error);
+            }
+            *self.client_ref.lock().await = Some(2);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_uses_the_swapped_client() {
+        let client_ref = Arc::new(Mutex::new(Some(1u32)));
+        let mut attempts = SwappedClientAttempts { client_ref: client_ref.clone(), used: Vec::new() };
+
+        assert_eq!(run_attempts(&client_ref, &mut attempts).await.unwrap(), 2);
+        assert_eq!(attempts.used, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_check_quota() {
+        assert!(check_quota(100, 1_000, 0, 0).is_ok());  // No limit
+        assert!(check_quota(100, 800, 100, 1_000).is_ok());
+        assert!(matches!(
+            check_quota(101, 800, 100, 1_000),
+            Err(TvaultError::QuotaExceeded { used: 800, reserved: 100, limit: 1_000, .. })
+        ));
+        assert!(check_quota(u64::MAX, 1, 0, 1_000).is_err());
+    }
+
+    #[test]
+    fn test_expected_folder_title() {
+        assert_eq!(expected_folder_title("/Photos/Vacation"), "T-Vault: /Photos/Vacation");
+        assert_eq!(folder_path_from_title(&expected_folder_title("/Docs")).as_deref(), Some("/Docs"));
+    }
+
+    #[test]
+    fn test_root_channel_title() {
+        assert!(is_root_channel_title(ROOT_CHANNEL_TITLE));
+        assert!(is_root_channel_title("T-Vault:/ "));
+        assert!(!is_root_channel_title("T-Vault: /Docs"));
+        assert_eq!(folder_path_from_title(ROOT_CHANNEL_TITLE), None);
+    }
+
+    #[test]
+    fn test_mime_type_usage() {
+        let typed = |name: &str, mime_type: &str, size| FileMetadata { mime_type: mime_type.to_string(), size, ..file(name, "/") };
+        let files = vec![
+            typed("a.jpg", "image/jpeg", 10),
+            typed("b.png", "image/png", 30),
+            typed("c.mp4", "video/mp4", 25),
+            typed("d.jpg", "image/jpeg", 5),
+            folder_entry("/Docs", None),
+        ];
+
+        let exact = mime_type_usage(&files, false);
+        assert_eq!(exact.iter().map(|u| u.mime_type.as_str()).collect::<Vec<_>>(), vec!["image/png", "video/mp4", "image/jpeg"]);
+        assert_eq!((exact[2].count, exact[2].total_size), (2, 15));
+
+        let grouped = mime_type_usage(&files, true);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!((grouped[0].mime_type.as_str(), grouped[0].count, grouped[0].total_size), ("image", 3, 45));
+    }
+
+    #[test]
+    fn test_check_photo_upload() {
+        assert!(check_photo_upload("a.jpg", "image/jpeg", 1024, false).is_ok());
+        assert!(check_photo_upload("a.jpg", "image/jpeg", 1024, true).is_err());
+        assert!(check_photo_upload("a.pdf", "application/pdf", 1024, false).is_err());
+        assert!(check_photo_upload("a.png", "image/png", MAX_PHOTO_BYTES + 1, false).is_err());
+    }
+
+    #[test]
+    fn test_size_disagrees() {
+        let mut entry = file("a.bin", "/");
+        entry.size = 100;
+        assert!(!size_disagrees(&entry, 100));
+        assert!(size_disagrees(&entry, 90));
+        assert!(!size_disagrees(&entry, 0));  // Telegram didn't say
+
+        entry.size = 0;
+        assert!(size_disagrees(&entry, 100));
+
+        entry.size = 100;
+        entry.encrypted = true;
+        assert!(!size_disagrees(&entry, crate::encryption::encrypted_size(100, 0)));
+        assert!(size_disagrees(&entry, 100 + 1));
+    }
+
+    #[test]
+    fn test_migrate_v1_schema() {
+        let mut raw = serde_json::json!({
+            "version": 1,
+            "files": [{
+                "id": "42", "name": "a.txt", "size": 3, "mime_type": "text/plain",
+                "created_at": 0, "folder": "/Docs", "is_folder": false,
+                "thumbnail": null, "message_id": 42, "encrypted": false
+            }],
+            "folders": ["/", "/Docs"]
+        });
+
+        let steps = migrate_schema(&mut raw).unwrap();
+        assert_eq!(steps.len(), CURRENT_SCHEMA_VERSION as usize - 1);
+
+        let store: MetadataStore = serde_json::from_value(raw.clone()).unwrap();
+        assert_eq!(store.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(store.files.len(), 1);
+        assert_eq!(store.files[0].chat_id, None);
+        assert_eq!(store.folder_metadata.len(), 1);
+        assert_eq!(store.folder_metadata[0].path, "/Docs");
+        assert_eq!(store.folder_metadata[0].chat_id, None);
+        // Its channel gets created on the next upload rather than failing it
+        assert_eq!(folder_upload_chat(&store, "/Docs").unwrap(), FolderChat::Legacy(ChannelKind::Broadcast));
+        assert!(folder_upload_chat(&store, "/Missing").is_err());
+
+        // Already current: nothing to do
+        assert!(migrate_schema(&mut raw).unwrap().is_empty());
+        assert!(schema_steps(CURRENT_SCHEMA_VERSION).is_empty());
+    }
+
+    #[test]
+    fn test_file_by_message() {
+        let mut saved = file("a.txt", "/");
+        saved.message_id = Some(7);
+        let mut in_channel = file("b.txt", "/Docs");
+        in_channel.chat_id = Some(-100);
+        in_channel.message_id = Some(7);
+        let files = vec![saved, in_channel];
+
+        assert_eq!(file_by_message(&files, None, 7).unwrap().name, "a.txt");
+        assert_eq!(file_by_message(&files, Some(-100), 7).unwrap().name, "b.txt");
+        assert!(file_by_message(&files, Some(-100), 8).is_none());
+    }
+
+    #[test]
+    fn test_lru_evictions() {
+        let at = |secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let files = vec![
+            (std::path::PathBuf::from("new"), 40, at(300)),
+            (std::path::PathBuf::from("old"), 30, at(100)),
+            (std::path::PathBuf::from("mid"), 50, at(200)),
+        ];
+
+        let evicted: Vec<_> = lru_evictions(files.clone(), 60).into_iter().map(|(p, _, _)| p).collect();
+        assert_eq!(evicted, vec![std::path::PathBuf::from("old"), std::path::PathBuf::from("mid")]);
+        assert!(lru_evictions(files.clone(), 120).is_empty());
+        assert_eq!(lru_evictions(files, 0).len(), 3);
+    }
+
+    #[test]
+    fn test_unreferenced_uploads() {
+        let mut metadata = MetadataStore::new();
+        metadata.folders.push("/Photos".to_string());
+        metadata.folder_metadata.push(folder_meta("/Photos", Some(-100)));
+        metadata.files.push(FileMetadata { message_id: Some(5), versions: vec![3], ..file("a.txt", "/") });
+        metadata.files.push(FileMetadata { message_id: Some(5), chat_id: Some(-100), ..file("b.jpg", "/Photos") });
+
+        assert!(is_referenced(&metadata, None, 5));
+        assert!(is_referenced(&metadata, None, 3));
+        assert!(is_referenced(&metadata, Some(-100), 5));
+        assert!(!is_referenced(&metadata, Some(-100), 3));
+        assert!(!is_referenced(&metadata, None, 6));
+
+        // Caption folder first, then the chat's folder, then root
+        let found = |folder: &str, chat_id| FileMetadata { chat_id, ..file("c.jpg", folder) };
+        assert_eq!(unreferenced_upload_folder(&metadata, &found("/Photos", None)), "/Photos");
+        assert_eq!(unreferenced_upload_folder(&metadata, &found("/Gone", Some(-100))), "/Photos");
+        assert_eq!(unreferenced_upload_folder(&metadata, &found("/Gone", None)), "/");
     }
-    
-    Ok(MigrationReport {
-        total: total_files,
-        migrated,
-        failed,
-        skipped,
-    })
 }
@@ -3,12 +3,23 @@ use grammers_client::peer::{User, Peer};
 use grammers_session::storages::SqliteSession;
 use grammers_mtsender::{SenderPool, SenderPoolHandle};
 use anyhow::{Result, Context};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use lazy_static::lazy_static;
 
 use crate::api_keys::ApiKeys;
 
+lazy_static! {
+    // Resolved channel peers by raw chat id, so repeated lookups don't rescan dialogs
+    static ref PEER_CACHE: Mutex<HashMap<i64, Peer>> = Mutex::new(HashMap::new());
+}
+
+// Unix time of the last verified connection. Wall-clock on purpose: a monotonic clock may
+// not advance while the machine sleeps, which is exactly the gap this has to notice.
+static LAST_ALIVE: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(0);
+
 // Load API credentials from stored config file or environment variables (fallback)
 async fn get_api_id() -> Result<i32> {
     // First try to load from stored config file
@@ -34,16 +45,246 @@ async fn get_api_hash() -> Result<String> {
         .context("Telegram API credentials not configured. Please set them up in the app.")
 }
 
+const DEFAULT_SESSION_FILE: &str = "telegram_session.session";
+
+/// Resolve a session file override. Bare names and relative paths are taken relative to the
+/// data directory; the result has to stay inside it and end in `.session`.
+fn resolve_session_path(data_dir: &Path, requested: &str) -> Result<PathBuf> {
+    let requested = Path::new(requested.trim());
+    if requested.as_os_str().is_empty() {
+        return Err(anyhow::anyhow!("Session path cannot be empty"));
+    }
+    if requested.components().any(|c| matches!(c, Component::ParentDir)) {
+        return Err(anyhow::anyhow!("Session path must not contain '..'"));
+    }
+
+    let path = if requested.is_absolute() {
+        requested.to_path_buf()
+    } else {
+        data_dir.join(requested)
+    };
+    if !path.starts_with(data_dir) {
+        return Err(anyhow::anyhow!("Session file must be inside {}", data_dir.display()));
+    }
+    if path.extension().and_then(|e| e.to_str()) != Some("session") {
+        return Err(anyhow::anyhow!("Session file name must end in .session"));
+    }
+
+    Ok(path)
+}
+
+/// Make sure the session file's directory exists, is writable and (after resolving
+/// symlinks) still lies inside the data directory
+async fn ensure_session_path_usable(data_dir: &Path, path: &Path) -> Result<()> {
+    let parent = path.parent().ok_or_else(|| anyhow::anyhow!("Invalid session path"))?;
+    tokio::fs::create_dir_all(parent).await
+        .with_context(|| format!("Cannot create {}", parent.display()))?;
+
+    let real_parent = tokio::fs::canonicalize(parent).await?;
+    let real_data_dir = tokio::fs::canonicalize(data_dir).await?;
+    if !real_parent.starts_with(&real_data_dir) {
+        return Err(anyhow::anyhow!("Session file must be inside {}", data_dir.display()));
+    }
+
+    let probe = parent.join(".tvault_write_test");
+    tokio::fs::write(&probe, b"").await
+        .with_context(|| format!("{} is not writable", parent.display()))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+    Ok(())
+}
+
+/// Check a session override before it is saved to the config
+pub async fn validate_session_path(requested: &str) -> Result<PathBuf> {
+    let data_dir = directories::ProjectDirs::from("com", "tvault", "t-vault")
+        .ok_or_else(|| anyhow::anyhow!("Failed to get data directory"))?
+        .data_dir()
+        .to_path_buf();
+    let path = resolve_session_path(&data_dir, requested)?;
+    ensure_session_path_usable(&data_dir, &path).await?;
+    Ok(path)
+}
+
+/// Session file to open: `TVAULT_SESSION_PATH`, then the configured override, then the default
+async fn session_file_path(data_dir: &Path) -> Result<PathBuf> {
+    let requested = std::env::var("TVAULT_SESSION_PATH").ok()
+        .filter(|p| !p.trim().is_empty())
+        .or(crate::config::AppConfig::load().await.session_path);
+
+    match requested {
+        Some(requested) => {
+            let path = resolve_session_path(data_dir, &requested)?;
+            ensure_session_path_usable(data_dir, &path).await?;
+            println!("Using session file {}", path.display());
+            Ok(path)
+        }
+        None => Ok(data_dir.join(DEFAULT_SESSION_FILE)),
+    }
+}
+
+/// Prefix of the links Telegram apps scan to approve a login
+const LOGIN_TOKEN_URL_PREFIX: &str = "tg://login?token=";
+
+/// State of a "scan to log in" attempt: either a link to show as a QR code, or done
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QrLogin {
+    pub authorized: bool,
+    pub url: Option<String>,
+    pub expires_at: Option<i64>,  // Unix time; call export_login_token again for a fresh link
+}
+
+/// Raw token bytes from a tg://login link or its bare base64url payload
+fn decode_login_token(token: &str) -> Result<Vec<u8>> {
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+
+    let token = token.trim();
+    let payload = token.strip_prefix(LOGIN_TOKEN_URL_PREFIX).unwrap_or(token);
+    URL_SAFE_NO_PAD.decode(payload.trim_end_matches('='))
+        .map_err(|_| anyhow::anyhow!("Not a valid login token"))
+}
+
+/// Approve another device's login link from this (already authorized) session
+pub async fn accept_login_token(client: &Client, token: &str) -> Result<()> {
+    use grammers_tl_types as tl;
+
+    let request = tl::functions::auth::AcceptLoginToken { token: decode_login_token(token)? };
+    client.invoke(&request).await
+        .map_err(|e| {
+            let error_str = format!("{:?}", e);
+            if error_str.contains("AUTH_TOKEN_EXPIRED") {
+                anyhow::anyhow!("The login code has expired. Refresh it on the new device and try again.")
+            } else if error_str.contains("AUTH_TOKEN_ALREADY_ACCEPTED") {
+                anyhow::anyhow!("This login code was already used")
+            } else {
+                anyhow::anyhow!("Failed to approve login: {}", error_str)
+            }
+        })?;
+    Ok(())
+}
+
+/// First bytes of every SQLite database, i.e. of an unencrypted session file
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Copy the active session file to `destination`, sealed with the vault key if `encryptor`
+/// is given. Returns the number of bytes written.
+pub async fn export_session(destination: &Path, encryptor: Option<&crate::encryption::Encryptor>) -> Result<u64> {
+    let data_dir = directories::ProjectDirs::from("com", "tvault", "t-vault")
+        .ok_or_else(|| anyhow::anyhow!("Failed to get data directory"))?
+        .data_dir()
+        .to_path_buf();
+    let session_file = session_file_path(&data_dir).await?;
+
+    let bytes = tokio::fs::read(&session_file).await
+        .with_context(|| format!("Failed to read {}", session_file.display()))?;
+    let bytes = match encryptor {
+        Some(encryptor) => encryptor.encrypt(&bytes)?,
+        None => bytes,
+    };
+    tokio::fs::write(destination, &bytes).await
+        .with_context(|| format!("Failed to write {}", destination.display()))?;
+
+    Ok(bytes.len() as u64)
+}
+
+/// Read a session backup (decrypting it with the vault key if it was sealed), check that it
+/// is still logged in, and stage it next to the active session. Returns the staged file.
+pub async fn stage_session_import(path: &Path, encryptor: Option<&crate::encryption::Encryptor>) -> Result<PathBuf> {
+    let bytes = tokio::fs::read(path).await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let bytes = if bytes.starts_with(SQLITE_HEADER) {
+        bytes
+    } else {
+        let encryptor = encryptor.ok_or(crate::error::TvaultError::VaultLocked)?;
+        let plain = encryptor.decrypt(&bytes)
+            .map_err(|_| anyhow::anyhow!("Not a session backup, or it was encrypted with a different password"))?;
+        if !plain.starts_with(SQLITE_HEADER) {
+            return Err(anyhow::anyhow!("Not a session backup"));
+        }
+        plain
+    };
+
+    let data_dir = directories::ProjectDirs::from("com", "tvault", "t-vault")
+        .ok_or_else(|| anyhow::anyhow!("Failed to get data directory"))?
+        .data_dir()
+        .to_path_buf();
+    let staged = session_file_path(&data_dir).await?.with_extension("session.import");
+    tokio::fs::write(&staged, &bytes).await
+        .with_context(|| format!("Failed to write {}", staged.display()))?;
+
+    match session_authorizes(&staged).await {
+        Ok(true) => Ok(staged),
+        Ok(false) => {
+            let _ = tokio::fs::remove_file(&staged).await;
+            Err(anyhow::anyhow!("The session in this backup is no longer logged in"))
+        }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&staged).await;
+            Err(e)
+        }
+    }
+}
+
+/// Open a session file with a throwaway client and ask Telegram whether it is logged in
+async fn session_authorizes(path: &Path) -> Result<bool> {
+    let session: Arc<SqliteSession> = Arc::new(
+        SqliteSession::open(path.to_str().ok_or_else(|| anyhow::anyhow!("Invalid session path"))?)?
+    );
+    let pool = SenderPool::new(Arc::clone(&session), get_api_id().await?);
+    let pool_handle = pool.handle.clone();
+    let client = Client::new(&pool);
+
+    let runner = pool.runner;
+    let runner_handle = tokio::spawn(async move {
+        runner.run().await;
+    });
+
+    let result = tokio::time::timeout(
+        tokio::time::Duration::from_secs(20),
+        client.is_authorized()
+    ).await;
+
+    pool_handle.quit();
+    runner_handle.abort();
+
+    match result {
+        Ok(Ok(authorized)) => Ok(authorized),
+        Ok(Err(e)) => Err(anyhow::anyhow!("Failed to check the session: {}", e)),
+        Err(_) => Err(anyhow::anyhow!("Timed out checking the session")),
+    }
+}
+
+/// Make a staged import the active session. The previous one is kept as <name>.session.bak.
+/// The current client must be shut down first.
+pub async fn install_session(staged: &Path) -> Result<()> {
+    let data_dir = directories::ProjectDirs::from("com", "tvault", "t-vault")
+        .ok_or_else(|| anyhow::anyhow!("Failed to get data directory"))?
+        .data_dir()
+        .to_path_buf();
+    let session_file = session_file_path(&data_dir).await?;
+
+    if session_file.exists() {
+        tokio::fs::copy(&session_file, session_file.with_extension("session.bak")).await
+            .context("Failed to back up the current session")?;
+    }
+    tokio::fs::rename(staged, &session_file).await
+        .context("Failed to replace the session file")?;
+
+    // Peers were resolved for the old session, which may be another account
+    PEER_CACHE.lock().await.clear();
+    Ok(())
+}
+
 pub struct TelegramClient {
     client: Arc<Mutex<Option<Client>>>,
-    // Kept for potential future use in connection management
-    #[allow(dead_code)]
+    session: Arc<SqliteSession>,
+    // Used to shut the old connection down when the client is rebuilt
     pool_handle: Arc<Mutex<Option<SenderPoolHandle>>>,
     login_token: Arc<Mutex<Option<LoginToken>>>,
     // Kept for reference, may be used for session management in future
     #[allow(dead_code)]
     session_file: PathBuf,
     phone: String,
+    created_for_login: bool,  // Set when telegram_login had to create this client
 }
 
 impl TelegramClient {
@@ -120,7 +361,7 @@ impl TelegramClient {
             .to_path_buf();
         
         tokio::fs::create_dir_all(&data_dir).await?;
-        let session_file = data_dir.join("telegram_session.session");
+        let session_file = session_file_path(&data_dir).await?;
         
         // Create session using SqliteSession for persistence
         let session: Arc<SqliteSession> = Arc::new(
@@ -145,10 +386,13 @@ impl TelegramClient {
 
         Ok(Self {
             client: Arc::new(Mutex::new(Some(client))),
+            session,
             pool_handle: Arc::new(Mutex::new(Some(pool_handle))),
             login_token: Arc::new(Mutex::new(None)),
             session_file,
-            phone: String::new(),
+            // Pre-filled from the last login so re-auth after a restart knows the number
+            phone: crate::config::AppConfig::load().await.last_phone().unwrap_or_default(),
+            created_for_login: false,
         })
     }
 
@@ -207,13 +451,19 @@ impl TelegramClient {
             
             match result {
                 Ok(_user) => {
-                    // Clear token after successful login
+                    // Clear token after successful login; the client is a regular one from now on
                     let mut token_guard = self.login_token.lock().await;
                     *token_guard = None;
+                    self.created_for_login = false;
                     Ok(())
                 }
-                Err(SignInError::PasswordRequired(_)) => {
-                    Err(anyhow::anyhow!("2FA password required - please disable 2FA temporarily"))
+                Err(SignInError::PasswordRequired(password_token)) => {
+                    // Only passed on to remind the user; never stored
+                    let hint = password_token.hint()
+                        .map(str::trim)
+                        .filter(|hint| !hint.is_empty())
+                        .map(str::to_string);
+                    Err(crate::error::TvaultError::PasswordRequired { hint }.into())
                 }
                 Err(e) => {
                     eprintln!("Sign in error: {:?}", e);
@@ -225,6 +475,68 @@ impl TelegramClient {
         }
     }
 
+    // "Scan to log in": fetch a login link for an already signed-in device to approve. Call it
+    // again to poll; once approved it reports `authorized`, and an expired link is replaced.
+    // Accounts on another DC are imported there and the session's home DC is switched.
+    pub async fn export_login_token(&mut self) -> Result<QrLogin> {
+        use grammers_tl_types as tl;
+
+        let client = self.client.lock().await.as_ref().cloned()
+            .ok_or_else(|| anyhow::anyhow!("Client not available"))?;
+        if client.is_authorized().await? {
+            return Ok(self.finish_qr_login());
+        }
+
+        let request = tl::functions::auth::ExportLoginToken {
+            api_id: get_api_id().await?,
+            api_hash: get_api_hash().await?,
+            except_ids: Vec::new(),
+        };
+        let mut result = client.invoke(&request).await.map_err(qr_login_error)?;
+
+        if let tl::enums::auth::LoginToken::MigrateTo(migrate) = result {
+            println!("QR login continues on DC {}", migrate.dc_id);
+            let import = tl::functions::auth::ImportLoginToken { token: migrate.token };
+            result = client.invoke_in_dc(migrate.dc_id, &import).await.map_err(qr_login_error)?;
+            if matches!(result, tl::enums::auth::LoginToken::Success(_)) {
+                self.session.set_home_dc_id(migrate.dc_id).await;
+            }
+        }
+
+        match result {
+            tl::enums::auth::LoginToken::Token(token) => {
+                use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+                Ok(QrLogin {
+                    authorized: false,
+                    url: Some(format!("{}{}", LOGIN_TOKEN_URL_PREFIX, URL_SAFE_NO_PAD.encode(&token.token))),
+                    expires_at: Some(token.expires as i64),
+                })
+            }
+            tl::enums::auth::LoginToken::Success(_) => Ok(self.finish_qr_login()),
+            tl::enums::auth::LoginToken::MigrateTo(_) => {
+                Err(anyhow::anyhow!("Telegram asked to switch data centers twice; please try again"))
+            }
+        }
+    }
+
+    fn finish_qr_login(&mut self) -> QrLogin {
+        self.created_for_login = false;
+        QrLogin { authorized: true, url: None, expires_at: None }
+    }
+
+    // Mark this client as created just for a login attempt, so cancelling it can drop the client
+    pub fn mark_created_for_login(&mut self) {
+        self.created_for_login = true;
+    }
+
+    // Abandon a login in progress: forget the code request and phone.
+    // Returns true if the client only exists for this attempt and should be dropped.
+    pub async fn cancel_login(&mut self) -> bool {
+        *self.login_token.lock().await = None;
+        self.phone.clear();
+        self.created_for_login
+    }
+
     pub async fn is_authenticated(&self) -> Result<bool> {
         let client_guard = self.client.lock().await;
         if let Some(ref client) = *client_guard {
@@ -234,6 +546,63 @@ impl TelegramClient {
         }
     }
 
+    // Before a user-initiated operation: whether nothing verified the connection for
+    // `stale_after_secs` (e.g. the machine slept) and it no longer answers. Takes the bare
+    // client, so callers don't hold the app's client lock across the round trip.
+    pub async fn connection_is_dead(client: &Client, stale_after_secs: u64) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let last_alive = LAST_ALIVE.load(std::sync::atomic::Ordering::SeqCst);
+        if now.saturating_sub(last_alive) < stale_after_secs as i64 {
+            return false;
+        }
+        if test_client_connection(client).await {
+            LAST_ALIVE.store(now, std::sync::atomic::Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    // When the connection was last verified or rebuilt, as a Unix timestamp
+    pub fn last_alive() -> i64 {
+        LAST_ALIVE.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    // Rebuild a connection connection_is_dead gave up on
+    pub async fn rebuild_connection(&self) -> Result<()> {
+        println!("Client connection is stale, rebuilding it from the session...");
+        self.reconnect().await?;
+        LAST_ALIVE.store(chrono::Utc::now().timestamp(), std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Replace the connection with a fresh one on the same session. The client is swapped
+    // inside the shared slot, so everything holding the client ref picks up the new one.
+    async fn reconnect(&self) -> Result<()> {
+        let api_id = get_api_id().await?;
+        let pool = SenderPool::new(Arc::clone(&self.session), api_id);
+        let pool_handle = pool.handle.clone();
+        let client = Client::new(&pool);
+
+        let runner = pool.runner;
+        tokio::spawn(async move {
+            runner.run().await;
+        });
+
+        *self.client.lock().await = Some(client);
+        if let Some(old_handle) = self.pool_handle.lock().await.replace(pool_handle) {
+            old_handle.quit();
+        }
+        Ok(())
+    }
+
+    // Drop the connection for good, e.g. before the session file is replaced
+    pub async fn shutdown(&self) {
+        *self.client.lock().await = None;
+        if let Some(pool_handle) = self.pool_handle.lock().await.take() {
+            pool_handle.quit();
+        }
+    }
+
     // Get client reference for storage operations
     pub fn get_client_ref(&self) -> Arc<Mutex<Option<Client>>> {
         self.client.clone()
@@ -252,18 +621,73 @@ impl TelegramClient {
 }
 
 // Channel management functions for folder-based storage
-/// Create a private Telegram channel for a folder
+/// Raw (id, access_hash) for peers backed by a Telegram channel.
+/// Broadcast channels surface as `Peer::Channel`, supergroups as `Peer::Group`.
+fn channel_parts(peer: &Peer) -> Option<(i64, Option<i64>)> {
+    use grammers_tl_types as tl;
+
+    match peer {
+        Peer::Channel(c) => Some((c.raw.id, c.raw.access_hash)),
+        Peer::Group(g) => match &g.raw {
+            tl::enums::Chat::Channel(c) => Some((c.id, c.access_hash)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Title prefix given to every folder channel T-Vault creates
+pub const FOLDER_CHANNEL_PREFIX: &str = "T-Vault:";
+
+/// Title of a channel or supergroup peer
+fn channel_title(peer: &Peer) -> Option<&str> {
+    use grammers_tl_types as tl;
+
+    match peer {
+        Peer::Channel(c) => Some(c.raw.title.as_str()),
+        Peer::Group(g) => match &g.raw {
+            tl::enums::Chat::Channel(c) => Some(c.title.as_str()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// List every channel in the dialogs whose title marks it as a T-Vault folder channel
+pub async fn list_folder_channels(client: &Client) -> Result<Vec<(i64, String)>> {
+    let mut channels = Vec::new();
+    let mut dialogs = client.iter_dialogs();
+
+    while let Some(dialog) = dialogs.next().await
+        .map_err(|e| anyhow::anyhow!("Failed to iterate dialogs: {:?}", e))? {
+        let Some((id, _)) = channel_parts(&dialog.peer) else {
+            continue;
+        };
+        PEER_CACHE.lock().await.insert(id, dialog.peer.clone());
+
+        if let Some(title) = channel_title(&dialog.peer) {
+            if title.starts_with(FOLDER_CHANNEL_PREFIX) {
+                channels.push((id, title.to_string()));
+            }
+        }
+    }
+
+    Ok(channels)
+}
+
+/// Create a private Telegram channel for a folder (a supergroup when `megagroup` is set)
 pub async fn create_folder_channel(
     client: &Client,
     title: &str,
     description: &str,
+    megagroup: bool,
 ) -> Result<(i64, String)> {
     use grammers_tl_types as tl;
 
     // Create channel using raw TL request
     let request = tl::functions::channels::CreateChannel {
-        broadcast: true,    // Private channel (not group)
-        megagroup: false,   // Not a supergroup
+        broadcast: !megagroup,  // Private broadcast channel by default
+        megagroup,              // Supergroup when requested
         title: title.to_string(),
         about: description.to_string(),
         geo_point: None,
@@ -310,13 +734,13 @@ pub async fn delete_channel(
     
     while let Some(dialog) = dialogs.next().await
         .map_err(|e| anyhow::anyhow!("Failed to iterate dialogs: {:?}", e))? {
-        if let Peer::Channel(c) = &dialog.peer {
+        if let Some((id, access_hash)) = channel_parts(&dialog.peer) {
             // Compare raw channel id directly
-            if c.raw.id == chat_id {
+            if id == chat_id {
                 // Found the channel, get its InputChannel
                 channel_input = Some(tl::enums::InputChannel::Channel(tl::types::InputChannel {
-                    channel_id: c.raw.id,
-                    access_hash: c.raw.access_hash.unwrap_or(0),
+                    channel_id: id,
+                    access_hash: access_hash.unwrap_or(0),
                 }));
                 break;
             }
@@ -333,6 +757,8 @@ pub async fn delete_channel(
     
     client.invoke(&request).await
         .map_err(|e| anyhow::anyhow!("Failed to delete channel: {:?}", e))?;
+
+    forget_chat_peer(chat_id).await;
     
     Ok(())
 }
@@ -342,35 +768,418 @@ pub async fn get_chat_peer(
     client: &Client,
     chat_id: i64,
 ) -> Result<Peer> {
+    if let Some(peer) = PEER_CACHE.lock().await.get(&chat_id).cloned() {
+        return Ok(peer);
+    }
+
     println!("Debug: searching for chat_id: {}", chat_id);
 
-    // Search through dialogs but with a reasonable limit to prevent hanging
+    // Search through dialogs with a configurable cap to prevent hanging.
+    // If the first pass misses, keep scanning up to the (higher) second-pass limit.
+    let config = crate::config::AppConfig::load().await;
+    let first_pass_limit = config.max_dialogs_to_search.max(1);
+    let second_pass_limit = config.max_dialogs_second_pass.max(first_pass_limit);
+
     let mut dialogs = client.iter_dialogs();
     let mut count = 0;
-    const MAX_DIALOGS_TO_SEARCH: usize = 50; // Reduced limit to prevent hanging
     
     while let Some(dialog) = dialogs.next().await
         .map_err(|e| anyhow::anyhow!("Failed to iterate dialogs: {:?}", e))? {
         
         count += 1;
-        if count > MAX_DIALOGS_TO_SEARCH {
-            println!("Debug: Stopped search after {} dialogs to prevent hanging", count);
+        if count == first_pass_limit + 1 {
+            println!("Debug: Chat not found in first {} dialogs, extending search to {}", first_pass_limit, second_pass_limit);
+        }
+        if count > second_pass_limit {
+            println!("Debug: Stopped search after {} dialogs to prevent hanging", count - 1);
             break;
         }
         
-        if let Peer::Channel(channel) = &dialog.peer {
+        if let Some((id, _)) = channel_parts(&dialog.peer) {
+            // Remember every channel we pass so later lookups skip the scan
+            PEER_CACHE.lock().await.insert(id, dialog.peer.clone());
+
             // Compare raw channel id directly
-            if channel.raw.id == chat_id {
+            if id == chat_id {
                 println!("Debug: Found chat in dialogs at index {}", count);
                 return Ok(dialog.peer.clone());
             }
         }
     }
     
-    println!("Debug: Chat not found after scanning {} dialogs", count);
+    println!("Debug: Chat not found after scanning {} dialogs", count.min(second_pass_limit));
     Err(anyhow::anyhow!("Chat with ID {} not found. The channel may not exist or you may not have access.", chat_id))
 }
 
+/// Result of probing a folder channel before using it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ChannelStatus {
+    Ok,
+    NotFound,
+    NoPermission,
+    NetworkError,
+}
+
+/// Classify a failed channel lookup from its error text
+fn channel_error_status(error_str: &str) -> ChannelStatus {
+    let upper = error_str.to_uppercase();
+    if upper.contains("CHANNEL_INVALID") || upper.contains("CHANNEL_PRIVATE") || upper.contains("NOT FOUND") {
+        ChannelStatus::NotFound
+    } else if upper.contains("CHAT_WRITE_FORBIDDEN") || upper.contains("USER_BANNED") || upper.contains("CHAT_ADMIN_REQUIRED") {
+        ChannelStatus::NoPermission
+    } else {
+        ChannelStatus::NetworkError
+    }
+}
+
+/// Whether the current user may post files into this channel
+fn can_post(channel: &grammers_tl_types::types::Channel) -> bool {
+    use grammers_tl_types as tl;
+
+    if channel.left {
+        return false;
+    }
+    if channel.creator {
+        return true;
+    }
+
+    let admin_can_post = channel.admin_rights.as_ref().map(|rights| match rights {
+        tl::enums::ChatAdminRights::Rights(r) => r.post_messages || !channel.broadcast,
+    });
+
+    if channel.broadcast {
+        // Only the creator and admins with posting rights can write to a broadcast channel
+        return admin_can_post.unwrap_or(false);
+    }
+    if admin_can_post == Some(true) {
+        return true;
+    }
+
+    // Supergroup member: check both personal and default restrictions
+    let banned = |rights: &Option<tl::enums::ChatBannedRights>| rights.as_ref().map_or(false, |r| match r {
+        tl::enums::ChatBannedRights::Rights(r) => r.send_messages || r.send_media,
+    });
+    !banned(&channel.banned_rights) && !banned(&channel.default_banned_rights)
+}
+
+/// Check that a folder channel still exists and that we can post into it.
+/// Re-fetches the channel with its access hash instead of trusting the cached peer.
+pub async fn check_channel_access(client: &Client, chat_id: i64) -> ChannelStatus {
+    use grammers_tl_types as tl;
+
+    let peer = match get_chat_peer(client, chat_id).await {
+        Ok(peer) => peer,
+        Err(e) => return channel_error_status(&e.to_string()),
+    };
+
+    let Some((id, access_hash)) = channel_parts(&peer) else {
+        return ChannelStatus::NotFound;
+    };
+
+    let request = tl::functions::channels::GetChannels {
+        id: vec![tl::enums::InputChannel::Channel(tl::types::InputChannel {
+            channel_id: id,
+            access_hash: access_hash.unwrap_or(0),
+        })],
+    };
+
+    let chats = match client.invoke(&request).await {
+        Ok(tl::enums::messages::Chats::Chats(c)) => c.chats,
+        Ok(tl::enums::messages::Chats::Slice(c)) => c.chats,
+        Err(e) => {
+            let status = channel_error_status(&format!("{:?}", e));
+            if status == ChannelStatus::NotFound {
+                forget_chat_peer(chat_id).await;
+            }
+            return status;
+        }
+    };
+
+    match chats.into_iter().next() {
+        Some(tl::enums::Chat::Channel(channel)) if can_post(&channel) => ChannelStatus::Ok,
+        Some(tl::enums::Chat::Channel(_)) | Some(tl::enums::Chat::ChannelForbidden(_)) => ChannelStatus::NoPermission,
+        _ => {
+            forget_chat_peer(chat_id).await;
+            ChannelStatus::NotFound
+        }
+    }
+}
+
+/// Part size for profile photo downloads (upload.getFile allows up to 1 MiB)
+const CHANNEL_PHOTO_PART_SIZE: i32 = 512 * 1024;
+
+/// Input peer and channel for a cached channel peer
+fn channel_inputs(peer: &Peer, chat_id: i64) -> Result<(grammers_tl_types::enums::InputPeer, grammers_tl_types::enums::InputChannel)> {
+    use grammers_tl_types as tl;
+
+    let (id, access_hash) = channel_parts(peer)
+        .ok_or_else(|| anyhow::anyhow!("Chat {} is not a channel", chat_id))?;
+    let access_hash = access_hash.unwrap_or(0);
+    Ok((
+        tl::enums::InputPeer::Channel(tl::types::InputPeerChannel { channel_id: id, access_hash }),
+        tl::enums::InputChannel::Channel(tl::types::InputChannel { channel_id: id, access_hash }),
+    ))
+}
+
+/// Download a channel's small profile photo to `destination_dir`.
+/// Returns None when the channel has no photo. A photo already on disk is not fetched again.
+pub async fn download_channel_photo(client: &Client, chat_id: i64, destination_dir: &Path) -> Result<Option<PathBuf>> {
+    use grammers_tl_types as tl;
+
+    let peer = get_chat_peer(client, chat_id).await?;
+    let (input_peer, input_channel) = channel_inputs(&peer, chat_id)?;
+
+    // Fetch the channel fresh so a photo changed elsewhere is picked up
+    let request = tl::functions::channels::GetChannels { id: vec![input_channel] };
+    let chats = match client.invoke(&request).await
+        .map_err(|e| anyhow::anyhow!("Failed to fetch channel: {:?}", e))? {
+        tl::enums::messages::Chats::Chats(c) => c.chats,
+        tl::enums::messages::Chats::Slice(c) => c.chats,
+    };
+    let photo = match chats.into_iter().next() {
+        Some(tl::enums::Chat::Channel(channel)) => match channel.photo {
+            tl::enums::ChatPhoto::Photo(photo) => photo,
+            tl::enums::ChatPhoto::Empty => return Ok(None),
+        },
+        _ => return Err(anyhow::anyhow!("Channel {} not found", chat_id)),
+    };
+
+    // The photo id is part of the name, so a new photo never reuses a stale file
+    let destination = destination_dir.join(format!("tvault-avatar-{}-{}.jpg", chat_id, photo.photo_id));
+    if destination.exists() {
+        return Ok(Some(destination));
+    }
+
+    let location = tl::enums::InputFileLocation::InputPeerPhotoFileLocation(tl::types::InputPeerPhotoFileLocation {
+        big: false,
+        peer: input_peer,
+        photo_id: photo.photo_id,
+    });
+
+    let mut bytes = Vec::new();
+    loop {
+        let request = tl::functions::upload::GetFile {
+            precise: false,
+            cdn_supported: false,
+            location: location.clone(),
+            offset: bytes.len() as i64,
+            limit: CHANNEL_PHOTO_PART_SIZE,
+        };
+        let part = match client.invoke_in_dc(photo.dc_id, &request).await
+            .map_err(|e| anyhow::anyhow!("Failed to download channel photo: {:?}", e))? {
+            tl::enums::upload::File::File(file) => file.bytes,
+            tl::enums::upload::File::CdnRedirect(_) => {
+                return Err(anyhow::anyhow!("Channel photo is served from a CDN, which is not supported"));
+            }
+        };
+        let done = part.len() < CHANNEL_PHOTO_PART_SIZE as usize;
+        bytes.extend_from_slice(&part);
+        if done {
+            break;
+        }
+    }
+
+    tokio::fs::create_dir_all(destination_dir).await?;
+    // Write under a temporary name so an interrupted download isn't mistaken for a cached photo
+    let partial = destination.with_extension("jpg.part");
+    tokio::fs::write(&partial, &bytes).await?;
+    tokio::fs::rename(&partial, &destination).await?;
+
+    Ok(Some(destination))
+}
+
+/// Upload `image_path` and make it the channel's profile photo
+pub async fn set_channel_photo(client: &Client, chat_id: i64, image_path: &Path) -> Result<()> {
+    use grammers_tl_types as tl;
+
+    let peer = get_chat_peer(client, chat_id).await?;
+    let (_, input_channel) = channel_inputs(&peer, chat_id)?;
+
+    let uploaded = client.upload_file(image_path).await
+        .map_err(|e| anyhow::anyhow!("Failed to upload photo: {}", e))?;
+
+    let request = tl::functions::channels::EditPhoto {
+        channel: input_channel,
+        photo: tl::enums::InputChatPhoto::InputChatUploadedPhoto(tl::types::InputChatUploadedPhoto {
+            file: Some(uploaded.raw),
+            video: None,
+            video_start_ts: None,
+            video_emoji_markup: None,
+        }),
+    };
+
+    client.invoke(&request).await
+        .map_err(|e| anyhow::anyhow!("Failed to set channel photo: {:?}", e))?;
+
+    Ok(())
+}
+
+/// Current title of a channel, fetched fresh rather than from the peer cache
+pub async fn fetch_channel_title(client: &Client, chat_id: i64) -> Result<String> {
+    use grammers_tl_types as tl;
+
+    let peer = get_chat_peer(client, chat_id).await?;
+    let (_, input_channel) = channel_inputs(&peer, chat_id)?;
+
+    let request = tl::functions::channels::GetChannels { id: vec![input_channel] };
+    let chats = match client.invoke(&request).await
+        .map_err(|e| anyhow::anyhow!("Failed to fetch channel: {:?}", e))? {
+        tl::enums::messages::Chats::Chats(c) => c.chats,
+        tl::enums::messages::Chats::Slice(c) => c.chats,
+    };
+    match chats.into_iter().next() {
+        Some(tl::enums::Chat::Channel(channel)) => Ok(channel.title),
+        _ => Err(anyhow::anyhow!("Channel {} not found", chat_id)),
+    }
+}
+
+/// Rename a channel. Setting the title it already has is not an error.
+pub async fn set_channel_title(client: &Client, chat_id: i64, title: &str) -> Result<()> {
+    use grammers_tl_types as tl;
+
+    let peer = get_chat_peer(client, chat_id).await?;
+    let (_, input_channel) = channel_inputs(&peer, chat_id)?;
+
+    let request = tl::functions::channels::EditTitle {
+        channel: input_channel,
+        title: title.to_string(),
+    };
+    match client.invoke(&request).await {
+        Ok(_) => Ok(()),
+        Err(e) if format!("{:?}", e).contains("CHAT_NOT_MODIFIED") => Ok(()),
+        Err(e) => Err(anyhow::anyhow!("Failed to set channel title: {:?}", e)),
+    }
+}
+
+/// What the current user is and may do in a channel
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelPermissions {
+    pub member: bool,
+    pub creator: bool,
+    pub admin: bool,
+    pub can_post: bool,
+    pub can_edit: bool,         // Edit others' messages, e.g. captions of files uploaded elsewhere
+    pub can_delete: bool,
+    pub can_change_info: bool,  // Title and photo
+}
+
+/// Look up the current user's participant record in a channel (`channels.getParticipant`).
+/// Not being a participant isn't an error; it comes back as a non-member with no rights.
+/// Rights a plain supergroup member gets from the group's defaults aren't included;
+/// `check_channel_access` covers posting for those.
+pub async fn channel_permissions(client: &Client, chat_id: i64) -> Result<ChannelPermissions> {
+    use grammers_tl_types as tl;
+
+    let peer = get_chat_peer(client, chat_id).await?;
+    let (_, input_channel) = channel_inputs(&peer, chat_id)?;
+
+    let request = tl::functions::channels::GetParticipant {
+        channel: input_channel,
+        participant: tl::enums::InputPeer::PeerSelf,
+    };
+    let participant = match client.invoke(&request).await {
+        Ok(tl::enums::channels::ChannelParticipant::Participant(p)) => p.participant,
+        Err(e) => {
+            let error = format!("{:?}", e);
+            if error.contains("USER_NOT_PARTICIPANT") || error.contains("CHANNEL_PRIVATE") {
+                return Ok(ChannelPermissions::default());
+            }
+            return Err(anyhow::anyhow!("Failed to fetch channel membership: {}", error));
+        }
+    };
+
+    let admin_rights = |rights: &tl::enums::ChatAdminRights| match rights {
+        tl::enums::ChatAdminRights::Rights(r) => ChannelPermissions {
+            member: true,
+            creator: false,
+            admin: true,
+            can_post: r.post_messages,
+            can_edit: r.edit_messages,
+            can_delete: r.delete_messages,
+            can_change_info: r.change_info,
+        },
+    };
+
+    Ok(match participant {
+        tl::enums::ChannelParticipant::Creator(_) => ChannelPermissions {
+            member: true,
+            creator: true,
+            admin: true,
+            can_post: true,
+            can_edit: true,
+            can_delete: true,
+            can_change_info: true,
+        },
+        tl::enums::ChannelParticipant::Admin(a) => admin_rights(&a.admin_rights),
+        tl::enums::ChannelParticipant::Participant(_) | tl::enums::ChannelParticipant::ParticipantSelf(_) => {
+            ChannelPermissions { member: true, ..Default::default() }
+        }
+        tl::enums::ChannelParticipant::Banned(_) | tl::enums::ChannelParticipant::Left(_) => ChannelPermissions::default(),
+    })
+}
+
+/// Raw id of any dialog peer (user, basic group, supergroup or channel)
+fn peer_raw_id(peer: &Peer) -> Option<i64> {
+    use grammers_tl_types as tl;
+
+    match peer {
+        Peer::User(u) => Some(u.raw.id),
+        Peer::Group(g) => match &g.raw {
+            tl::enums::Chat::Chat(c) => Some(c.id),
+            tl::enums::Chat::Channel(c) => Some(c.id),
+            _ => None,
+        },
+        Peer::Channel(c) => Some(c.raw.id),
+    }
+}
+
+/// Resolve any chat the user has a dialog with (contact, group or channel) by raw id.
+/// Unlike `get_chat_peer` this isn't limited to channels, so it can target arbitrary chats.
+pub async fn get_dialog_peer(client: &Client, chat_id: i64) -> Result<Peer> {
+    if let Some(peer) = PEER_CACHE.lock().await.get(&chat_id).cloned() {
+        return Ok(peer);
+    }
+
+    let config = crate::config::AppConfig::load().await;
+    let limit = config.max_dialogs_second_pass.max(config.max_dialogs_to_search).max(1);
+
+    let mut dialogs = client.iter_dialogs();
+    let mut count = 0;
+
+    while let Some(dialog) = dialogs.next().await
+        .map_err(|e| anyhow::anyhow!("Failed to iterate dialogs: {:?}", e))? {
+        count += 1;
+        if count > limit {
+            break;
+        }
+
+        if peer_raw_id(&dialog.peer) == Some(chat_id) {
+            PEER_CACHE.lock().await.insert(chat_id, dialog.peer.clone());
+            return Ok(dialog.peer.clone());
+        }
+    }
+
+    Err(anyhow::anyhow!("Chat with ID {} not found in your recent chats", chat_id))
+}
+
+/// Drop a cached peer (e.g. after its channel was deleted)
+pub async fn forget_chat_peer(chat_id: i64) {
+    PEER_CACHE.lock().await.remove(&chat_id);
+}
+
+/// Readable errors for the QR login calls
+fn qr_login_error(e: impl std::fmt::Debug) -> anyhow::Error {
+    let error_str = format!("{:?}", e);
+    if error_str.contains("SESSION_PASSWORD_NEEDED") {
+        anyhow::anyhow!("2FA password required - please disable 2FA temporarily")
+    } else if error_str.contains("AUTH_TOKEN_EXPIRED") {
+        anyhow::anyhow!("The login code has expired. Request a new one.")
+    } else {
+        anyhow::anyhow!("QR login failed: {}", error_str)
+    }
+}
+
 /// Test if a client connection is still valid by making a lightweight API call
 pub async fn test_client_connection(client: &Client) -> bool {
     // Use get_me which is a lightweight API call
@@ -392,3 +1201,82 @@ pub async fn test_client_connection(client: &Client) -> bool {
         }
     }
 }
+
+/// Round-trip times of repeated `help.getConfig` calls, in milliseconds
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LatencyReport {
+    pub samples_ms: Vec<u64>,
+    pub failures: u32,
+    pub min_ms: Option<u64>,
+    pub avg_ms: Option<u64>,
+    pub max_ms: Option<u64>,
+    pub dc_id: Option<i32>,
+}
+
+/// Measure latency to the current DC with `samples` lightweight calls.
+/// Each call gets the same 10s timeout as `test_client_connection`; failed or timed out
+/// calls are counted but not included in the statistics.
+pub async fn ping_telegram(client: &Client, samples: u32) -> LatencyReport {
+    use grammers_tl_types as tl;
+
+    let mut report = LatencyReport::default();
+
+    for _ in 0..samples {
+        let started = std::time::Instant::now();
+        match tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.invoke(&tl::functions::help::GetConfig {})
+        ).await {
+            Ok(Ok(tl::enums::Config::Config(config))) => {
+                report.samples_ms.push(started.elapsed().as_millis() as u64);
+                report.dc_id = Some(config.this_dc);
+            }
+            Ok(Err(e)) => {
+                println!("Latency probe failed: {:?}", e);
+                report.failures += 1;
+            }
+            Err(_) => {
+                println!("Latency probe timed out");
+                report.failures += 1;
+            }
+        }
+    }
+
+    if !report.samples_ms.is_empty() {
+        report.min_ms = report.samples_ms.iter().min().copied();
+        report.max_ms = report.samples_ms.iter().max().copied();
+        report.avg_ms = Some(report.samples_ms.iter().sum::<u64>() / report.samples_ms.len() as u64);
+    }
+
+    report
+}
+
+/// Forward a single message between chats server-side, without re-transferring the file.
+/// Returns the id of the new message in the destination chat.
+pub async fn forward_message(
+    client: &Client,
+    source: &Peer,
+    destination: &Peer,
+    message_id: i32,
+) -> Result<i32> {
+    let source_ref = source.to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get source peer reference"))?;
+    let destination_ref = destination.to_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get destination peer reference"))?;
+
+    let forwarded = client.forward_messages(destination_ref, &[message_id], source_ref).await
+        .map_err(|e| anyhow::anyhow!("Failed to forward message: {}", e))?;
+
+    forwarded.into_iter()
+        .flatten()
+        .next()
+        .map(|m| m.id())
+        .ok_or_else(|| anyhow::anyhow!("Telegram did not return the forwarded message"))
+}
+
+/// Check if a forward failed because the source chat has content protection enabled
+pub fn is_forward_restricted(error_str: &str) -> bool {
+    let error_lower = error_str.to_lowercase();
+    error_lower.contains("chat_forwards_restricted") || error_lower.contains("forwards_restricted")
+}
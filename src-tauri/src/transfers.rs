@@ -0,0 +1,467 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::bandwidth::Direction;
+
+pub const MAX_CONCURRENT_TRANSFERS: usize = 10;
+const INITIAL_UPLOAD_DELAY_MS: u64 = 500;
+const FLOOD_WAIT_DELAY_MS: u64 = 2000;    // Least delay right after a flood wait
+const UPLOAD_DELAY_QUIET_SECS: u64 = 120; // Flood-free time before the delay starts shrinking
+const UPLOAD_DELAY_JITTER_MS: u64 = 500;
+const MIN_AUTO_DOWNLOAD_BUFFER: u64 = 4 * 1024 * 1024;
+const MAX_AUTO_DOWNLOAD_BUFFER: u64 = 256 * 1024 * 1024;
+const AUTO_DOWNLOAD_BUFFER_SHARE: u64 = 32; // Automatic cap = available memory / this
+
+lazy_static! {
+    // One budget per direction, shared by single, batch and background transfers
+    static ref DOWNLOADS: TransferLimiter = TransferLimiter::default();
+    static ref UPLOADS: TransferLimiter = TransferLimiter::default();
+    // Downloaded parts waiting in memory, across every download running in parallel
+    pub static ref DOWNLOAD_BUFFER: ByteBudget = ByteBudget::default();
+    static ref UPLOAD_PACING: std::sync::Mutex<UploadPacing> = std::sync::Mutex::new(UploadPacing {
+        delay_ms: INITIAL_UPLOAD_DELAY_MS,
+        last_flood_wait: None,
+    });
+}
+
+// Identifies a queue entry even when two transfers share an operation id
+static NEXT_QUEUE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// Pause between uploads. Doubles after every flood wait and shrinks again once
+// Telegram has been quiet for a while, always within the configured bounds.
+struct UploadPacing {
+    delay_ms: u64,
+    last_flood_wait: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadDelayStatus {
+    pub enabled: bool,
+    pub delay_ms: u64,                        // Before jitter
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub secs_since_flood_wait: Option<u64>,   // None = none seen since the app started
+}
+
+// A transfer waiting for a slot. Its operation id is the one the UI already tracks the
+// transfer by: the file path for uploads (the URL for URL uploads), the file id for downloads.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedTransfer {
+    pub operation_id: String,
+    pub direction: &'static str,  // "upload" or "download"
+    pub name: String,
+    pub queued_at: i64,
+    #[serde(skip)]
+    seq: u64,
+}
+
+// Counts running transfers and queues the ones waiting. Slots go to the queue in order,
+// so moving an entry to the front (prioritize) decides who starts next.
+#[derive(Default)]
+struct TransferLimiter {
+    state: std::sync::Mutex<LimiterState>,
+    released: Notify,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    active: usize,
+    queued: Vec<QueuedTransfer>,  // In the order they'll start
+    paused: bool,                 // Nothing new starts; running transfers go on
+}
+
+impl TransferLimiter {
+    fn state(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Wait for a free slot, queued behind whoever already waits. `limit` is asked again on
+    // every attempt. None if the entry was taken off the queue while waiting.
+    async fn acquire<F, Fut>(&'static self, direction: Direction, operation_id: &str, name: &str, limit: F) -> Option<TransferPermit>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = usize>,
+    {
+        let mut slot: Option<QueueSlot> = None;
+        loop {
+            // Register interest before checking, so a release in between isn't missed
+            let released = self.released.notified();
+            let limit = limit().await;
+            {
+                let mut state = self.state();
+                // A newcomer lines up behind everyone already waiting
+                let position = match slot {
+                    Some(ref slot) => state.queued.iter().position(|q| q.seq == slot.seq)?,
+                    None => state.queued.len(),
+                };
+                if !state.paused && state.active + position < limit {
+                    state.active += 1;
+                    if slot.is_some() {
+                        // Unlisted under the same lock, so nobody counts it as still waiting
+                        state.queued.remove(position);
+                    }
+                    return Some(TransferPermit { limiter: self });
+                }
+                if slot.is_none() {
+                    let seq = NEXT_QUEUE_SEQ.fetch_add(1, Ordering::SeqCst);
+                    state.queued.push(QueuedTransfer {
+                        operation_id: operation_id.to_string(),
+                        direction: match direction {
+                            Direction::Upload => "upload",
+                            Direction::Download => "download",
+                        },
+                        name: name.to_string(),
+                        queued_at: chrono::Utc::now().timestamp(),
+                        seq,
+                    });
+                    slot = Some(QueueSlot { limiter: self, seq });
+                }
+            }
+            released.await;
+        }
+    }
+
+    // Move this id's waiting entries to the front, keeping their order among themselves.
+    // False if none is waiting, e.g. because it's already running.
+    fn prioritize(&self, operation_id: &str) -> bool {
+        let moved = {
+            let mut state = self.state();
+            let (mut front, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut state.queued)
+                .into_iter()
+                .partition(|q| q.operation_id == operation_id);
+            let moved = !front.is_empty();
+            front.extend(rest);
+            state.queued = front;
+            moved
+        };
+        if moved {
+            self.released.notify_waiters();
+        }
+        moved
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.state().paused = paused;
+        if !paused {
+            self.released.notify_waiters();
+        }
+    }
+}
+
+// Held for the duration of one transfer; frees the slot when dropped
+pub struct TransferPermit {
+    limiter: &'static TransferLimiter,
+}
+
+impl Drop for TransferPermit {
+    fn drop(&mut self) {
+        {
+            let mut state = self.limiter.state();
+            state.active = state.active.saturating_sub(1);
+        }
+        self.limiter.released.notify_waiters();
+    }
+}
+
+// Keeps a waiting transfer listed until its wait ends, however it ends
+struct QueueSlot {
+    limiter: &'static TransferLimiter,
+    seq: u64,
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        let removed = {
+            let mut state = self.limiter.state();
+            let before = state.queued.len();
+            state.queued.retain(|q| q.seq != self.seq);
+            state.queued.len() != before
+        };
+        // Those behind a transfer that gave up waiting move up
+        if removed {
+            self.limiter.released.notify_waiters();
+        }
+    }
+}
+
+fn limiter(direction: Direction) -> &'static TransferLimiter {
+    match direction {
+        Direction::Upload => &UPLOADS,
+        Direction::Download => &DOWNLOADS,
+    }
+}
+
+async fn current_limit(direction: Direction) -> usize {
+    let config = crate::config::AppConfig::load().await;
+    let limit = match direction {
+        Direction::Upload => config.max_concurrent_uploads,
+        Direction::Download => config.max_concurrent_downloads,
+    };
+    limit.clamp(1, MAX_CONCURRENT_TRANSFERS)
+}
+
+// Wait for a free slot in the given direction. A transfer that has to wait is listed
+// under `operation_id` meanwhile; None if it was taken off the queue with cancel_queued.
+pub async fn acquire(direction: Direction, operation_id: &str, name: &str) -> Option<TransferPermit> {
+    limiter(direction).acquire(direction, operation_id, name, || current_limit(direction)).await
+}
+
+// Transfers waiting for a slot, uploads first, each direction in the order they'll start
+pub fn queued() -> Vec<QueuedTransfer> {
+    [&*UPLOADS, &*DOWNLOADS]
+        .iter()
+        .flat_map(|limiter| limiter.state().queued.clone())
+        .collect()
+}
+
+// Take every waiting transfer with this id off the queue; they fail without starting.
+// Running transfers aren't affected. Returns false if none was waiting.
+pub fn cancel_queued(operation_id: &str) -> bool {
+    let mut removed = false;
+    for limiter in [&*UPLOADS, &*DOWNLOADS] {
+        {
+            let mut state = limiter.state();
+            let before = state.queued.len();
+            state.queued.retain(|q| q.operation_id != operation_id);
+            removed |= state.queued.len() != before;
+        }
+        limiter.released.notify_waiters();
+    }
+    removed
+}
+
+// Let a waiting transfer start before everything else queued in its direction.
+// A transfer that's already running is left alone; returns false then.
+pub fn prioritize(operation_id: &str) -> bool {
+    let uploads = UPLOADS.prioritize(operation_id);
+    let downloads = DOWNLOADS.prioritize(operation_id);
+    uploads || downloads
+}
+
+// Hold every new transfer in the queue (pause) or let the queue run again
+pub fn set_queue_paused(paused: bool) {
+    UPLOADS.set_paused(paused);
+    DOWNLOADS.set_paused(paused);
+}
+
+pub fn queue_paused() -> bool {
+    UPLOADS.state().paused
+}
+
+// Take every waiting transfer off the queue; they fail without starting
+pub fn clear_queue() {
+    for limiter in [&*UPLOADS, &*DOWNLOADS] {
+        limiter.state().queued.clear();
+        limiter.released.notify_waiters();
+    }
+}
+
+// Wake queued transfers after the limit was raised
+pub fn limits_changed() {
+    DOWNLOADS.released.notify_waiters();
+    UPLOADS.released.notify_waiters();
+    DOWNLOAD_BUFFER.released.notify_waiters();
+}
+
+// Counts bytes held in memory. The cap is passed on every acquire, like the transfer
+// limit, so a changed setting applies without a restart.
+#[derive(Default)]
+pub struct ByteBudget {
+    in_use: std::sync::Mutex<u64>,
+    released: Notify,
+}
+
+// Held while a part is in memory; returns its bytes to the budget when dropped
+pub struct BufferPermit<'a> {
+    budget: &'a ByteBudget,
+    bytes: u64,
+}
+
+impl Drop for BufferPermit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut in_use) = self.budget.in_use.lock() {
+            *in_use = in_use.saturating_sub(self.bytes);
+        }
+        self.budget.released.notify_waiters();
+    }
+}
+
+impl ByteBudget {
+    // Wait until `bytes` more fit under `cap`. A request larger than the cap goes
+    // through once nothing else is held, so it can't wait forever.
+    pub async fn acquire(&self, bytes: u64, cap: u64) -> BufferPermit<'_> {
+        loop {
+            let released = self.released.notified();
+            {
+                let mut in_use = self.in_use.lock().unwrap_or_else(|e| e.into_inner());
+                if *in_use == 0 || *in_use + bytes <= cap {
+                    *in_use += bytes;
+                    return BufferPermit { budget: self, bytes };
+                }
+            }
+            released.await;
+        }
+    }
+
+    pub fn in_use(&self) -> u64 {
+        *self.in_use.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Download buffer cap when none is configured: a small share of the memory available right now
+pub fn auto_download_buffer_bytes() -> u64 {
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    (system.available_memory() / AUTO_DOWNLOAD_BUFFER_SHARE)
+        .clamp(MIN_AUTO_DOWNLOAD_BUFFER, MAX_AUTO_DOWNLOAD_BUFFER)
+}
+
+fn grow_delay(delay_ms: u64, max_ms: u64) -> u64 {
+    delay_ms.saturating_mul(2).max(FLOOD_WAIT_DELAY_MS).min(max_ms)
+}
+
+fn shrink_delay(delay_ms: u64, min_ms: u64) -> u64 {
+    (delay_ms * 3 / 4).max(min_ms)
+}
+
+// The delay for the upload finishing at `now`, shrinking the next one if Telegram has been
+// quiet long enough
+fn take_delay(pacing: &mut UploadPacing, min_ms: u64, max_ms: u64, now: Instant) -> u64 {
+    let quiet = pacing.last_flood_wait
+        .map_or(true, |at| now.duration_since(at) >= Duration::from_secs(UPLOAD_DELAY_QUIET_SECS));
+    let delay_ms = pacing.delay_ms.clamp(min_ms, max_ms);
+    pacing.delay_ms = if quiet { shrink_delay(delay_ms, min_ms) } else { delay_ms };
+    delay_ms
+}
+
+// Telegram made an upload wait; slow down the ones that follow
+pub async fn record_flood_wait(wait_secs: u64) {
+    let (_, max_ms) = crate::config::AppConfig::load().await.upload_delay_bounds();
+    let mut pacing = UPLOAD_PACING.lock().unwrap_or_else(|e| e.into_inner());
+    pacing.delay_ms = grow_delay(pacing.delay_ms, max_ms);
+    pacing.last_flood_wait = Some(Instant::now());
+    println!("Flood wait of {}s: upload delay raised to {}ms", wait_secs, pacing.delay_ms);
+}
+
+// How long to pause after an upload (zero when disabled). Each call after a quiet
+// stretch also shrinks the delay for the next one.
+pub async fn next_upload_delay() -> Duration {
+    let config = crate::config::AppConfig::load().await;
+    if !config.upload_delay_enabled {
+        return Duration::ZERO;
+    }
+    let (min_ms, max_ms) = config.upload_delay_bounds();
+
+    let delay_ms = {
+        let mut pacing = UPLOAD_PACING.lock().unwrap_or_else(|e| e.into_inner());
+        take_delay(&mut pacing, min_ms, max_ms, Instant::now())
+    };
+
+    // Jitter keeps parallel uploads from finishing their pauses in lockstep
+    let jitter_ms = if delay_ms > 0 { rand::random::<u64>() % UPLOAD_DELAY_JITTER_MS } else { 0 };
+    Duration::from_millis(delay_ms + jitter_ms)
+}
+
+pub async fn upload_delay_status() -> UploadDelayStatus {
+    let config = crate::config::AppConfig::load().await;
+    let (min_ms, max_ms) = config.upload_delay_bounds();
+    let pacing = UPLOAD_PACING.lock().unwrap_or_else(|e| e.into_inner());
+    UploadDelayStatus {
+        enabled: config.upload_delay_enabled,
+        delay_ms: if config.upload_delay_enabled { pacing.delay_ms.clamp(min_ms, max_ms) } else { 0 },
+        min_delay_ms: min_ms,
+        max_delay_ms: max_ms,
+        secs_since_flood_wait: pacing.last_flood_wait.map(|at| at.elapsed().as_secs()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_limiter() -> &'static TransferLimiter {
+        Box::leak(Box::default())
+    }
+
+    async fn wait_until_queued(limiter: &TransferLimiter, count: usize) {
+        while limiter.state().queued.len() < count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prioritized_transfer_starts_next() {
+        let limiter = test_limiter();
+        let running = limiter.acquire(Direction::Download, "a", "a", || async { 1 }).await.unwrap();
+
+        let (started_tx, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
+        for (index, id) in ["b", "c", "d"].into_iter().enumerate() {
+            let started_tx = started_tx.clone();
+            tokio::spawn(async move {
+                let permit = limiter.acquire(Direction::Download, id, id, || async { 1 }).await;
+                started_tx.send(id).unwrap();
+                // Hold the slot a moment so the next start is a separate step
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                drop(permit);
+            });
+            wait_until_queued(limiter, index + 1).await;
+        }
+
+        assert!(!limiter.prioritize("a"), "running transfers aren't queued");
+        assert!(limiter.prioritize("d"));
+        let order: Vec<String> = limiter.state().queued.iter().map(|q| q.operation_id.clone()).collect();
+        assert_eq!(order, ["d", "b", "c"]);
+
+        drop(running);
+        assert_eq!(started_rx.recv().await, Some("d"));
+        assert_eq!(started_rx.recv().await, Some("b"));
+        assert_eq!(started_rx.recv().await, Some("c"));
+    }
+
+    #[test]
+    fn test_upload_delay_grows_and_shrinks() {
+        // Doubles on a flood wait, but never below the post-flood floor or above the max
+        assert_eq!(grow_delay(1500, 60_000), 3000);
+        assert_eq!(grow_delay(100, 60_000), FLOOD_WAIT_DELAY_MS);
+        assert_eq!(grow_delay(40_000, 60_000), 60_000);
+        assert_eq!(grow_delay(u64::MAX, 60_000), 60_000);
+        // Shrinks by a quarter, down to the min
+        assert_eq!(shrink_delay(4000, 500), 3000);
+        assert_eq!(shrink_delay(600, 500), 500);
+
+        let flood_at = Instant::now();
+        let mut pacing = UploadPacing { delay_ms: 4000, last_flood_wait: Some(flood_at) };
+        // Right after a flood wait the delay holds
+        assert_eq!(take_delay(&mut pacing, 500, 60_000, flood_at + Duration::from_secs(10)), 4000);
+        assert_eq!(pacing.delay_ms, 4000);
+        // After the quiet period each upload shrinks it for the next
+        let quiet = flood_at + Duration::from_secs(UPLOAD_DELAY_QUIET_SECS);
+        assert_eq!(take_delay(&mut pacing, 500, 60_000, quiet), 4000);
+        assert_eq!(take_delay(&mut pacing, 500, 60_000, quiet), 3000);
+        // A lowered max applies at once
+        assert_eq!(take_delay(&mut pacing, 500, 1000, quiet), 1000);
+        assert_eq!(pacing.delay_ms, 750);
+    }
+
+    #[tokio::test]
+    async fn test_paused_queue_holds_new_transfers() {
+        let limiter = test_limiter();
+        limiter.set_paused(true);
+
+        let (started_tx, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let permit = limiter.acquire(Direction::Upload, "a", "a", || async { 2 }).await;
+            started_tx.send(permit.is_some()).unwrap();
+        });
+        wait_until_queued(limiter, 1).await;
+        assert!(started_rx.try_recv().is_err(), "a free slot isn't handed out while paused");
+
+        limiter.set_paused(false);
+        assert_eq!(started_rx.recv().await, Some(true));
+    }
+}